@@ -0,0 +1,22 @@
+//! With the "capi" feature, regenerate `include/matcher.h` from `src/capi.rs`.
+
+fn main() {
+    #[cfg(feature = "capi")]
+    {
+        println!("cargo:rerun-if-changed=src/capi.rs");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        cbindgen::Builder::new()
+            .with_config(cbindgen::Config {
+                usize_is_size_t: true,
+                ..Default::default()
+            })
+            .with_src(format!("{dir}/src/capi.rs"))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("MATCHER_H")
+            .with_cpp_compat(true)
+            .with_header("/* Generated by cbindgen from src/capi.rs; do not edit. */")
+            .generate()
+            .expect("generate include/matcher.h")
+            .write_to_file(format!("{dir}/include/matcher.h"));
+    }
+}
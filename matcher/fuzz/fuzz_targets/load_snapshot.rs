@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// Any byte string must load or fail with an error: no panic, no abort.
+fuzz_target!(|data: &[u8]| {
+    let _ = matcher::matching::load_snapshot_from(Cursor::new(data), "<fuzz>");
+});
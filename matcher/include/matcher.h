@@ -0,0 +1,134 @@
+/* Generated by cbindgen from src/capi.rs; do not edit. */
+
+#ifndef MATCHER_H
+#define MATCHER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The pairs of one `matcher_run_all_pairs` (opaque).
+ */
+typedef struct MatcherResults MatcherResults;
+
+/**
+ * A loaded snapshot (opaque).
+ */
+typedef struct MatcherSnapshot MatcherSnapshot;
+
+/**
+ * One pair of a `MatcherResults`, as `matcher_result_get` fills it in. The
+ * key pointers stay valid until the results are freed.
+ */
+typedef struct MatcherPair {
+  const int32_t *key_left;
+  size_t key_left_len;
+  const int32_t *key_right;
+  size_t key_right_len;
+  size_t rows1;
+  size_t rows2;
+  double subtotal;
+  /**
+   * Subtotal if every row pair were compatible.
+   */
+  double weight_mass;
+  /**
+   * Seconds spent on the pair.
+   */
+  double t_total;
+  /**
+   * Left out by a `MATCHER_MAX_PAIR_*` limit (subtotal 0).
+   */
+  bool skipped;
+} MatcherPair;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message of the last failed call on this thread, or NULL if none failed.
+ * Valid until the next failing call on the thread.
+ */
+const char *matcher_last_error(void);
+
+/**
+ * Load a snapshot or snapshot view from `path` (NUL-terminated, UTF-8).
+ * Returns NULL on failure. Free with `matcher_snapshot_free`.
+ *
+ * # Safety
+ * `path` must be NULL or point to a NUL-terminated string.
+ */
+struct MatcherSnapshot *matcher_snapshot_load(const char *path);
+
+/**
+ * Number of buckets of `snap` (0 for NULL).
+ *
+ * # Safety
+ * `snap` must be NULL or a live snapshot from `matcher_snapshot_load`.
+ */
+size_t matcher_snapshot_num_buckets(const struct MatcherSnapshot *snap);
+
+/**
+ * Free a snapshot; NULL is ignored.
+ *
+ * # Safety
+ * `snap` must be NULL or a snapshot from `matcher_snapshot_load` not freed
+ * before.
+ */
+void matcher_snapshot_free(struct MatcherSnapshot *snap);
+
+/**
+ * Match every pair of `snap` on `n_threads` threads (0: one per core, or
+ * `RAYON_NUM_THREADS`). Returns NULL on failure. Free with
+ * `matcher_results_free`; the snapshot may be freed first.
+ *
+ * # Safety
+ * `snap` must be NULL or a live snapshot from `matcher_snapshot_load`.
+ */
+struct MatcherResults *matcher_run_all_pairs(const struct MatcherSnapshot *snap, size_t n_threads);
+
+/**
+ * Number of pairs in `res` (0 for NULL).
+ *
+ * # Safety
+ * `res` must be NULL or live results from `matcher_run_all_pairs`.
+ */
+size_t matcher_results_len(const struct MatcherResults *res);
+
+/**
+ * Omega: the pair subtotals summed in pair order, as the binary prints it
+ * (0 for NULL).
+ *
+ * # Safety
+ * `res` must be NULL or live results from `matcher_run_all_pairs`.
+ */
+double matcher_results_omega(const struct MatcherResults *res);
+
+/**
+ * Fill `*out` with pair `i` of `res`. Returns 0, or -1 when `i` is out of
+ * range or a pointer is NULL.
+ *
+ * # Safety
+ * `res` must be NULL or live results from `matcher_run_all_pairs`, and `out`
+ * NULL or writable.
+ */
+int matcher_result_get(const struct MatcherResults *res, size_t i, struct MatcherPair *out);
+
+/**
+ * Free results; NULL is ignored.
+ *
+ * # Safety
+ * `res` must be NULL or results from `matcher_run_all_pairs` not freed
+ * before.
+ */
+void matcher_results_free(struct MatcherResults *res);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MATCHER_H */
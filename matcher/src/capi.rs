@@ -0,0 +1,233 @@
+//! C API (feature "capi"), so C, C++, Fortran or Julia codes can load a
+//! snapshot and match it in process. The header `include/matcher.h` is
+//! generated from this file by cbindgen when the feature is built.
+//!
+//! ```c
+//! MatcherSnapshot *snap = matcher_snapshot_load("cjpt8_snapshot.npz");
+//! if (!snap) { fprintf(stderr, "%s\n", matcher_last_error()); return 1; }
+//! MatcherResults *res = matcher_run_all_pairs(snap, 0);
+//! MatcherPair p;
+//! for (size_t i = 0; i < matcher_results_len(res); i++) {
+//!     matcher_result_get(res, i, &p);
+//!     printf("%zu x %zu rows: %g\n", p.rows1, p.rows2, p.subtotal);
+//! }
+//! printf("Omega = %.17g\n", matcher_results_omega(res));
+//! matcher_results_free(res);
+//! matcher_snapshot_free(snap);
+//! ```
+//!
+//! Functions that can fail return NULL (or -1) and leave the reason for
+//! `matcher_last_error`, per thread; a panic inside the matcher is reported
+//! the same way instead of unwinding into the caller. Settings come from the
+//! `ENUM_*` / `MATCHER_*` environment variables, as for the binary.
+
+use anyhow::{Result, anyhow};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr;
+
+use crate::matching::types::Snapshot;
+use crate::matching::{self, MatchConfig, PairResult};
+
+/// A loaded snapshot (opaque).
+pub struct MatcherSnapshot(Snapshot);
+
+/// The pairs of one `matcher_run_all_pairs` (opaque).
+pub struct MatcherResults {
+    pairs: Vec<PairResult>,
+    omega: f64,
+}
+
+/// One pair of a `MatcherResults`, as `matcher_result_get` fills it in. The
+/// key pointers stay valid until the results are freed.
+#[repr(C)]
+pub struct MatcherPair {
+    pub key_left: *const i32,
+    pub key_left_len: usize,
+    pub key_right: *const i32,
+    pub key_right_len: usize,
+    pub rows1: usize,
+    pub rows2: usize,
+    pub subtotal: f64,
+    /// Subtotal if every row pair were compatible.
+    pub weight_mass: f64,
+    /// Seconds spent on the pair.
+    pub t_total: f64,
+    /// Left out by a `MATCHER_MAX_PAIR_*` limit (subtotal 0).
+    pub skipped: bool,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, turning an error or a panic into `None` and the thread's last
+/// error.
+fn guarded<T>(f: impl FnOnce() -> Result<T>) -> Option<T> {
+    let outcome = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let msg = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(anyhow!("panic: {}", msg))
+    });
+    match outcome {
+        Ok(v) => Some(v),
+        Err(e) => {
+            let msg = format!("{:#}", e).replace('\0', " ");
+            LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).ok());
+            None
+        }
+    }
+}
+
+/// Message of the last failed call on this thread, or NULL if none failed.
+/// Valid until the next failing call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn matcher_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+/// Load a snapshot or snapshot view from `path` (NUL-terminated, UTF-8).
+/// Returns NULL on failure. Free with `matcher_snapshot_free`.
+///
+/// # Safety
+/// `path` must be NULL or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_snapshot_load(path: *const c_char) -> *mut MatcherSnapshot {
+    guarded(|| {
+        if path.is_null() {
+            return Err(anyhow!("matcher_snapshot_load: path is NULL"));
+        }
+        // SAFETY: non-NULL and NUL-terminated per the contract.
+        let path = unsafe { CStr::from_ptr(path) }.to_str()?;
+        Ok(Box::into_raw(Box::new(MatcherSnapshot(
+            matching::load_snapshot(path)?,
+        ))))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Number of buckets of `snap` (0 for NULL).
+///
+/// # Safety
+/// `snap` must be NULL or a live snapshot from `matcher_snapshot_load`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_snapshot_num_buckets(snap: *const MatcherSnapshot) -> usize {
+    // SAFETY: NULL or live per the contract.
+    unsafe { snap.as_ref() }.map_or(0, |s| s.0.buckets.len())
+}
+
+/// Free a snapshot; NULL is ignored.
+///
+/// # Safety
+/// `snap` must be NULL or a snapshot from `matcher_snapshot_load` not freed
+/// before.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_snapshot_free(snap: *mut MatcherSnapshot) {
+    if !snap.is_null() {
+        // SAFETY: allocated by `matcher_snapshot_load`, freed once.
+        drop(unsafe { Box::from_raw(snap) });
+    }
+}
+
+/// Match every pair of `snap` on `n_threads` threads (0: one per core, or
+/// `RAYON_NUM_THREADS`). Returns NULL on failure. Free with
+/// `matcher_results_free`; the snapshot may be freed first.
+///
+/// # Safety
+/// `snap` must be NULL or a live snapshot from `matcher_snapshot_load`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_run_all_pairs(
+    snap: *const MatcherSnapshot,
+    n_threads: usize,
+) -> *mut MatcherResults {
+    guarded(|| {
+        // SAFETY: NULL or live per the contract.
+        let snap = unsafe { snap.as_ref() }
+            .ok_or_else(|| anyhow!("matcher_run_all_pairs: snapshot is NULL"))?;
+        let threads = (n_threads > 0).then_some(n_threads);
+        let (pairs, _) = matching::run_all_pairs_on(&snap.0, &MatchConfig::default(), threads)?;
+        let omega = matching::omega_sum(&pairs);
+        Ok(Box::into_raw(Box::new(MatcherResults { pairs, omega })))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Number of pairs in `res` (0 for NULL).
+///
+/// # Safety
+/// `res` must be NULL or live results from `matcher_run_all_pairs`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_results_len(res: *const MatcherResults) -> usize {
+    // SAFETY: NULL or live per the contract.
+    unsafe { res.as_ref() }.map_or(0, |r| r.pairs.len())
+}
+
+/// Omega: the pair subtotals summed in pair order, as the binary prints it
+/// (0 for NULL).
+///
+/// # Safety
+/// `res` must be NULL or live results from `matcher_run_all_pairs`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_results_omega(res: *const MatcherResults) -> f64 {
+    // SAFETY: NULL or live per the contract.
+    unsafe { res.as_ref() }.map_or(0.0, |r| r.omega)
+}
+
+/// Fill `*out` with pair `i` of `res`. Returns 0, or -1 when `i` is out of
+/// range or a pointer is NULL.
+///
+/// # Safety
+/// `res` must be NULL or live results from `matcher_run_all_pairs`, and `out`
+/// NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_result_get(
+    res: *const MatcherResults,
+    i: usize,
+    out: *mut MatcherPair,
+) -> c_int {
+    // SAFETY: NULL or live per the contract.
+    let got = guarded(|| {
+        let res = unsafe { res.as_ref() }
+            .ok_or_else(|| anyhow!("matcher_result_get: results are NULL"))?;
+        let out =
+            unsafe { out.as_mut() }.ok_or_else(|| anyhow!("matcher_result_get: out is NULL"))?;
+        let r = res.pairs.get(i).ok_or_else(|| {
+            anyhow!(
+                "matcher_result_get: pair {} out of range ({} pairs)",
+                i,
+                res.pairs.len()
+            )
+        })?;
+        *out = MatcherPair {
+            key_left: r.key_left.as_ptr(),
+            key_left_len: r.key_left.len(),
+            key_right: r.key_right.as_ptr(),
+            key_right_len: r.key_right.len(),
+            rows1: r.rows1,
+            rows2: r.rows2,
+            subtotal: r.subtotal,
+            weight_mass: r.weight_mass,
+            t_total: r.t_total,
+            skipped: r.skipped.is_some(),
+        };
+        Ok(())
+    });
+    if got.is_some() { 0 } else { -1 }
+}
+
+/// Free results; NULL is ignored.
+///
+/// # Safety
+/// `res` must be NULL or results from `matcher_run_all_pairs` not freed
+/// before.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn matcher_results_free(res: *mut MatcherResults) {
+    if !res.is_null() {
+        // SAFETY: allocated by `matcher_run_all_pairs`, freed once.
+        drop(unsafe { Box::from_raw(res) });
+    }
+}
@@ -0,0 +1,110 @@
+//! Failure injection, so checkpoint, resume and cleanup paths can be exercised
+//! deterministically instead of waiting for a real cluster failure. The hidden
+//! `--chaos <spec>` flag takes comma-separated faults:
+//!
+//! - `panic-root=<i>`: panic when enumeration reaches root i
+//! - `oom-root=<i>`: kill the process with SIGKILL at root i, as the kernel's
+//!   OOM killer would (no cleanup, nothing written)
+//! - `panic-pair=<k>` / `oom-pair=<k>`: the same when matching starts the k-th
+//!   scheduled pair (0-based, in schedule order)
+//! - `enospc-save=<b>`: fail the snapshot save with "no space left on device"
+//!   just before bucket b is written
+//!
+//! Without `--chaos` every hook is a single load of an unset `OnceLock`.
+
+use anyhow::{Result, bail};
+use std::io;
+use std::sync::OnceLock;
+
+#[derive(Default)]
+struct Faults {
+    panic_root: Option<usize>,
+    oom_root: Option<usize>,
+    panic_pair: Option<usize>,
+    oom_pair: Option<usize>,
+    enospc_save: Option<usize>,
+}
+
+static FAULTS: OnceLock<Faults> = OnceLock::new();
+
+/// Parse `spec` and arm its faults for the rest of the process.
+pub fn configure(spec: &str) -> Result<()> {
+    let mut faults = Faults::default();
+    for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((name, value)) = item.split_once('=') else {
+            bail!("--chaos {:?}: expected fault=<index>", item);
+        };
+        let Ok(at) = value.parse::<usize>() else {
+            bail!("--chaos {:?}: {:?} is not an index", item, value);
+        };
+        let slot = match name {
+            "panic-root" => &mut faults.panic_root,
+            "oom-root" => &mut faults.oom_root,
+            "panic-pair" => &mut faults.panic_pair,
+            "oom-pair" => &mut faults.oom_pair,
+            "enospc-save" => &mut faults.enospc_save,
+            _ => bail!(
+                "--chaos: unknown fault {:?} (panic-root, oom-root, panic-pair, oom-pair, enospc-save)",
+                name
+            ),
+        };
+        *slot = Some(at);
+    }
+    eprintln!("[chaos] armed: {}", spec);
+    if FAULTS.set(faults).is_err() {
+        bail!("--chaos given twice");
+    }
+    Ok(())
+}
+
+fn faults() -> Option<&'static Faults> {
+    FAULTS.get()
+}
+
+/// Enumeration is about to vacate root `i`.
+pub fn at_root(i: usize) {
+    let Some(f) = faults() else { return };
+    if f.oom_root == Some(i) {
+        kill(&format!("root {}", i));
+    }
+    if f.panic_root == Some(i) {
+        panic!("[chaos] injected panic at root {}", i);
+    }
+}
+
+/// Matching is starting scheduled pair `k`.
+pub fn at_pair(k: usize) {
+    let Some(f) = faults() else { return };
+    if f.oom_pair == Some(k) {
+        kill(&format!("pair {}", k));
+    }
+    if f.panic_pair == Some(k) {
+        panic!("[chaos] injected panic at pair {}", k);
+    }
+}
+
+/// The snapshot save is about to write bucket `b`.
+pub fn before_save_bucket(b: usize) -> io::Result<()> {
+    match faults() {
+        Some(f) if f.enospc_save == Some(b) => Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "no space left on device (injected by --chaos before bucket {})",
+                b
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// What the OOM killer does: SIGKILL, so no destructor, flush or handler runs.
+fn kill(at: &str) -> ! {
+    eprintln!("[chaos] killing the process at {} (simulated OOM)", at);
+    #[cfg(feature = "platform")]
+    // SAFETY: kill and getpid have no preconditions.
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGKILL);
+    }
+    // without `platform`, or should SIGKILL somehow return
+    std::process::abort()
+}
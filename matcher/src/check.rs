@@ -0,0 +1,222 @@
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use crate::compare::print_report;
+use crate::enumeration::spill;
+use crate::human::Secs;
+use crate::matching::{load_results_json, results_equivalent};
+
+/// Lines of a failed fixture run's stderr echoed in the failure message.
+const STDERR_TAIL: usize = 20;
+
+/// One entry of the baseline's `fixtures` list.
+struct Fixture {
+    name: String,
+    input: PathBuf,
+    env: Vec<(String, String)>,
+    results: PathBuf,
+}
+
+/// Read the baseline at `path`: `{"fixtures": [{"name", "input", "env",
+/// "results"}, ...]}`, with `input` and `results` relative to the baseline's
+/// directory and `env` (optional) a map of settings the fixture runs under.
+fn load_fixtures(path: &Path) -> Result<Vec<Fixture>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let doc: Value =
+        serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let list = doc
+        .get("fixtures")
+        .and_then(Value::as_array)
+        .with_context(|| {
+            format!(
+                "{}: expected an object with a \"fixtures\" array",
+                path.display()
+            )
+        })?;
+    if list.is_empty() {
+        bail!("{}: no fixtures listed", path.display());
+    }
+    list.iter()
+        .enumerate()
+        .map(|(i, e)| {
+            let field = |k: &str| {
+                e.get(k).and_then(Value::as_str).with_context(|| {
+                    format!("{}: fixture {} has no {:?} path", path.display(), i, k)
+                })
+            };
+            let input = dir.join(field("input")?);
+            let env = match e.get("env") {
+                None => Vec::new(),
+                Some(Value::Object(m)) => m
+                    .iter()
+                    .map(|(k, v)| match v {
+                        Value::String(s) => Ok((k.clone(), s.clone())),
+                        Value::Number(n) => Ok((k.clone(), n.to_string())),
+                        _ => bail!(
+                            "{}: fixture {} sets {} to {}; use a string or number",
+                            path.display(),
+                            i,
+                            k,
+                            v
+                        ),
+                    })
+                    .collect::<Result<_>>()?,
+                Some(other) => bail!(
+                    "{}: fixture {} env is {}, expected an object",
+                    path.display(),
+                    i,
+                    other
+                ),
+            };
+            Ok(Fixture {
+                name: e
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| input.display().to_string()),
+                results: dir.join(field("results")?),
+                input,
+                env,
+            })
+        })
+        .collect()
+}
+
+/// Enumerate and match `fx.input` in a child process under `fx.env`, writing
+/// its results to `out`.
+fn run_fixture(fx: &Fixture, out: &Path) -> Result<()> {
+    let exe = std::env::current_exe().context("locate matcher binary")?;
+    let snapshot = spill::spill_path(&format!("check_{}.npz", std::process::id()));
+    let output = Command::new(exe)
+        .arg(&fx.input)
+        .arg(&snapshot)
+        .arg("--force-enumerate")
+        .arg("--results-json")
+        .arg(out)
+        .envs(fx.env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("spawn fixture run");
+    let _ = std::fs::remove_file(&snapshot);
+    let output = output?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<&str> = stderr.lines().collect();
+        bail!(
+            "run of {} failed ({}):\n{}",
+            fx.input.display(),
+            output.status,
+            lines[lines.len().saturating_sub(STDERR_TAIL)..].join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Fields of a results file that change from one run to the next of the same
+/// fixture: timings, the worker that ran each pair, and where the run ran.
+const VOLATILE_RUN: [&str; 7] = [
+    "args",
+    "host",
+    "cpu_model",
+    "cpus",
+    "ram_bytes",
+    "threads",
+    "job",
+];
+
+/// Write the results at `fresh` to `dest` without their volatile fields, so
+/// blessing unchanged results leaves the recorded file as it was.
+fn bless_results(fresh: &Path, dest: &Path) -> Result<()> {
+    let text =
+        std::fs::read_to_string(fresh).with_context(|| format!("read {}", fresh.display()))?;
+    let mut doc: Value =
+        serde_json::from_str(&text).with_context(|| format!("parse {}", fresh.display()))?;
+    if let Some(doc) = doc.as_object_mut() {
+        doc.remove("wall");
+        if let Some(run) = doc.get_mut("run").and_then(Value::as_object_mut) {
+            for k in VOLATILE_RUN {
+                run.remove(k);
+            }
+        }
+    }
+    for pair in doc
+        .get_mut("pairs")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+    {
+        pair.retain(|k, _| !k.starts_with("t_") && k != "worker");
+    }
+    let mut out = serde_json::to_string_pretty(&doc)?;
+    out.push('\n');
+    std::fs::write(dest, out).with_context(|| format!("write {}", dest.display()))
+}
+
+/// `matcher check --baseline golden.json`: run every fixture the baseline
+/// lists and compare its per-pair subtotals and Omega with the recorded
+/// results, relative tolerance `tol`. With `bless` the recorded results are
+/// replaced by the fresh ones instead, less their timings and host details. Returns whether every fixture passed.
+pub fn check(baseline: &Path, tol: f64, bless: bool) -> Result<bool> {
+    let fixtures = load_fixtures(baseline)?;
+    let fresh = spill::spill_path(&format!("check_{}.json", std::process::id()));
+    let mut failed = Vec::new();
+    for fx in &fixtures {
+        let t0 = Instant::now();
+        if let Err(e) = run_fixture(fx, &fresh) {
+            eprintln!("[check] {}: {:#}", fx.name, e);
+            failed.push(fx.name.as_str());
+            continue;
+        }
+        let took = Secs::from(t0.elapsed());
+        if bless {
+            bless_results(&fresh, &fx.results)?;
+            eprintln!(
+                "[check] {}: results written to {} ({})",
+                fx.name,
+                fx.results.display(),
+                took
+            );
+            continue;
+        }
+        let golden = load_results_json(&fx.results.to_string_lossy())?;
+        let now = load_results_json(&fresh.to_string_lossy())?;
+        let report = results_equivalent(&golden, &now, tol, 0.0);
+        let omega_drift = if report.omega_a != 0.0 {
+            (report.omega_delta() / report.omega_a).abs()
+        } else {
+            report.omega_b.abs()
+        };
+        let ok = report.is_equivalent() && omega_drift <= tol;
+        println!(
+            "[check] {}: {} ({} pairs, Omega drift {:.3e}, {})",
+            fx.name,
+            if ok { "ok" } else { "DRIFT" },
+            report.aligned,
+            omega_drift,
+            took
+        );
+        if !ok {
+            print_report(&report);
+            failed.push(fx.name.as_str());
+        }
+    }
+    let _ = std::fs::remove_file(&fresh);
+    if failed.is_empty() {
+        println!("[check] {} fixtures pass", fixtures.len());
+    } else {
+        println!(
+            "[check] {} of {} fixtures failed: {}",
+            failed.len(),
+            fixtures.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(failed.is_empty())
+}
@@ -0,0 +1,157 @@
+//! `matcher clean`: remove the files a killed run leaves behind. Every temp
+//! file a run writes carries its pid: frontier spills
+//! (`frontier_<pid>_<root>.spill`), the `tune` and `check` scratch snapshots
+//! (`tune_<pid>.npz`, `check_<pid>.*`) and the temp copies that snapshots,
+//! views and checkpoints are written to before the rename
+//! (`.<name>.<pid>.tmp`). A file is removed only when its process is gone.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::enumeration::spill;
+use crate::human::Bytes;
+
+/// Pid in the name of a temp file a run writes, `None` for any other file.
+fn owner_pid(name: &str) -> Option<u32> {
+    if let Some(rest) = name.strip_prefix('.')
+        && let Some(rest) = rest.strip_suffix(".tmp")
+    {
+        return rest.rsplit_once('.')?.1.parse().ok();
+    }
+    if let Some(rest) = name.strip_prefix("frontier_")
+        && let Some(rest) = rest.strip_suffix(".spill")
+    {
+        let (pid, root) = rest.split_once('_')?;
+        root.parse::<usize>().ok()?;
+        return pid.parse().ok();
+    }
+    let (stem, ext) = name.rsplit_once('.')?;
+    match (stem.split_once('_')?, ext) {
+        (("tune", pid), "npz") | (("check", pid), "npz" | "json") => pid.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "platform")]
+fn alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists.
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    found || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without `platform`, ask /proc; where there is none, treat every pid as
+/// alive so nothing in use is removed.
+#[cfg(not(feature = "platform"))]
+fn alive(pid: u32) -> bool {
+    !Path::new("/proc/self").exists() || Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Leftover temp files in `dir` whose process has exited, with their sizes.
+fn leftovers(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut found = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(found),
+        Err(e) => return Err(e).with_context(|| format!("list {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("list {}", dir.display()))?;
+        let Some(pid) = owner_pid(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        if pid == std::process::id() || alive(pid) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if meta.is_file() {
+            found.push((entry.path(), meta.len()));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// The spill directory, then `dirs` (the current directory when none).
+pub fn dirs_to_scan(dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut scan = vec![spill::spill_dir().to_path_buf()];
+    let dirs = if dirs.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        dirs
+    };
+    for dir in dirs {
+        if !scan.contains(&dir) {
+            scan.push(dir);
+        }
+    }
+    scan
+}
+
+/// Remove (or with `dry_run`, list) the leftovers in `dirs`. Returns the
+/// files and bytes found.
+pub fn clean(dirs: &[PathBuf], dry_run: bool) -> Result<(usize, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    for dir in dirs {
+        for (path, len) in leftovers(dir)? {
+            if dry_run {
+                println!("{} ({})", path.display(), Bytes(len));
+            } else {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("remove {}", path.display()))?;
+                eprintln!("[clean] removed {} ({})", path.display(), Bytes(len));
+            }
+            files += 1;
+            bytes += len;
+        }
+    }
+    Ok((files, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_carry_the_pid_of_their_run() {
+        assert_eq!(owner_pid("frontier_4242_17.spill"), Some(4242));
+        assert_eq!(owner_pid(".cjpt8_snapshot.npz.4242.tmp"), Some(4242));
+        assert_eq!(owner_pid(".run.ckpt.4242.tmp"), Some(4242));
+        assert_eq!(owner_pid("tune_4242.npz"), Some(4242));
+        assert_eq!(owner_pid("check_4242.json"), Some(4242));
+        assert_eq!(owner_pid("cjpt8_snapshot.npz"), None);
+        assert_eq!(owner_pid("frontier_4242.spill"), None);
+        assert_eq!(owner_pid("tune_x.npz"), None);
+        assert_eq!(owner_pid(".hidden.tmp"), None);
+    }
+
+    #[test]
+    fn only_files_of_exited_runs_are_removed() {
+        let dir = std::env::temp_dir().join(format!("matcher-clean-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        let me = std::process::id();
+        let gone = [
+            format!("frontier_{dead}_3.spill"),
+            format!(".out.npz.{dead}.tmp"),
+        ];
+        let kept = [
+            format!("frontier_{me}_3.spill"),
+            format!(".out.npz.{me}.tmp"),
+            "out.npz".to_string(),
+        ];
+        for name in gone.iter().chain(&kept) {
+            std::fs::write(dir.join(name), b"0123").unwrap();
+        }
+        assert_eq!(clean(std::slice::from_ref(&dir), true).unwrap(), (2, 8));
+        assert!(gone.iter().all(|name| dir.join(name).exists()));
+        clean(std::slice::from_ref(&dir), false).unwrap();
+        assert!(gone.iter().all(|name| !dir.join(name).exists()));
+        assert!(kept.iter().all(|name| dir.join(name).exists()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
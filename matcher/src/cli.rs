@@ -0,0 +1,879 @@
+//! Command line. `matcher <inputs.npz>` enumerates if needed and then matches;
+//! `enumerate`, `match` and `resume` run one step each, and the other
+//! subcommands are tools around results and snapshots. The older
+//! `matcher --resume <snapshot.npz>` is kept as a hidden alias of `match`.
+//!
+//! Parsing happens in two steps: `Args::parse` reads the arguments with clap,
+//! `main` applies `--config` / `--set` and initializes the settings, then
+//! `Args::into_cli` checks paths and builds the `Cli`, which may read settings
+//! for its defaults.
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{chaos, enumeration, matching, settings};
+
+pub enum RunMode {
+    Enumerate {
+        input: PathBuf,
+        /// Further pre-CSR parts merged into `input` (`--merge-input`).
+        parts: Vec<PathBuf>,
+        snapshot_out: PathBuf,
+    },
+    /// Match a saved snapshot (`match`, `resume`, or `--resume`).
+    Resume {
+        snapshot: PathBuf,
+    },
+    Watch {
+        dir: PathBuf,
+        out: PathBuf,
+        poll: Duration,
+    },
+    History {
+        run_id: Option<i64>,
+        limit: usize,
+    },
+    Compare {
+        a: PathBuf,
+        b: PathBuf,
+        rel_tol: f64,
+        abs_tol: f64,
+    },
+    Why {
+        a: PathBuf,
+        b: PathBuf,
+    },
+    Check {
+        baseline: PathBuf,
+        tol: f64,
+        bless: bool,
+    },
+    Scavenge {
+        spill: PathBuf,
+    },
+    Clean {
+        dirs: Vec<PathBuf>,
+        dry_run: bool,
+    },
+    Merge {
+        inputs: Vec<PathBuf>,
+    },
+    VerifySnapshot {
+        snapshot: PathBuf,
+    },
+    PlanShards {
+        snapshot: PathBuf,
+        count: usize,
+    },
+    SavePlans {
+        snapshot: PathBuf,
+        out: PathBuf,
+    },
+    PackView {
+        snapshot: PathBuf,
+        out: PathBuf,
+    },
+    Reweight {
+        snapshot: PathBuf,
+        weights: PathBuf,
+    },
+    Export {
+        snapshot: PathBuf,
+        format: matching::export::ExportFormat,
+        dir: PathBuf,
+    },
+    Estimate {
+        input: PathBuf,
+    },
+    Tune {
+        input: PathBuf,
+        slice: usize,
+        config_out: PathBuf,
+    },
+}
+
+pub struct Cli {
+    pub mode: RunMode,
+    /// Only read an input once `<input>.done` exists.
+    pub require_done: bool,
+    /// How long a direct run waits for an incomplete input before giving up.
+    pub input_wait: Duration,
+    pub pairs_file: Option<PathBuf>,
+    pub results_db: Option<PathBuf>,
+    pub results_json: Option<PathBuf>,
+    /// `--report-format csv` rows written to a file, next to the stdout report.
+    pub csv_out: Option<PathBuf>,
+    /// Where the (j, x) co-occurrence matrix goes, computed after matching.
+    pub cooccurrence_out: Option<PathBuf>,
+    /// Stored solver plans (`matcher save-plans`) for the snapshot's pairs.
+    pub plans: Option<PathBuf>,
+    /// Re-enumerate even when a fresh snapshot for the input already exists.
+    pub force_enumerate: bool,
+    /// Enumeration checkpoint to write, and to continue from.
+    pub checkpointing: enumeration::Checkpointing,
+    /// Stop after the snapshot is written (or found).
+    pub skip_match: bool,
+    /// madvise(MADV_HUGEPAGE) large buffers (`--hugepages` or `ENUM_HUGEPAGES=1`).
+    pub hugepages: bool,
+    /// Where spill files go (default: the system temp dir).
+    pub tmpdir: Option<PathBuf>,
+    /// Cap on bytes spill files may occupy at once.
+    pub max_disk_bytes: Option<u64>,
+    /// Partial results of an earlier run of the same configuration to finish.
+    pub resume_results: Option<PathBuf>,
+    /// Results of an earlier run whose pair times balance the shards.
+    pub shard_history: Option<PathBuf>,
+    pub match_cfg: matching::MatchConfig,
+}
+
+#[derive(Parser)]
+#[command(
+    name = "matcher",
+    version,
+    about = "Enumerate cjpt inputs into a bucketed snapshot and match bucket pairs into Omega.",
+    override_usage = "matcher <inputs.npz> [snapshot.npz] [options]   (enumerate if needed, then match)\n       matcher <command> [args] [options]",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    arg_required_else_help = true,
+    after_long_help = settings::help()
+)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Input to enumerate (or, with --resume, a snapshot to match)
+    #[arg(value_name = "inputs.npz", required = true)]
+    input: Option<PathBuf>,
+    /// Where the snapshot is cached (default: ENUM_SNAPSHOT_PATH, else <input>_snapshot.npz)
+    #[arg(value_name = "snapshot.npz")]
+    snapshot: Option<PathBuf>,
+    /// Older spelling of `matcher match <snapshot.npz>`
+    #[arg(long, hide = true)]
+    resume: bool,
+    /// Stop once the snapshot exists (no matching)
+    #[arg(long, visible_alias = "enumerate-only")]
+    skip_match: bool,
+    #[command(flatten)]
+    wait: WaitArgs,
+    #[command(flatten)]
+    enumerate: EnumerateArgs,
+    #[command(flatten)]
+    solve: SolveArgs,
+    #[command(flatten)]
+    shard: ShardArgs,
+    /// Skip the pairs an earlier --results-json of this configuration finished
+    #[arg(long, value_name = "results.json")]
+    resume_results: Option<PathBuf>,
+    #[command(flatten)]
+    global: GlobalArgs,
+}
+
+/// Options every command takes.
+#[derive(clap::Args)]
+struct GlobalArgs {
+    /// Read KEY=VALUE settings (e.g. from `matcher tune`); the environment wins
+    #[arg(long, global = true, value_name = "file")]
+    config: Option<PathBuf>,
+    /// Set one setting (listed below); wins over the environment and --config. Repeatable
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+    /// Back large buffers with transparent huge pages (ENUM_HUGEPAGES=1)
+    #[arg(long, global = true)]
+    hugepages: bool,
+    /// Where spill files go (default: $TMPDIR or /tmp)
+    #[arg(long, global = true, value_name = "dir")]
+    tmpdir: Option<PathBuf>,
+    /// Fail cleanly once spill files would exceed this many GiB
+    #[arg(long, global = true, value_name = "gb")]
+    max_disk_gb: Option<f64>,
+    #[arg(long, global = true, hide = true)]
+    chaos: Option<String>,
+}
+
+/// Waiting for an input another process is still writing.
+#[derive(clap::Args)]
+struct WaitArgs {
+    /// Only read an input once <input>.done exists
+    #[arg(long)]
+    require_done: bool,
+    /// Wait this long for an input still being written (default: 0)
+    #[arg(long = "wait-input", value_name = "secs", value_parser = parse_secs)]
+    wait_input: Option<Duration>,
+}
+
+#[derive(clap::Args)]
+struct EnumerateArgs {
+    /// Re-enumerate even if the snapshot is up to date with the input
+    #[arg(long)]
+    force_enumerate: bool,
+    /// Merge another pre-CSR part (same N/M, disjoint roots) into the input; repeatable
+    #[arg(long = "merge-input", value_name = "part.npz")]
+    merge_input: Vec<PathBuf>,
+    /// Checkpoint enumeration to this file every ENUM_CHECKPOINT_EVERY roots and on Ctrl-C or SIGTERM
+    #[arg(long, value_name = "file")]
+    checkpoint: Option<PathBuf>,
+    /// Continue an enumeration from its checkpoint (and keep checkpointing to it)
+    #[arg(long = "resume-enumeration", value_name = "checkpoint")]
+    resume_enumeration: Option<PathBuf>,
+    /// Enumerate at most this many columns of roots, then checkpoint and exit with status 75
+    #[arg(long = "column-block", value_name = "columns", value_parser = clap::value_parser!(u32).range(1..))]
+    column_block: Option<u32>,
+}
+
+impl EnumerateArgs {
+    fn checkpointing(&self) -> Result<enumeration::Checkpointing> {
+        let path = self
+            .checkpoint
+            .clone()
+            .or_else(|| self.resume_enumeration.clone());
+        if self.column_block.is_some() && path.is_none() {
+            bail!(
+                "--column-block needs --checkpoint (or --resume-enumeration) to hand the next block its state"
+            );
+        }
+        Ok(enumeration::Checkpointing {
+            path,
+            resume: self.resume_enumeration.clone(),
+            column_block: self.column_block.map(|cols| cols as usize),
+        })
+    }
+}
+
+/// Which pairs are planned, and how.
+#[derive(clap::Args)]
+struct PlanArgs {
+    /// Run only the listed bucket pairs
+    #[arg(long, value_name = "pairs.txt|pairs.json")]
+    pairs: Option<PathBuf>,
+    /// Neutral-bucket handling (default: pair)
+    #[arg(long, value_name = "pair|exclude|self")]
+    neutral: Option<matching::NeutralMode>,
+    /// Which pop a pop pairs with (default: n-minus, p -> N - p)
+    #[arg(long, value_name = "n-minus|same", value_parser = matching::complement_rule)]
+    complement: Option<matching::ComplementFn>,
+}
+
+#[derive(clap::Args)]
+struct SolveArgs {
+    #[command(flatten)]
+    plan: PlanArgs,
+    /// Per-pair lines and Omega summary on stdout (default: text)
+    #[arg(long, value_name = "text|jsonl|csv")]
+    report_format: Option<matching::report::ReportFormat>,
+    /// Also report Omega / (sum of pair weight products) or Omega / x
+    #[arg(long, value_name = "weights|x")]
+    normalize: Option<matching::Normalize>,
+    /// Print partial pair subtotals every <rows> bucket1 rows
+    #[arg(long, value_name = "rows")]
+    report_every: Option<usize>,
+    /// Re-run each pair sequentially and check the subtotal is bit-identical
+    #[arg(long)]
+    audit_determinism: bool,
+    /// Also count each pair in exact integer arithmetic and print the exact Omega
+    #[arg(long)]
+    exact: bool,
+    /// Count only matchings that pair jbt j of the left state with jbt x
+    #[arg(long, value_name = "j=x")]
+    given: Option<matching::Given>,
+    /// Record the run (hashes, Omega, timings, pair subtotals) in SQLite
+    #[arg(long, value_name = "runs.db")]
+    results_db: Option<PathBuf>,
+    /// Also write the per-pair rows and Omega as CSV, whatever --report-format
+    #[arg(long, value_name = "pairs.csv")]
+    csv_out: Option<PathBuf>,
+    /// Write the weighted (j, x) assignment co-occurrence matrix (sparse NPZ)
+    #[arg(long, value_name = "cooc.npz")]
+    cooccurrence_out: Option<PathBuf>,
+    /// Only accumulate weights over the pairs stored by `matcher save-plans`
+    #[arg(long, value_name = "plans.npz")]
+    plans: Option<PathBuf>,
+}
+
+/// Splitting one run into shards, and its results file.
+#[derive(clap::Args)]
+struct ShardArgs {
+    /// Run only part k of n (0-based) of the planned pairs, balanced by predicted runtime
+    #[arg(long, value_name = "k/n")]
+    shard: Option<matching::Shard>,
+    /// Balance shards with the pair times an earlier --results-json recorded
+    #[arg(long, value_name = "results.json")]
+    shard_history: Option<PathBuf>,
+    /// Write per-pair subtotals and Omega as JSON
+    #[arg(long, visible_alias = "results-out", value_name = "path")]
+    results_json: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Enumerate an input into a snapshot, without matching
+    Enumerate {
+        #[arg(value_name = "inputs.npz")]
+        input: PathBuf,
+        /// Where the snapshot goes (default: ENUM_SNAPSHOT_PATH, else <input>_snapshot.npz)
+        #[arg(long, value_name = "snapshot.npz")]
+        snapshot_out: Option<PathBuf>,
+        #[command(flatten)]
+        wait: WaitArgs,
+        #[command(flatten)]
+        enumerate: EnumerateArgs,
+    },
+    /// Match the bucket pairs of a saved snapshot
+    Match {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        #[command(flatten)]
+        solve: SolveArgs,
+        #[command(flatten)]
+        shard: ShardArgs,
+        /// Skip the pairs an earlier --results-json of this configuration finished
+        #[arg(long, value_name = "results.json")]
+        resume_results: Option<PathBuf>,
+    },
+    /// Resume matching from a saved snapshot; with <partial.json>, skip the pairs it holds
+    Resume {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        /// --results-json of an interrupted or partial match of this configuration
+        #[arg(value_name = "partial.json")]
+        partial: Option<PathBuf>,
+        #[command(flatten)]
+        solve: SolveArgs,
+        #[command(flatten)]
+        shard: ShardArgs,
+    },
+    /// Enumerate and match every input that appears in a directory
+    Watch {
+        #[arg(value_name = "incoming_dir")]
+        dir: PathBuf,
+        /// Where snapshots and Omega results are written
+        #[arg(long, value_name = "results_dir")]
+        out: PathBuf,
+        /// Directory poll interval
+        #[arg(long, value_name = "secs", default_value = "5", value_parser = parse_secs)]
+        poll: Duration,
+        /// Only read an input once <input>.done exists
+        #[arg(long)]
+        require_done: bool,
+        /// Stop once each snapshot exists (no matching)
+        #[arg(long, visible_alias = "enumerate-only")]
+        skip_match: bool,
+        #[command(flatten)]
+        solve: SolveArgs,
+    },
+    /// List the runs recorded in a --results-db, or one run's pairs
+    History {
+        run_id: Option<i64>,
+        #[arg(long, value_name = "runs.db", required = true)]
+        results_db: PathBuf,
+        /// Number of runs listed
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Compare two --results-json files pair by pair (exit status 3 if they disagree)
+    Compare {
+        #[arg(value_name = "results_a.json")]
+        a: PathBuf,
+        #[arg(value_name = "results_b.json")]
+        b: PathBuf,
+        /// Per-pair relative tolerance
+        #[arg(long, default_value_t = 1e-9)]
+        rel_tol: f64,
+        /// Per-pair absolute tolerance
+        #[arg(long, default_value_t = 0.0)]
+        abs_tol: f64,
+    },
+    /// Run the fixtures a baseline lists and compare with their recorded results (exit status 3 on drift)
+    Check {
+        #[arg(long, value_name = "golden.json")]
+        baseline: PathBuf,
+        /// Relative tolerance on per-pair subtotals and Omega
+        #[arg(long, default_value_t = 1e-9)]
+        tol: f64,
+        /// Record the fresh results as the baseline's instead of comparing
+        #[arg(long)]
+        bless: bool,
+    },
+    /// Settings that differ between two runs
+    Why {
+        #[arg(value_name = "old_results.json")]
+        a: PathBuf,
+        #[arg(value_name = "new_results.json")]
+        b: PathBuf,
+    },
+    /// Reduce shards and partial runs of one configuration
+    Merge {
+        #[arg(value_name = "results.json", required = true)]
+        inputs: Vec<PathBuf>,
+        /// Write the merged container here
+        #[arg(long, value_name = "out.json")]
+        results_json: Option<PathBuf>,
+    },
+    /// Predicted runtime of each --shard k/n
+    PlanShards {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        #[arg(value_name = "n", value_parser = clap::value_parser!(u64).range(1..))]
+        count: u64,
+        #[command(flatten)]
+        plan: PlanArgs,
+        /// Balance shards with the pair times an earlier --results-json recorded
+        #[arg(long, value_name = "results.json")]
+        shard_history: Option<PathBuf>,
+    },
+    /// Store the weight-free solver plan of every pair, for --plans
+    SavePlans {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        #[arg(value_name = "plans.npz")]
+        out: PathBuf,
+        #[command(flatten)]
+        plan: PlanArgs,
+    },
+    /// Masks-only pass: projected peak frontier, snapshot size and runtime
+    Estimate {
+        #[arg(value_name = "inputs.npz")]
+        input: PathBuf,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Pick ENUM_PEND_FLUSH and the thread count from calibration runs
+    Tune {
+        #[arg(value_name = "inputs.npz")]
+        input: PathBuf,
+        /// pre_jbt of root (0,0) enumerated per calibration run
+        #[arg(long, default_value_t = 3)]
+        slice: usize,
+        /// Config file written
+        #[arg(long, value_name = "matcher.conf", default_value = "matcher.conf")]
+        out: PathBuf,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Truncate a torn tail left by a crash mid-spill
+    Scavenge {
+        #[arg(value_name = "spill file")]
+        spill: PathBuf,
+    },
+    /// Remove the spill and temp files that killed runs left behind
+    Clean {
+        /// Directories searched besides --tmpdir (default: the current directory)
+        #[arg(value_name = "dir")]
+        dirs: Vec<PathBuf>,
+        /// List the files instead of removing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Checksums and invariants of a snapshot; lists every defect
+    VerifySnapshot {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+    },
+    /// Rewrite a snapshot as a view that matching maps instead of reading
+    PackView {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        #[arg(value_name = "snapshot.view")]
+        out: PathBuf,
+    },
+    /// Replace a snapshot's row weights (b<i>_weights members) in place
+    Reweight {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        #[arg(value_name = "new_weights.npz")]
+        weights: PathBuf,
+    },
+    /// Write a snapshot as tables (rows.parquet, buckets.parquet) or as snapshot.arrow
+    Export {
+        #[arg(value_name = "snapshot.npz")]
+        snapshot: PathBuf,
+        #[arg(value_name = "dir")]
+        dir: PathBuf,
+        /// Table format (needs the matching build feature)
+        #[arg(long, value_name = "parquet|arrow", default_value = "parquet")]
+        format: matching::export::ExportFormat,
+    },
+}
+
+fn parse_secs(v: &str) -> Result<Duration, String> {
+    v.parse::<f64>()
+        .ok()
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        .ok_or_else(|| format!("{:?} is not a number of seconds", v))
+}
+
+fn default_snapshot_path(input: &Path) -> PathBuf {
+    let parent = input
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let stem = input
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("snapshot");
+    parent.join(format!("{stem}_snapshot.npz"))
+}
+
+/// `explicit`, else `ENUM_SNAPSHOT_PATH`, else next to the input.
+fn snapshot_out(input: &Path, explicit: Option<PathBuf>) -> PathBuf {
+    explicit
+        .or_else(|| settings::get().snapshot_path.clone())
+        .unwrap_or_else(|| default_snapshot_path(input))
+}
+
+impl WaitArgs {
+    fn apply(self, cli: &mut Cli) {
+        cli.require_done = self.require_done;
+        cli.input_wait = self.wait_input.unwrap_or_default();
+    }
+}
+
+impl PlanArgs {
+    fn apply(self, cli: &mut Cli) {
+        cli.pairs_file = self.pairs;
+        if let Some(neutral) = self.neutral {
+            cli.match_cfg.neutral = neutral;
+        }
+        if let Some(complement) = self.complement {
+            cli.match_cfg.complement = complement;
+        }
+    }
+}
+
+impl SolveArgs {
+    fn apply(self, cli: &mut Cli) {
+        self.plan.apply(cli);
+        let cfg = &mut cli.match_cfg;
+        if let Some(format) = self.report_format {
+            cfg.report_format = format;
+        }
+        cfg.normalize = self.normalize;
+        cfg.report_every_rows = self.report_every;
+        cfg.audit_determinism = self.audit_determinism;
+        cfg.exact = self.exact;
+        cfg.given = self.given;
+        cli.results_db = self.results_db;
+        cli.csv_out = self.csv_out;
+        cli.cooccurrence_out = self.cooccurrence_out;
+        cli.plans = self.plans;
+    }
+}
+
+impl ShardArgs {
+    fn apply(self, cli: &mut Cli) {
+        cli.match_cfg.shard = self.shard;
+        cli.shard_history = self.shard_history;
+        cli.results_json = self.results_json;
+    }
+}
+
+impl Args {
+    /// Parse the command line, exiting with the usage error (status 1) or the
+    /// help text (status 0) when it does not describe a run.
+    pub fn parse() -> Self {
+        match <Self as Parser>::try_parse() {
+            Ok(args) => args,
+            Err(err) if err.use_stderr() => {
+                let _ = err.print();
+                std::process::exit(1);
+            }
+            Err(err) => err.exit(),
+        }
+    }
+
+    /// `--config` and `--set`, which must be applied before `settings::init`.
+    pub fn settings_sources(&self) -> (Option<&Path>, &[String]) {
+        (self.global.config.as_deref(), &self.global.set)
+    }
+
+    /// The run these arguments describe. Reads the settings, so call it after
+    /// `settings::init`.
+    pub fn into_cli(self) -> Result<Cli> {
+        let g = self.global;
+        if let Some(spec) = &g.chaos {
+            chaos::configure(spec)?;
+        }
+        let max_disk_bytes = match g.max_disk_gb {
+            Some(gb) if gb.is_nan() || gb <= 0.0 => {
+                bail!("--max-disk-gb must be positive, got {}", gb)
+            }
+            Some(gb) => Some((gb * (1u64 << 30) as f64) as u64),
+            None => None,
+        };
+        let base = |mode| Cli {
+            mode,
+            require_done: false,
+            input_wait: Duration::ZERO,
+            pairs_file: None,
+            results_db: None,
+            results_json: None,
+            csv_out: None,
+            cooccurrence_out: None,
+            plans: None,
+            force_enumerate: false,
+            checkpointing: Default::default(),
+            skip_match: false,
+            hugepages: g.hugepages || settings::get().hugepages,
+            tmpdir: g.tmpdir.clone(),
+            max_disk_bytes,
+            resume_results: None,
+            shard_history: None,
+            match_cfg: matching::MatchConfig {
+                verbose: true,
+                ..Default::default()
+            },
+        };
+
+        let Some(command) = self.command else {
+            // the pipeline: enumerate if needed, then match
+            let first = self.input.context("an input is required")?;
+            let mut checkpointing = Default::default();
+            let mut cli = if self.resume {
+                if self.snapshot.is_some() {
+                    bail!("--resume takes one snapshot");
+                }
+                let e = &self.enumerate;
+                if !e.merge_input.is_empty()
+                    || e.checkpoint.is_some()
+                    || e.resume_enumeration.is_some()
+                    || e.column_block.is_some()
+                {
+                    bail!(
+                        "--merge-input, --checkpoint, --resume-enumeration and --column-block only apply to enumerating an input"
+                    );
+                }
+                base(RunMode::Resume { snapshot: first })
+            } else {
+                checkpointing = self.enumerate.checkpointing()?;
+                let parts = self.enumerate.merge_input;
+                for path in std::iter::once(&first).chain(&parts) {
+                    if !path.exists() {
+                        bail!("input {:?} does not exist", path);
+                    }
+                }
+                let snapshot_out = snapshot_out(&first, self.snapshot);
+                base(RunMode::Enumerate {
+                    input: first,
+                    parts,
+                    snapshot_out,
+                })
+            };
+            cli.skip_match = self.skip_match;
+            cli.force_enumerate = self.enumerate.force_enumerate;
+            cli.checkpointing = checkpointing;
+            cli.resume_results = self.resume_results;
+            self.wait.apply(&mut cli);
+            self.solve.apply(&mut cli);
+            self.shard.apply(&mut cli);
+            return Ok(cli);
+        };
+
+        Ok(match command {
+            Command::Enumerate {
+                input,
+                snapshot_out: explicit,
+                wait,
+                enumerate,
+            } => {
+                let checkpointing = enumerate.checkpointing()?;
+                let parts = enumerate.merge_input;
+                for path in std::iter::once(&input).chain(&parts) {
+                    if !path.exists() {
+                        bail!("input {:?} does not exist", path);
+                    }
+                }
+                let snapshot_out = snapshot_out(&input, explicit);
+                let mut cli = base(RunMode::Enumerate {
+                    input,
+                    parts,
+                    snapshot_out,
+                });
+                cli.skip_match = true;
+                cli.force_enumerate = enumerate.force_enumerate;
+                cli.checkpointing = checkpointing;
+                wait.apply(&mut cli);
+                cli
+            }
+            Command::Match {
+                snapshot,
+                solve,
+                shard,
+                resume_results,
+            } => {
+                let mut cli = base(RunMode::Resume { snapshot });
+                cli.resume_results = resume_results;
+                solve.apply(&mut cli);
+                shard.apply(&mut cli);
+                cli
+            }
+            Command::Resume {
+                snapshot,
+                partial,
+                solve,
+                shard,
+            } => {
+                let mut cli = base(RunMode::Resume { snapshot });
+                cli.resume_results = partial;
+                solve.apply(&mut cli);
+                shard.apply(&mut cli);
+                cli
+            }
+            Command::Watch {
+                dir,
+                out,
+                poll,
+                require_done,
+                skip_match,
+                solve,
+            } => {
+                if !dir.is_dir() {
+                    bail!("watch dir {:?} is not a directory", dir);
+                }
+                let mut cli = base(RunMode::Watch { dir, out, poll });
+                cli.require_done = require_done;
+                cli.skip_match = skip_match;
+                solve.apply(&mut cli);
+                cli
+            }
+            Command::History {
+                run_id,
+                results_db,
+                limit,
+            } => {
+                let mut cli = base(RunMode::History { run_id, limit });
+                cli.results_db = Some(results_db);
+                cli
+            }
+            Command::Compare {
+                a,
+                b,
+                rel_tol,
+                abs_tol,
+            } => base(RunMode::Compare {
+                a,
+                b,
+                rel_tol,
+                abs_tol,
+            }),
+            Command::Why { a, b } => base(RunMode::Why { a, b }),
+            Command::Check {
+                baseline,
+                tol,
+                bless,
+            } => {
+                if tol.is_nan() || tol < 0.0 {
+                    bail!("--tol must be non-negative, got {}", tol);
+                }
+                base(RunMode::Check {
+                    baseline,
+                    tol,
+                    bless,
+                })
+            }
+            Command::Merge {
+                inputs,
+                results_json,
+            } => {
+                let mut cli = base(RunMode::Merge { inputs });
+                cli.results_json = results_json;
+                cli
+            }
+            Command::PlanShards {
+                snapshot,
+                count,
+                plan,
+                shard_history,
+            } => {
+                let count = usize::try_from(count).context("plan-shards: shard count")?;
+                let mut cli = base(RunMode::PlanShards { snapshot, count });
+                plan.apply(&mut cli);
+                cli.shard_history = shard_history;
+                cli
+            }
+            Command::SavePlans {
+                snapshot,
+                out,
+                plan,
+            } => {
+                let mut cli = base(RunMode::SavePlans { snapshot, out });
+                plan.apply(&mut cli);
+                cli
+            }
+            Command::Estimate { input, wait } => {
+                let mut cli = base(RunMode::Estimate { input });
+                wait.apply(&mut cli);
+                cli
+            }
+            Command::Tune {
+                input,
+                slice,
+                out,
+                wait,
+            } => {
+                let mut cli = base(RunMode::Tune {
+                    input,
+                    slice,
+                    config_out: out,
+                });
+                wait.apply(&mut cli);
+                cli
+            }
+            Command::Scavenge { spill } => base(RunMode::Scavenge { spill }),
+            Command::Clean { dirs, dry_run } => base(RunMode::Clean { dirs, dry_run }),
+            Command::VerifySnapshot { snapshot } => base(RunMode::VerifySnapshot { snapshot }),
+            Command::PackView { snapshot, out } => base(RunMode::PackView { snapshot, out }),
+            Command::Reweight { snapshot, weights } => {
+                base(RunMode::Reweight { snapshot, weights })
+            }
+            Command::Export {
+                snapshot,
+                dir,
+                format,
+            } => base(RunMode::Export {
+                snapshot,
+                format,
+                dir,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(argv: &[&str]) -> Cli {
+        let args = <Args as Parser>::try_parse_from(
+            std::iter::once("matcher").chain(argv.iter().copied()),
+        )
+        .unwrap();
+        args.into_cli().unwrap()
+    }
+
+    #[test]
+    fn resume_takes_a_snapshot_and_an_optional_partial() {
+        let c = cli(&["resume", "snap.npz"]);
+        assert!(
+            matches!(&c.mode, RunMode::Resume { snapshot } if snapshot == Path::new("snap.npz"))
+        );
+        assert!(c.resume_results.is_none());
+
+        let c = cli(&["resume", "snap.npz", "partial.json"]);
+        assert!(matches!(c.mode, RunMode::Resume { .. }));
+        assert_eq!(c.resume_results.as_deref(), Some(Path::new("partial.json")));
+    }
+
+    #[test]
+    fn complement_picks_a_named_rule() {
+        let default = cli(&["match", "snap.npz"]).match_cfg.complement;
+        assert_eq!(default(&[1, 3], 8), [5, 7]);
+        let same = cli(&["match", "snap.npz", "--complement", "same"])
+            .match_cfg
+            .complement;
+        assert_eq!(same(&[3, 1], 8), [1, 3]);
+        assert!(matching::complement_overrides(default, 8).is_empty());
+        assert_eq!(matching::complement_overrides(same, 2), [(2, 2)]);
+        let args = ["matcher", "match", "snap.npz", "--complement", "mirror"];
+        assert!(<Args as Parser>::try_parse_from(args).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+
+use crate::matching::report::fmt_value;
+use crate::matching::{OneSided, Report, load_results_json, results_equivalent};
+
+fn print_one_sided(tag: &str, p: &OneSided) {
+    println!(
+        "[{}] pair {:?} vs {:?}: subtotal={}{}",
+        tag,
+        p.pair.0,
+        p.pair.1,
+        fmt_value(p.subtotal, None),
+        if p.significant {
+            ""
+        } else {
+            " (within abs tol of 0)"
+        }
+    );
+}
+
+/// `matcher compare a.json b.json`: print every pair `results_equivalent`
+/// flags, then the Omega delta. Returns whether the sets agree.
+pub fn compare_results(path_a: &str, path_b: &str, rel_tol: f64, abs_tol: f64) -> Result<bool> {
+    let a = load_results_json(path_a)?;
+    let b = load_results_json(path_b)?;
+    let report = results_equivalent(&a, &b, rel_tol, abs_tol);
+    print_report(&report);
+    Ok(report.is_equivalent())
+}
+
+/// Print the flagged pairs of `report`, the pair counts and the Omega delta.
+pub fn print_report(report: &Report) {
+    for d in &report.diffs {
+        println!(
+            "[diff] pair {:?} vs {:?}: a={} b={} abs={:.6e} rel={:.3e}",
+            d.pair.0,
+            d.pair.1,
+            fmt_value(d.a, None),
+            fmt_value(d.b, None),
+            d.abs,
+            d.rel
+        );
+    }
+    for p in &report.only_a {
+        print_one_sided("only-a", p);
+    }
+    for p in &report.only_b {
+        print_one_sided("only-b", p);
+    }
+
+    let rel_delta = if report.omega_a != 0.0 {
+        report.omega_delta() / report.omega_a.abs()
+    } else {
+        0.0
+    };
+    println!(
+        "pairs: {} aligned, {} only in a, {} only in b, {} beyond tolerance (max abs={:.6e}, max rel={:.3e})",
+        report.aligned,
+        report.only_a.len(),
+        report.only_b.len(),
+        report.diffs.len(),
+        report.max_abs,
+        report.max_rel
+    );
+    println!(
+        "Omega a={} b={} delta={} (rel {:.3e})",
+        fmt_value(report.omega_a, None),
+        fmt_value(report.omega_b, None),
+        fmt_value(report.omega_delta(), None),
+        rel_delta
+    );
+}
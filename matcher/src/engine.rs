@@ -0,0 +1,217 @@
+//! `MatcherEngine`: one snapshot and the configuration to match it with, built
+//! either by enumerating inputs or by loading a saved snapshot. This is the
+//! same path the binary takes, minus its command line, reports and run records.
+
+use anyhow::{Result, ensure};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "npz")]
+use crate::enumeration;
+use crate::enumeration::Checkpointing;
+use crate::matching::{self, IndexWidth, MatchConfig, PairResult, types::Snapshot};
+use crate::runtime::{CancelToken, Cancelled};
+
+/// Where the snapshot of a `MatcherEngine` comes from.
+enum Source {
+    #[cfg(feature = "npz")]
+    Inputs(Vec<PathBuf>),
+    Snapshot(PathBuf),
+}
+
+/// Configures a `MatcherEngine` before the (possibly long) enumeration or load
+/// that `build` runs.
+pub struct EngineBuilder {
+    source: Source,
+    config: MatchConfig,
+    checkpointing: Checkpointing,
+}
+
+impl EngineBuilder {
+    /// Matching configuration (default: `MatchConfig::default()`, which prints
+    /// nothing). Its cancel token also stops enumeration.
+    pub fn config(mut self, config: MatchConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Stop enumeration before the next root, and matching before the next
+    /// pair, once `cancel` fires.
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.config.cancel = cancel;
+        self
+    }
+
+    /// Checkpoint the enumeration, or continue it from a checkpoint (ignored
+    /// when loading a snapshot).
+    pub fn checkpointing(mut self, checkpointing: Checkpointing) -> Self {
+        self.checkpointing = checkpointing;
+        self
+    }
+
+    /// Enumerate the inputs or load the snapshot. Errors are those of the
+    /// binary: `EmptyDataError`, `SchemaError`, `Cancelled` and (with a column
+    /// block) `BlockDone` can be told apart with `downcast_ref`.
+    pub fn build(self) -> Result<MatcherEngine> {
+        let snapshot = match &self.source {
+            #[cfg(feature = "npz")]
+            Source::Inputs(paths) => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .collect();
+                let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+                enumeration::enumerate_to_snapshot_from_npzs(
+                    &paths,
+                    &self.checkpointing,
+                    &self.config.cancel,
+                )?
+            }
+            Source::Snapshot(path) => matching::load_snapshot(&path.to_string_lossy())?,
+        };
+        Ok(MatcherEngine {
+            snapshot,
+            config: self.config,
+        })
+    }
+}
+
+/// A snapshot ready to match.
+pub struct MatcherEngine {
+    snapshot: Snapshot,
+    config: MatchConfig,
+}
+
+impl MatcherEngine {
+    /// Enumerate `paths`: one input NPZ, or the parts of a split pre-CSR as
+    /// with `--merge-input` (compat and checks come from the first).
+    #[cfg(feature = "npz")]
+    pub fn from_inputs<P: AsRef<Path>>(paths: &[P]) -> EngineBuilder {
+        EngineBuilder {
+            source: Source::Inputs(paths.iter().map(|p| p.as_ref().to_path_buf()).collect()),
+            config: MatchConfig::default(),
+            checkpointing: Checkpointing::default(),
+        }
+    }
+
+    /// Load a snapshot saved by `save_snapshot` or `matcher enumerate`.
+    pub fn from_snapshot(path: impl AsRef<Path>) -> EngineBuilder {
+        EngineBuilder {
+            source: Source::Snapshot(path.as_ref().to_path_buf()),
+            config: MatchConfig::default(),
+            checkpointing: Checkpointing::default(),
+        }
+    }
+
+    /// Wrap a snapshot already in memory.
+    pub fn with_snapshot(snapshot: Snapshot, config: MatchConfig) -> Self {
+        MatcherEngine { snapshot, config }
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn config(&self) -> &MatchConfig {
+        &self.config
+    }
+
+    /// Change the configuration between runs (pairs, shard, neutral mode...).
+    pub fn config_mut(&mut self) -> &mut MatchConfig {
+        &mut self.config
+    }
+
+    /// Write the snapshot so later runs can start `from_snapshot`.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>, width: IndexWidth) -> Result<()> {
+        matching::save_snapshot(
+            &path.as_ref().to_string_lossy(),
+            &self.snapshot,
+            width,
+            &self.config.cancel,
+        )
+    }
+
+    /// Match every planned pair with bucket `i` restricted to rows `rows[i]`
+    /// (one ascending list per bucket, see `Snapshot::restrict_rows`): Omega
+    /// counted over those states only, as for a conditional query. The
+    /// snapshot itself is left whole.
+    pub fn run_rows(&self, rows: &[Vec<usize>]) -> Result<MatchOutcome> {
+        let buckets = &self.snapshot.buckets;
+        ensure!(
+            rows.len() == buckets.len(),
+            "{} row lists for {} buckets",
+            rows.len(),
+            buckets.len()
+        );
+        for (i, (b, rows)) in buckets.iter().zip(rows).enumerate() {
+            ensure!(
+                rows.windows(2).all(|w| w[0] < w[1]),
+                "rows of bucket {} ({:?}) are not strictly ascending",
+                i,
+                b.key
+            );
+            if let Some(&last) = rows.last() {
+                ensure!(
+                    last < b.n_rows(),
+                    "row {} out of range for bucket {} ({:?}, {} rows)",
+                    last,
+                    i,
+                    b.key,
+                    b.n_rows()
+                );
+            }
+        }
+        let snapshot = self.snapshot.restrict_rows(rows);
+        let (pairs, wall) = matching::run_all_pairs_parallel(&snapshot, &self.config);
+        Ok(MatchOutcome {
+            pairs,
+            wall,
+            cancelled: self.config.cancel.is_cancelled(),
+        })
+    }
+
+    /// Match every planned pair on the rayon pool.
+    pub fn run(&self) -> MatchOutcome {
+        let (pairs, wall) = matching::run_all_pairs_parallel(&self.snapshot, &self.config);
+        MatchOutcome {
+            pairs,
+            wall,
+            cancelled: self.config.cancel.is_cancelled(),
+        }
+    }
+}
+
+/// The pairs one `MatcherEngine::run` finished.
+#[derive(Debug)]
+pub struct MatchOutcome {
+    pub pairs: Vec<PairResult>,
+    /// Matching wall time in seconds.
+    pub wall: f64,
+    /// The cancel token fired: `pairs` holds only the pairs that finished.
+    pub cancelled: bool,
+}
+
+impl MatchOutcome {
+    /// Sum of the pair subtotals (partial when cancelled or when pairs were
+    /// skipped).
+    pub fn omega(&self) -> f64 {
+        matching::omega_sum(&self.pairs)
+    }
+
+    /// Pairs over the `MATCHER_MAX_PAIR_*` limits, left out of `omega`.
+    pub fn skipped(&self) -> impl Iterator<Item = &PairResult> {
+        self.pairs.iter().filter(|r| r.skipped.is_some())
+    }
+
+    /// `Err(Cancelled)` when the run was cut short, so `?` treats a partial
+    /// Omega as an error.
+    pub fn complete(self) -> Result<Self> {
+        if self.cancelled {
+            return Err(Cancelled(format!(
+                "matching interrupted after {} pairs",
+                self.pairs.len()
+            ))
+            .into());
+        }
+        Ok(self)
+    }
+}
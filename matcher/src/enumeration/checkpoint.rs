@@ -0,0 +1,534 @@
+//! Resumable enumeration (`--checkpoint`, `--resume-enumeration`). Roots are
+//! vacated in order, so between two roots the whole enumeration state is the
+//! frontiers of the roots still ahead, the completed buckets, and the clamp and
+//! overflow counts. A checkpoint holds exactly that, flushed, and is written
+//! every `ENUM_CHECKPOINT_EVERY` roots and when the run is cancelled (Ctrl-C,
+//! or the SIGTERM a batch scheduler sends before preempting the job). A root is
+//! never checkpointed halfway: the work since the last root boundary is redone.
+//!
+//! The file reuses the spill record framing (`spill.rs`) under its own magic: a
+//! JSON header record, then one `SpillChunk` per frontier bucket (its root and
+//! mask) and per completed bucket (root `COMPLETED`, its packed pop-key as the
+//! mask), at the run's width and split into pieces when large; frontier chunks spilled to disk are
+//! copied in as they are. It is written beside its final path
+//! and renamed over it once synced, so a kill mid-write keeps the previous
+//! checkpoint. With `ENUM_CHECKPOINT_KEEP=K` the K-1 checkpoints before it
+//! stay beside it as `<file>.1` (the newest) to `<file>.<K-1>`.
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::repr::Repr;
+use super::spill::{self, MAX_CHUNK_CODES, SpillChunk, scan_records, write_record};
+use super::{AOBucket, FrontierEntry, PreCsr};
+use crate::human::{Bytes, Secs};
+use crate::matching::io::{replace_file, temp_beside};
+use crate::settings;
+
+const MAGIC: &[u8; 8] = b"CJCKPT01";
+const VERSION: u64 = 3;
+/// `SpillChunk::root` of a completed bucket.
+const COMPLETED: u32 = u32::MAX;
+
+/// Where enumeration writes checkpoints, and the checkpoint it starts from.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpointing {
+    /// Write checkpoints here, every `ENUM_CHECKPOINT_EVERY` roots and when
+    /// the run is cancelled.
+    pub path: Option<PathBuf>,
+    /// Continue from this checkpoint instead of root 0.
+    pub resume: Option<PathBuf>,
+    /// Vacate at most this many columns of roots (N roots each), then
+    /// checkpoint to `path` and return `BlockDone`, so a board too large for one
+    /// job's wall-clock limit is enumerated over several jobs.
+    pub column_block: Option<usize>,
+}
+
+/// Returned when a `column_block` ended with roots left: the checkpoint holds
+/// the state before `next_root`.
+#[derive(Debug)]
+pub struct BlockDone {
+    pub next_root: usize,
+    pub total_roots: usize,
+    pub checkpoint: PathBuf,
+}
+
+impl std::fmt::Display for BlockDone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stopped at the column boundary before root {} of {}; continue with --resume-enumeration {}",
+            self.next_root,
+            self.total_roots,
+            self.checkpoint.display()
+        )
+    }
+}
+
+impl std::error::Error for BlockDone {}
+
+/// Hash of everything the enumeration state depends on: N, M, the code and
+/// weight widths, `ENUM_FIRST_LIMIT`, the pre-CSR (regrouped by
+/// `ENUM_ROOT_ORDER`) and the pop of every j.
+pub(super) fn inputs_sha256(
+    n: u32,
+    b: u32,
+    weight_bits: u32,
+    first_limit: Option<usize>,
+    pre: &PreCsr,
+    jbt_ref_pop: &[i32],
+) -> String {
+    let mut h = Sha256::new();
+    h.update(format!(
+        "N={};M={};code_bits={};weight_bits={};first_limit={:?};",
+        n,
+        jbt_ref_pop.len(),
+        b,
+        weight_bits,
+        first_limit
+    ));
+    let bytes = |it: &mut dyn Iterator<Item = u8>| it.collect::<Vec<u8>>();
+    h.update(bytes(
+        &mut pre.offsets.iter().flat_map(|&o| (o as u64).to_le_bytes()),
+    ));
+    h.update(bytes(&mut pre.masks.iter().flat_map(|m| m.to_le_bytes())));
+    h.update(&pre.pops);
+    h.update(bytes(&mut pre.jidx.iter().flat_map(|j| j.to_le_bytes())));
+    h.update(bytes(&mut jbt_ref_pop.iter().flat_map(|p| p.to_le_bytes())));
+    format!("{:x}", h.finalize())
+}
+
+/// `Snapshot::fingerprint`: `inputs_sha256` and `ENUM_MIN_WEIGHT`, which
+/// decides at build which states the snapshot keeps.
+pub(super) fn snapshot_fingerprint(inputs_sha256: &str, min_weight: Option<u64>) -> String {
+    let mut h = Sha256::new();
+    h.update(format!(
+        "inputs={};min_weight={:?};",
+        inputs_sha256, min_weight
+    ));
+    format!("{:x}", h.finalize())
+}
+
+/// What a checkpoint records besides the buckets.
+pub(super) struct Header {
+    pub inputs_sha256: String,
+    pub total_roots: usize,
+    /// First root not yet vacated.
+    pub next_root: usize,
+    pub saturated_weights: u64,
+    pub code_overflows: u64,
+    /// Clamp sites so far: (root, completed pop-key) -> count.
+    pub saturations: BTreeMap<(usize, Option<u128>), u64>,
+    /// Frontier states vacated so far; `None` in checkpoints written before
+    /// enumeration counts were kept.
+    pub frontier_states: Option<u64>,
+    /// Completed states per pop-key so far.
+    pub completed: BTreeMap<u128, u64>,
+}
+
+impl Header {
+    fn to_json(&self, chunks: usize) -> Value {
+        json!({
+            "version": VERSION,
+            "inputs_sha256": self.inputs_sha256,
+            "total_roots": self.total_roots,
+            "next_root": self.next_root,
+            "chunks": chunks,
+            "saturated_weights": self.saturated_weights,
+            "code_overflows": self.code_overflows,
+            "saturations": self
+                .saturations
+                .iter()
+                // pop-keys pass u64 on wide boards, so they go as strings
+                .map(|(&(root, key), &count)| json!([root, key.map(|k| k.to_string()), count]))
+                .collect::<Vec<_>>(),
+            "frontier_states": self.frontier_states,
+            "completed": self
+                .completed
+                .iter()
+                .map(|(&key, &count)| json!([key.to_string(), count]))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// The header and its chunk count.
+    fn from_json(v: &Value) -> Option<(Self, usize)> {
+        let num = |k: &str| v.get(k)?.as_u64();
+        let mut saturations = BTreeMap::new();
+        for s in v.get("saturations")?.as_array()? {
+            let root = s.get(0)?.as_u64()? as usize;
+            let key = match s.get(1)? {
+                Value::Null => None,
+                key => Some(key.as_str()?.parse().ok()?),
+            };
+            saturations.insert((root, key), s.get(2)?.as_u64()?);
+        }
+        let mut completed = BTreeMap::new();
+        for c in v
+            .get("completed")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            completed.insert(c.get(0)?.as_str()?.parse().ok()?, c.get(1)?.as_u64()?);
+        }
+        let header = Header {
+            inputs_sha256: v.get("inputs_sha256")?.as_str()?.to_string(),
+            total_roots: num("total_roots")? as usize,
+            next_root: num("next_root")? as usize,
+            saturated_weights: num("saturated_weights")?,
+            code_overflows: num("code_overflows")?,
+            saturations,
+            frontier_states: num("frontier_states"),
+            completed,
+        };
+        Some((header, num("chunks")? as usize))
+    }
+}
+
+/// Write a checkpoint of the state before `header.next_root`: the flushed
+/// frontier buckets (root, mask, bucket) of the roots ahead, the spill files
+/// (path, records) holding the rest of their frontiers, and the completed
+/// buckets (pop-key, bucket).
+pub(super) fn write<R: Repr>(
+    path: &Path,
+    header: &Header,
+    frontier: &[FrontierEntry<R>],
+    spilled: &[(&Path, usize)],
+    completed: &[(R::Mask, &AOBucket<R>)],
+) -> Result<()> {
+    let t0 = Instant::now();
+    let tmp = temp_beside(path);
+    let (written, spilled_states) = write_file::<R>(&tmp, header, frontier, spilled, completed)
+        .and_then(|written| {
+            rotate(path, settings::get().checkpoint_keep)?;
+            replace_file(&tmp, path)?;
+            Ok(written)
+        })
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+    let states: usize = frontier
+        .iter()
+        .map(|(_, _, b)| b.codes.len())
+        .chain(completed.iter().map(|(_, b)| b.codes.len()))
+        .sum::<usize>()
+        + spilled_states;
+    eprintln!(
+        "[checkpoint] before root {}/{}: {} states in {} buckets{}, {} written to {} in {}",
+        header.next_root,
+        header.total_roots,
+        states,
+        frontier.len() + completed.len(),
+        match spilled.iter().map(|&(_, records)| records).sum::<usize>() {
+            0 => String::new(),
+            records => format!(" and {} spilled chunks", records),
+        },
+        Bytes(written),
+        path.display(),
+        Secs::from(t0.elapsed())
+    );
+    Ok(())
+}
+
+/// `path.<i>`: the checkpoint `i` writes before the latest.
+fn older(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+/// Shift the checkpoints kept at `path` one place back (`path` to `path.1`,
+/// `path.1` to `path.2`, ...), dropping the oldest so that with the next write
+/// `keep` checkpoints remain.
+fn rotate(path: &Path, keep: usize) -> Result<()> {
+    for i in (1..keep).rev() {
+        let from = if i == 1 {
+            path.to_path_buf()
+        } else {
+            older(path, i - 1)
+        };
+        if from.exists() {
+            let to = older(path, i);
+            std::fs::rename(&from, &to)
+                .with_context(|| format!("rename {} to {}", from.display(), to.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the checkpoint at `path` and the older ones kept beside it; the
+/// paths removed.
+pub fn remove(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let paths = std::iter::once(path.to_path_buf()).chain((1..).map(|i| older(path, i)));
+    for (i, p) in paths.enumerate() {
+        if !p.exists() {
+            if i > 0 {
+                break;
+            }
+            continue;
+        }
+        std::fs::remove_file(&p).with_context(|| format!("remove {}", p.display()))?;
+        removed.push(p);
+    }
+    Ok(removed)
+}
+
+/// Bytes written, and states copied from the spill files.
+fn write_file<R: Repr>(
+    tmp: &Path,
+    header: &Header,
+    frontier: &[FrontierEntry<R>],
+    spilled: &[(&Path, usize)],
+    completed: &[(R::Mask, &AOBucket<R>)],
+) -> Result<(u64, usize)> {
+    let f = File::create(tmp).with_context(|| format!("create {}", tmp.display()))?;
+    let mut out = BufWriter::new(f);
+    out.write_all(MAGIC)?;
+    let mut bytes = MAGIC.len() as u64;
+    let buckets = || {
+        frontier
+            .iter()
+            .map(|&(root, mask, b)| (root as u32, mask, b))
+            .chain(completed.iter().map(|&(key, b)| (COMPLETED, key, b)))
+    };
+    let pieces: usize = buckets()
+        .map(|(_, _, b)| b.codes.len().div_ceil(MAX_CHUNK_CODES).max(1))
+        .sum::<usize>()
+        + spilled.iter().map(|&(_, records)| records).sum::<usize>();
+    bytes += write_record(&mut out, header.to_json(pieces).to_string().as_bytes())?;
+    for (root, mask, b) in buckets() {
+        debug_assert!(b.pend_codes.is_empty(), "checkpoint of an unflushed bucket");
+        for (codes, weights) in b
+            .codes
+            .chunks(MAX_CHUNK_CODES)
+            .zip(b.weights.chunks(MAX_CHUNK_CODES))
+        {
+            let payload = SpillChunk::<R>::encode_parts(root, mask, codes, weights);
+            bytes += write_record(&mut out, &payload)?;
+        }
+    }
+    let mut spilled_states = 0;
+    for &(spill_path, records) in spilled {
+        let mut copied = 0;
+        spill::scan::<R>(spill_path, |chunk| {
+            let payload =
+                SpillChunk::<R>::encode_parts(chunk.root, chunk.mask, &chunk.codes, &chunk.weights);
+            bytes += write_record(&mut out, &payload)?;
+            spilled_states += chunk.codes.len();
+            copied += 1;
+            Ok(())
+        })?;
+        if copied != records {
+            bail!(
+                "spill {} holds {} intact chunks, {} were written",
+                spill_path.display(),
+                copied,
+                records
+            );
+        }
+    }
+    out.flush()?;
+    out.get_ref()
+        .sync_all()
+        .with_context(|| format!("sync {}", tmp.display()))?;
+    Ok((bytes, spilled_states))
+}
+
+/// Read the checkpoint at `path`, check it was written for the same inputs,
+/// and hand every bucket piece to `frontier(root, mask, piece)` or
+/// `completed(pop-key, piece)`. A bucket may come in several pieces, not
+/// necessarily in code order, so they are merged as pending codes.
+pub(super) fn load<R: Repr>(
+    path: &Path,
+    inputs_sha256: &str,
+    total_roots: usize,
+    mut frontier: impl FnMut(usize, R::Mask, AOBucket<R>),
+    mut completed: impl FnMut(R::Mask, AOBucket<R>),
+) -> Result<Header> {
+    let mut header: Option<(Header, usize)> = None;
+    let mut chunks = 0usize;
+    let scan = scan_records(path, MAGIC, "checkpoint", |payload| {
+        let Some((head, _)) = &header else {
+            let v: Value = serde_json::from_slice(payload)
+                .with_context(|| format!("{}: header is not JSON", path.display()))?;
+            if v.get("version").and_then(Value::as_u64) != Some(VERSION) {
+                bail!(
+                    "{}: checkpoint version {} (this matcher reads {})",
+                    path.display(),
+                    v.get("version").unwrap_or(&Value::Null),
+                    VERSION
+                );
+            }
+            let parsed = Header::from_json(&v)
+                .with_context(|| format!("{}: malformed checkpoint header", path.display()))?;
+            if parsed.0.inputs_sha256 != inputs_sha256 {
+                bail!(
+                    "{} was written for other inputs or settings (N, M, ENUM_CODE_BITS, ENUM_WEIGHT_BITS, ENUM_FIRST_LIMIT, ENUM_ROOT_ORDER or the pre-CSR differ)",
+                    path.display()
+                );
+            }
+            if parsed.0.total_roots != total_roots || parsed.0.next_root > total_roots {
+                bail!(
+                    "{}: next root {} of {} does not fit {} roots",
+                    path.display(),
+                    parsed.0.next_root,
+                    parsed.0.total_roots,
+                    total_roots
+                );
+            }
+            header = Some(parsed);
+            return Ok(true);
+        };
+        let chunk = SpillChunk::<R>::decode(payload)
+            .with_context(|| format!("{}: bucket record {}", path.display(), chunks))?;
+        let bucket = AOBucket {
+            codes: chunk.codes,
+            weights: chunk.weights,
+            ..AOBucket::default()
+        };
+        match chunk.root {
+            COMPLETED => completed(chunk.mask, bucket),
+            root if (head.next_root..total_roots).contains(&(root as usize)) => {
+                frontier(root as usize, chunk.mask, bucket)
+            }
+            root => bail!(
+                "{}: frontier bucket for root {}, outside {}..{}",
+                path.display(),
+                root,
+                head.next_root,
+                total_roots
+            ),
+        }
+        chunks += 1;
+        Ok(true)
+    })?;
+    let Some((header, expected)) = header else {
+        bail!("{}: checkpoint has no header", path.display());
+    };
+    if scan.torn > 0 || chunks != expected {
+        bail!(
+            "{}: incomplete checkpoint ({} of {} buckets intact)",
+            path.display(),
+            chunks,
+            expected
+        );
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumeration::repr::Narrow;
+    use crate::enumeration::spill::SpillWriter;
+
+    fn bucket(codes: &[u128], weights: &[u64]) -> AOBucket<Narrow> {
+        AOBucket {
+            codes: codes.to_vec(),
+            weights: weights.to_vec(),
+            ..AOBucket::default()
+        }
+    }
+
+    fn header(next_root: usize) -> Header {
+        Header {
+            inputs_sha256: "inputs".to_string(),
+            total_roots: 8,
+            next_root,
+            saturated_weights: 2,
+            code_overflows: 1,
+            saturations: BTreeMap::from([((3, None), 1), ((4, Some(u64::MAX as u128 + 5)), 1)]),
+            frontier_states: Some(1234),
+            completed: BTreeMap::from([(77, 9)]),
+        }
+    }
+
+    #[test]
+    fn checkpoints_read_back_what_was_written() {
+        let dir = std::env::temp_dir().join(format!("matcher-ckpt-rt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.ckpt");
+        let spill_path = dir.join("frontier.spill");
+        let mut spill = SpillWriter::create(&spill_path).unwrap();
+        spill
+            .write_bucket::<Narrow>(6, 0b110, &[u128::MAX], &[40])
+            .unwrap();
+        spill.finish().unwrap();
+        let ahead = bucket(&[1, 2, 3], &[10, 20, 30]);
+        let done = bucket(&[5], &[50]);
+        write::<Narrow>(
+            &path,
+            &header(4),
+            &[(4, 0b11, &ahead)],
+            &[(spill_path.as_path(), 1)],
+            &[(77, &done)],
+        )
+        .unwrap();
+
+        let (mut frontier, mut completed) = (Vec::new(), Vec::new());
+        let got = load::<Narrow>(
+            &path,
+            "inputs",
+            8,
+            |root, mask, b| frontier.push((root, mask, b.codes, b.weights)),
+            |key, b| completed.push((key, b.codes, b.weights)),
+        )
+        .unwrap();
+        assert_eq!((got.next_root, got.total_roots), (4, 8));
+        assert_eq!((got.saturated_weights, got.code_overflows), (2, 1));
+        assert_eq!(got.saturations, header(4).saturations);
+        assert_eq!(
+            frontier,
+            [
+                (4, 0b11, vec![1, 2, 3], vec![10, 20, 30]),
+                (6, 0b110, vec![u128::MAX], vec![40])
+            ]
+        );
+        assert_eq!(completed, [(77, vec![5], vec![50])]);
+
+        let other = load::<Narrow>(&path, "other inputs", 8, |_, _, _| (), |_, _| ());
+        assert!(other.err().unwrap().to_string().contains("other inputs"));
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 4)
+            .unwrap();
+        let torn = load::<Narrow>(&path, "inputs", 8, |_, _, _| (), |_, _| ());
+        assert!(
+            torn.err()
+                .unwrap()
+                .to_string()
+                .contains("incomplete checkpoint")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_checkpoints() {
+        let dir = std::env::temp_dir().join(format!("matcher-ckpt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.ckpt");
+        for write in 0..5 {
+            rotate(&path, 3).unwrap();
+            std::fs::write(&path, write.to_string()).unwrap();
+        }
+        let read = |p: PathBuf| std::fs::read_to_string(p).unwrap();
+        assert_eq!(read(path.clone()), "4");
+        assert_eq!(read(older(&path, 1)), "3");
+        assert_eq!(read(older(&path, 2)), "2");
+        assert!(!older(&path, 3).exists());
+        assert_eq!(remove(&path).unwrap().len(), 3);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
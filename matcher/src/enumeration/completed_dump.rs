@@ -0,0 +1,80 @@
+//! Raw completed-state stream, written when `ENUM_COMPLETED_STATES=<path>` is
+//! set. Each completed state is appended as it reaches the merge thread, before
+//! any pop-key bucketing or deduplication, as one text line
+//! `<root>\t<weight>\t<j1 j2 ...>` (root = the root being vacated, j indices
+//! ascending). The same j list can appear several times; summing its weights
+//! gives the snapshot row weight. Expect files several times the snapshot size.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use super::code_iter;
+use super::repr::Repr;
+use crate::human::Bytes;
+
+pub struct CompletedDump {
+    path: PathBuf,
+    out: BufWriter<File>,
+    b: u32,
+    states: u64,
+    bytes: u64,
+}
+
+impl CompletedDump {
+    /// `Some(dump)` when `ENUM_COMPLETED_STATES` is set; `b` is the code bitwidth.
+    pub fn from_env(b: u32) -> Result<Option<Self>> {
+        let Some(path) = crate::settings::get().completed_states.clone() else {
+            return Ok(None);
+        };
+        let f = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+        Ok(Some(CompletedDump {
+            path,
+            out: BufWriter::with_capacity(1 << 20, f),
+            b,
+            states: 0,
+            bytes: 0,
+        }))
+    }
+
+    /// Append one batch of completed states reached while vacating `root`.
+    pub fn write_batch<R: Repr>(
+        &mut self,
+        root: usize,
+        codes: &[R::Code],
+        weights: &[R::Weight],
+    ) -> Result<()> {
+        let mut line = String::new();
+        for (&c, &w) in codes.iter().zip(weights) {
+            line.clear();
+            line += &format!("{}\t{}\t", root, w);
+            for (i, j) in code_iter(c, self.b).enumerate() {
+                if i > 0 {
+                    line.push(' ');
+                }
+                line += &j.to_string();
+            }
+            line.push('\n');
+            self.out
+                .write_all(line.as_bytes())
+                .with_context(|| format!("write {}", self.path.display()))?;
+            self.bytes += line.len() as u64;
+        }
+        self.states += codes.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out
+            .flush()
+            .with_context(|| format!("write {}", self.path.display()))?;
+        eprintln!(
+            "[completed] wrote {} raw completed states ({}) to {}",
+            self.states,
+            Bytes(self.bytes),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
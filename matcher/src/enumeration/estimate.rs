@@ -0,0 +1,230 @@
+//! `matcher estimate`: a masks-only enumeration pass for sizing a run before
+//! submitting it. Frontiers hold one counter per mask instead of code/weight
+//! vectors, so the pass is far cheaper than the real one. Mask counts are exact;
+//! state counts are path counts, i.e. the real states before deduplication, so
+//! they are upper bounds.
+
+use ahash::AHashMap;
+use anyhow::Result;
+use rayon::prelude::*;
+use std::mem;
+use std::time::Instant;
+
+use super::prune_table::PruneTable;
+use super::repr::{self, Bits, Narrow, Repr, Wide, Width};
+use super::{
+    AOBucket, EvilMemo, Inputs, MaskComponents, code_bitwidth, find_root, first_bucket_limit,
+    load_inputs_npz, order_roots,
+};
+use crate::human::{Bytes, Secs};
+use crate::runtime::resumable_progress_bar;
+use crate::settings;
+
+/// Snapshot bytes per completed state (row indptr + f64 weight + ~4 row entries),
+/// measured on n = 8 snapshots.
+const SNAPSHOT_BYTES_PER_STATE: f64 = 32.0;
+
+/// Real-enumeration wall time per path-count state moved, calibrated on n = 8
+/// (one core; deduplication makes this far below the per-real-state cost).
+/// Override with `ENUM_EST_NS_PER_STATE` once a node has been measured.
+const DEFAULT_NS_PER_STATE: f64 = 30.0;
+
+#[derive(Default)]
+pub struct Estimate {
+    /// Most frontier masks pending after any root (exact), and that root.
+    pub peak_masks: usize,
+    pub peak_masks_root: usize,
+    /// Largest pending frontier by estimated bytes: masks, state bound, root.
+    pub peak_bytes_masks: usize,
+    pub peak_states: u64,
+    pub peak_root: usize,
+    /// States handed between roots or completed, summed over the run (bound).
+    pub states_moved: u64,
+    pub completed_states: u64,
+    pub wall: f64,
+    /// Heap per state and per frontier mask at the run's code and mask width.
+    pub state_bytes: usize,
+    pub mask_bytes: usize,
+}
+
+impl Estimate {
+    /// Heap estimate for a frontier of `masks` buckets holding `states` states.
+    fn frontier_bytes(&self, masks: usize, states: u64) -> f64 {
+        states as f64 * self.state_bytes as f64 + masks as f64 * self.mask_bytes as f64
+    }
+
+    pub fn print(&self) {
+        let ns_per_state = crate::settings::get()
+            .est_ns_per_state
+            .unwrap_or(DEFAULT_NS_PER_STATE);
+        let threads = rayon::current_num_threads() as f64;
+        println!("masks-only pass: {}", Secs(self.wall));
+        println!(
+            "peak frontier masks: {} after root {}",
+            self.peak_masks, self.peak_masks_root
+        );
+        println!(
+            "peak frontier: {} masks, <= {} states after root {} (<= {})",
+            self.peak_bytes_masks,
+            self.peak_states,
+            self.peak_root,
+            Bytes(self.frontier_bytes(self.peak_bytes_masks, self.peak_states) as u64)
+        );
+        println!(
+            "completed states: <= {} (out buckets <= {}, snapshot <= {})",
+            self.completed_states,
+            Bytes(self.frontier_bytes(0, self.completed_states) as u64),
+            Bytes((self.completed_states as f64 * SNAPSHOT_BYTES_PER_STATE) as u64)
+        );
+        println!(
+            "enumeration runtime: ~{} on {} threads ({} path states moved at {} ns each)",
+            Secs(self.states_moved as f64 * ns_per_state * 1e-9 / threads),
+            threads,
+            self.states_moved,
+            ns_per_state
+        );
+    }
+}
+
+/// Run the masks-only pass over `path`; honours `ENUM_FIRST_LIMIT` like the real run.
+pub fn estimate_from_npz(path: &str) -> Result<Estimate> {
+    let t0 = Instant::now();
+    let inputs = load_inputs_npz(path)?;
+    let b = code_bitwidth(inputs.m)?;
+    let mut est = match (
+        repr::pick(inputs.n, inputs.m, b)?,
+        settings::get().weight_bits,
+    ) {
+        (Width::Narrow, 32) => estimate::<Narrow<u32>>(inputs),
+        (Width::Narrow, _) => estimate::<Narrow>(inputs),
+        (Width::Wide, 32) => estimate::<Wide<u32>>(inputs),
+        (Width::Wide, _) => estimate::<Wide>(inputs),
+    };
+    est.wall = t0.elapsed().as_secs_f64();
+    Ok(est)
+}
+
+/// The masks-only pass at the width `R`.
+fn estimate<R: Repr>(inputs: Inputs) -> Estimate {
+    let n = inputs.n;
+    let (pre, geom) = order_roots::<R::Mask>(n, inputs.pre);
+    let total_roots = ((n / 2) as usize) * n as usize;
+    let evil_cut = total_roots - n as usize;
+    let prune_table = PruneTable::for_run(&geom);
+
+    // per root: mask -> number of paths reaching it (saturating)
+    let mut frontiers: Vec<AHashMap<R::Mask, u64>> = vec![AHashMap::default(); total_roots];
+    frontiers[0].insert(R::Mask::ZERO, 1);
+    let mut est = Estimate {
+        state_bytes: mem::size_of::<R::Code>() + mem::size_of::<R::Weight>(),
+        mask_bytes: mem::size_of::<AOBucket<R>>() + 3 * mem::size_of::<R::Mask>(),
+        ..Estimate::default()
+    };
+
+    let pb = resumable_progress_bar(total_roots as u64, "roots (estimate)", 0);
+    for i in 0..total_roots {
+        let frontier: Vec<(R::Mask, u64)> = mem::take(&mut frontiers[i]).into_iter().collect();
+        let s = pre.offsets[i];
+        let e = pre.offsets[i + 1];
+        let e_eff = if i == 0 {
+            first_bucket_limit().map_or(e, |limit| s + (e - s).min(limit))
+        } else {
+            e
+        };
+        if s == e || frontier.is_empty() {
+            pb.inc(1);
+            continue;
+        }
+        let do_evil = i < evil_cut;
+        let comps: Vec<MaskComponents<R::Mask>> = if do_evil && prune_table.is_none() {
+            frontier
+                .par_iter()
+                .map(|&(pm, _)| MaskComponents::new(pm, &geom))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let (moved, completed, dests) = (s..e_eff)
+            .into_par_iter()
+            .fold(
+                || {
+                    (
+                        0u64,
+                        0u64,
+                        AHashMap::<(usize, R::Mask), u64>::default(),
+                        EvilMemo::default(),
+                    )
+                },
+                |(mut moved, mut completed, mut dests, mut memo), k_pre| {
+                    let pmask_pre = R::Mask::from_u128(pre.masks[k_pre]);
+                    for (idx, &(pm, paths)) in frontier.iter().enumerate() {
+                        if pm & pmask_pre != R::Mask::ZERO {
+                            continue;
+                        }
+                        let new_mask = pm | pmask_pre;
+                        if do_evil
+                            && match &prune_table {
+                                Some(table) => table.is_evil(new_mask),
+                                None => memo.is_evil(new_mask, &geom, || {
+                                    comps[idx].extension_is_evil(pm, pmask_pre, &geom)
+                                }),
+                            }
+                        {
+                            continue;
+                        }
+                        moved = moved.saturating_add(paths);
+                        let dst = match &prune_table {
+                            Some(table) => table.root(new_mask),
+                            None => find_root(new_mask, &geom),
+                        };
+                        match dst {
+                            None => completed = completed.saturating_add(paths),
+                            Some(root) => {
+                                let ent = dests.entry((root, new_mask)).or_insert(0);
+                                *ent = ent.saturating_add(paths);
+                            }
+                        }
+                    }
+                    (moved, completed, dests, memo)
+                },
+            )
+            .map(|(moved, completed, dests, _)| (moved, completed, dests))
+            .reduce(
+                || (0, 0, AHashMap::default()),
+                |(m1, c1, mut d1), (m2, c2, d2)| {
+                    for (key, paths) in d2 {
+                        let ent = d1.entry(key).or_insert(0);
+                        *ent = ent.saturating_add(paths);
+                    }
+                    (m1.saturating_add(m2), c1.saturating_add(c2), d1)
+                },
+            );
+        for ((root, mask), paths) in dests {
+            let ent = frontiers[root].entry(mask).or_insert(0);
+            *ent = ent.saturating_add(paths);
+        }
+        est.states_moved = est.states_moved.saturating_add(moved);
+        est.completed_states = est.completed_states.saturating_add(completed);
+
+        let masks: usize = frontiers.iter().map(|f| f.len()).sum();
+        let states = frontiers
+            .iter()
+            .flat_map(|f| f.values())
+            .fold(0u64, |acc, &p| acc.saturating_add(p));
+        if masks > est.peak_masks {
+            est.peak_masks = masks;
+            est.peak_masks_root = i;
+        }
+        if est.frontier_bytes(masks, states)
+            > est.frontier_bytes(est.peak_bytes_masks, est.peak_states)
+        {
+            est.peak_bytes_masks = masks;
+            est.peak_states = states;
+            est.peak_root = i;
+        }
+        pb.inc(1);
+    }
+    pb.finish_and_clear();
+    est
+}
@@ -0,0 +1,145 @@
+//! Optional invariants carried by the input NPZ, checked right after
+//! enumeration: per pop-key, the expected total completed weight (and
+//! optionally the number of distinct states) from an independent calculation.
+//!
+//! - `expect_keys_data[i32]`, `expect_keys_indptr[i64]`: one pop-key per entry
+//! - `expect_weight[f64]`: total weight of that key's bucket
+//! - `expect_states[i64]` (optional): rows in that key's bucket
+//!
+//! A key listed with expected weight 0 must be absent (or empty) in the snapshot.
+
+use ahash::AHashMap;
+use anyhow::{Context, Result, bail};
+use ndarray::Array1;
+use ndarray_npy::NpzReader;
+use std::fs::File;
+
+use super::npz_member_names;
+use crate::matching::npz_check::check_offsets;
+use crate::matching::stats::SnapshotStats;
+use crate::matching::types::key_sorted_vec;
+
+/// Relative tolerance on weights; counts are integers carried as f64, so any
+/// real difference is far above it.
+const WEIGHT_REL_TOL: f64 = 1e-9;
+
+/// Mismatch lines printed before the summary.
+const MAX_LISTED: usize = 20;
+
+pub struct Expected {
+    /// (sorted key, weight, states)
+    entries: Vec<(Vec<i32>, f64, Option<i64>)>,
+}
+
+impl Expected {
+    /// `Some` when the NPZ at `path` carries `expect_*` arrays.
+    pub fn from_npz(path: &str) -> Result<Option<Self>> {
+        let f = File::open(path).with_context(|| format!("open {}", path))?;
+        let mut npz = NpzReader::new(f).with_context(|| format!("read npz {}", path))?;
+        let names = npz_member_names(&mut npz, path)?;
+        if !names.contains("expect_weight.npy") {
+            return Ok(None);
+        }
+        let weights: Array1<f64> = npz
+            .by_name("expect_weight.npy")
+            .with_context(|| format!("read expect_weight.npy from {}", path))?;
+        let key_data: Array1<i32> = npz
+            .by_name("expect_keys_data.npy")
+            .with_context(|| format!("read expect_keys_data.npy from {}", path))?;
+        let key_indptr: Array1<i64> = npz
+            .by_name("expect_keys_indptr.npy")
+            .with_context(|| format!("read expect_keys_indptr.npy from {}", path))?;
+        let states: Option<Array1<i64>> = if names.contains("expect_states.npy") {
+            Some(
+                npz.by_name("expect_states.npy")
+                    .with_context(|| format!("read expect_states.npy from {}", path))?,
+            )
+        } else {
+            None
+        };
+        if key_indptr.len() != weights.len() + 1
+            || states.as_ref().is_some_and(|s| s.len() != weights.len())
+        {
+            bail!(
+                "{}: expect_* arrays disagree in length ({} weights, {} key offsets)",
+                path,
+                weights.len(),
+                key_indptr.len()
+            );
+        }
+        check_offsets(
+            "expect_keys_indptr",
+            key_indptr.as_slice().unwrap_or(&[]),
+            key_data.len(),
+        )
+        .with_context(|| format!("input {}", path))?;
+        let entries = (0..weights.len())
+            .map(|i| {
+                let (lo, hi) = (key_indptr[i] as usize, key_indptr[i + 1] as usize);
+                let key = key_data
+                    .as_slice()
+                    .and_then(|d| d.get(lo..hi))
+                    .with_context(|| format!("{}: expect_keys_indptr out of range", path))?;
+                Ok((
+                    key_sorted_vec(key),
+                    weights[i],
+                    states.as_ref().map(|s| s[i]),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Expected { entries }))
+    }
+
+    /// Compare against the enumerated buckets, given by their keys and
+    /// statistics, print a `[check]` line per mismatch and a summary; returns
+    /// the number of mismatching keys.
+    pub fn check(&self, keys: &[Vec<i32>], stats: &SnapshotStats) -> usize {
+        let actual: AHashMap<Vec<i32>, (f64, usize)> = keys
+            .iter()
+            .zip(stats.bucket_weight.iter().zip(&stats.bucket_states))
+            .map(|(key, (&w, &states))| (key_sorted_vec(key), (w, states as usize)))
+            .collect();
+        let mut mismatches = 0usize;
+        for (key, want_w, want_states) in &self.entries {
+            let (got_w, got_states) = actual.get(key).copied().unwrap_or((0.0, 0));
+            let weight_ok = (got_w - want_w).abs() <= WEIGHT_REL_TOL * want_w.abs().max(1.0);
+            let states_ok = want_states.is_none_or(|s| s == got_states as i64);
+            if weight_ok && states_ok {
+                continue;
+            }
+            mismatches += 1;
+            if mismatches <= MAX_LISTED {
+                let states = match want_states {
+                    Some(s) => format!(", states {} (expected {})", got_states, s),
+                    None => String::new(),
+                };
+                eprintln!(
+                    "[check] key {:?}: weight {} (expected {}, diff {:+}){}",
+                    key,
+                    got_w,
+                    want_w,
+                    got_w - want_w,
+                    states
+                );
+            }
+        }
+        let listed: std::collections::HashSet<&Vec<i32>> =
+            self.entries.iter().map(|(k, _, _)| k).collect();
+        let unlisted = actual.keys().filter(|k| !listed.contains(k)).count();
+        if mismatches == 0 {
+            eprintln!(
+                "[check] all {} expected pop-key totals match ({} snapshot keys not listed)",
+                self.entries.len(),
+                unlisted
+            );
+        } else {
+            eprintln!(
+                "[check] MISMATCH: {} of {} expected pop-key totals differ ({} snapshot keys not listed); enumeration may be pruning or double-counting",
+                mismatches,
+                self.entries.len(),
+                unlisted
+            );
+        }
+        mismatches
+    }
+}
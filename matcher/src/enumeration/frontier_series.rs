@@ -0,0 +1,99 @@
+//! Per-root frontier size time series, written as CSV when
+//! `ENUM_FRONTIER_CSV=<path>` is set. One row per root, taken right after the
+//! root is vacated, so the columns trace the memory profile of the run.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::human::Bytes;
+
+/// Frontier masks and states (committed + pending) held by a set of buckets,
+/// with a rough heap estimate.
+#[derive(Default, Clone, Copy)]
+pub struct FrontierSize {
+    pub masks: usize,
+    pub codes: usize,
+    pub bytes: u64,
+}
+
+pub struct FrontierSeries {
+    path: PathBuf,
+    out: BufWriter<File>,
+    t0: Instant,
+    /// Board cell (u, v) of each root.
+    cells: Vec<(usize, usize)>,
+    peak_bytes: u64,
+    peak_root: usize,
+}
+
+impl FrontierSeries {
+    /// `Some(series)` with the header written when `ENUM_FRONTIER_CSV` is set.
+    pub fn from_env(cells: Vec<(usize, usize)>) -> Result<Option<Self>> {
+        let Some(path) = crate::settings::get().frontier_csv.clone() else {
+            return Ok(None);
+        };
+        let f = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+        let mut out = BufWriter::new(f);
+        writeln!(
+            out,
+            "root,u,v,elapsed_s,frontier_masks,frontier_codes,frontier_bytes,vacated_codes,out_codes,out_bytes,rss_bytes"
+        )?;
+        Ok(Some(FrontierSeries {
+            path,
+            out,
+            t0: Instant::now(),
+            cells,
+            peak_bytes: 0,
+            peak_root: 0,
+        }))
+    }
+
+    /// One row after vacating `root`: the frontiers still waiting, the states
+    /// the root just handed on, and the completed-state buckets.
+    pub fn row(
+        &mut self,
+        root: usize,
+        frontier: FrontierSize,
+        vacated_codes: usize,
+        out: FrontierSize,
+        rss: Option<u64>,
+    ) -> Result<()> {
+        let total = frontier.bytes + out.bytes;
+        if total > self.peak_bytes {
+            self.peak_bytes = total;
+            self.peak_root = root;
+        }
+        writeln!(
+            self.out,
+            "{},{},{},{:.3},{},{},{},{},{},{},{}",
+            root,
+            self.cells[root].0,
+            self.cells[root].1,
+            self.t0.elapsed().as_secs_f64(),
+            frontier.masks,
+            frontier.codes,
+            frontier.bytes,
+            vacated_codes,
+            out.codes,
+            out.bytes,
+            rss.map_or(String::new(), |r| r.to_string())
+        )?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out
+            .flush()
+            .with_context(|| format!("write {}", self.path.display()))?;
+        eprintln!(
+            "[frontier] wrote {} (estimated peak {} after root {})",
+            self.path.display(),
+            Bytes(self.peak_bytes),
+            self.peak_root
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+//! Evil-mask checks on the GPU (`ENUM_GPU=1`, feature "gpu"). For a slab of
+//! the pre_jbt of one root, a compute shader runs `detect_evil_pmask` on every
+//! (pre_jbt, frontier mask) pair and returns a keep-bitmap: a pair's bit is set
+//! when the two are disjoint and their union is not evil. Masks travel as four
+//! u32 words, so the one shader serves narrow and wide boards. Without the
+//! feature, without an adapter, or once a dispatch fails, the vacate workers
+//! check on the CPU as before; either way the snapshot is the same.
+
+/// Largest keep-bitmap per slab; a root with more pairs is checked a slab of
+/// pre_jbt at a time.
+const MAX_SLAB_BYTES: usize = 64 << 20;
+
+/// Verdicts for the pairs of pre_jbt `first..first + rows` with every
+/// frontier mask of the root.
+pub(super) struct KeepBitmap {
+    words: Vec<u32>,
+    /// u32 words per pre_jbt row (one bit per frontier mask)
+    per_pre: usize,
+    first: usize,
+}
+
+impl KeepBitmap {
+    /// Pre_jbt `k_pre` and frontier mask `idx_pm` are disjoint and leave no
+    /// untileable component.
+    #[inline]
+    pub(super) fn keep(&self, k_pre: usize, idx_pm: usize) -> bool {
+        let word = self.words[(k_pre - self.first) * self.per_pre + idx_pm / 32];
+        word >> (idx_pm % 32) & 1 != 0
+    }
+}
+
+/// Pre_jbt rows per slab for a root with `n_pmasks` frontier masks.
+pub(super) fn slab_rows(n_pmasks: usize) -> usize {
+    (MAX_SLAB_BYTES / (4 * n_pmasks.div_ceil(32).max(1))).max(1)
+}
+
+/// Without the "gpu" feature there is no device; `new` says so once.
+#[cfg(not(feature = "gpu"))]
+pub(super) struct EvilGpu;
+
+#[cfg(not(feature = "gpu"))]
+impl EvilGpu {
+    pub(super) fn new<M: super::repr::Bits>(_g: &super::BoardGeometry<M>) -> Option<Self> {
+        eprintln!(
+            "[gpu] ENUM_GPU=1 but this matcher was built without the \"gpu\" feature; evil-mask checks stay on the CPU"
+        );
+        None
+    }
+
+    pub(super) fn keep_bitmap(
+        &self,
+        _pres: &[u128],
+        _first: usize,
+        _pmasks: &[u128],
+    ) -> anyhow::Result<KeepBitmap> {
+        unreachable!("EvilGpu is never built without the gpu feature")
+    }
+}
+
+#[cfg(feature = "gpu")]
+pub(super) use device::EvilGpu;
+
+#[cfg(feature = "gpu")]
+mod device {
+    use anyhow::{Context, Result, bail};
+    use std::future::Future;
+    use std::sync::mpsc;
+    use std::task::{Context as TaskContext, Poll, Waker};
+    use wgpu::util::DeviceExt;
+
+    use super::super::BoardGeometry;
+    use super::super::repr::Bits;
+    use super::KeepBitmap;
+
+    /// One invocation writes one keep word: 32 frontier masks against one pre_jbt.
+    const SHADER: &str = r#"
+struct Params {
+    n: u32,
+    per_pre: u32,
+    rows: u32,
+    n_pmasks: u32,
+    half_cells: vec4<u32>,
+    escape: vec4<u32>,
+    not_top: vec4<u32>,
+    not_bot: vec4<u32>,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> pres: array<vec4<u32>>;
+@group(0) @binding(2) var<storage, read> pmasks: array<vec4<u32>>;
+@group(0) @binding(3) var<storage, read_write> keep: array<u32>;
+
+fn is_zero(a: vec4<u32>) -> bool {
+    return all(a == vec4<u32>(0u));
+}
+
+// shifts by 1..31 across the four words
+fn shl(a: vec4<u32>, s: u32) -> vec4<u32> {
+    let r = 32u - s;
+    return vec4<u32>(a.x << s, (a.y << s) | (a.x >> r), (a.z << s) | (a.y >> r), (a.w << s) | (a.z >> r));
+}
+
+fn shr(a: vec4<u32>, s: u32) -> vec4<u32> {
+    let l = 32u - s;
+    return vec4<u32>((a.x >> s) | (a.y << l), (a.y >> s) | (a.z << l), (a.z >> s) | (a.w << l), a.w >> s);
+}
+
+fn lowest_bit(a: vec4<u32>) -> vec4<u32> {
+    if (a.x != 0u) {
+        return vec4<u32>(a.x & (~a.x + 1u), 0u, 0u, 0u);
+    }
+    if (a.y != 0u) {
+        return vec4<u32>(0u, a.y & (~a.y + 1u), 0u, 0u);
+    }
+    if (a.z != 0u) {
+        return vec4<u32>(0u, 0u, a.z & (~a.z + 1u), 0u);
+    }
+    return vec4<u32>(0u, 0u, 0u, a.w & (~a.w + 1u));
+}
+
+fn pop_count(a: vec4<u32>) -> u32 {
+    let c = countOneBits(a);
+    return c.x + c.y + c.z + c.w;
+}
+
+fn flood_fill(seed: vec4<u32>, domain: vec4<u32>) -> vec4<u32> {
+    var frontier = seed & domain;
+    var todo = domain & ~frontier;
+    loop {
+        if (is_zero(frontier)) {
+            break;
+        }
+        let up = shl(frontier & params.not_top, 1u);
+        let down = shr(frontier & params.not_bot, 1u);
+        let left = shr(frontier, params.n);
+        let right = shl(frontier, params.n);
+        frontier = (up | down | left | right) & todo;
+        todo = todo & ~frontier;
+    }
+    return domain & ~todo;
+}
+
+fn is_evil(mask: vec4<u32>) -> bool {
+    var complement = mask ^ params.half_cells;
+    loop {
+        if (is_zero(complement)) {
+            break;
+        }
+        let comp = flood_fill(lowest_bit(complement), complement);
+        if (is_zero(comp & params.escape) && pop_count(comp) % params.n != 0u) {
+            return true;
+        }
+        complement = complement ^ comp;
+    }
+    return false;
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let word = gid.x + gid.y * groups.x * 64u;
+    if (word >= params.per_pre * params.rows) {
+        return;
+    }
+    let pre = pres[word / params.per_pre];
+    let first = (word % params.per_pre) * 32u;
+    var bits = 0u;
+    for (var j = 0u; j < 32u; j++) {
+        let p = first + j;
+        if (p >= params.n_pmasks) {
+            break;
+        }
+        let pm = pmasks[p];
+        if (is_zero(pm & pre) && !is_evil(pm | pre)) {
+            bits = bits | (1u << j);
+        }
+    }
+    keep[word] = bits;
+}
+"#;
+
+    /// The four u32 words of a mask, lowest first.
+    fn words(m: u128) -> [u32; 4] {
+        std::array::from_fn(|w| (m >> (32 * w)) as u32)
+    }
+
+    const WORKGROUP: u32 = 64;
+    const MAX_GROUPS: u32 = 65_535;
+
+    pub(in super::super) struct EvilGpu {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        pipeline: wgpu::ComputePipeline,
+        /// n, then the half, escape, not_top and not_bot masks as in `Params`
+        geometry: [[u32; 4]; 4],
+        n: u32,
+        max_binding: u64,
+    }
+
+    /// Drive a wgpu future to completion; native backends resolve them once
+    /// the device is polled, so spinning on a no-op waker is enough.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = std::pin::pin!(f);
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+                return v;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    impl EvilGpu {
+        /// The first adapter wgpu offers, or `None` (with a `[gpu]` line) when
+        /// there is none to use.
+        pub(in super::super) fn new<M: Bits>(g: &BoardGeometry<M>) -> Option<Self> {
+            match Self::open(g) {
+                Ok(gpu) => Some(gpu),
+                Err(e) => {
+                    eprintln!("[gpu] {:#}; evil-mask checks stay on the CPU", e);
+                    None
+                }
+            }
+        }
+
+        fn open<M: Bits>(g: &BoardGeometry<M>) -> Result<Self> {
+            let instance = wgpu::Instance::new(
+                wgpu::InstanceDescriptor::new_without_display_handle_from_env(),
+            );
+            let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface: None,
+            }))
+            .context("no GPU adapter")?;
+            let info = adapter.get_info();
+            let limits = adapter.limits();
+            let (device, queue) = block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+                label: Some("evil-mask"),
+                required_limits: limits.clone(),
+                ..Default::default()
+            }))
+            .with_context(|| format!("open {}", info.name))?;
+            let scope = device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("evil-mask"),
+                source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+            });
+            let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("evil-mask"),
+                layout: None,
+                module: &module,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+            if let Some(e) = block_on(scope.pop()) {
+                bail!("build the evil-mask shader on {}: {}", info.name, e);
+            }
+            eprintln!(
+                "[gpu] evil-mask checks on {} ({:?}, {:?})",
+                info.name, info.backend, info.device_type
+            );
+            Ok(EvilGpu {
+                device,
+                queue,
+                pipeline,
+                geometry: [
+                    words(g.half.to_u128()),
+                    words(g.escape.to_u128()),
+                    words(g.not_top.to_u128()),
+                    words(g.not_bot.to_u128()),
+                ],
+                n: g.n,
+                max_binding: limits.max_storage_buffer_binding_size,
+            })
+        }
+
+        /// Check every pair of `pres` (pre_jbt `first..`) with `pmasks`.
+        pub(in super::super) fn keep_bitmap(
+            &self,
+            pres: &[u128],
+            first: usize,
+            pmasks: &[u128],
+        ) -> Result<KeepBitmap> {
+            let per_pre = pmasks.len().div_ceil(32).max(1);
+            let n_words = per_pre * pres.len();
+            let out_bytes = 4 * n_words as u64;
+            if 16 * pmasks.len() as u64 > self.max_binding || out_bytes > self.max_binding {
+                bail!(
+                    "{} frontier masks x {} pre_jbt exceed the device's {} byte storage binding",
+                    pmasks.len(),
+                    pres.len(),
+                    self.max_binding
+                );
+            }
+            let mut params: Vec<u8> = Vec::with_capacity(80);
+            for v in [
+                self.n,
+                per_pre as u32,
+                pres.len() as u32,
+                pmasks.len() as u32,
+            ] {
+                params.extend_from_slice(&v.to_le_bytes());
+            }
+            for w in self.geometry.iter().flatten() {
+                params.extend_from_slice(&w.to_le_bytes());
+            }
+            let mask_bytes = |ms: &[u128]| -> Vec<u8> {
+                // an empty binding is invalid; one zero mask stands in
+                let mut out: Vec<u8> = ms.iter().flat_map(|m| m.to_le_bytes()).collect();
+                out.resize(out.len().max(16), 0);
+                out
+            };
+
+            let scope = self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let init = |label, contents: &[u8], usage| {
+                self.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some(label),
+                        contents,
+                        usage,
+                    })
+            };
+            let params = init("params", &params, wgpu::BufferUsages::UNIFORM);
+            let pres_buf = init("pres", &mask_bytes(pres), wgpu::BufferUsages::STORAGE);
+            let pm_buf = init("pmasks", &mask_bytes(pmasks), wgpu::BufferUsages::STORAGE);
+            let keep = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("keep"),
+                size: out_bytes,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+            let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("keep readback"),
+                size: out_bytes,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let bind = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("evil-mask"),
+                layout: &self.pipeline.get_bind_group_layout(0),
+                entries: &[(0, &params), (1, &pres_buf), (2, &pm_buf), (3, &keep)].map(
+                    |(binding, buf)| wgpu::BindGroupEntry {
+                        binding,
+                        resource: buf.as_entire_binding(),
+                    },
+                ),
+            });
+
+            // a 2-D grid when one row of workgroups is not enough
+            let groups = (n_words as u64).div_ceil(WORKGROUP as u64);
+            let groups_x = groups.min(MAX_GROUPS as u64) as u32;
+            let groups_y = groups.div_ceil(groups_x as u64) as u32;
+            let mut encoder = self.device.create_command_encoder(&Default::default());
+            {
+                let mut pass = encoder.begin_compute_pass(&Default::default());
+                pass.set_pipeline(&self.pipeline);
+                pass.set_bind_group(0, &bind, &[]);
+                pass.dispatch_workgroups(groups_x, groups_y, 1);
+            }
+            encoder.copy_buffer_to_buffer(&keep, 0, &readback, 0, out_bytes);
+            self.queue.submit([encoder.finish()]);
+            if let Some(e) = block_on(scope.pop()) {
+                bail!("evil-mask dispatch: {}", e);
+            }
+
+            let (tx, rx) = mpsc::channel();
+            readback.slice(..).map_async(wgpu::MapMode::Read, move |r| {
+                let _ = tx.send(r);
+            });
+            self.device
+                .poll(wgpu::PollType::wait_indefinitely())
+                .context("wait for the evil-mask dispatch")?;
+            rx.recv()
+                .context("keep-bitmap readback dropped")?
+                .context("map the keep bitmap")?;
+            let words = readback
+                .slice(..)
+                .get_mapped_range()
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                .collect();
+            readback.unmap();
+            Ok(KeepBitmap {
+                words,
+                per_pre,
+                first,
+            })
+        }
+    }
+}
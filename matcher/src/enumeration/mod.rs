@@ -1,23 +1,63 @@
 use anyhow::{Context, Result, bail};
-use indicatif::{ProgressBar, ProgressStyle};
-use libc;
-use ndarray::{Array1, Array2};
+#[cfg(feature = "npz")]
+use ndarray::{Array1, Array2, ArrayD};
+#[cfg(feature = "npz")]
 use ndarray_npy::NpzReader;
+use num_bigint::BigUint;
 use smallvec::SmallVec;
 use std::fs::File;
 use std::mem;
 
 use ahash::AHashMap; // fast maps for hot paths
 use rayon::prelude::*;
+use std::collections::BTreeMap;
 use std::collections::HashMap as StdHashMap; // std map for Snapshot.compat // parallel within a root
 
-use std::sync::atomic::{AtomicU64, Ordering};
-
-use crate::matching::types::{Bucket, Snapshot};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::time::Instant;
+
+use crate::hugepages::AllocPhase;
+use crate::human::{Bytes, Secs};
+#[cfg(feature = "npz")]
+use crate::matching::npz_check::{check_member_sizes, check_offsets};
+use crate::matching::stats::{EnumCounts, SnapshotStats};
+use crate::matching::types::{
+    Bucket, Indptr, Pruning, Saturation, Snapshot, warn_saturation_sites,
+};
+#[cfg(feature = "npz")]
+use crate::matching::types::{EmptyDataError, SchemaError};
+use crate::runtime::{CancelToken, Cancelled, UtilSampler, resumable_progress_bar};
+use crate::settings;
 
 // expose the compat helper module you added at src/enumeration/compat.rs
+pub mod checkpoint;
 pub mod compat;
+mod completed_dump;
+#[cfg(feature = "npz")]
+pub mod estimate;
+#[cfg(feature = "npz")]
+mod expected;
+mod frontier_series;
+mod gpu;
+mod prune_table;
+mod repr;
+mod root_graph;
+pub mod root_order;
+pub mod spill;
+pub use checkpoint::{BlockDone, Checkpointing};
+#[cfg(feature = "npz")]
 use compat::{build_compat_map, debug_summary as compat_debug_summary};
+use completed_dump::CompletedDump;
+#[cfg(feature = "npz")]
+use expected::Expected;
+use frontier_series::{FrontierSeries, FrontierSize};
+use gpu::{EvilGpu, KeepBitmap};
+use prune_table::PruneTable;
+use repr::{Bits, Code, Count, Narrow, Repr, Wide, Width};
+use root_graph::RootGraph;
+pub use root_order::RootOrder;
+use spill::SpillWriter;
 
 // -------------------------------------------------------------------------------------
 // Tunables & light-weight typedefs
@@ -26,50 +66,65 @@ use compat::{build_compat_map, debug_summary as compat_debug_summary};
 /// Pending-batch size that triggers an early flush (keeps peaks down).
 /// Now runtime-tunable via `ENUM_PEND_FLUSH`; default 32_768.
 fn pend_flush_codes() -> usize {
-    std::env::var("ENUM_PEND_FLUSH")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(32_768)
+    settings::get().pend_flush
 }
 
-/// Limit how many pre_jbt from the (0,0) root we enumerate.
+/// Limit how many pre_jbt from the first root (0,0 in column order) we enumerate.
 /// Set via `ENUM_FIRST_LIMIT` (e.g., "500"); unset/empty -> no limit.
 fn first_bucket_limit() -> Option<usize> {
-    match std::env::var("ENUM_FIRST_LIMIT") {
-        Ok(s) if !s.is_empty() => s.parse().ok(),
-        _ => None,
-    }
+    settings::get().first_limit
 }
 
-/// Enumeration-time weight type (integer counts). Cast to f64 at snapshot build.
-type Weight = u32;
+/// Worker outputs allowed in flight before workers block on the merging thread.
+fn merge_queue_cap() -> usize {
+    2 * rayon::current_num_threads()
+}
 
-/// Count how many times we had to clamp Weight (u32) during reductions.
-static SATURATED_WEIGHTS: AtomicU64 = AtomicU64::new(0);
+/// Parallel (codes, weights) arrays moved between frontiers and out buckets.
+type CodeBatch<R> = (Vec<<R as Repr>::Code>, Vec<<R as Repr>::Weight>);
 
-// -------------------------------------------------------------------------------------
-// Memory tracking helpers (HPC safety)
-// -------------------------------------------------------------------------------------
+/// One vacate worker's codes bound for frontier (root_code, new_mask).
+type FrontierBatch<R> = ((i32, <R as Repr>::Mask), CodeBatch<R>);
 
-const KB: u64 = 1024;
-const MB: u64 = KB * 1024;
-const GB: u64 = MB * 1024;
+/// Per-worker vacate output: (one batch per (root_code, new_mask), completed_map keyed by pop-key).
+type VacateMaps<R> = (
+    Vec<FrontierBatch<R>>,
+    AHashMap<<R as Repr>::Mask, CodeBatch<R>>,
+);
 
-fn parse_budget_var(var: &str, multiplier: u64) -> Option<u64> {
-    let raw = std::env::var(var).ok()?;
-    if raw.trim().is_empty() {
-        return None;
+/// A flushed frontier bucket (root, mask, bucket), as checkpoints write them.
+type FrontierEntry<'a, R> = (usize, <R as Repr>::Mask, &'a AOBucket<R>);
+
+/// pop -> (key1, key2), same layout as `Snapshot.compat`.
+#[cfg(feature = "npz")]
+type CompatMap = StdHashMap<i32, (Vec<i32>, Vec<i32>)>;
+
+/// Count how many times we had to clamp a weight (`ENUM_WEIGHT_BITS`) during reductions.
+static SATURATED_WEIGHTS: AtomicU64 = AtomicU64::new(0);
+
+/// Where the clamps happened: (root, packed pop-key for completed buckets) -> count.
+/// Only touched when a flush actually clamps, so the lock is off the hot path.
+static SATURATIONS: Mutex<BTreeMap<(usize, Option<u128>), u64>> = Mutex::new(BTreeMap::new());
+
+/// Record `count` clamps in the frontier of `root` (`key == None`) or in the
+/// completed bucket `key` while vacating `root`.
+fn note_saturation(root: usize, key: Option<u128>, count: u64) {
+    if count == 0 {
+        return;
     }
-    raw.trim()
-        .parse::<u64>()
-        .ok()
-        .map(|v| v.saturating_mul(multiplier))
+    SATURATED_WEIGHTS.fetch_add(count, Ordering::Relaxed);
+    *SATURATIONS.lock().unwrap().entry((root, key)).or_insert(0) += count;
 }
 
+/// Count inserts dropped because a code already held `Code::MAX_LEN` entries.
+static CODE_OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+
+// -------------------------------------------------------------------------------------
+// Memory tracking helpers (HPC safety)
+// -------------------------------------------------------------------------------------
+
 fn memory_budget_bytes() -> Option<u64> {
-    parse_budget_var("ENUM_MAX_RSS_BYTES", 1)
-        .or_else(|| parse_budget_var("ENUM_MAX_RSS_MB", MB))
-        .or_else(|| parse_budget_var("ENUM_MAX_RSS_GB", GB))
+    settings::get().max_rss_bytes
 }
 
 fn current_rss_bytes() -> Option<u64> {
@@ -77,80 +132,112 @@ fn current_rss_bytes() -> Option<u64> {
     let mut parts = contents.split_whitespace();
     let _total = parts.next()?;
     let resident_pages: u64 = parts.next()?.parse().ok()?;
-    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    let page_size = page_size();
     if page_size <= 0 {
         return None;
     }
     Some(resident_pages.saturating_mul(page_size as u64))
 }
 
-fn bytes_to_gib(bytes: u64) -> f64 {
-    bytes as f64 / GB as f64
+/// Memory the run can still take: `MemAvailable`, capped by the
+/// `ENUM_MAX_RSS_*` budget when one is set. `None` when neither is known.
+fn available_memory_bytes() -> Option<u64> {
+    // "MemAvailable:  16318480 kB"
+    let free = std::fs::read_to_string("/proc/meminfo")
+        .ok()?
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024);
+    let budget = memory_budget_bytes().map(|b| b.saturating_sub(current_rss_bytes().unwrap_or(0)));
+    match (free, budget) {
+        (Some(f), Some(b)) => Some(f.min(b)),
+        (f, b) => f.or(b),
+    }
 }
 
-fn report_memory_after_vacate(root_idx: usize, budget: Option<u64>) -> Result<()> {
-    if let Some(rss) = current_rss_bytes() {
-        match budget {
-            Some(limit) => {
-                eprintln!(
-                    "[mem] root={} rss={:.2} GiB (limit {:.2} GiB)",
-                    root_idx,
-                    bytes_to_gib(rss),
-                    bytes_to_gib(limit)
-                );
-                if rss > limit {
-                    bail!(
-                        "RSS {:.2} GiB exceeded limit {:.2} GiB (set via ENUM_MAX_RSS_*)",
-                        bytes_to_gib(rss),
-                        bytes_to_gib(limit)
-                    );
-                }
-            }
-            None => {
-                eprintln!("[mem] root={} rss={:.2} GiB", root_idx, bytes_to_gib(rss));
-            }
+#[cfg(feature = "platform")]
+fn page_size() -> i64 {
+    // SAFETY: sysconf has no preconditions.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as i64 }
+}
+
+/// Without `platform`, assume the common 4 KiB page.
+#[cfg(not(feature = "platform"))]
+fn page_size() -> i64 {
+    4096
+}
+
+/// Log RSS after taking root `root_idx`; returns how far it is over `budget`.
+fn report_memory_after_vacate(root_idx: usize, budget: Option<u64>) -> Option<u64> {
+    let rss = current_rss_bytes()?;
+    match budget {
+        Some(limit) => {
+            eprintln!(
+                "[mem] root={} rss={} (limit {})",
+                root_idx,
+                Bytes(rss),
+                Bytes(limit)
+            );
+            rss.checked_sub(limit).filter(|&over| over > 0)
+        }
+        None => {
+            eprintln!("[mem] root={} rss={}", root_idx, Bytes(rss));
+            None
         }
     }
-    Ok(())
 }
 
-// --- NPZ compat loader (no `zip` crate needed) ---
-fn try_load_compat_npz(
+#[cfg(feature = "npz")]
+/// Member names of an NPZ, so optional arrays can be told apart from unreadable ones.
+/// Works for both `np.savez` (stored) and `np.savez_compressed` (deflate) members.
+fn npz_member_names<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
     path: &str,
-) -> anyhow::Result<Option<std::collections::HashMap<i32, (Vec<i32>, Vec<i32>)>>> {
-    let f = match File::open(path) {
-        Ok(f) => f,
-        Err(_) => return Ok(None),
-    };
-    let mut npz = match NpzReader::new(f) {
-        Ok(r) => r,
-        Err(_) => return Ok(None),
-    };
+) -> Result<std::collections::HashSet<String>> {
+    Ok(npz
+        .names()
+        .with_context(|| format!("list members of {}", path))?
+        .into_iter()
+        .collect())
+}
+
+#[cfg(feature = "npz")]
+// --- NPZ compat loader (no `zip` crate needed) ---
+fn try_load_compat_npz(path: &str) -> anyhow::Result<Option<CompatMap>> {
+    let f = File::open(path).with_context(|| format!("open {}", path))?;
+    let mut npz = NpzReader::new(f).with_context(|| format!("read npz {}", path))?;
+    let names = npz_member_names(&mut npz, path)?;
 
     // If this key is missing, assume compat not provided
-    let pops: Array1<i32> = match npz.by_name("meta_compat_pops.npy") {
-        Ok(a) => a,
-        Err(_) => return Ok(None),
-    };
+    if !names.contains("meta_compat_pops.npy") {
+        return Ok(None);
+    }
+    let pops: Array1<i32> = npz
+        .by_name("meta_compat_pops.npy")
+        .with_context(|| format!("read meta_compat_pops.npy from {}", path))?;
 
     let mut compat: std::collections::HashMap<i32, (Vec<i32>, Vec<i32>)> =
         std::collections::HashMap::new();
     for &p in pops.iter() {
-        let key1 = format!("compat_p{}_key1.npy", p);
-        let key2 = format!("compat_p{}_key2.npy", p);
-
-        let arr1: Array1<i32> = npz
-            .by_name(&key1)
-            .unwrap_or_else(|_| Array1::from_vec(vec![]));
-        let arr2: Array1<i32> = npz
-            .by_name(&key2)
-            .unwrap_or_else(|_| Array1::from_vec(vec![]));
+        let mut read_key = |key: String| -> Result<Vec<i32>> {
+            if !names.contains(&key) {
+                return Ok(Vec::new());
+            }
+            let arr: Array1<i32> = npz
+                .by_name(&key)
+                .with_context(|| format!("read {} from {}", key, path))?;
+            Ok(arr.to_vec())
+        };
+        let key1 = read_key(format!("compat_p{}_key1.npy", p))?;
+        let key2 = read_key(format!("compat_p{}_key2.npy", p))?;
 
-        compat.insert(p, (arr1.to_vec(), arr2.to_vec()));
+        compat.insert(p, (key1, key2));
     }
     Ok(Some(compat))
 }
 
+#[cfg(feature = "npz")]
 /// Ensure the solver will never panic:
 /// - Have entries for every pop in 1..N-1
 /// - Mirror-fill q=N-p if only p was provided
@@ -173,6 +260,7 @@ fn cover_and_symmetrize_compat(
     c
 }
 
+#[cfg(feature = "npz")]
 fn debug_pop_quickline(compat: &std::collections::HashMap<i32, (Vec<i32>, Vec<i32>)>, p: i32) {
     if let Some((k1, k2)) = compat.get(&p) {
         eprintln!("[compat] p={} -> (#k1={}, #k2={})", p, k1.len(), k2.len());
@@ -182,7 +270,7 @@ fn debug_pop_quickline(compat: &std::collections::HashMap<i32, (Vec<i32>, Vec<i3
 }
 
 // -------------------------------------------------------------------------------------
-// Packed row code (u128) utilities
+// Packed row code utilities (layouts in `repr`)
 // -------------------------------------------------------------------------------------
 
 #[inline(always)]
@@ -191,169 +279,172 @@ fn bitwidth(m: usize) -> u32 {
     1u32.max(m1.next_power_of_two().trailing_zeros())
 }
 
-#[inline(always)]
-fn code_len(code: u128) -> u32 {
-    (code & 0xF) as u32
-}
-
-#[inline(always)]
-fn code_len_u128(code: u128) -> usize {
-    (code & 0xF) as usize
-}
-
-#[inline(always)]
-fn code_get(code: u128, i: u32, b: u32) -> u32 {
-    let shift = 4 + i * b;
-    if shift < 64 {
-        let rem = 64 - shift;
-        if b <= rem {
-            ((code as u64 >> shift) & ((1u64 << b) - 1)) as u32
-        } else {
-            let low = (code as u64 >> shift) & ((1u64 << rem) - 1);
-            let hi = (code >> 64) as u64 & ((1u64 << (b - rem)) - 1);
-            ((hi as u128) << rem | (low as u128)) as u32
-        }
-    } else {
-        let s = shift - 64;
-        (((code >> 64) as u64 >> s) & ((1u64 << b) - 1)) as u32
+/// Bits per j index: `bitwidth(M)`, or `ENUM_CODE_BITS` if set (must be at least
+/// that). Whether codes that wide fit is up to `repr::pick`.
+fn code_bitwidth(m: usize) -> Result<u32> {
+    let need = bitwidth(m);
+    match settings::get().code_bits {
+        Some(b) if b < need => bail!(
+            "ENUM_CODE_BITS={} is too narrow for M={} (needs {})",
+            b,
+            m,
+            need
+        ),
+        Some(b) => Ok(b),
+        None => Ok(need),
     }
 }
 
-#[inline(always)]
-fn code_set(code: &mut u128, i: u32, b: u32, val: u32) {
-    let shift = 4 + i * b;
-    let v = (val as u128) & ((1u128 << b) - 1);
-    if shift < 64 {
-        let rem = 64 - shift;
-        if b <= rem {
-            let mask = !(((1u128 << b) - 1) << shift);
-            *code = (*code & mask) | (v << shift);
-        } else {
-            // split across 64-bit boundary
-            let low_bits = rem;
-            let low_mask = ((1u128 << low_bits) - 1) << shift;
-            let hi_bits = b - low_bits;
-            let hi_mask = ((1u128 << hi_bits) - 1) << 64;
-
-            let low_part = (v & ((1u128 << low_bits) - 1)) << shift;
-            let hi_part = (v >> low_bits) << 64;
-
-            *code = (*code & !low_mask) | low_part;
-            *code = (*code & !hi_mask) | hi_part;
-        }
-    } else {
-        let s = shift - 64;
-        let mask = !(((1u128 << b) - 1) << (64 + s));
-        *code = (*code & mask) | (v << (64 + s));
-    }
-}
-
-#[inline(always)]
-fn code_with_len(mut code: u128, k: u32) -> u128 {
-    code = (code & !0xFu128) | (k as u128 & 0xF);
-    code
-}
-
 /// Insert j into sorted set inside `code`. Returns (new_code, inserted).
 #[inline(always)]
-fn code_insert(code: u128, j: u32, b: u32) -> (u128, bool) {
-    let mut k = code_len(code);
+fn code_insert<C: Code>(code: C, j: u32, b: u32) -> (C, bool) {
+    let mut k = code.entries();
     let mut lo = 0i32;
     let mut hi = k as i32;
     while lo < hi {
         let mid = (lo + hi) >> 1;
-        let v = code_get(code, mid as u32, b);
+        let v = code.get(mid as u32, b);
         if v < j {
             lo = mid + 1;
         } else {
             hi = mid;
         }
     }
-    if lo < k as i32 && code_get(code, lo as u32, b) == j {
+    if lo < k as i32 && code.get(lo as u32, b) == j {
         return (code, false);
     }
-    if k >= 10 {
+    if k >= C::MAX_LEN {
+        CODE_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
         return (code, false);
     }
     let mut out = code;
     let mut idx = k;
     while idx > lo as u32 {
-        let prev = code_get(out, idx - 1, b);
-        code_set(&mut out, idx, b, prev);
+        let prev = out.get(idx - 1, b);
+        out.set(idx, b, prev);
         idx -= 1;
     }
-    code_set(&mut out, lo as u32, b, j);
+    out.set(lo as u32, b, j);
     k += 1;
-    out = code_with_len(out, k);
+    out = out.with_entries(k);
     (out, true)
 }
 
 /// Iterate j's in code (ascending).
 #[inline(always)]
-fn code_iter<'a>(code: u128, b: u32) -> impl Iterator<Item = u32> + 'a {
-    let k = code_len(code);
-    (0..k).map(move |i| code_get(code, i, b))
+fn code_iter<'a, C: Code>(code: C, b: u32) -> impl Iterator<Item = u32> + 'a {
+    let k = code.entries();
+    (0..k).map(move |i| code.get(i, b))
 }
 
 // -------------------------------------------------------------------------------------
-// Bitboard helpers (N<=10, left half <= 50 bits)
+// Bitboard helpers (left half of n * (n / 2) <= 128 bits)
 // -------------------------------------------------------------------------------------
 
 #[inline]
-fn left_half_mask(n: u32) -> u64 {
-    if n == 0 {
-        0
-    } else {
-        ((1u128 << (n as u128 * (n / 2) as u128)) - 1) as u64
+fn left_half_mask<M: Bits>(n: u32) -> M {
+    match n * (n / 2) {
+        128 => M::from_u128(u128::MAX),
+        bits => M::from_u128((1u128 << bits) - 1),
     }
 }
 #[inline]
-fn col_mask(n: u32, x: u32) -> u64 {
-    (((1u64 << n) - 1) as u64) << (x * n)
+fn col_mask<M: Bits>(n: u32, x: u32) -> M {
+    M::from_u128(((1u128 << n) - 1) << (x * n))
 }
 #[inline]
-fn edge_masks(n: u32) -> (u64, u64) {
-    let mut top = 0u64;
-    let mut bot = 0u64;
+fn edge_masks<M: Bits>(n: u32) -> (M, M) {
+    let mut top = M::ZERO;
+    let mut bot = M::ZERO;
     for x in 0..n {
-        top |= 1u64 << (x * n + (n - 1));
-        bot |= 1u64 << (x * n + 0);
+        top |= M::ONE << (x * n + (n - 1));
+        bot |= M::ONE << (x * n);
     }
     (top, bot)
 }
 
-#[inline]
-fn flood_fill(seed: u64, domain: u64, n: u32) -> u64 {
-    if seed == 0 {
-        return 0;
+/// Per-N bitboard constants, built once per enumeration and threaded through
+/// the hot checks instead of being recomputed on every call.
+#[derive(Clone, Copy)]
+struct BoardGeometry<M> {
+    n: u32,
+    /// all left-half cells
+    half: M,
+    /// column n/2 - 1: components touching it can still be closed from the right half
+    escape: M,
+    /// cells that may shift up / down without wrapping into the next column
+    not_top: M,
+    not_bot: M,
+    /// left-half cells by root index (`ENUM_ROOT_ORDER`), first `n * (n / 2)` used
+    cells: [u8; 128],
+    /// roots are the bit indices themselves, so `find_root` needs no lookup
+    column_major: bool,
+}
+impl<M: Bits> BoardGeometry<M> {
+    fn new(n: u32) -> Self {
+        let (top, bot) = edge_masks::<M>(n);
+        BoardGeometry {
+            n,
+            half: left_half_mask(n),
+            escape: col_mask(n, n / 2 - 1),
+            not_top: !top,
+            not_bot: !bot,
+            cells: std::array::from_fn(|c| c as u8),
+            column_major: true,
+        }
+    }
+
+    /// Vacate roots in the order of `cells` instead of by bit index.
+    fn with_order(mut self, cells: &[u8]) -> Self {
+        self.cells[..cells.len()].copy_from_slice(cells);
+        self.column_major = false;
+        self
+    }
+
+    /// Board cell (u, v) of root `root`.
+    fn root_cell(&self, root: usize) -> (usize, usize) {
+        let c = self.cells[root] as usize;
+        (c / self.n as usize, c % self.n as usize)
+    }
+
+    /// Board cell of every root, in root order.
+    fn root_cells(&self) -> Vec<(usize, usize)> {
+        (0..self.half.count_ones() as usize)
+            .map(|root| self.root_cell(root))
+            .collect()
     }
-    let (top_mask, bot_mask) = edge_masks(n);
-    let mut comp = 0u64;
+
+    /// A complement component that can never be tiled: sealed off from the
+    /// escape column and not a multiple of n cells.
+    #[inline(always)]
+    fn is_sealed_bad(&self, comp: M) -> bool {
+        (comp & self.escape) == M::ZERO && !comp.count_ones().is_multiple_of(self.n)
+    }
+}
+
+#[inline]
+fn flood_fill<M: Bits>(seed: M, domain: M, g: &BoardGeometry<M>) -> M {
     let mut frontier = seed & domain;
-    while frontier != 0 {
-        comp |= frontier;
-        let up = (frontier & !top_mask) << 1;
-        let down = (frontier & !bot_mask) >> 1;
-        let left = frontier >> n;
-        let right = frontier << n;
-        frontier = (up | down | left | right) & domain & !comp;
+    // `todo` shrinks as cells are reached, so each step needs a single mask
+    let mut todo = domain & !frontier;
+    while frontier != M::ZERO {
+        let up = (frontier & g.not_top) << 1;
+        let down = (frontier & g.not_bot) >> 1;
+        let left = frontier >> g.n;
+        let right = frontier << g.n;
+        frontier = (up | down | left | right) & todo;
+        todo &= !frontier;
     }
-    comp
+    domain & !todo
 }
 
 #[inline]
-fn detect_evil_pmask(partial_mask: u64, n: u32) -> bool {
-    let half = left_half_mask(n);
-    let escape = col_mask(n, n / 2 - 1);
-    let mut complement = partial_mask ^ half;
-    while complement != 0 {
+fn detect_evil_pmask<M: Bits>(partial_mask: M, g: &BoardGeometry<M>) -> bool {
+    let mut complement = partial_mask ^ g.half;
+    while complement != M::ZERO {
         let seed = complement & complement.wrapping_neg();
-        let comp = flood_fill(seed, complement, n);
-        if (comp & escape) != 0 {
-            complement ^= comp;
-            continue;
-        }
-        if comp.count_ones() % n != 0 {
+        let comp = flood_fill(seed, complement, g);
+        if g.is_sealed_bad(comp) {
             return true;
         }
         complement ^= comp;
@@ -361,25 +452,139 @@ fn detect_evil_pmask(partial_mask: u64, n: u32) -> bool {
     false
 }
 
+/// Complement components of one frontier mask, computed once per vacated root.
+/// Placing a (connected) pre_jbt inside the complement only splits the single
+/// component it lands in, so extensions re-flood just that component.
+struct MaskComponents<M> {
+    comps: SmallVec<[M; 4]>,
+    /// bit k set if comps[k] is sealed off from the escape column with size % n != 0
+    /// (a mask has a bit per left-half cell, so one per component)
+    bad: M,
+}
+impl<M: Bits> MaskComponents<M> {
+    fn new(partial_mask: M, g: &BoardGeometry<M>) -> Self {
+        let mut comps = SmallVec::new();
+        let mut bad = M::ZERO;
+        let mut complement = partial_mask ^ g.half;
+        while complement != M::ZERO {
+            let seed = complement & complement.wrapping_neg();
+            let comp = flood_fill(seed, complement, g);
+            if g.is_sealed_bad(comp) {
+                bad |= M::ONE << comps.len() as u32;
+            }
+            comps.push(comp);
+            complement ^= comp;
+        }
+        MaskComponents { comps, bad }
+    }
+
+    /// Same verdict as `detect_evil_pmask(partial_mask | pmask_pre, g)`.
+    fn extension_is_evil(&self, partial_mask: M, pmask_pre: M, g: &BoardGeometry<M>) -> bool {
+        let Some(k) = self.comps.iter().position(|&c| c & pmask_pre == pmask_pre) else {
+            // pre straddles components: no shortcut
+            return detect_evil_pmask(partial_mask | pmask_pre, g);
+        };
+        if self.bad & !(M::ONE << k as u32) != M::ZERO {
+            return true;
+        }
+        let mut rest = self.comps[k] & !pmask_pre;
+        while rest != M::ZERO {
+            let seed = rest & rest.wrapping_neg();
+            let comp = flood_fill(seed, rest, g);
+            if g.is_sealed_bad(comp) {
+                return true;
+            }
+            rest ^= comp;
+        }
+        false
+    }
+}
+
+/// Evil-mask memo hit/miss totals, flushed from each `EvilMemo` on drop.
+static EVIL_MEMO_HITS: AtomicU64 = AtomicU64::new(0);
+static EVIL_MEMO_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Entries kept per memo before it is reset (bounds per-worker memory).
+const EVIL_MEMO_CAP: usize = 1 << 20;
+
+/// Worker-local memo of `detect_evil_pmask` verdicts. The same new_mask recurs
+/// across pre_jbts of a root, so each rayon split keeps its own lock-free map.
+#[derive(Default)]
+struct EvilMemo<M> {
+    verdicts: AHashMap<M, bool>,
+    hits: u64,
+    misses: u64,
+}
+impl<M: Bits> EvilMemo<M> {
+    #[inline]
+    fn is_evil(&mut self, mask: M, g: &BoardGeometry<M>, compute: impl FnOnce() -> bool) -> bool {
+        if let Some(&v) = self.verdicts.get(&mask) {
+            self.hits += 1;
+            return v;
+        }
+        self.misses += 1;
+        if self.verdicts.len() >= EVIL_MEMO_CAP {
+            self.verdicts.clear();
+        }
+        let v = compute();
+        debug_assert_eq!(v, detect_evil_pmask(mask, g));
+        self.verdicts.insert(mask, v);
+        v
+    }
+}
+impl<M> Drop for EvilMemo<M> {
+    fn drop(&mut self) {
+        EVIL_MEMO_HITS.fetch_add(self.hits, Ordering::Relaxed);
+        EVIL_MEMO_MISSES.fetch_add(self.misses, Ordering::Relaxed);
+    }
+}
+
+/// Root of the first left-half cell `partial_mask` leaves uncovered, in root
+/// order; `None` once the left half is covered.
 #[inline]
-fn find_root(partial_mask: u64, n: u32) -> Option<(u32, u32)> {
-    let left = left_half_mask(n);
-    let complement = partial_mask ^ left;
-    if complement == 0 {
+fn find_root<M: Bits>(partial_mask: M, g: &BoardGeometry<M>) -> Option<usize> {
+    let complement = partial_mask ^ g.half;
+    if complement == M::ZERO {
         return None;
     }
-    let lsb = complement & complement.wrapping_neg();
-    let bit_pos = lsb.trailing_zeros();
-    let (x, y) = (bit_pos / n, bit_pos % n);
-    Some((x, y))
+    if g.column_major {
+        return Some(complement.trailing_zeros() as usize);
+    }
+    g.cells.iter().position(|&c| complement.has(c as u32))
+}
+
+/// Apply `ENUM_ROOT_ORDER`: the pre-CSR regrouped by root in that order, and
+/// the geometry whose `find_root` follows it.
+fn order_roots<M: Bits>(n: u32, pre: PreCsr) -> (PreCsr, BoardGeometry<M>) {
+    let geom = BoardGeometry::new(n);
+    let Some(cells) = root_cell_order(n, &pre) else {
+        return (pre, geom);
+    };
+    let geom = geom.with_order(&cells);
+    eprintln!(
+        "[order] roots vacated in {} order: {}",
+        settings::get().root_order,
+        geom.root_cells()
+            .iter()
+            .map(|(u, v)| format!("({},{})", u, v))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    (root_order::reanchor(pre, &cells), geom)
 }
 
+/// The root cells in `ENUM_ROOT_ORDER`, or `None` for the column order the
+/// pre-CSR already has.
+fn root_cell_order(n: u32, pre: &PreCsr) -> Option<Vec<u8>> {
+    let order = settings::get().root_order;
+    (order != RootOrder::Column).then(|| root_order::cell_order(order, n, pre))
+}
 // -------------------------------------------------------------------------------------
 // Input CSR for pre_jbt
 // -------------------------------------------------------------------------------------
 
 pub struct PreCsr {
-    pub masks: Vec<u64>,     // len = nnz
+    pub masks: Vec<u128>,    // len = nnz; run width is picked per N (see `repr`)
     pub pops: Vec<u8>,       // len = nnz
     pub jidx: Vec<u32>,      // len = nnz
     pub offsets: Vec<usize>, // len = n_roots + 1
@@ -394,30 +599,78 @@ pub struct Inputs {
     pub jbt_ref_comps: Vec<[u16; 3]>, // len = M (or empty if not provided)
 }
 
+/// First few offending entries of a failed range check, for the error message.
+fn list_offenders(idx: &[usize], show: impl Fn(usize) -> String) -> String {
+    const MAX_LISTED: usize = 10;
+    let shown: Vec<String> = idx.iter().take(MAX_LISTED).map(|&e| show(e)).collect();
+    shown.join(", ")
+}
+
 /// Load NPZ with:
 /// - N, M
-/// - pre_masks[u64], pre_pops[u8], pre_jidx[u32], pre_offsets[i64]
+/// - pre_masks[u64] (nnz, or nnz x 2 as [low, high] words when the
+///   half-board passes 64 bits), pre_pops[u8], pre_jidx[u32], pre_offsets[i64]
 /// - jbt_ref_pop[i32], jbt_ref_comps[u16] (M x 3)
+#[cfg(feature = "npz")]
 pub fn load_inputs_npz(path: &str) -> Result<Inputs> {
     let f = File::open(path).with_context(|| format!("open {}", path))?;
-    let mut npz = NpzReader::new(f)?;
-    let n_arr: Array1<i32> = npz.by_name("N.npy")?;
-    let m_arr: Array1<i32> = npz.by_name("M.npy")?;
+    load_inputs_from(f, path)
+}
 
-    let n = n_arr[0] as u32;
-    let m = m_arr[0] as usize;
+/// `load_inputs_npz` over any seekable reader; `path` only labels messages.
+/// Malformed contents are reported as errors, never panics (see `fuzz/`).
+#[cfg(feature = "npz")]
+pub fn load_inputs_from<R: std::io::Read + std::io::Seek>(reader: R, path: &str) -> Result<Inputs> {
+    let reader = check_member_sizes(reader, path)?;
+    let mut npz = NpzReader::new(reader).with_context(|| format!("read npz {}", path))?;
+    let names = npz_member_names(&mut npz, path)?;
+    macro_rules! member {
+        ($name:expr) => {
+            npz.by_name($name)
+                .with_context(|| format!("read {} from {}", $name, path))?
+        };
+    }
+    let n_arr: Array1<i32> = member!("N.npy");
+    let m_arr: Array1<i32> = member!("M.npy");
 
-    let masks: Array1<u64> = npz.by_name("pre_masks.npy")?;
-    let pops: Array1<u8> = npz.by_name("pre_pops.npy")?;
-    let jidx: Array1<u32> = npz.by_name("pre_jidx.npy")?;
-    let offs: Array1<i64> = npz.by_name("pre_offsets.npy")?;
-    let jpop: Array1<i32> = npz.by_name("jbt_ref_pop.npy")?;
+    let n = *n_arr.first().context("N.npy is empty")?;
+    let m = *m_arr.first().context("M.npy is empty")?;
+    if n <= 0 || m < 0 {
+        bail!(
+            "{}: N={} must be positive and M={} non-negative",
+            path,
+            n,
+            m
+        );
+    }
+    let (n, m) = (n as u32, m as usize);
+
+    let masks: ArrayD<u64> = member!("pre_masks.npy");
+    let masks: Vec<u128> = match *masks.shape() {
+        [_] => masks.iter().map(|&m| m as u128).collect(),
+        [_, 2] => {
+            let words: Vec<u64> = masks.iter().copied().collect();
+            words
+                .chunks_exact(2)
+                .map(|w| w[0] as u128 | (w[1] as u128) << 64)
+                .collect()
+        }
+        ref shape => bail!(
+            "pre_masks.npy has shape {:?} (expected nnz, or nnz x 2 words)",
+            shape
+        ),
+    };
+    let pops: Array1<u8> = member!("pre_pops.npy");
+    let jidx: Array1<u32> = member!("pre_jidx.npy");
+    let offs: Array1<i64> = member!("pre_offsets.npy");
+    let jpop: Array1<i32> = member!("jbt_ref_pop.npy");
 
     // Optional comps — if missing, we proceed with empty compat (keys still filled)
     let jbt_ref_comps: Vec<[u16; 3]> = {
-        let a2: Array2<u16> = match npz.by_name("jbt_ref_comps.npy") {
-            Ok(arr) => arr,
-            Err(_) => Array2::<u16>::zeros((0, 3)),
+        let a2: Array2<u16> = if names.contains("jbt_ref_comps.npy") {
+            member!("jbt_ref_comps.npy")
+        } else {
+            Array2::<u16>::zeros((0, 3))
         };
         if a2.is_empty() {
             Vec::new()
@@ -446,24 +699,74 @@ pub fn load_inputs_npz(path: &str) -> Result<Inputs> {
             jidx.len()
         );
     }
-    let off_last = *offs.last().context("pre_offsets is empty")? as usize;
-    if off_last != nnz {
-        bail!("pre_offsets last={} does not equal nnz={}", off_last, nnz);
+    if offs.is_empty() {
+        return Err(EmptyDataError(format!("{}: pre_offsets is empty (no roots)", path)).into());
+    }
+    check_offsets("pre_offsets", offs.as_slice().unwrap_or(&[]), nnz)
+        .with_context(|| format!("input {}", path))?;
+    // one root per left-half cell; enumeration sizes per-root state from this
+    let n_roots = offs.len() - 1;
+    if n_roots as u64 != n as u64 * (n as u64 / 2) {
+        return Err(SchemaError(format!(
+            "{}: pre_offsets has {} roots, N={} needs {}",
+            path,
+            n_roots,
+            n,
+            n as u64 * (n as u64 / 2)
+        ))
+        .into());
     }
     if jpop.len() as usize != m {
         bail!("jbt_ref_pop has len {}, expected M={}", jpop.len(), m);
     }
+    if nnz == 0 {
+        return Err(EmptyDataError(format!(
+            "{}: no pre_jbt entries (nnz=0 across {} roots)",
+            path,
+            offs.len().saturating_sub(1)
+        ))
+        .into());
+    }
+
+    // jidx indexes jbt_ref_pop; mask bits must stay on the left half-board
+    let bad_j: Vec<usize> = (0..nnz).filter(|&e| jidx[e] as usize >= m).collect();
+    if !bad_j.is_empty() {
+        bail!(
+            "pre_jidx out of range (M={}): {} entries, first {}",
+            m,
+            bad_j.len(),
+            list_offenders(&bad_j, |e| format!("[{}]={}", e, jidx[e]))
+        );
+    }
+    let half_bits = n as u64 * (n as u64 / 2);
+    if half_bits > 128 {
+        bail!(
+            "N={} gives a {}-bit half-board; masks hold 128 bits",
+            n,
+            half_bits
+        );
+    }
+    let allowed = if half_bits == 128 {
+        u128::MAX
+    } else {
+        (1u128 << half_bits) - 1
+    };
+    let bad_m: Vec<usize> = (0..nnz).filter(|&e| masks[e] & !allowed != 0).collect();
+    if !bad_m.is_empty() {
+        bail!(
+            "pre_masks have bits beyond n*(n/2)={}: {} entries, first {}",
+            half_bits,
+            bad_m.len(),
+            list_offenders(&bad_m, |e| format!("[{}]={:#x}", e, masks[e]))
+        );
+    }
 
     let offsets: Vec<usize> = offs.iter().map(|&x| x as usize).collect();
-    let n_roots = offsets
-        .len()
-        .checked_sub(1)
-        .context("offsets must be >= 1")?;
     Ok(Inputs {
         n,
         m,
         pre: PreCsr {
-            masks: masks.to_vec(),
+            masks,
             pops: pops.to_vec(),
             jidx: jidx.to_vec(),
             offsets,
@@ -474,33 +777,140 @@ pub fn load_inputs_npz(path: &str) -> Result<Inputs> {
     })
 }
 
+/// Concatenate pre-CSR parts split across several NPZs (same N, M and jbt_ref
+/// tables, each root's pre_jbt in at most one part) into one `Inputs`.
+pub fn merge_inputs(mut parts: Vec<(String, Inputs)>) -> Result<Inputs> {
+    if parts.len() <= 1 {
+        return parts
+            .pop()
+            .map(|(_, inp)| inp)
+            .context("no inputs to merge");
+    }
+    let (first_path, first) = &parts[0];
+    for (path, inp) in &parts[1..] {
+        if (inp.n, inp.m, inp.pre.n_roots) != (first.n, first.m, first.pre.n_roots) {
+            bail!(
+                "{}: N={} M={} roots={} does not match {} (N={} M={} roots={})",
+                path,
+                inp.n,
+                inp.m,
+                inp.pre.n_roots,
+                first_path,
+                first.n,
+                first.m,
+                first.pre.n_roots
+            );
+        }
+        if inp.jbt_ref_pop != first.jbt_ref_pop {
+            bail!("{}: jbt_ref_pop differs from {}", path, first_path);
+        }
+        if !inp.jbt_ref_comps.is_empty()
+            && !first.jbt_ref_comps.is_empty()
+            && inp.jbt_ref_comps != first.jbt_ref_comps
+        {
+            bail!("{}: jbt_ref_comps differs from {}", path, first_path);
+        }
+    }
+
+    let n_roots = first.pre.n_roots;
+    let owners = |r: usize| {
+        parts
+            .iter()
+            .filter(|(_, inp)| inp.pre.offsets[r + 1] > inp.pre.offsets[r])
+            .count()
+    };
+    let shared: Vec<usize> = (0..n_roots).filter(|&r| owners(r) > 1).collect();
+    if !shared.is_empty() {
+        bail!(
+            "input parts overlap: {} roots have pre_jbt in more than one part, first {}",
+            shared.len(),
+            list_offenders(&shared, |r| r.to_string())
+        );
+    }
+
+    let nnz: usize = parts.iter().map(|(_, inp)| inp.pre.masks.len()).sum();
+    let mut pre = PreCsr {
+        masks: Vec::with_capacity(nnz),
+        pops: Vec::with_capacity(nnz),
+        jidx: Vec::with_capacity(nnz),
+        offsets: Vec::with_capacity(n_roots + 1),
+        n_roots,
+    };
+    pre.offsets.push(0);
+    for r in 0..n_roots {
+        for (_, inp) in &parts {
+            let range = inp.pre.offsets[r]..inp.pre.offsets[r + 1];
+            pre.masks.extend_from_slice(&inp.pre.masks[range.clone()]);
+            pre.pops.extend_from_slice(&inp.pre.pops[range.clone()]);
+            pre.jidx.extend_from_slice(&inp.pre.jidx[range]);
+        }
+        pre.offsets.push(pre.masks.len());
+    }
+    eprintln!(
+        "[inputs] merged {} parts: {} pre_jbt across {} roots",
+        parts.len(),
+        nnz,
+        (0..n_roots).filter(|&r| owners(r) == 1).count()
+    );
+
+    let jbt_ref_comps = parts
+        .iter()
+        .map(|(_, inp)| &inp.jbt_ref_comps)
+        .find(|c| !c.is_empty())
+        .cloned()
+        .unwrap_or_default();
+    let (_, first) = parts.swap_remove(0);
+    Ok(Inputs {
+        n: first.n,
+        m: first.m,
+        pre,
+        jbt_ref_pop: first.jbt_ref_pop,
+        jbt_ref_comps,
+    })
+}
+
 // -------------------------------------------------------------------------------------
 // Frontier + Out buckets
 // -------------------------------------------------------------------------------------
 
-#[derive(Default)]
-struct AOBucket {
+struct AOBucket<R: Repr> {
     // committed
-    codes: Vec<u128>,
-    weights: Vec<Weight>,
+    codes: Vec<R::Code>,
+    weights: Vec<R::Weight>,
     // pending
-    pend_codes: Vec<u128>,
-    pend_w: Vec<Weight>,
+    pend_codes: Vec<R::Code>,
+    pend_w: Vec<R::Weight>,
+}
+impl<R: Repr> Default for AOBucket<R> {
+    fn default() -> Self {
+        AOBucket {
+            codes: Vec::new(),
+            weights: Vec::new(),
+            pend_codes: Vec::new(),
+            pend_w: Vec::new(),
+        }
+    }
 }
-impl AOBucket {
-    fn append_batch(&mut self, codes: Vec<u128>, w: Vec<Weight>) {
+impl<R: Repr> AOBucket<R> {
+    /// Returns the number of weights clamped if the batch triggered a flush.
+    #[must_use]
+    fn append_batch(&mut self, codes: Vec<R::Code>, w: Vec<R::Weight>) -> u64 {
         if codes.is_empty() {
-            return;
+            return 0;
         }
         self.pend_codes.extend(codes);
         self.pend_w.extend(w);
         if self.pend_codes.len() >= pend_flush_codes() {
-            self.flush();
+            return self.flush();
         }
+        0
     }
-    fn flush(&mut self) {
+    /// Sort and reduce pending into committed; returns the number of weights
+    /// clamped to `R::Weight::MAX`.
+    #[must_use]
+    fn flush(&mut self) -> u64 {
         if self.pend_codes.is_empty() {
-            return;
+            return 0;
         }
         // concat committed + pending, then sort & reduce
         let mut all_codes = Vec::with_capacity(self.codes.len() + self.pend_codes.len());
@@ -513,23 +923,24 @@ impl AOBucket {
         let mut idx: Vec<usize> = (0..all_codes.len()).collect();
         idx.sort_unstable_by_key(|&i| all_codes[i]);
 
-        let mut new_codes: Vec<u128> = Vec::with_capacity(all_codes.len());
-        let mut new_w: Vec<Weight> = Vec::with_capacity(all_w.len());
+        let mut new_codes: Vec<R::Code> = Vec::with_capacity(all_codes.len());
+        let mut new_w: Vec<R::Weight> = Vec::with_capacity(all_w.len());
+        let mut saturated = 0u64;
         let mut i = 0usize;
         while i < idx.len() {
             let c = all_codes[idx[i]];
-            let mut sum: u64 = all_w[idx[i]] as u64;
+            let mut sum: u128 = all_w[idx[i]].to_u128();
             i += 1;
             while i < idx.len() && all_codes[idx[i]] == c {
-                sum = sum.saturating_add(all_w[idx[i]] as u64);
+                sum = sum.saturating_add(all_w[idx[i]].to_u128());
                 i += 1;
             }
             new_codes.push(c);
-            let packed = if sum > Weight::MAX as u64 {
-                SATURATED_WEIGHTS.fetch_add(1, Ordering::Relaxed);
-                Weight::MAX
+            let packed = if sum > R::Weight::MAX.to_u128() {
+                saturated += 1;
+                R::Weight::MAX
             } else {
-                sum as Weight
+                R::Weight::from_u128(sum)
             };
             new_w.push(packed);
         }
@@ -537,17 +948,37 @@ impl AOBucket {
         self.weights = new_w;
         self.pend_codes.clear();
         self.pend_w.clear();
+        saturated
+    }
+    /// Count this bucket into `size`: its states, and heap held by the code and
+    /// weight vectors plus the bucket, its mask and its index slot (`M`).
+    fn add_to(&self, size: &mut FrontierSize) {
+        size.masks += 1;
+        size.codes += self.codes.len() + self.pend_codes.len();
+        size.bytes += ((self.codes.capacity() + self.pend_codes.capacity())
+            * mem::size_of::<R::Code>()
+            + (self.weights.capacity() + self.pend_w.capacity()) * mem::size_of::<R::Weight>()
+            + mem::size_of::<AOBucket<R>>()
+            + 3 * mem::size_of::<R::Mask>()) as u64;
     }
 }
 
-#[derive(Default)]
-struct RootFrontier {
-    masks: Vec<u64>,
-    buckets: Vec<AOBucket>,
-    index: AHashMap<u64, usize>,
+struct RootFrontier<R: Repr> {
+    masks: Vec<R::Mask>,
+    buckets: Vec<AOBucket<R>>,
+    index: AHashMap<R::Mask, usize>,
+}
+impl<R: Repr> Default for RootFrontier<R> {
+    fn default() -> Self {
+        RootFrontier {
+            masks: Vec::new(),
+            buckets: Vec::new(),
+            index: AHashMap::default(),
+        }
+    }
 }
-impl RootFrontier {
-    fn get_bucket_mut(&mut self, mask: u64) -> &mut AOBucket {
+impl<R: Repr> RootFrontier<R> {
+    fn get_bucket_mut(&mut self, mask: R::Mask) -> &mut AOBucket<R> {
         if let Some(&pos) = self.index.get(&mask) {
             return &mut self.buckets[pos];
         }
@@ -557,66 +988,261 @@ impl RootFrontier {
         self.buckets.push(AOBucket::default());
         &mut self.buckets[pos]
     }
-    fn flush(&mut self) {
-        for b in &mut self.buckets {
-            b.flush();
-        }
+    fn flush(&mut self) -> u64 {
+        self.buckets.iter_mut().map(|b| b.flush()).sum()
     }
 
     // (Removed the unused `clear` method to avoid a warning)
 }
 
-#[derive(Default)]
-struct OutBuckets {
-    by_key: AHashMap<u64, AOBucket>, // key = packed pop multiset; low nibble = k (fits u64 for N<=10)
+/// Size of every frontier not yet vacated.
+fn frontier_size<R: Repr>(all_frontiers: &[RootFrontier<R>]) -> FrontierSize {
+    let mut size = FrontierSize::default();
+    for bkt in all_frontiers.iter().flat_map(|rf| &rf.buckets) {
+        bkt.add_to(&mut size);
+    }
+    size
 }
-impl OutBuckets {
-    fn append_completed(&mut self, key: u64, codes: Vec<u128>, w: Vec<Weight>) {
-        let b = self.by_key.entry(key).or_default();
-        b.append_batch(codes, w);
+
+/// The buckets of frontier `root`, with their masks.
+fn root_buckets_mut<R: Repr>(rf: &mut RootFrontier<R>) -> Vec<(R::Mask, &mut AOBucket<R>)> {
+    rf.masks
+        .iter()
+        .copied()
+        .zip(rf.buckets.iter_mut())
+        .collect()
+}
+
+/// Frontiers moved to disk to stay under `ENUM_MAX_RSS_*`: per root, a spill
+/// file of committed (sorted, reduced) chunks, read back into the root's
+/// buckets when it is vacated. Files still open when enumeration ends early
+/// are removed on drop.
+struct SpilledRoots {
+    files: Vec<Option<(std::path::PathBuf, SpillWriter)>>,
+}
+impl SpilledRoots {
+    fn new(total_roots: usize) -> Self {
+        SpilledRoots {
+            files: (0..total_roots).map(|_| None).collect(),
+        }
     }
-    fn flush_all(&mut self) {
-        for b in self.by_key.values_mut() {
-            b.flush();
+
+    /// Flush the buckets of `root` and move their committed codes to its spill
+    /// file; returns the heap bytes released.
+    fn spill_root<R: Repr>(
+        &mut self,
+        root: usize,
+        buckets: Vec<(R::Mask, &mut AOBucket<R>)>,
+    ) -> Result<u64> {
+        let mut freed = 0u64;
+        for (mask, bkt) in buckets {
+            note_saturation(root, None, bkt.flush());
+            if bkt.codes.is_empty() {
+                continue;
+            }
+            let (_, writer) = match &mut self.files[root] {
+                Some(file) => file,
+                slot => {
+                    let path = spill::spill_path(&format!(
+                        "frontier_{}_{}.spill",
+                        std::process::id(),
+                        root
+                    ));
+                    let writer = SpillWriter::create(&path)?;
+                    slot.insert((path, writer))
+                }
+            };
+            writer.write_bucket::<R>(root as u32, mask, &bkt.codes, &bkt.weights)?;
+            freed += (bkt.codes.capacity() * mem::size_of::<R::Code>()
+                + bkt.weights.capacity() * mem::size_of::<R::Weight>()) as u64;
+            bkt.codes = Vec::new();
+            bkt.weights = Vec::new();
+        }
+        Ok(freed)
+    }
+
+    /// Hand the spilled chunks of `root` to `restore(mask, codes, weights)` and
+    /// delete its spill file.
+    fn restore_root<R: Repr>(
+        &mut self,
+        root: usize,
+        mut restore: impl FnMut(R::Mask, Vec<R::Code>, Vec<R::Weight>),
+    ) -> Result<()> {
+        let Some((path, writer)) = self.files[root].take() else {
+            return Ok(());
+        };
+        let records = writer.records();
+        writer.finish()?;
+        let mut read = 0usize;
+        spill::scan::<R>(&path, |chunk| {
+            restore(chunk.mask, chunk.codes, chunk.weights);
+            read += 1;
+            Ok(())
+        })?;
+        spill::remove(&path)?;
+        if read != records {
+            bail!(
+                "spill {} for root {} holds {} intact chunks, {} were written",
+                path.display(),
+                root,
+                read,
+                records
+            );
+        }
+        Ok(())
+    }
+
+    /// Open spill files of roots `from..` with their record counts, flushed so
+    /// a checkpoint can copy them.
+    fn flushed_from(&mut self, from: usize) -> Result<Vec<(&std::path::Path, usize)>> {
+        let mut out = Vec::new();
+        for (path, writer) in self.files.iter_mut().skip(from).flatten() {
+            writer.flush()?;
+            out.push((path.as_path(), writer.records()));
+        }
+        Ok(out)
+    }
+}
+impl Drop for SpilledRoots {
+    fn drop(&mut self) {
+        for (path, writer) in self.files.iter_mut().filter_map(Option::take) {
+            drop(writer);
+            let _ = spill::remove(&path);
         }
     }
 }
 
-fn pack_pop_key(mut pops: SmallVec<[u8; 10]>) -> u64 {
-    pops.sort_unstable();
-    let k = pops.len() as u64;
-    let mut out = k & 0xF;
-    let mut shift = 4u32;
-    for p in pops {
-        out |= ((p as u64) & 0xF) << shift;
-        shift += 4;
+struct OutBuckets<R: Repr> {
+    by_key: AHashMap<R::Mask, AOBucket<R>>, // key = packed pop multiset (`repr::pack_pop_key`)
+    /// Completed states appended per key, before equal signatures merge.
+    completed: AHashMap<R::Mask, u64>,
+}
+impl<R: Repr> Default for OutBuckets<R> {
+    fn default() -> Self {
+        OutBuckets {
+            by_key: AHashMap::default(),
+            completed: AHashMap::default(),
+        }
+    }
+}
+impl<R: Repr> OutBuckets<R> {
+    /// `root` is the root being vacated, for saturation provenance.
+    fn append_completed(
+        &mut self,
+        root: usize,
+        key: R::Mask,
+        codes: Vec<R::Code>,
+        w: Vec<R::Weight>,
+    ) {
+        *self.completed.entry(key).or_default() += codes.len() as u64;
+        let b = self.by_key.entry(key).or_default();
+        note_saturation(root, Some(key.to_u128()), b.append_batch(codes, w));
+    }
+    /// Final flush after the last root (`root`) is vacated.
+    fn flush_all(&mut self, root: usize) {
+        for (&key, b) in &mut self.by_key {
+            note_saturation(root, Some(key.to_u128()), b.flush());
+        }
+    }
+    fn size(&self) -> FrontierSize {
+        let mut size = FrontierSize::default();
+        for b in self.by_key.values() {
+            b.add_to(&mut size);
+        }
+        size
     }
-    out
 }
 
-fn code_pop_key(code: u128, b: u32, j_pop: &[i32]) -> u64 {
-    let mut pops: SmallVec<[u8; 10]> = SmallVec::new();
+fn code_pop_key<R: Repr>(code: R::Code, b: u32, j_pop: &[i32]) -> R::Mask {
+    let mut pops: SmallVec<[u8; 16]> = SmallVec::new();
     for j in code_iter(code, b) {
         pops.push(j_pop[j as usize] as u8);
     }
-    pack_pop_key(pops)
+    repr::pack_pop_key::<R>(&mut pops)
 }
 
 // -------------------------------------------------------------------------------------
 // Public API
 // -------------------------------------------------------------------------------------
 
-pub fn enumerate_to_snapshot_from_npz(
-    path_npz: &str,
+#[cfg(feature = "npz")]
+/// Enumerate the input NPZ into a snapshot. With several `paths` the pre-CSR is
+/// split across them (see `merge_inputs`); compat and any `expect_*`
+/// invariants come from the first file.
+pub fn enumerate_to_snapshot_from_npzs(
+    paths: &[&str],
+    checkpointing: &Checkpointing,
+    cancel: &CancelToken,
 ) -> anyhow::Result<crate::matching::types::Snapshot> {
+    let mut buckets = Vec::new();
+    let mut snap = enumerate_npzs_into(paths, checkpointing, cancel, &mut |bucket| {
+        buckets.push(bucket);
+        Ok(())
+    })?;
+    snap.buckets = buckets;
+    Ok(snap)
+}
+
+#[cfg(feature = "npz")]
+/// `enumerate_to_snapshot_from_npzs`, handing each bucket to `hand_off` as it
+/// is built (in snapshot order) instead of collecting them: the snapshot
+/// returned has no buckets. `SnapshotWriter::add_bucket` in `hand_off` saves
+/// a snapshot without ever holding all of its buckets.
+pub fn enumerate_npzs_into(
+    paths: &[&str],
+    checkpointing: &Checkpointing,
+    cancel: &CancelToken,
+    hand_off: &mut dyn FnMut(Bucket) -> Result<()>,
+) -> anyhow::Result<crate::matching::types::Snapshot> {
+    settings::init()?;
+    let path_npz = *paths.first().context("no input paths")?;
+    let parts = paths
+        .iter()
+        .map(|&p| Ok((p.to_string(), load_inputs_npz(p)?)))
+        .collect::<Result<Vec<_>>>()?;
     let Inputs {
         n,
         m,
         pre,
         jbt_ref_pop,
         jbt_ref_comps,
-    } = load_inputs_npz(path_npz)?;
-    let mut snap = enumerate_to_snapshot(n, m, pre, &jbt_ref_pop)?;
+    } = merge_inputs(parts)?;
+    let expected = Expected::from_npz(path_npz)?;
+    // only the keys are kept, for the `expect_*` check
+    let mut built = 0usize;
+    let mut keys: Vec<Vec<i32>> = Vec::new();
+    let mut snap = enumerate_into(
+        n,
+        m,
+        pre,
+        &jbt_ref_pop,
+        checkpointing,
+        cancel,
+        &mut |bucket| {
+            built += 1;
+            if expected.is_some() {
+                keys.push(bucket.key.clone());
+            }
+            hand_off(bucket)
+        },
+    )?;
+    if built == 0 {
+        return Err(EmptyDataError(format!(
+            "{}: enumeration produced zero buckets (no completed states)",
+            path_npz
+        ))
+        .into());
+    }
+
+    if let (Some(expected), Some(stats)) = (expected, &snap.stats) {
+        if first_bucket_limit().is_some() {
+            eprintln!(
+                "[check] skipped: ENUM_FIRST_LIMIT truncates the first root, so totals cannot match"
+            );
+        } else {
+            expected.check(&keys, stats);
+        }
+    }
 
     // Prefer Python-provided compat (authoritative); if not present, fall back to local build.
     if let Some(compat_npz) = try_load_compat_npz(path_npz)? {
@@ -633,21 +1259,104 @@ pub fn enumerate_to_snapshot_from_npz(
     debug_pop_quickline(&snap.compat, 4);
 
     // Optional full summary (avoids “function never used” warning in compat.rs)
-    if std::env::var("ENUM_COMPAT_DEBUG").ok().as_deref() == Some("1") {
+    if settings::get().compat_debug {
         compat_debug_summary(&snap.compat, &snap.jbt_ref_pop, snap.n_total);
     }
 
     Ok(snap)
 }
 
+#[cfg(feature = "npz")]
+/// The fingerprint a snapshot enumerated from `paths` under the current
+/// settings would carry (`Snapshot::fingerprint`), computed from the inputs
+/// alone, without enumerating.
+pub fn snapshot_fingerprint_for(paths: &[&str]) -> Result<String> {
+    settings::init()?;
+    let parts = paths
+        .iter()
+        .map(|&p| Ok((p.to_string(), load_inputs_npz(p)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let Inputs {
+        n,
+        m,
+        pre,
+        jbt_ref_pop,
+        ..
+    } = merge_inputs(parts)?;
+    let b = code_bitwidth(m)?;
+    let pre = match root_cell_order(n, &pre) {
+        Some(cells) => root_order::reanchor(pre, &cells),
+        None => pre,
+    };
+    let weight_bits = match settings::get().weight_bits {
+        32 => 32,
+        _ => 64,
+    };
+    let inputs_sha256 =
+        checkpoint::inputs_sha256(n, b, weight_bits, first_bucket_limit(), &pre, &jbt_ref_pop);
+    Ok(checkpoint::snapshot_fingerprint(
+        &inputs_sha256,
+        min_state_weight(),
+    ))
+}
+
+/// Enumerate all roots into a snapshot. `cancel` is checked before each root;
+/// a cancelled run returns `Cancelled`, after writing a last checkpoint when
+/// `checkpointing.path` is set (see `checkpoint`). With
+/// `checkpointing.column_block`, returns `BlockDone` after that many columns of
+/// roots when roots are left.
 pub fn enumerate_to_snapshot(
     n: u32,
     m: usize,
     pre: PreCsr,
     jbt_ref_pop: &[i32],
+    checkpointing: &Checkpointing,
+    cancel: &CancelToken,
 ) -> Result<Snapshot> {
-    let b = bitwidth(m);
+    let mut buckets = Vec::new();
+    let mut snap = enumerate_into(
+        n,
+        m,
+        pre,
+        jbt_ref_pop,
+        checkpointing,
+        cancel,
+        &mut |bucket| {
+            buckets.push(bucket);
+            Ok(())
+        },
+    )?;
+    snap.buckets = buckets;
+    Ok(snap)
+}
+
+/// `enumerate_to_snapshot`, handing each bucket to `hand_off` as it is built
+/// (see `enumerate_npzs_into`); the snapshot returned has no buckets.
+pub fn enumerate_into(
+    n: u32,
+    m: usize,
+    pre: PreCsr,
+    jbt_ref_pop: &[i32],
+    checkpointing: &Checkpointing,
+    cancel: &CancelToken,
+    hand_off: &mut dyn FnMut(Bucket) -> Result<()>,
+) -> Result<Snapshot> {
+    settings::init()?;
+    let b = code_bitwidth(m)?;
+    // run-scoped counters; reset so repeated runs in one process (watch mode) start clean
+    for counter in [
+        &SATURATED_WEIGHTS,
+        &CODE_OVERFLOWS,
+        &EVIL_MEMO_HITS,
+        &EVIL_MEMO_MISSES,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    SATURATIONS.lock().unwrap().clear();
     let total_roots = ((n / 2) as usize) * n as usize;
+    let mut phase = crate::telemetry::phase("enumerate");
+    phase.attr("matcher.n", n as u64);
+    phase.attr("matcher.roots", total_roots as u64);
     if pre.n_roots != total_roots {
         bail!(
             "pre.offsets len mismatch: got {}, expected {}",
@@ -655,49 +1364,221 @@ pub fn enumerate_to_snapshot(
             total_roots
         );
     }
-
-    let mut all_frontiers: Vec<RootFrontier> =
-        (0..total_roots).map(|_| RootFrontier::default()).collect();
-    let mem_budget = memory_budget_bytes();
-
-    // Seed (0,0) with one empty code (k=0) at mask 0 with weight 1.
-    {
-        let rf = &mut all_frontiers[0];
-        let b0 = rf.get_bucket_mut(0);
-        b0.append_batch(vec![0u128], vec![1 as Weight]);
+    match (repr::pick(n, m, b)?, settings::get().weight_bits) {
+        (Width::Narrow, 32) => {
+            enumerate::<Narrow<u32>>(n, b, pre, jbt_ref_pop, checkpointing, cancel, hand_off)
+        }
+        (Width::Narrow, _) => {
+            enumerate::<Narrow>(n, b, pre, jbt_ref_pop, checkpointing, cancel, hand_off)
+        }
+        (Width::Wide, 32) => {
+            enumerate::<Wide<u32>>(n, b, pre, jbt_ref_pop, checkpointing, cancel, hand_off)
+        }
+        (Width::Wide, _) => {
+            enumerate::<Wide>(n, b, pre, jbt_ref_pop, checkpointing, cancel, hand_off)
+        }
     }
+}
 
-    let mut out = OutBuckets::default();
+/// `enumerate_into` with masks and codes of `R`, `b` bits per j index.
+fn enumerate<R: Repr>(
+    n: u32,
+    b: u32,
+    pre: PreCsr,
+    jbt_ref_pop: &[i32],
+    checkpointing: &Checkpointing,
+    cancel: &CancelToken,
+    hand_off: &mut dyn FnMut(Bucket) -> Result<()>,
+) -> Result<Snapshot> {
+    let total_roots = ((n / 2) as usize) * n as usize;
+    let (pre, geom) = order_roots::<R::Mask>(n, pre);
 
-    let pb = ProgressBar::new(total_roots as u64);
-    pb.set_style(
-        ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos}/{len} roots {msg}")
-            .unwrap()
-            .progress_chars("=>-"),
+    let mut all_frontiers: Vec<RootFrontier<R>> =
+        (0..total_roots).map(|_| RootFrontier::default()).collect();
+    let mem_budget = memory_budget_bytes();
+    let mut spilled = SpilledRoots::new(total_roots);
+    let mut warned_over = false;
+    let mut graph = RootGraph::from_env(n, geom.root_cells());
+    let mut series = FrontierSeries::from_env(geom.root_cells())?;
+    let mut dump = CompletedDump::from_env(b)?;
+    let mut out = OutBuckets::<R>::default();
+    // frontier states vacated, for `EnumCounts`; unknown after resuming an
+    // older checkpoint
+    let mut vacated_states = Some(0u64);
+    let inputs_sha256 = checkpoint::inputs_sha256(
+        n,
+        b,
+        R::Weight::BITS,
+        first_bucket_limit(),
+        &pre,
+        jbt_ref_pop,
     );
+    let fingerprint = (checkpointing.path.is_some() || checkpointing.resume.is_some())
+        .then(|| inputs_sha256.clone());
+
+    let start = if let Some(path) = &checkpointing.resume {
+        let header = checkpoint::load::<R>(
+            path,
+            fingerprint.as_deref().unwrap_or_default(),
+            total_roots,
+            |root, mask, piece| {
+                let bkt = all_frontiers[root].get_bucket_mut(mask);
+                bkt.pend_codes.extend(piece.codes);
+                bkt.pend_w.extend(piece.weights);
+            },
+            |key, piece| {
+                let bkt = out.by_key.entry(key).or_default();
+                bkt.pend_codes.extend(piece.codes);
+                bkt.pend_w.extend(piece.weights);
+            },
+        )?;
+        SATURATED_WEIGHTS.store(header.saturated_weights, Ordering::Relaxed);
+        CODE_OVERFLOWS.store(header.code_overflows, Ordering::Relaxed);
+        *SATURATIONS.lock().unwrap() = header.saturations;
+        vacated_states = header.frontier_states;
+        if vacated_states.is_none() {
+            eprintln!(
+                "[checkpoint] written before enumeration counts were kept; the snapshot will have none"
+            );
+        }
+        out.completed = header
+            .completed
+            .iter()
+            .map(|(&key, &count)| (R::Mask::from_u128(key), count))
+            .collect();
+        eprintln!(
+            "[checkpoint] resuming before root {}/{} from {}",
+            header.next_root,
+            total_roots,
+            path.display()
+        );
+        if header.next_root > 0 && (graph.is_some() || series.is_some() || dump.is_some()) {
+            eprintln!(
+                "[checkpoint] warn: ENUM_ROOT_GRAPH / ENUM_FRONTIER_CSV / ENUM_COMPLETED_STATES cover only roots {}.. of this run",
+                header.next_root
+            );
+        }
+        header.next_root
+    } else {
+        // Seed the first root with one empty code (k=0) at mask 0 with weight 1.
+        let b0 = all_frontiers[0].get_bucket_mut(R::Mask::ZERO);
+        let _ = b0.append_batch(vec![R::Code::default()], vec![R::Weight::ONE]);
+        0
+    };
+    let checkpoint_every = settings::get().checkpoint_every;
+    // a block ends on a column boundary, even when resumed mid-column
+    let block_end = checkpointing
+        .column_block
+        .map(|cols| ((start / n as usize + cols) * n as usize).min(total_roots));
+
+    let pb = resumable_progress_bar(total_roots as u64, "roots", start as u64);
+    let util = UtilSampler::start("enumeration");
+    let allocs = AllocPhase::start("enumeration");
 
     // small loop hoist to avoid recomputing every survivor
     let evil_cut = total_roots - n as usize;
-
-    for i in 0..total_roots {
+    let prune_table = PruneTable::for_run(&geom);
+    let evil_gpu = settings::get().gpu.then(|| EvilGpu::new(&geom)).flatten();
+    // set by the first failed dispatch; later slabs go back to the CPU
+    let gpu_failed = AtomicBool::new(false);
+    let (mut gpu_pairs, mut gpu_secs) = (0u64, 0.0f64);
+
+    for i in start..total_roots {
+        if block_end == Some(i)
+            && let (Some(path), Some(fingerprint)) = (&checkpointing.path, &fingerprint)
         {
-            let rf = &mut all_frontiers[i];
-            rf.flush();
+            write_checkpoint(
+                path,
+                fingerprint,
+                i,
+                total_roots,
+                &mut all_frontiers,
+                &mut spilled,
+                &mut out,
+                vacated_states,
+            )?;
+            pb.finish_and_clear();
+            return Err(BlockDone {
+                next_root: i,
+                total_roots,
+                checkpoint: path.clone(),
+            }
+            .into());
+        }
+        let cancelled = cancel.is_cancelled();
+        if let (Some(path), Some(fingerprint)) = (&checkpointing.path, &fingerprint)
+            && (cancelled || (i > start && (i - start) % checkpoint_every == 0))
+        {
+            write_checkpoint(
+                path,
+                fingerprint,
+                i,
+                total_roots,
+                &mut all_frontiers,
+                &mut spilled,
+                &mut out,
+                vacated_states,
+            )
+            .unwrap_or_else(|err| eprintln!("[checkpoint] warn: {:#}", err));
+        }
+        if cancelled {
+            pb.finish_and_clear();
+            let hint = match &checkpointing.path {
+                Some(path) => format!("; continue with --resume-enumeration {}", path.display()),
+                None => String::new(),
+            };
+            return Err(Cancelled(format!(
+                "enumeration stopped before root {} of {}{}",
+                i, total_roots, hint
+            ))
+            .into());
         }
+        crate::chaos::at_root(i);
+        spilled.restore_root::<R>(i, |mask, codes, w| {
+            let bkt = all_frontiers[i].get_bucket_mut(mask);
+            bkt.pend_codes.extend(codes);
+            bkt.pend_w.extend(w);
+        })?;
+        let mut root_span = crate::telemetry::span("root");
+        root_span.attr("matcher.root", i as u64);
         let (pmasks, buckets) = {
             let rf = &mut all_frontiers[i];
+            note_saturation(i, None, rf.flush());
             let pmasks = mem::take(&mut rf.masks);
             let buckets = mem::take(&mut rf.buckets);
             rf.index.clear();
             (pmasks, buckets)
         };
 
-        report_memory_after_vacate(i, mem_budget)?;
+        if let Some(over) = report_memory_after_vacate(i, mem_budget) {
+            // the roots vacated last go to disk first
+            let mut freed = 0u64;
+            for root in (i + 1..total_roots).rev() {
+                if freed >= over {
+                    break;
+                }
+                let buckets = root_buckets_mut(&mut all_frontiers[root]);
+                freed += spilled.spill_root::<R>(root, buckets)?;
+            }
+            eprintln!(
+                "[mem] {} over the limit: spilled {} of frontiers ahead to {}",
+                Bytes(over),
+                Bytes(freed),
+                spill::spill_dir().display()
+            );
+            if freed < over && !warned_over {
+                warned_over = true;
+                eprintln!(
+                    "[mem] warn: still over the limit with every frontier ahead on disk (from root {}); the root being vacated and the completed buckets stay in memory",
+                    i
+                );
+            }
+        }
 
         let s = pre.offsets[i];
         let e = pre.offsets[i + 1];
 
-        // Apply limit only to the (0,0) bucket = root index 0
+        // Apply limit only to the first root (root index 0)
         let e_eff = if i == 0 {
             first_bucket_limit()
                 .map(|limit| s + (e - s).min(limit))
@@ -713,230 +1594,494 @@ pub fn enumerate_to_snapshot(
             pmasks.len()
         ));
 
+        let frontier_states = || buckets.iter().map(|bk| bk.codes.len()).sum::<usize>();
+        if let Some(v) = &mut vacated_states {
+            *v += frontier_states() as u64;
+        }
         if s == e || pmasks.is_empty() {
+            if let Some((_, g)) = &mut graph {
+                g.finish_root(i, e_eff - s, pmasks.len(), frontier_states());
+            }
+            if let Some(fs) = &mut series {
+                let frontier = frontier_size(&all_frontiers);
+                fs.row(
+                    i,
+                    frontier,
+                    frontier_states(),
+                    out.size(),
+                    current_rss_bytes(),
+                )?;
+            }
             pb.inc(1);
             continue;
         }
 
+        // complement components per frontier mask, shared by all pre_jbt of this root
+        let do_evil = i < evil_cut; // skip last N roots
+        let pm_comps: Vec<MaskComponents<R::Mask>> = if do_evil && prune_table.is_none() {
+            pmasks
+                .par_iter()
+                .map(|&pm| MaskComponents::new(pm, &geom))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         // --- parallelized vacate of this root ---
-        // Each worker returns: (frontier_map, completed_map), both thread-local.
-        // frontier_map: key=(root_code, new_mask) -> (codes, weights)
+        let graph_ref = graph.as_ref().map(|(_, g)| g);
+        // Each worker returns: (frontier_batches, completed_map), both thread-local.
+        // frontier_batches: one (root_code, new_mask) -> (codes, weights) per destination
         // completed_map: key=popkey -> (codes, weights)
-        let jobs: Vec<(
-            AHashMap<(i32, u64), (Vec<u128>, Vec<Weight>)>,
-            AHashMap<u64, (Vec<u128>, Vec<Weight>)>,
-        )> = (s..e_eff)
-            .into_par_iter()
-            .map(|k_pre| {
-                let pmask_pre = pre.masks[k_pre];
-                let pop_pre = pre.pops[k_pre] as u32;
-                let jidx_pre = pre.jidx[k_pre];
-
-                // find survivors
-                let mut survivors = Vec::<usize>::new();
-                survivors.reserve(pmasks.len());
-                for (idx, &pm) in pmasks.iter().enumerate() {
-                    if (pm & pmask_pre) == 0 {
-                        survivors.push(idx);
-                    }
-                }
-                if survivors.is_empty() {
-                    return (AHashMap::default(), AHashMap::default());
+        let vacate_one = |k_pre: usize,
+                          memo: &mut EvilMemo<R::Mask>,
+                          keep: Option<&KeepBitmap>|
+         -> VacateMaps<R> {
+            let pmask_pre = R::Mask::from_u128(pre.masks[k_pre]);
+            let pop_pre = pre.pops[k_pre] as u32;
+            let jidx_pre = pre.jidx[k_pre];
+
+            // Survivors map 1:1 onto destinations: pmasks are distinct and disjoint
+            // from pmask_pre, so each (root_code, new_mask) gets exactly one source
+            // bucket and no grouping map is needed.
+            let lift = |c: R::Code| -> R::Code {
+                if pop_pre == n {
+                    c // no signature update; codes unchanged
+                } else {
+                    code_insert(c, jidx_pre, b).0 // insert jidx_pre once into each code
                 }
+            };
 
-                // group by destination
-                let mut group: AHashMap<(i32, u64), SmallVec<[usize; 8]>> = AHashMap::default();
-                for &idx_pm in &survivors {
-                    let new_mask = pmasks[idx_pm] | pmask_pre;
-                    let do_evil = i < evil_cut; // skip last N roots
-                    if do_evil && detect_evil_pmask(new_mask, n) {
-                        continue;
-                    }
+            // local accumulators
+            let mut frontier_batches: Vec<FrontierBatch<R>> = Vec::new();
+            let mut completed_map: AHashMap<R::Mask, CodeBatch<R>> = AHashMap::default();
 
-                    let root_code: i32 = match find_root(new_mask, n) {
-                        None => -1,
-                        Some((u, v)) => (u as i32) * (n as i32) + v as i32,
-                    };
-                    group
-                        .entry((root_code, new_mask))
-                        .or_insert_with(|| SmallVec::new())
-                        .push(idx_pm);
+            for (idx_pm, &pm) in pmasks.iter().enumerate() {
+                if (pm & pmask_pre) != R::Mask::ZERO {
+                    continue;
                 }
-                if group.is_empty() {
-                    return (AHashMap::default(), AHashMap::default());
+                let bkt = &buckets[idx_pm];
+                if bkt.codes.is_empty() {
+                    continue;
+                }
+                let new_mask = pm | pmask_pre;
+                if do_evil {
+                    let evil = match (keep, &prune_table) {
+                        (Some(keep), _) => !keep.keep(k_pre, idx_pm),
+                        (None, Some(table)) => table.is_evil(new_mask),
+                        (None, None) => memo.is_evil(new_mask, &geom, || {
+                            pm_comps[idx_pm].extension_is_evil(pm, pmask_pre, &geom)
+                        }),
+                    };
+                    if evil {
+                        continue;
+                    }
                 }
 
-                // local accumulators
-                let mut frontier_map: AHashMap<(i32, u64), (Vec<u128>, Vec<Weight>)> =
-                    AHashMap::default();
-                let mut completed_map: AHashMap<u64, (Vec<u128>, Vec<Weight>)> =
-                    AHashMap::default();
-
-                if pop_pre == n {
-                    // no signature update; codes unchanged
-                    for ((root_code, new_mask), idx_list) in group.into_iter() {
-                        let mut codes_cat = Vec::<u128>::new();
-                        let mut w_cat = Vec::<Weight>::new();
-                        for &idx_pm in &idx_list {
-                            let bkt = &buckets[idx_pm];
-                            if bkt.codes.is_empty() {
-                                continue;
-                            }
-                            codes_cat.extend_from_slice(&bkt.codes);
-                            w_cat.extend_from_slice(&bkt.weights);
-                        }
-                        if codes_cat.is_empty() {
-                            continue;
+                let dst = match &prune_table {
+                    Some(table) => table.root(new_mask),
+                    None => find_root(new_mask, &geom),
+                };
+                if let Some(g) = graph_ref {
+                    let to = dst.unwrap_or(g.done());
+                    g.record(to, bkt.codes.len());
+                }
+                match dst {
+                    None => {
+                        // completed → bucket per pop-key; key the codes first so
+                        // each destination grows once, by its exact count
+                        let keyed: Vec<(R::Code, R::Mask)> = bkt
+                            .codes
+                            .iter()
+                            .map(|&c| {
+                                let c2 = lift(c);
+                                (c2, code_pop_key::<R>(c2, b, jbt_ref_pop))
+                            })
+                            .collect();
+                        let mut per_key: AHashMap<R::Mask, usize> = AHashMap::default();
+                        for &(_, key) in &keyed {
+                            *per_key.entry(key).or_insert(0) += 1;
                         }
-
-                        if root_code == -1 {
-                            // completed → group by pop-key locally
-                            let mut by_key: AHashMap<u64, (Vec<u128>, Vec<Weight>)> =
-                                AHashMap::default();
-                            for (&c, &w) in codes_cat.iter().zip(w_cat.iter()) {
-                                let key = code_pop_key(c, b, jbt_ref_pop);
-                                let entry = by_key
-                                    .entry(key)
-                                    .or_insert_with(|| (Vec::new(), Vec::new()));
-                                entry.0.push(c);
-                                entry.1.push(w);
-                            }
-                            // merge into completed_map
-                            for (key, (cc, ww)) in by_key {
-                                let ent = completed_map
-                                    .entry(key)
-                                    .or_insert_with(|| (Vec::new(), Vec::new()));
-                                ent.0.extend(cc);
-                                ent.1.extend(ww);
-                            }
-                        } else {
-                            // frontier destination
-                            let ent = frontier_map
-                                .entry((root_code, new_mask))
+                        for (key, count) in per_key {
+                            let ent = completed_map
+                                .entry(key)
                                 .or_insert_with(|| (Vec::new(), Vec::new()));
-                            ent.0.extend(codes_cat);
-                            ent.1.extend(w_cat);
+                            ent.0.reserve(count);
+                            ent.1.reserve(count);
+                        }
+                        for (&(c2, key), &w) in keyed.iter().zip(bkt.weights.iter()) {
+                            let ent = completed_map.get_mut(&key).unwrap();
+                            ent.0.push(c2);
+                            ent.1.push(w);
                         }
                     }
-                } else {
-                    // signature update: insert jidx_pre once into each code
-                    for ((root_code, new_mask), idx_list) in group.into_iter() {
-                        if root_code == -1 {
-                            // completed → compute codes2 then bucket per pop-key
-                            let mut by_key: AHashMap<u64, (Vec<u128>, Vec<Weight>)> =
-                                AHashMap::default();
-                            for &idx_pm in &idx_list {
-                                let bkt = &buckets[idx_pm];
-                                if bkt.codes.is_empty() {
-                                    continue;
-                                }
-                                for (&c, &w) in bkt.codes.iter().zip(bkt.weights.iter()) {
-                                    let (c2, _ins) = code_insert(c, jidx_pre, b);
-                                    let key = code_pop_key(c2, b, jbt_ref_pop);
-                                    let entry = by_key
-                                        .entry(key)
-                                        .or_insert_with(|| (Vec::new(), Vec::new()));
-                                    entry.0.push(c2);
-                                    entry.1.push(w);
-                                }
+                    Some(root) => {
+                        // frontier destination
+                        let root_code = root as i32;
+                        let codes: Vec<R::Code> = bkt.codes.iter().map(|&c| lift(c)).collect();
+                        frontier_batches
+                            .push(((root_code, new_mask), (codes, bkt.weights.clone())));
+                    }
+                }
+            }
+
+            (frontier_batches, completed_map)
+        };
+
+        // Stream worker outputs through a bounded channel and merge them into the
+        // global structures as they arrive, so only a few worker maps are resident.
+        let (tx, rx) = mpsc::sync_channel::<VacateMaps<R>>(merge_queue_cap());
+        let gpu = evil_gpu.as_ref().filter(|_| do_evil);
+        let gpu_failed = &gpu_failed;
+        let gpu_use = std::thread::scope(|scope| {
+            let vacate_one = &vacate_one;
+            let util = &util;
+            let (pmasks, pre_masks) = (&pmasks, &pre.masks);
+            let workers = scope.spawn(move || {
+                let run = |ks: std::ops::Range<usize>, keep: Option<&KeepBitmap>| {
+                    ks.into_par_iter().for_each_init(
+                        || (tx.clone(), EvilMemo::default()),
+                        |(tx, memo), k_pre| {
+                            let _busy = util.busy();
+                            let maps = vacate_one(k_pre, memo, keep);
+                            if !maps.0.is_empty() || !maps.1.is_empty() {
+                                let _ = tx.send(maps);
                             }
-                            for (key, (cc, ww)) in by_key {
-                                let ent = completed_map
-                                    .entry(key)
-                                    .or_insert_with(|| (Vec::new(), Vec::new()));
-                                ent.0.extend(cc);
-                                ent.1.extend(ww);
+                        },
+                    )
+                };
+                let Some(gpu) = gpu else {
+                    run(s..e_eff, None);
+                    return (0, 0.0);
+                };
+                // pre_jbt in slabs, each checked against every frontier mask at once
+                let pm_wide: Vec<u128> = pmasks.iter().map(|m| m.to_u128()).collect();
+                let rows = gpu::slab_rows(pmasks.len());
+                let (mut pairs, mut secs) = (0u64, 0.0f64);
+                for a in (s..e_eff).step_by(rows) {
+                    let z = (a + rows).min(e_eff);
+                    let t0 = Instant::now();
+                    let keep = if gpu_failed.load(Ordering::Relaxed) {
+                        None
+                    } else {
+                        match gpu.keep_bitmap(&pre_masks[a..z], a, &pm_wide) {
+                            Ok(keep) => {
+                                pairs += ((z - a) * pmasks.len()) as u64;
+                                secs += t0.elapsed().as_secs_f64();
+                                Some(keep)
                             }
-                        } else {
-                            // frontier destination
-                            let ent = frontier_map
-                                .entry((root_code, new_mask))
-                                .or_insert_with(|| (Vec::new(), Vec::new()));
-                            for &idx_pm in &idx_list {
-                                let bkt = &buckets[idx_pm];
-                                if bkt.codes.is_empty() {
-                                    continue;
-                                }
-                                for (&c, &w) in bkt.codes.iter().zip(bkt.weights.iter()) {
-                                    let (c2, _ins) = code_insert(c, jidx_pre, b);
-                                    ent.0.push(c2);
-                                    ent.1.push(w);
-                                }
+                            Err(e) => {
+                                eprintln!("[gpu] {:#}; evil-mask checks back on the CPU", e);
+                                gpu_failed.store(true, Ordering::Relaxed);
+                                None
                             }
                         }
-                    }
+                    };
+                    run(a..z, keep.as_ref());
                 }
+                (pairs, secs)
+            });
 
-                (frontier_map, completed_map)
-            })
-            .collect();
-
-        // Merge thread-local accumulators into global structures (sequential)
-        for (frontier_map, completed_map) in jobs {
-            for ((root_code, new_mask), (codes, w)) in frontier_map {
-                if root_code == -1 {
-                    // Shouldn't happen here, but guard anyway
-                    let mut by_key: AHashMap<u64, (Vec<u128>, Vec<Weight>)> = AHashMap::default();
-                    for (&c, &ww) in codes.iter().zip(w.iter()) {
-                        let key = code_pop_key(c, b, jbt_ref_pop);
-                        let entry = by_key
-                            .entry(key)
-                            .or_insert_with(|| (Vec::new(), Vec::new()));
-                        entry.0.push(c);
-                        entry.1.push(ww);
-                    }
-                    for (key, (cc, ww)) in by_key {
-                        out.append_completed(key, cc, ww);
-                    }
-                } else {
+            // Merge thread-local accumulators into global structures (sequential)
+            for (frontier_batches, completed_map) in rx {
+                // root_code is never -1 here: completed states arrive via completed_map
+                for ((root_code, new_mask), (codes, w)) in frontier_batches {
                     let rf_dst = &mut all_frontiers[root_code as usize];
                     let bdst = rf_dst.get_bucket_mut(new_mask);
-                    bdst.append_batch(codes, w);
+                    note_saturation(root_code as usize, None, bdst.append_batch(codes, w));
+                }
+                for (key, (codes, w)) in completed_map {
+                    if let Some(d) = &mut dump {
+                        d.write_batch::<R>(i, &codes, &w)?;
+                    }
+                    out.append_completed(i, key, codes, w);
                 }
             }
-            for (key, (codes, w)) in completed_map {
-                out.append_completed(key, codes, w);
-            }
+            anyhow::Ok(workers.join().expect("vacate workers panicked"))
+        })?;
+        gpu_pairs += gpu_use.0;
+        gpu_secs += gpu_use.1;
+        if let Some((_, g)) = &mut graph {
+            g.finish_root(i, e_eff - s, pmasks.len(), frontier_states());
+        }
+        if let Some(fs) = &mut series {
+            let frontier = frontier_size(&all_frontiers);
+            fs.row(
+                i,
+                frontier,
+                frontier_states(),
+                out.size(),
+                current_rss_bytes(),
+            )?;
         }
 
         pb.inc(1);
     }
     pb.finish_and_clear();
+    util.finish();
+    if let Some(a) = allocs {
+        a.finish();
+    }
 
-    out.flush_all();
+    out.flush_all(total_roots - 1);
+    if let Some(fs) = series {
+        fs.finish()?;
+    }
+    if let Some(d) = dump {
+        d.finish()?;
+    }
+    if let Some((path, g)) = &graph {
+        g.write(path)?;
+    }
+
+    if gpu_pairs > 0 {
+        eprintln!(
+            "[gpu] {} (pre_jbt, mask) pairs checked in {}",
+            gpu_pairs,
+            Secs(gpu_secs)
+        );
+    }
+    let hits = EVIL_MEMO_HITS.load(Ordering::Relaxed);
+    let misses = EVIL_MEMO_MISSES.load(Ordering::Relaxed);
+    if hits + misses > 0 {
+        eprintln!(
+            "[evil] memo hits={} misses={} (hit rate {:.1}%)",
+            hits,
+            misses,
+            100.0 * hits as f64 / (hits + misses) as f64
+        );
+    }
 
     let sat = SATURATED_WEIGHTS.load(Ordering::Relaxed);
+    let saturations = take_saturations::<R>(&geom);
     if sat > 0 {
-        eprintln!("[warn] weight saturations (u32->clamped): {}", sat);
+        eprintln!(
+            "[warn] weight saturations ({}-bit clamp): {} at {} sites",
+            R::Weight::BITS,
+            sat,
+            saturations.len()
+        );
+        warn_saturation_sites(&saturations);
+    }
+    let overflows = CODE_OVERFLOWS.load(Ordering::Relaxed);
+    if overflows > 0 {
+        bail!(
+            "{} code inserts exceeded the {} j entries a code holds; signatures would be truncated",
+            overflows,
+            R::Code::MAX_LEN
+        );
+    }
+
+    let mut snap =
+        build_snapshot_from_out::<R>(out, b, jbt_ref_pop, n as i32, vacated_states, hand_off)?;
+    snap.saturations = saturations;
+    snap.fingerprint = Some(checkpoint::snapshot_fingerprint(
+        &inputs_sha256,
+        min_state_weight(),
+    ));
+    Ok(snap)
+}
+
+/// Flush the frontiers of roots `next_root..` and the completed buckets, and
+/// write them, with the spill files of those roots and the run's counters, as a
+/// checkpoint before `next_root`.
+#[allow(clippy::too_many_arguments)]
+fn write_checkpoint<R: Repr>(
+    path: &std::path::Path,
+    fingerprint: &str,
+    next_root: usize,
+    total_roots: usize,
+    all_frontiers: &mut [RootFrontier<R>],
+    spilled: &mut SpilledRoots,
+    out: &mut OutBuckets<R>,
+    vacated_states: Option<u64>,
+) -> Result<()> {
+    let mut frontier: Vec<FrontierEntry<R>> = Vec::new();
+    for (root, rf) in all_frontiers.iter_mut().enumerate().skip(next_root) {
+        note_saturation(root, None, rf.flush());
+        for (&mask, bkt) in rf.masks.iter().zip(&rf.buckets) {
+            if !bkt.codes.is_empty() {
+                frontier.push((root, mask, bkt));
+            }
+        }
     }
+    // clamps in this flush belong to the root vacated last, as in `flush_all`
+    out.flush_all(next_root.saturating_sub(1));
+    let completed: Vec<(R::Mask, &AOBucket<R>)> = out
+        .by_key
+        .iter()
+        .filter(|(_, bkt)| !bkt.codes.is_empty())
+        .map(|(&key, bkt)| (key, bkt))
+        .collect();
+    let header = checkpoint::Header {
+        inputs_sha256: fingerprint.to_string(),
+        total_roots,
+        next_root,
+        saturated_weights: SATURATED_WEIGHTS.load(Ordering::Relaxed),
+        code_overflows: CODE_OVERFLOWS.load(Ordering::Relaxed),
+        saturations: SATURATIONS.lock().unwrap().clone(),
+        frontier_states: vacated_states,
+        completed: out
+            .completed
+            .iter()
+            .map(|(&key, &count)| (key.to_u128(), count))
+            .collect(),
+    };
+    let spilled = spilled.flushed_from(next_root)?;
+    checkpoint::write::<R>(path, &header, &frontier, &spilled, &completed)
+}
 
-    build_snapshot_from_out(out, b, jbt_ref_pop, n as i32)
+/// Drain the recorded clamp sites as snapshot metadata.
+fn take_saturations<R: Repr>(geom: &BoardGeometry<R::Mask>) -> Vec<Saturation> {
+    mem::take(&mut *SATURATIONS.lock().unwrap())
+        .into_iter()
+        .map(|((root, key), count)| Saturation {
+            root: geom.root_cell(root),
+            key: key.map(repr::decode_pop_key::<R>),
+            count,
+        })
+        .collect()
 }
 
-fn build_snapshot_from_out(
-    mut out: OutBuckets,
+/// A completed bucket's rows, one per signature, with `f64` weights, and how
+/// many rows were merged away. A flushed bucket is already sorted and unique
+/// and is only converted; anything else (pending codes, duplicates) is sorted
+/// and its weights summed in `f64`, so a merged weight cannot clamp at u32.
+fn unique_rows<R: Repr>(bkt: AOBucket<R>) -> (Vec<R::Code>, Vec<u128>, usize) {
+    let AOBucket {
+        mut codes,
+        mut weights,
+        pend_codes,
+        pend_w,
+    } = bkt;
+    codes.extend(pend_codes);
+    weights.extend(pend_w);
+    if codes.windows(2).all(|w| w[0] < w[1]) {
+        let weights = weights.iter().map(|&w| w.to_u128()).collect();
+        return (codes, weights, 0);
+    }
+    // stable, so equal codes are summed in the order they were appended
+    let mut idx: Vec<usize> = (0..codes.len()).collect();
+    idx.sort_by_key(|&i| codes[i]);
+    let mut out_codes: Vec<R::Code> = Vec::with_capacity(codes.len());
+    let mut out_w: Vec<u128> = Vec::with_capacity(codes.len());
+    for i in idx {
+        if out_codes.last() == Some(&codes[i]) {
+            *out_w.last_mut().unwrap() += weights[i].to_u128();
+        } else {
+            out_codes.push(codes[i]);
+            out_w.push(weights[i].to_u128());
+        }
+    }
+    let merged = codes.len() - out_codes.len();
+    (out_codes, out_w, merged)
+}
+
+/// `ENUM_COUNTS_CSV`: one row per bucket, its pop-key (space-separated pops),
+/// the completed states that reached it, how many were distinct, and their
+/// total weight.
+fn write_counts_csv(
+    path: &std::path::Path,
+    keys: &[Vec<i32>],
+    counts: &EnumCounts,
+    stats: &SnapshotStats,
+) -> Result<()> {
+    use std::io::Write;
+    let f = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut w = std::io::BufWriter::new(f);
+    writeln!(w, "key,completed,distinct,weight")?;
+    for (b, key) in keys.iter().enumerate() {
+        let key: Vec<String> = key.iter().map(i32::to_string).collect();
+        writeln!(
+            w,
+            "{},{},{},{}",
+            key.join(" "),
+            counts.bucket_completed[b],
+            stats.bucket_states[b],
+            stats.bucket_weight[b]
+        )?;
+    }
+    w.flush()
+        .with_context(|| format!("write {}", path.display()))?;
+    eprintln!("[counts] wrote {}", path.display());
+    Ok(())
+}
+
+/// Drop completed states lighter than this at snapshot build, via
+/// `ENUM_MIN_WEIGHT` (unset or 0: keep everything).
+fn min_state_weight() -> Option<u64> {
+    settings::get().min_weight
+}
+
+/// Build the completed buckets of `out` one at a time, in key order, handing
+/// each to `hand_off` as soon as it is built (and dropping its codes), so the
+/// codes and the built rows are never both held whole. The snapshot returned
+/// has the statistics but no buckets.
+fn build_snapshot_from_out<R: Repr>(
+    mut out: OutBuckets<R>,
     b: u32,
     jbt_ref_pop: &[i32],
     n_total: i32,
+    vacated_states: Option<u64>,
+    hand_off: &mut dyn FnMut(Bucket) -> Result<()>,
 ) -> Result<Snapshot> {
-    let mut keys: Vec<u64> = out.by_key.keys().copied().collect();
+    let mut keys: Vec<R::Mask> = out.by_key.keys().copied().collect();
     keys.sort_unstable();
 
-    let mut buckets: Vec<Bucket> = Vec::with_capacity(keys.len());
-
+    let mut stats = SnapshotStats::default();
+    let mut built_keys: Vec<Vec<i32>> = Vec::with_capacity(keys.len());
+    let mut bucket_completed: Vec<u64> = Vec::with_capacity(keys.len());
+    let min_weight = min_state_weight();
+    let mut pruning = min_weight.map(|min| Pruning {
+        min_weight: min as f64,
+        states: 0,
+        weight: 0.0,
+        total_weight: 0.0,
+    });
+
+    let mut merged_rows = 0usize;
     for key in keys {
         // take ownership of this bucket (move out, no clone)
         let bkt = out.by_key.remove(&key).unwrap();
+        let (mut codes, mut weights, merged) = unique_rows(bkt);
+        merged_rows += merged;
+        if let (Some(min), Some(p)) = (min_weight, pruning.as_mut()) {
+            let mut kept = 0usize;
+            for i in 0..codes.len() {
+                let w = weights[i];
+                p.total_weight += w as f64;
+                if w < min as u128 {
+                    p.states += 1;
+                    p.weight += w as f64;
+                    continue;
+                }
+                codes[kept] = codes[i];
+                weights[kept] = w;
+                kept += 1;
+            }
+            codes.truncate(kept);
+            weights.truncate(kept);
+            if kept == 0 {
+                continue;
+            }
+        }
 
-        let n_rows = bkt.codes.len();
+        let n_rows = codes.len();
 
-        // rows_data: Vec<i32>, indptr: Vec<i64>, weights: Vec<f64>, key: Vec<i32>
-        let total_len: usize = bkt.codes.iter().map(|&c| code_len_u128(c)).sum();
+        // rows_data: Vec<i32>, indptr: Vec<i64>, weights: Vec<f64>, key: Vec<i32>;
+        // weights past 2^53 keep their exact value in `exact`
+        let exact: Vec<(usize, BigUint)> = weights
+            .iter()
+            .enumerate()
+            .filter(|&(_, &w)| w > 1 << f64::MANTISSA_DIGITS)
+            .map(|(r, &w)| (r, BigUint::from(w)))
+            .collect();
+        let weights: Vec<f64> = weights.iter().map(|&w| w as f64).collect();
+        let total_len: usize = codes.iter().map(|&c| c.entries() as usize).sum();
         let mut rows_data: Vec<i32> = Vec::with_capacity(total_len);
         let mut indptr: Vec<i64> = Vec::with_capacity(n_rows + 1);
         indptr.push(0);
 
-        for &c in &bkt.codes {
+        for &c in &codes {
             let mut cnt = 0i64;
             for j in code_iter(c, b) {
                 rows_data.push(j as i32);
@@ -946,32 +2091,155 @@ fn build_snapshot_from_out(
             indptr.push(last + cnt);
         }
 
-        // Cast `u32` weights to `f64` only here:
-        let weights: Vec<f64> = bkt.weights.iter().map(|&w| w as f64).collect();
-
-        // decode pop-key back into Vec<i32>
-        let mut key_vec: Vec<i32> = Vec::new();
-        let k = (key & 0xF) as u32;
-        let mut shift = 4u32;
-        for _ in 0..k {
-            let p = ((key >> shift) & 0xF) as i32;
-            key_vec.push(p);
-            shift += 4;
-        }
+        let key_vec = repr::decode_pop_key::<R>(key.to_u128());
 
-        buckets.push(Bucket {
-            rows_data,
-            indptr,
-            weights,
+        let bucket = Bucket {
+            rows_data: rows_data.into(),
+            indptr: Indptr::from_i64(indptr),
+            weights: weights.into(),
+            exact,
             key: key_vec,
-        });
+        };
+        stats.add(&bucket);
+        built_keys.push(bucket.key.clone());
+        bucket_completed.push(out.completed.get(&key).copied().unwrap_or(0));
+        hand_off(bucket)?;
+    }
+
+    if merged_rows > 0 {
+        eprintln!(
+            "[build] merged {} rows whose signature was already in their bucket",
+            merged_rows
+        );
+    }
+    if let Some(p) = &pruning {
+        eprintln!("[prune] ENUM_MIN_WEIGHT: {}", p);
+    }
+    stats.print(&built_keys);
+    let counts = vacated_states.map(|frontier_states| EnumCounts {
+        frontier_states,
+        bucket_completed,
+    });
+    if let Some(c) = &counts {
+        c.print(stats.bucket_states.iter().sum());
+        if let Some(path) = &settings::get().counts_csv {
+            write_counts_csv(path, &built_keys, c, &stats)?;
+        }
     }
 
     let jpop_vec: Vec<i32> = jbt_ref_pop.to_vec();
     Ok(Snapshot {
-        buckets,
+        buckets: Vec::new(),
         jbt_ref_pop: jpop_vec,
         n_total,
         compat: StdHashMap::new(),
+        saturations: Vec::new(),
+        stats: Some(stats),
+        counts,
+        pruning,
+        truncated: None,
+        fingerprint: None,
     })
 }
+
+#[cfg(all(test, feature = "npz"))]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read, Write};
+    use std::path::PathBuf;
+
+    const INPUTS8: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../data/pre_ref_compat_inputs8.npz"
+    );
+
+    /// The NPZ at `path` re-packed with every member deflated, as
+    /// `np.savez_compressed` writes it, in a temp file labeled `name`.
+    fn deflated_copy(path: &str, name: &str) -> PathBuf {
+        let out_path =
+            std::env::temp_dir().join(format!("matcher-{}-{}.npz", name, std::process::id()));
+        let mut src = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut out = zip::ZipWriter::new(File::create(&out_path).unwrap());
+        let opts =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for i in 0..src.len() {
+            let mut member = src.by_index(i).unwrap();
+            let mut bytes = Vec::new();
+            member.read_to_end(&mut bytes).unwrap();
+            out.start_file(member.name(), opts).unwrap();
+            out.write_all(&bytes).unwrap();
+        }
+        out.finish().unwrap();
+        out_path
+    }
+
+    /// The NPZ at `path` with member `name` replaced by `bytes`.
+    fn with_member(path: &str, name: &str, bytes: &[u8]) -> Cursor<Vec<u8>> {
+        let mut src = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut out = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for i in 0..src.len() {
+            let mut member = src.by_index(i).unwrap();
+            let mut kept = Vec::new();
+            member.read_to_end(&mut kept).unwrap();
+            out.start_file(member.name(), Default::default()).unwrap();
+            out.write_all(if member.name() == name { bytes } else { &kept })
+                .unwrap();
+        }
+        let mut packed = out.finish().unwrap();
+        packed.set_position(0);
+        packed
+    }
+
+    #[test]
+    fn malformed_inputs_are_refused_without_panicking() {
+        use ndarray_npy::WriteNpyExt;
+        let mut npy = Vec::new();
+        Array1::from(vec![-1i32]).write_npy(&mut npy).unwrap();
+        let err = load_inputs_from(with_member(INPUTS8, "N.npy", &npy), "n")
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("N=-1 must be positive"),
+            "{:#}",
+            err
+        );
+
+        // truncated and corrupted copies load or fail cleanly
+        let good = std::fs::read(INPUTS8).unwrap();
+        for len in (0..good.len()).step_by(good.len() / 40) {
+            assert!(load_inputs_from(Cursor::new(&good[..len]), "cut").is_err());
+        }
+        for at in (0..good.len()).step_by(good.len() / 40) {
+            let mut bytes = good.clone();
+            bytes[at] ^= 0xa5;
+            let _ = load_inputs_from(Cursor::new(bytes), "flipped");
+        }
+    }
+
+    #[test]
+    fn compressed_inputs_load_like_stored_ones() {
+        let path = deflated_copy(INPUTS8, "deflated-inputs");
+        let stored = load_inputs_npz(INPUTS8).unwrap();
+        let deflated = load_inputs_npz(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((deflated.n, deflated.m), (stored.n, stored.m));
+        assert_eq!(deflated.pre.masks, stored.pre.masks);
+        assert_eq!(deflated.pre.pops, stored.pre.pops);
+        assert_eq!(deflated.pre.jidx, stored.pre.jidx);
+        assert_eq!(deflated.pre.offsets, stored.pre.offsets);
+        assert_eq!(deflated.jbt_ref_pop, stored.jbt_ref_pop);
+        assert_eq!(deflated.jbt_ref_comps, stored.jbt_ref_comps);
+    }
+
+    #[test]
+    fn compressed_compat_loads_like_stored_compat() {
+        let path = deflated_copy(INPUTS8, "deflated-compat");
+        let stored = try_load_compat_npz(INPUTS8).unwrap().unwrap();
+        let deflated = try_load_compat_npz(&path.to_string_lossy())
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!stored.is_empty());
+        assert_eq!(deflated, stored);
+    }
+}
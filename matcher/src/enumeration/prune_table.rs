@@ -0,0 +1,110 @@
+//! Evil verdicts and destination roots of every left-half mask, tabulated once
+//! before enumeration (`ENUM_PRUNE_TABLE`). A mask has one bit per left-half
+//! cell, so up to N = 8 there are few enough masks to check them all up front:
+//! the flood fills of `detect_evil_pmask` and the scan of `find_root` become
+//! one byte lookup per (pre_jbt, mask) pair. The verdicts are those of the
+//! on-the-fly checks, so the snapshot is identical.
+
+use rayon::prelude::*;
+use std::time::Instant;
+
+use super::repr::Bits;
+use super::{BoardGeometry, available_memory_bytes, detect_evil_pmask, find_root};
+use crate::human::{Bytes, Secs};
+use crate::settings;
+
+/// Entry flag: the mask leaves a component that can never be tiled.
+const EVIL: u8 = 0x80;
+/// Entry root for a mask that covers the whole left half.
+const DONE: u8 = 0x7f;
+
+/// Left-half cells up to which the table is built by default whatever the
+/// memory (N <= 6: 256 KiB, built in milliseconds).
+const SMALL_CELLS: u32 = 18;
+/// The most a table covers (N = 8: 4 GiB, 2^32 flood fills).
+const MAX_CELLS: u32 = 32;
+/// A larger table is built by default when it takes at most this fraction
+/// of the available memory (see `available_memory_bytes`).
+const AUTO_MEMORY_SHARE: u64 = 4;
+
+/// Masks filled per rayon task.
+const FILL_CHUNK: usize = 1 << 16;
+
+/// One byte per left-half mask: `EVIL`, and the root it moves to (`DONE` once
+/// the left half is covered).
+pub(super) struct PruneTable {
+    entries: Vec<u8>,
+}
+
+impl PruneTable {
+    /// The table for this board, when `ENUM_PRUNE_TABLE` asks for one. By
+    /// default: up to N = 6, and up to N = 8 when the table takes at most a
+    /// quarter of the available memory.
+    pub(super) fn for_run<M: Bits>(g: &BoardGeometry<M>) -> Option<Self> {
+        let cells = g.half.count_ones();
+        match settings::get().prune_table {
+            Some(false) => return None,
+            None if cells > MAX_CELLS => return None,
+            Some(true) if cells > MAX_CELLS => {
+                eprintln!(
+                    "[table] ENUM_PRUNE_TABLE=1 but N={} has {} left-half cells, more than the {} a table covers; checks stay on the fly",
+                    g.n, cells, MAX_CELLS
+                );
+                return None;
+            }
+            None if cells > SMALL_CELLS => {
+                let size = 1u64 << cells;
+                let avail = available_memory_bytes();
+                if avail.is_none_or(|a| size > a / AUTO_MEMORY_SHARE) {
+                    eprintln!(
+                        "[table] N={}: a {} table is more than a quarter of the available memory ({}); checks stay on the fly (ENUM_PRUNE_TABLE=1 builds it anyway)",
+                        g.n,
+                        Bytes(size),
+                        avail.map_or("unknown".to_string(), |a| Bytes(a).to_string())
+                    );
+                    return None;
+                }
+            }
+            _ => {}
+        }
+        let t0 = Instant::now();
+        let mut entries = vec![0u8; 1usize << cells];
+        entries
+            .par_chunks_mut(FILL_CHUNK)
+            .enumerate()
+            .for_each(|(c, chunk)| {
+                for (i, e) in chunk.iter_mut().enumerate() {
+                    let mask = M::from_u128((c * FILL_CHUNK + i) as u128);
+                    let root = find_root(mask, g).map_or(DONE, |r| r as u8);
+                    *e = root | if detect_evil_pmask(mask, g) { EVIL } else { 0 };
+                }
+            });
+        eprintln!(
+            "[table] evil verdicts and roots of all {} left-half masks ({}) in {}",
+            entries.len(),
+            Bytes(entries.len() as u64),
+            Secs::from(t0.elapsed())
+        );
+        Some(PruneTable { entries })
+    }
+
+    #[inline]
+    fn entry<M: Bits>(&self, mask: M) -> u8 {
+        self.entries[mask.to_u128() as usize]
+    }
+
+    /// Same verdict as `detect_evil_pmask(mask, g)`.
+    #[inline]
+    pub(super) fn is_evil<M: Bits>(&self, mask: M) -> bool {
+        self.entry(mask) & EVIL != 0
+    }
+
+    /// Same root as `find_root(mask, g)`.
+    #[inline]
+    pub(super) fn root<M: Bits>(&self, mask: M) -> Option<usize> {
+        match self.entry(mask) & !EVIL {
+            DONE => None,
+            r => Some(r as usize),
+        }
+    }
+}
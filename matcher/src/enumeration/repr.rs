@@ -0,0 +1,395 @@
+//! Packed left-half masks, row codes and pop-keys, in two widths picked at run
+//! time from N and the code bitwidth (`pick`). `Narrow` is the fast path: u64
+//! masks and u128 codes of up to 10 j entries, for N <= 10. `Wide` takes u128
+//! masks and 256-bit codes of up to 16 entries, for N <= 16. Both lay a code
+//! out as `[len][j_0][j_1]...` from the low bit and a pop-key as
+//! `[k][pop_0][pop_1]...` (4 bits per pop), and order both numerically, so a
+//! run gives the same snapshot at either width. State weights are u64 unless
+//! `ENUM_WEIGHT_BITS=32` trades exact counts for half the weight memory.
+
+use anyhow::{Result, bail};
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not, Shl, Shr};
+
+/// A fixed-width bitboard over the left-half cells (bit `x * N + y`).
+pub trait Bits:
+    Copy
+    + Default
+    + Eq
+    + Ord
+    + Hash
+    + Debug
+    + Send
+    + Sync
+    + 'static
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+    + Not<Output = Self>
+    + Shl<u32, Output = Self>
+    + Shr<u32, Output = Self>
+    + BitAndAssign
+    + BitOrAssign
+    + BitXorAssign
+{
+    const BITS: u32;
+    const ZERO: Self;
+    const ONE: Self;
+    fn count_ones(self) -> u32;
+    fn trailing_zeros(self) -> u32;
+    fn wrapping_neg(self) -> Self;
+    /// The low `BITS` bits of `v`.
+    fn from_u128(v: u128) -> Self;
+    fn to_u128(self) -> u128;
+    /// Fold to 64 bits for hashing into shards.
+    fn fold_u64(self) -> u64;
+
+    #[inline(always)]
+    fn has(self, i: u32) -> bool {
+        (self >> i) & Self::ONE != Self::ZERO
+    }
+
+    fn put_le(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_u128().to_le_bytes()[..Self::BITS as usize / 8]);
+    }
+
+    /// From the first `BITS / 8` bytes of `bytes`.
+    fn from_le(bytes: &[u8]) -> Self {
+        let mut wide = [0u8; 16];
+        wide[..Self::BITS as usize / 8].copy_from_slice(&bytes[..Self::BITS as usize / 8]);
+        Self::from_u128(u128::from_le_bytes(wide))
+    }
+}
+
+macro_rules! impl_bits {
+    ($t:ty, $fold:expr) => {
+        impl Bits for $t {
+            const BITS: u32 = <$t>::BITS;
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            #[inline(always)]
+            fn count_ones(self) -> u32 {
+                <$t>::count_ones(self)
+            }
+            #[inline(always)]
+            fn trailing_zeros(self) -> u32 {
+                <$t>::trailing_zeros(self)
+            }
+            #[inline(always)]
+            fn wrapping_neg(self) -> Self {
+                <$t>::wrapping_neg(self)
+            }
+            #[inline(always)]
+            fn from_u128(v: u128) -> Self {
+                v as $t
+            }
+            #[inline(always)]
+            fn to_u128(self) -> u128 {
+                self as u128
+            }
+            #[inline(always)]
+            fn fold_u64(self) -> u64 {
+                $fold(self)
+            }
+        }
+    };
+}
+impl_bits!(u64, |m: u64| m);
+impl_bits!(u128, |m: u128| m as u64 ^ (m >> 64) as u64);
+
+/// A sorted set of j indices, `b` bits each, after a `LEN_BITS` length field.
+pub trait Code: Copy + Default + Eq + Ord + Hash + Debug + Send + Sync + 'static {
+    /// Most entries a code holds (a state has at most N).
+    const MAX_LEN: u32;
+    /// Bits of the length field, also the k field of pop-keys.
+    const LEN_BITS: u32;
+    /// Bits left for entries after the length field.
+    const PAYLOAD_BITS: u32;
+    const BYTES: usize;
+    fn entries(self) -> u32;
+    fn with_entries(self, k: u32) -> Self;
+    fn get(self, i: u32, b: u32) -> u32;
+    fn set(&mut self, i: u32, b: u32, val: u32);
+    fn put_le(self, buf: &mut Vec<u8>);
+    /// From the first `BYTES` bytes of `bytes`.
+    fn from_le(bytes: &[u8]) -> Self;
+}
+
+impl Code for u128 {
+    const MAX_LEN: u32 = 10;
+    const LEN_BITS: u32 = 4;
+    const PAYLOAD_BITS: u32 = 124;
+    const BYTES: usize = 16;
+
+    #[inline(always)]
+    fn entries(self) -> u32 {
+        (self & 0xF) as u32
+    }
+
+    #[inline(always)]
+    fn with_entries(self, k: u32) -> Self {
+        (self & !0xFu128) | (k as u128 & 0xF)
+    }
+
+    #[inline(always)]
+    fn get(self, i: u32, b: u32) -> u32 {
+        let shift = 4 + i * b;
+        if shift < 64 {
+            let rem = 64 - shift;
+            if b <= rem {
+                ((self as u64 >> shift) & ((1u64 << b) - 1)) as u32
+            } else {
+                let low = (self as u64 >> shift) & ((1u64 << rem) - 1);
+                let hi = (self >> 64) as u64 & ((1u64 << (b - rem)) - 1);
+                ((hi as u128) << rem | (low as u128)) as u32
+            }
+        } else {
+            let s = shift - 64;
+            (((self >> 64) as u64 >> s) & ((1u64 << b) - 1)) as u32
+        }
+    }
+
+    #[inline(always)]
+    fn set(&mut self, i: u32, b: u32, val: u32) {
+        let shift = 4 + i * b;
+        let v = (val as u128) & ((1u128 << b) - 1);
+        if shift < 64 {
+            let rem = 64 - shift;
+            if b <= rem {
+                let mask = !(((1u128 << b) - 1) << shift);
+                *self = (*self & mask) | (v << shift);
+            } else {
+                // split across 64-bit boundary
+                let low_bits = rem;
+                let low_mask = ((1u128 << low_bits) - 1) << shift;
+                let hi_bits = b - low_bits;
+                let hi_mask = ((1u128 << hi_bits) - 1) << 64;
+
+                let low_part = (v & ((1u128 << low_bits) - 1)) << shift;
+                let hi_part = (v >> low_bits) << 64;
+
+                *self = (*self & !low_mask) | low_part;
+                *self = (*self & !hi_mask) | hi_part;
+            }
+        } else {
+            let s = shift - 64;
+            let mask = !(((1u128 << b) - 1) << (64 + s));
+            *self = (*self & mask) | (v << (64 + s));
+        }
+    }
+
+    fn put_le(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn from_le(bytes: &[u8]) -> Self {
+        u128::from_le_bytes(bytes[..16].try_into().unwrap())
+    }
+}
+
+/// 256-bit code, `.0[0]` lowest, ordered as the integer it spells.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct WideCode([u64; 4]);
+
+impl Ord for WideCode {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for WideCode {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Code for WideCode {
+    const MAX_LEN: u32 = 16;
+    const LEN_BITS: u32 = 8;
+    const PAYLOAD_BITS: u32 = 248;
+    const BYTES: usize = 32;
+
+    #[inline(always)]
+    fn entries(self) -> u32 {
+        (self.0[0] & 0xFF) as u32
+    }
+
+    #[inline(always)]
+    fn with_entries(mut self, k: u32) -> Self {
+        self.0[0] = (self.0[0] & !0xFF) | (k as u64 & 0xFF);
+        self
+    }
+
+    // an entry (b <= 15 bits) straddles at most one word boundary
+    #[inline(always)]
+    fn get(self, i: u32, b: u32) -> u32 {
+        let bit = 8 + i * b;
+        let (w, s) = ((bit / 64) as usize, bit % 64);
+        let mut v = self.0[w] >> s;
+        if s + b > 64 {
+            v |= self.0[w + 1] << (64 - s);
+        }
+        (v & ((1u64 << b) - 1)) as u32
+    }
+
+    #[inline(always)]
+    fn set(&mut self, i: u32, b: u32, val: u32) {
+        let bit = 8 + i * b;
+        let (w, s) = ((bit / 64) as usize, bit % 64);
+        let m = (1u64 << b) - 1;
+        let v = val as u64 & m;
+        self.0[w] = (self.0[w] & !(m << s)) | (v << s);
+        if s + b > 64 {
+            self.0[w + 1] = (self.0[w + 1] & !(m >> (64 - s))) | (v >> (64 - s));
+        }
+    }
+
+    fn put_le(self, buf: &mut Vec<u8>) {
+        for w in self.0 {
+            buf.extend_from_slice(&w.to_le_bytes());
+        }
+    }
+
+    fn from_le(bytes: &[u8]) -> Self {
+        WideCode(std::array::from_fn(|w| {
+            u64::from_le_bytes(bytes[8 * w..8 * w + 8].try_into().unwrap())
+        }))
+    }
+}
+
+/// An unsigned tiling count, clamped at `MAX` when a merge overflows it.
+pub trait Count: Copy + Default + Debug + Display + Send + Sync + 'static {
+    const BITS: u32;
+    const ONE: Self;
+    const MAX: Self;
+    fn to_u128(self) -> u128;
+    /// The low `BITS` bits of `v`; callers clamp first.
+    fn from_u128(v: u128) -> Self;
+    fn to_f64(self) -> f64;
+
+    fn put_le(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_u128().to_le_bytes()[..Self::BITS as usize / 8]);
+    }
+
+    /// From the first `BITS / 8` bytes of `bytes`.
+    fn from_le(bytes: &[u8]) -> Self {
+        let mut wide = [0u8; 16];
+        wide[..Self::BITS as usize / 8].copy_from_slice(&bytes[..Self::BITS as usize / 8]);
+        Self::from_u128(u128::from_le_bytes(wide))
+    }
+}
+
+macro_rules! impl_count {
+    ($t:ty) => {
+        impl Count for $t {
+            const BITS: u32 = <$t>::BITS;
+            const ONE: Self = 1;
+            const MAX: Self = <$t>::MAX;
+            #[inline(always)]
+            fn to_u128(self) -> u128 {
+                self as u128
+            }
+            #[inline(always)]
+            fn from_u128(v: u128) -> Self {
+                v as $t
+            }
+            #[inline(always)]
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        }
+    };
+}
+impl_count!(u32);
+impl_count!(u64);
+
+/// The mask, code and weight types an enumeration runs with.
+pub trait Repr: Send + Sync + 'static {
+    /// Left-half bitboard; also holds packed pop-keys.
+    type Mask: Bits;
+    type Code: Code;
+    /// Per-state tiling count.
+    type Weight: Count;
+    /// Largest N whose left half fits `Mask` and whose states fit `Code`.
+    const MAX_N: u32;
+}
+
+pub struct Narrow<W = u64>(PhantomData<W>);
+impl<W: Count> Repr for Narrow<W> {
+    type Mask = u64;
+    type Code = u128;
+    type Weight = W;
+    const MAX_N: u32 = 10;
+}
+
+pub struct Wide<W = u64>(PhantomData<W>);
+impl<W: Count> Repr for Wide<W> {
+    type Mask = u128;
+    type Code = WideCode;
+    type Weight = W;
+    const MAX_N: u32 = 16;
+}
+
+/// Which `Repr` a run uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Narrow,
+    Wide,
+}
+
+fn fits<R: Repr>(n: u32, b: u32) -> bool {
+    n <= R::MAX_N && b * R::Code::MAX_LEN <= R::Code::PAYLOAD_BITS
+}
+
+/// The narrowest width that holds N-board states of j indices `b` bits wide.
+pub(super) fn pick(n: u32, m: usize, b: u32) -> Result<Width> {
+    if fits::<Narrow>(n, b) {
+        return Ok(Width::Narrow);
+    }
+    if fits::<Wide>(n, b) {
+        eprintln!(
+            "[code] N={} M={} at {} bits per j: wide codes ({} bits, up to {} entries) and u128 masks",
+            n,
+            m,
+            b,
+            8 * WideCode::BYTES,
+            WideCode::MAX_LEN
+        );
+        return Ok(Width::Wide);
+    }
+    bail!(
+        "N={} M={} at {} bits per j index fits no code width: narrow codes hold {} entries of up to {} bits (N <= {}), wide codes {} entries of up to {} bits (N <= {})",
+        n,
+        m,
+        b,
+        u128::MAX_LEN,
+        u128::PAYLOAD_BITS / u128::MAX_LEN,
+        <Narrow>::MAX_N,
+        WideCode::MAX_LEN,
+        WideCode::PAYLOAD_BITS / WideCode::MAX_LEN,
+        <Wide>::MAX_N
+    )
+}
+
+/// Pack a pop multiset (each pop < 16) as `[k][sorted pops]`.
+pub(super) fn pack_pop_key<R: Repr>(pops: &mut [u8]) -> R::Mask {
+    pops.sort_unstable();
+    let mut out = pops.len() as u128;
+    let mut shift = R::Code::LEN_BITS;
+    for &p in pops.iter() {
+        out |= ((p as u128) & 0xF) << shift;
+        shift += 4;
+    }
+    R::Mask::from_u128(out)
+}
+
+/// Unpack a pop-key (widened to u128) into its pops.
+pub(super) fn decode_pop_key<R: Repr>(key: u128) -> Vec<i32> {
+    let len_bits = R::Code::LEN_BITS;
+    let k = (key & ((1 << len_bits) - 1)) as u32;
+    (0..k)
+        .map(|i| ((key >> (len_bits + 4 * i)) & 0xF) as i32)
+        .collect()
+}
@@ -0,0 +1,168 @@
+//! Root-to-root transition graph, recorded during enumeration when
+//! `ENUM_ROOT_GRAPH=<path>` is set. A node is a root, labelled with its cell
+//! (u, v) under `ENUM_ROOT_ORDER`, with the frontier it
+//! held when vacated; an edge i -> j counts the states (and mask batches) that
+//! vacating root i sent to root j, with completed states going to a `done` sink.
+//! `<path>` ending in `.dot` is written as Graphviz, anything else as JSON.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default, Clone, Copy)]
+struct NodeStats {
+    pre: usize,
+    masks: usize,
+    states: usize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct EdgeStats {
+    states: u64,
+    batches: u64,
+}
+
+pub struct RootGraph {
+    n: u32,
+    /// Board cell (u, v) of each root.
+    cells: Vec<(usize, usize)>,
+    nodes: Vec<NodeStats>,
+    edges: BTreeMap<(usize, usize), EdgeStats>,
+    /// Per-destination counters for the root being vacated; index `nodes.len()` is the sink.
+    pending_states: Vec<AtomicU64>,
+    pending_batches: Vec<AtomicU64>,
+}
+
+impl RootGraph {
+    /// `Some((path, graph))` when `ENUM_ROOT_GRAPH` is set.
+    pub fn from_env(n: u32, cells: Vec<(usize, usize)>) -> Option<(PathBuf, Self)> {
+        let path = crate::settings::get().root_graph.clone()?;
+        let total_roots = cells.len();
+        let counters = || (0..=total_roots).map(|_| AtomicU64::new(0)).collect();
+        let graph = RootGraph {
+            n,
+            cells,
+            nodes: vec![NodeStats::default(); total_roots],
+            edges: BTreeMap::new(),
+            pending_states: counters(),
+            pending_batches: counters(),
+        };
+        Some((path, graph))
+    }
+
+    /// Sink index for completed states.
+    pub fn done(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Called from vacate workers: one batch of `states` headed for `dst`.
+    pub fn record(&self, dst: usize, states: usize) {
+        self.pending_states[dst].fetch_add(states as u64, Ordering::Relaxed);
+        self.pending_batches[dst].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Close out root `src` after its vacate: store its frontier size and move
+    /// the pending counters into edges.
+    pub fn finish_root(&mut self, src: usize, pre: usize, masks: usize, states: usize) {
+        self.nodes[src] = NodeStats { pre, masks, states };
+        for dst in 0..self.pending_states.len() {
+            let states = self.pending_states[dst].swap(0, Ordering::Relaxed);
+            let batches = self.pending_batches[dst].swap(0, Ordering::Relaxed);
+            if batches > 0 {
+                self.edges.insert((src, dst), EdgeStats { states, batches });
+            }
+        }
+    }
+
+    fn label(&self, root: usize) -> String {
+        if root == self.done() {
+            "done".to_string()
+        } else {
+            let (u, v) = self.cells[root];
+            format!("({},{})", u, v)
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let text = if path.extension().is_some_and(|e| e == "dot") {
+            self.to_dot()
+        } else {
+            serde_json::to_string_pretty(&self.to_json())?
+        };
+        std::fs::write(path, text).with_context(|| format!("write {}", path.display()))?;
+        eprintln!(
+            "[graph] wrote {} roots, {} edges to {}",
+            self.nodes.len(),
+            self.edges.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let nodes: Vec<serde_json::Value> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                serde_json::json!({
+                    "root": i,
+                    "label": self.label(i),
+                    "pre": s.pre,
+                    "masks": s.masks,
+                    "states": s.states,
+                })
+            })
+            .collect();
+        let edges: Vec<serde_json::Value> = self
+            .edges
+            .iter()
+            .map(|(&(src, dst), e)| {
+                let to = if dst == self.done() {
+                    serde_json::json!("done")
+                } else {
+                    serde_json::json!(dst)
+                };
+                serde_json::json!({
+                    "from": src,
+                    "to": to,
+                    "states": e.states,
+                    "batches": e.batches,
+                })
+            })
+            .collect();
+        serde_json::json!({ "n": self.n, "nodes": nodes, "edges": edges })
+    }
+
+    fn to_dot(&self) -> String {
+        let mut s = String::from("digraph roots {\n  rankdir=LR;\n  done [shape=doublecircle];\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.masks == 0 {
+                continue;
+            }
+            s += &format!(
+                "  r{} [label=\"{}\\nmasks={} states={}\"];\n",
+                i,
+                self.label(i),
+                node.masks,
+                node.states
+            );
+        }
+        for (&(src, dst), e) in &self.edges {
+            let to = if dst == self.done() {
+                "done".to_string()
+            } else {
+                format!("r{}", dst)
+            };
+            s += &format!(
+                "  r{} -> {} [label=\"{}\", penwidth={:.2}];\n",
+                src,
+                to,
+                e.states,
+                1.0 + (e.states as f64).log10().max(0.0)
+            );
+        }
+        s + "}\n"
+    }
+}
@@ -0,0 +1,129 @@
+//! The order roots are vacated in (`ENUM_ROOT_ORDER`). A state moves on to the
+//! first left-half cell it leaves uncovered in this order, and only pre_jbt
+//! whose own first cell is that root can cover it, so any fixed order of the
+//! cells enumerates the same tilings. The input lists pre_jbt by their lowest
+//! bit (column-major, the default); other orders regroup them by their first
+//! cell in the chosen order before enumeration. The order changes how many
+//! states wait in the frontiers at once, and so peak RSS, but not the snapshot;
+//! `matcher estimate` honours it, to compare orders before a long run.
+
+use anyhow::{Result, bail};
+
+use super::PreCsr;
+
+/// Which left-half cell is vacated next.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RootOrder {
+    /// Lowest bit first: down column 0, then column 1, ...
+    #[default]
+    Column,
+    /// Across each row of the left half, top row first.
+    Row,
+    /// Greedily, the cell the fewest remaining pre_jbt would be anchored at,
+    /// so each root branches as little as it can.
+    MinGrowth,
+}
+
+impl std::str::FromStr for RootOrder {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "column" => Ok(RootOrder::Column),
+            "row" => Ok(RootOrder::Row),
+            "min-growth" => Ok(RootOrder::MinGrowth),
+            other => bail!(
+                "unknown root order {:?} (expected column|row|min-growth)",
+                other
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for RootOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RootOrder::Column => "column",
+            RootOrder::Row => "row",
+            RootOrder::MinGrowth => "min-growth",
+        })
+    }
+}
+
+/// Left-half cells (bit indices) in the order their roots are vacated.
+pub(super) fn cell_order(order: RootOrder, n: u32, pre: &PreCsr) -> Vec<u8> {
+    let n_cells = n * (n / 2);
+    match order {
+        RootOrder::Column => (0..n_cells as u8).collect(),
+        RootOrder::Row => (0..n)
+            .flat_map(|y| (0..n / 2).map(move |x| (x * n + y) as u8))
+            .collect(),
+        RootOrder::MinGrowth => min_growth(n_cells as usize, &pre.masks),
+    }
+}
+
+/// Pick cells one at a time, each time the one covered by the fewest pre_jbt
+/// that no earlier cell anchors (lowest bit on ties).
+fn min_growth(n_cells: usize, masks: &[u128]) -> Vec<u8> {
+    let cells_of = |mask: u128| (0..n_cells).filter(move |&c| mask >> c & 1 != 0);
+    let mut by_cell: Vec<Vec<usize>> = vec![Vec::new(); n_cells];
+    let mut open = vec![0usize; n_cells];
+    for (k, &mask) in masks.iter().enumerate() {
+        for c in cells_of(mask) {
+            by_cell[c].push(k);
+            open[c] += 1;
+        }
+    }
+    let mut anchored = vec![false; masks.len()];
+    let mut chosen = vec![false; n_cells];
+    let mut order = Vec::with_capacity(n_cells);
+    for _ in 0..n_cells {
+        let next = (0..n_cells)
+            .filter(|&c| !chosen[c])
+            .min_by_key(|&c| (open[c], c))
+            .unwrap();
+        chosen[next] = true;
+        order.push(next as u8);
+        for &k in &by_cell[next] {
+            if !anchored[k] {
+                anchored[k] = true;
+                for c in cells_of(masks[k]) {
+                    open[c] -= 1;
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Regroup the pre_jbt under the root of their first cell in `cells` order,
+/// keeping their input order within a root.
+pub(super) fn reanchor(pre: PreCsr, cells: &[u8]) -> PreCsr {
+    let mut rank = [0usize; 128];
+    for (r, &c) in cells.iter().enumerate() {
+        rank[c as usize] = r;
+    }
+    let anchor = |mask: u128| {
+        (0..128)
+            .filter(|&c| mask >> c & 1 != 0)
+            .map(|c| rank[c])
+            .min()
+            .unwrap_or(0)
+    };
+    let mut by_root: Vec<Vec<usize>> = vec![Vec::new(); pre.n_roots];
+    for (k, &mask) in pre.masks.iter().enumerate() {
+        by_root[anchor(mask)].push(k);
+    }
+    let mut offsets = Vec::with_capacity(pre.n_roots + 1);
+    offsets.push(0);
+    for ks in &by_root {
+        offsets.push(offsets.last().unwrap() + ks.len());
+    }
+    let ks: Vec<usize> = by_root.into_iter().flatten().collect();
+    PreCsr {
+        masks: ks.iter().map(|&k| pre.masks[k]).collect(),
+        pops: ks.iter().map(|&k| pre.pops[k]).collect(),
+        jidx: ks.iter().map(|&k| pre.jidx[k]).collect(),
+        offsets,
+        n_roots: pre.n_roots,
+    }
+}
@@ -0,0 +1,467 @@
+//! On-disk format for spilled frontier chunks. Enumeration spills the frontiers
+//! of roots ahead here when RSS passes `ENUM_MAX_RSS_*`, and reads a root's
+//! file back when it is vacated.
+//!
+//! File = 8-byte magic, then records of
+//! `[payload_len: u32 LE][crc32(payload): u32 LE][payload]`.
+//! A chunk payload is `[root: u32][mask][count: u32][codes: count x code][weights: count x u32]`,
+//! all little-endian, with the mask and codes at the run's width (`repr`): a
+//! u64 mask and u128 codes, or a u128 mask and 32-byte codes. Records are only
+//! ever appended, so a crash mid-spill can at worst leave a short or corrupt
+//! tail; `scavenge` finds the last intact record and truncates everything
+//! after it.
+//!
+//! Spill files live under the configured temp directory and are charged
+//! against an optional disk budget (`--tmpdir`, `--max-disk-gb`); a write that
+//! would exceed the budget fails before anything reaches the disk.
+
+use anyhow::{Context, Result, bail};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::repr::{Bits, Code, Count, Narrow, Repr};
+use crate::human::Bytes;
+
+const MAGIC: &[u8; 8] = b"CJSPILL1";
+const RECORD_HEADER: u64 = 8;
+/// Codes per chunk (320 MiB of payload), well under the u32 record length;
+/// larger buckets are written as several chunks.
+pub(super) const MAX_CHUNK_CODES: usize = 1 << 24;
+
+static TMPDIR: OnceLock<PathBuf> = OnceLock::new();
+static MAX_BYTES: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Spill bytes currently on disk, their high-water mark, and all bytes ever written.
+static LIVE_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_BYTES: AtomicU64 = AtomicU64::new(0);
+static TOTAL_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Set where spill files go and how many bytes they may occupy at once. Call
+/// once at startup; without it spills go to the system temp dir, unbounded.
+pub fn configure(tmpdir: Option<&Path>, max_bytes: Option<u64>) -> Result<()> {
+    let dir = tmpdir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+    std::fs::create_dir_all(&dir).with_context(|| format!("create tmpdir {}", dir.display()))?;
+    if let Some(max) = max_bytes {
+        MAX_BYTES.store(max, Ordering::Relaxed);
+        if let Some(free) = free_bytes(&dir)
+            && free < max
+        {
+            eprintln!(
+                "[disk] warning: {} has {} free, below the {} budget",
+                dir.display(),
+                Bytes(free),
+                Bytes(max)
+            );
+        }
+    }
+    let _ = TMPDIR.set(dir);
+    Ok(())
+}
+
+/// Unknown without `platform`, which skips the free-space check.
+#[cfg(not(feature = "platform"))]
+fn free_bytes(_dir: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(feature = "platform")]
+fn free_bytes(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut st = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c is NUL-terminated and st is only read after statvfs succeeds.
+    if unsafe { libc::statvfs(c.as_ptr(), st.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let st = unsafe { st.assume_init() };
+    Some(st.f_bavail.saturating_mul(st.f_frsize))
+}
+
+/// The configured temp directory.
+pub fn spill_dir() -> &'static Path {
+    TMPDIR.get_or_init(std::env::temp_dir)
+}
+
+/// Path for a spill file named `name` inside the configured temp directory.
+pub fn spill_path(name: &str) -> PathBuf {
+    spill_dir().join(name)
+}
+
+/// Reserve `bytes` of the disk budget, failing without reserving anything if
+/// the spill files would then exceed it.
+fn charge(bytes: u64) -> Result<()> {
+    let max = MAX_BYTES.load(Ordering::Relaxed);
+    let prev = LIVE_BYTES
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+            live.checked_add(bytes).filter(|&next| next <= max)
+        })
+        .map_err(|live| {
+            anyhow::anyhow!(
+                "disk budget exceeded: spilling {} more would bring spill files to {}, over --max-disk-gb ({})",
+                Bytes(bytes),
+                Bytes(live + bytes),
+                Bytes(max)
+            )
+        })?;
+    PEAK_BYTES.fetch_max(prev + bytes, Ordering::Relaxed);
+    TOTAL_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    Ok(())
+}
+
+fn release(bytes: u64) {
+    LIVE_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// Delete a finished spill file and return its bytes to the budget.
+pub fn remove(path: &Path) -> Result<()> {
+    let len = std::fs::metadata(path)
+        .with_context(|| format!("stat spill {}", path.display()))?
+        .len();
+    std::fs::remove_file(path).with_context(|| format!("remove spill {}", path.display()))?;
+    release(len);
+    Ok(())
+}
+
+/// One-line summary of spill traffic; silent when nothing was spilled.
+pub fn report() {
+    let total = TOTAL_BYTES.load(Ordering::Relaxed);
+    if total == 0 {
+        return;
+    }
+    let max = MAX_BYTES.load(Ordering::Relaxed);
+    eprintln!(
+        "[disk] spilled {} in total, peak on disk {}{}",
+        Bytes(total),
+        Bytes(PEAK_BYTES.load(Ordering::Relaxed)),
+        if max == u64::MAX {
+            String::new()
+        } else {
+            format!(" of {} budget", Bytes(max))
+        }
+    );
+}
+
+/// Frontier batch for one (root, mask) bucket, as stored in a spill record.
+pub struct SpillChunk<R: Repr = Narrow> {
+    pub root: u32,
+    pub mask: R::Mask,
+    pub codes: Vec<R::Code>,
+    pub weights: Vec<R::Weight>,
+}
+
+impl<R: Repr> SpillChunk<R> {
+    /// Bytes before the codes: root, mask, count.
+    const HEADER: usize = 8 + R::Mask::BITS as usize / 8;
+    const WEIGHT: usize = R::Weight::BITS as usize / 8;
+
+    fn encode(&self) -> Vec<u8> {
+        Self::encode_parts(self.root, self.mask, &self.codes, &self.weights)
+    }
+
+    /// The payload of a chunk, from borrowed codes and weights.
+    pub(super) fn encode_parts(
+        root: u32,
+        mask: R::Mask,
+        codes: &[R::Code],
+        weights: &[R::Weight],
+    ) -> Vec<u8> {
+        let n = codes.len();
+        let mut buf = Vec::with_capacity(Self::HEADER + n * (R::Code::BYTES + Self::WEIGHT));
+        buf.extend_from_slice(&root.to_le_bytes());
+        mask.put_le(&mut buf);
+        buf.extend_from_slice(&(n as u32).to_le_bytes());
+        for c in codes {
+            c.put_le(&mut buf);
+        }
+        for w in weights {
+            w.put_le(&mut buf);
+        }
+        buf
+    }
+
+    pub(super) fn decode(buf: &[u8]) -> Result<Self> {
+        let take = |at: usize, len: usize| -> Result<&[u8]> {
+            buf.get(at..at + len)
+                .context("spill record shorter than its header says")
+        };
+        let (head, code_bytes, w_bytes) = (Self::HEADER, R::Code::BYTES, Self::WEIGHT);
+        let root = u32::from_le_bytes(take(0, 4)?.try_into()?);
+        let mask = R::Mask::from_le(take(4, head - 8)?);
+        let n = u32::from_le_bytes(take(head - 4, 4)?.try_into()?) as usize;
+        if buf.len() != head + n * (code_bytes + w_bytes) {
+            bail!("spill record length {} does not fit {} codes", buf.len(), n);
+        }
+        let codes = take(head, n * code_bytes)?
+            .chunks_exact(code_bytes)
+            .map(R::Code::from_le)
+            .collect();
+        let weights = take(head + n * code_bytes, n * w_bytes)?
+            .chunks_exact(w_bytes)
+            .map(R::Weight::from_le)
+            .collect();
+        Ok(SpillChunk {
+            root,
+            mask,
+            codes,
+            weights,
+        })
+    }
+}
+
+/// Appends checksummed chunk records; `finish` flushes and fsyncs.
+pub struct SpillWriter {
+    out: BufWriter<File>,
+    bytes: u64,
+    records: usize,
+}
+
+impl SpillWriter {
+    /// Create (truncating) a spill file at `path`.
+    pub fn create(path: &Path) -> Result<Self> {
+        charge(MAGIC.len() as u64)?;
+        let f = File::create(path).with_context(|| format!("create spill {}", path.display()))?;
+        let mut out = BufWriter::new(f);
+        out.write_all(MAGIC)?;
+        Ok(SpillWriter {
+            out,
+            bytes: MAGIC.len() as u64,
+            records: 0,
+        })
+    }
+
+    /// Reopen an existing spill for appending, after dropping any torn tail.
+    /// Its intact bytes are charged to the disk budget.
+    pub fn append(path: &Path) -> Result<Self> {
+        let valid = scavenge(path)?;
+        charge(valid.bytes)?;
+        let f = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open spill {}", path.display()))?;
+        Ok(SpillWriter {
+            out: BufWriter::new(f),
+            bytes: valid.bytes,
+            records: valid.records,
+        })
+    }
+
+    /// Append one record; fails cleanly (nothing written) if the record would
+    /// push spill files over the disk budget.
+    pub fn write_chunk<R: Repr>(&mut self, chunk: &SpillChunk<R>) -> Result<()> {
+        self.write_payload(chunk.encode())
+    }
+
+    /// Append a bucket's codes and weights as one chunk per `MAX_CHUNK_CODES`.
+    pub(super) fn write_bucket<R: Repr>(
+        &mut self,
+        root: u32,
+        mask: R::Mask,
+        codes: &[R::Code],
+        weights: &[R::Weight],
+    ) -> Result<()> {
+        for (codes, weights) in codes
+            .chunks(MAX_CHUNK_CODES)
+            .zip(weights.chunks(MAX_CHUNK_CODES))
+        {
+            self.write_payload(SpillChunk::<R>::encode_parts(root, mask, codes, weights))?;
+        }
+        Ok(())
+    }
+
+    fn write_payload(&mut self, payload: Vec<u8>) -> Result<()> {
+        charge(RECORD_HEADER + payload.len() as u64)?;
+        self.bytes += write_record(&mut self.out, &payload)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// File size so far (including the magic).
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Records written so far.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// Push buffered records to the file, so it can be read while open.
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Frame `payload` as one record; returns the bytes written.
+pub(super) fn write_record(out: &mut impl Write, payload: &[u8]) -> io::Result<u64> {
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    out.write_all(payload)?;
+    Ok(RECORD_HEADER + payload.len() as u64)
+}
+
+/// Intact prefix of a spill file, as found by `scan`.
+pub struct SpillScan {
+    pub records: usize,
+    /// Byte length of the intact prefix (magic + whole, checksum-valid records).
+    pub bytes: u64,
+    /// Bytes after the intact prefix (torn or corrupt tail).
+    pub torn: u64,
+}
+
+/// Walk the records, calling `visit` on each intact chunk (of width `R`), and
+/// stop at the first short or checksum-failing record.
+pub fn scan<R: Repr>(
+    path: &Path,
+    mut visit: impl FnMut(SpillChunk<R>) -> Result<()>,
+) -> Result<SpillScan> {
+    scan_records(path, MAGIC, "spill", |payload| {
+        let Ok(chunk) = SpillChunk::decode(payload) else {
+            return Ok(false);
+        };
+        visit(chunk)?;
+        Ok(true)
+    })
+}
+
+/// Walk the records of a `what` file that starts with `magic`, calling `visit` on
+/// each checksum-valid payload, and stop at the first short or corrupt record
+/// or when `visit` returns false (a payload it cannot decode).
+pub(super) fn scan_records(
+    path: &Path,
+    magic: &[u8; 8],
+    what: &str,
+    mut visit: impl FnMut(&[u8]) -> Result<bool>,
+) -> Result<SpillScan> {
+    let f = File::open(path).with_context(|| format!("open {} {}", what, path.display()))?;
+    let total = f.metadata()?.len();
+    let mut rd = BufReader::new(f);
+    let mut found = [0u8; 8];
+    if rd.read_exact(&mut found).is_err() || &found != magic {
+        bail!("{} is not a {} file (bad magic)", path.display(), what);
+    }
+    let mut pos = magic.len() as u64;
+    let mut records = 0usize;
+    loop {
+        let mut head = [0u8; RECORD_HEADER as usize];
+        if rd.read_exact(&mut head).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(head[0..4].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(head[4..8].try_into().unwrap());
+        if pos + RECORD_HEADER + len > total {
+            break;
+        }
+        let mut payload = vec![0u8; len as usize];
+        if rd.read_exact(&mut payload).is_err() || crc32fast::hash(&payload) != crc {
+            break;
+        }
+        if !visit(&payload)? {
+            break;
+        }
+        records += 1;
+        pos += RECORD_HEADER + len;
+    }
+    Ok(SpillScan {
+        records,
+        bytes: pos,
+        torn: total - pos,
+    })
+}
+
+/// Truncate a spill file to its intact prefix, so resume never reads garbage.
+/// Only the framing is checked, so this holds for chunks of either width.
+pub fn scavenge(path: &Path) -> Result<SpillScan> {
+    let found = scan_records(path, MAGIC, "spill", |_| Ok(true))?;
+    if found.torn > 0 {
+        let f = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("open spill {}", path.display()))?;
+        f.set_len(found.bytes)?;
+        f.sync_all()?;
+        eprintln!(
+            "[spill] {}: dropped {} torn bytes after {} intact records",
+            path.display(),
+            found.torn,
+            found.records
+        );
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(root: u32, codes: &[u128]) -> SpillChunk {
+        SpillChunk {
+            root,
+            mask: 0b1011 << root,
+            codes: codes.to_vec(),
+            weights: (1..=codes.len() as u64).map(|w| w << root).collect(),
+        }
+    }
+
+    /// (root, mask, codes, weights) of a chunk read back.
+    type ReadChunk = (u32, u64, Vec<u128>, Vec<u64>);
+
+    fn read_back(path: &Path) -> (Vec<ReadChunk>, SpillScan) {
+        let mut chunks = Vec::new();
+        let scan = scan::<Narrow>(path, |c| {
+            chunks.push((c.root, c.mask, c.codes, c.weights));
+            Ok(())
+        })
+        .unwrap();
+        (chunks, scan)
+    }
+
+    #[test]
+    fn torn_tails_are_dropped_and_appending_resumes_after_them() {
+        let path = std::env::temp_dir().join(format!("matcher-spill-{}", std::process::id()));
+        let mut w = SpillWriter::create(&path).unwrap();
+        w.write_chunk(&chunk(3, &[1, 5, u128::MAX])).unwrap();
+        w.write_chunk(&chunk(7, &[])).unwrap();
+        w.write_chunk(&chunk(9, &[42; 10])).unwrap();
+        let full = w.bytes();
+        w.finish().unwrap();
+        let (chunks, scan) = read_back(&path);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[0],
+            (3, 0b1011 << 3, vec![1, 5, u128::MAX], vec![8, 16, 24])
+        );
+        assert_eq!((scan.bytes, scan.torn), (full, 0));
+
+        // a crash halfway through the last record
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(full - 20).unwrap();
+        let (chunks, scan) = read_back(&path);
+        assert_eq!((chunks.len(), scan.torn), (2, full - 20 - scan.bytes));
+        assert_eq!(scavenge(&path).unwrap().records, 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), scan.bytes);
+
+        let mut w = SpillWriter::append(&path).unwrap();
+        w.write_chunk(&chunk(11, &[8])).unwrap();
+        w.finish().unwrap();
+        let (chunks, scan) = read_back(&path);
+        assert_eq!(chunks.iter().map(|c| c.0).collect::<Vec<_>>(), [3, 7, 11]);
+        assert_eq!(scan.torn, 0);
+
+        // a flipped bit fails the checksum
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(read_back(&path).0.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::human::Bytes;
+
+/// THP granule on x86_64/aarch64; advised buffers are aligned to it so the
+/// kernel can back them with whole huge pages.
+const HUGE_PAGE: usize = 2 << 20;
+
+/// Huge-page threshold, written at most once: `UNDECIDED` until `enable` runs
+/// or the first allocation of at least `HUGE_PAGE` bytes, then either `OFF` or
+/// the `min_bytes` passed to `enable` (always >= `HUGE_PAGE`). Because it never
+/// changes again, every block is freed with the layout it was allocated with.
+static THRESHOLD: AtomicUsize = AtomicUsize::new(UNDECIDED);
+const UNDECIDED: usize = 0;
+const OFF: usize = 1;
+static ADVISED: AtomicU64 = AtomicU64::new(0);
+static ADVISED_BYTES: AtomicU64 = AtomicU64::new(0);
+static ADVISE_FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Allocation counters, off unless `MATCHER_ALLOC_STATS=1` (one shared atomic
+/// per allocation would contend on many-core nodes).
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+static REALLOCS: AtomicU64 = AtomicU64::new(0);
+static ALLOC_BYTES: AtomicU64 = AtomicU64::new(0);
+
+#[inline]
+fn count(counter: &AtomicU64, bytes: usize) {
+    if COUNTING.load(Ordering::Relaxed) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// System allocator that, once `enable` has been called, places allocations of
+/// at least `min_bytes` on 2 MiB boundaries and `madvise(MADV_HUGEPAGE)`s them
+/// (frontier code/weight vectors, bucket arrays). If THP is unavailable the
+/// madvise fails and the buffer simply stays on regular pages.
+pub struct HugePageAlloc;
+
+impl HugePageAlloc {
+    /// Layout a block of `layout` is (or was) allocated with. Small blocks
+    /// never consult the threshold; the first large one settles it to `OFF`
+    /// if `enable` has not run yet.
+    #[inline]
+    fn huge_layout(layout: Layout) -> Option<Layout> {
+        if layout.size() < HUGE_PAGE {
+            return None;
+        }
+        let min = match THRESHOLD.load(Ordering::Acquire) {
+            UNDECIDED => {
+                match THRESHOLD.compare_exchange(
+                    UNDECIDED,
+                    OFF,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => OFF,
+                    Err(set) => set,
+                }
+            }
+            set => set,
+        };
+        if min == OFF || layout.size() < min {
+            return None;
+        }
+        Layout::from_size_align(layout.size(), layout.align().max(HUGE_PAGE)).ok()
+    }
+
+    /// Without `platform` there is no madvise; every request counts as failed.
+    #[cfg(not(feature = "platform"))]
+    fn advise(_ptr: *mut u8, _size: usize) {
+        ADVISE_FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "platform")]
+    fn advise(ptr: *mut u8, size: usize) {
+        // SAFETY: ptr..ptr+size is a live allocation we just obtained.
+        let rc = unsafe { libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_HUGEPAGE) };
+        if rc == 0 {
+            ADVISED.fetch_add(1, Ordering::Relaxed);
+            ADVISED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+        } else {
+            ADVISE_FAILED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for HugePageAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(&ALLOCS, layout.size());
+        match Self::huge_layout(layout) {
+            Some(huge) => {
+                let ptr = unsafe { System.alloc(huge) };
+                if !ptr.is_null() {
+                    Self::advise(ptr, huge.size());
+                }
+                ptr
+            }
+            None => unsafe { System.alloc(layout) },
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(&ALLOCS, layout.size());
+        match Self::huge_layout(layout) {
+            Some(huge) => {
+                let ptr = unsafe { System.alloc_zeroed(huge) };
+                if !ptr.is_null() {
+                    Self::advise(ptr, huge.size());
+                }
+                ptr
+            }
+            None => unsafe { System.alloc_zeroed(layout) },
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let layout = Self::huge_layout(layout).unwrap_or(layout);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        if Self::huge_layout(layout).is_none() && Self::huge_layout(new_layout).is_none() {
+            count(&REALLOCS, new_size.saturating_sub(layout.size()));
+            return unsafe { System.realloc(ptr, layout, new_size) };
+        }
+        // crossing into (or within) the huge path: move so alloc/dealloc layouts agree
+        // (counted as a fresh allocation)
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Turn on huge-page advice for allocations of at least `min_bytes`. Only the
+/// first call before any allocation of 2 MiB or more takes effect; later calls
+/// leave the threshold alone and return false.
+pub fn enable(min_bytes: usize) -> bool {
+    let min = min_bytes.max(HUGE_PAGE);
+    match THRESHOLD.compare_exchange(UNDECIDED, min, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            eprintln!(
+                "[hugepages] advising allocations >= {} (MADV_HUGEPAGE)",
+                Bytes(min as u64)
+            );
+            true
+        }
+        Err(_) => {
+            eprintln!("[hugepages] warn: large buffers already allocated; huge pages stay off");
+            false
+        }
+    }
+}
+
+fn enabled() -> bool {
+    THRESHOLD.load(Ordering::Acquire) >= HUGE_PAGE
+}
+
+/// Start counting allocations for `AllocPhase` reports.
+pub fn count_allocations() {
+    COUNTING.store(true, Ordering::Relaxed);
+}
+
+/// Allocation counts over one phase of the run (enumeration, matching);
+/// `finish` prints an `[alloc]` line. Inert unless counting is on.
+pub struct AllocPhase {
+    label: &'static str,
+    start: [u64; 3],
+}
+
+impl AllocPhase {
+    fn now() -> [u64; 3] {
+        [&ALLOCS, &REALLOCS, &ALLOC_BYTES].map(|c| c.load(Ordering::Relaxed))
+    }
+
+    pub fn start(label: &'static str) -> Option<Self> {
+        COUNTING.load(Ordering::Relaxed).then(|| AllocPhase {
+            label,
+            start: Self::now(),
+        })
+    }
+
+    pub fn finish(self) {
+        let end = Self::now();
+        let [allocs, reallocs, bytes] = [0, 1, 2].map(|i| end[i] - self.start[i]);
+        eprintln!(
+            "[alloc] {}: {} allocations, {} reallocations (growth), {} requested",
+            self.label,
+            allocs,
+            reallocs,
+            Bytes(bytes)
+        );
+    }
+}
+
+/// One-line summary; silent when huge pages were never enabled.
+pub fn report() {
+    if !enabled() {
+        return;
+    }
+    eprintln!(
+        "[hugepages] advised {} allocations ({}), madvise failures {}",
+        ADVISED.load(Ordering::Relaxed),
+        Bytes(ADVISED_BYTES.load(Ordering::Relaxed)),
+        ADVISE_FAILED.load(Ordering::Relaxed)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reset() {
+        THRESHOLD.store(UNDECIDED, Ordering::Release);
+    }
+
+    // Both cases share THRESHOLD, so they run in one test.
+    #[test]
+    fn blocks_are_freed_with_their_allocation_layout() {
+        let a = HugePageAlloc;
+        let small = Layout::from_size_align(1 << 10, 8).unwrap();
+        let big = Layout::from_size_align(4 << 20, 8).unwrap();
+
+        // a large block allocated before `enable` keeps huge pages off
+        reset();
+        unsafe {
+            let p = a.alloc(big);
+            assert!(!p.is_null());
+            assert!(!enable(HUGE_PAGE));
+            let p = a.realloc(p, big, 8 << 20);
+            assert!(!p.is_null());
+            a.dealloc(p, Layout::from_size_align(8 << 20, 8).unwrap());
+        }
+
+        // small blocks do not settle the threshold; growing one past it moves
+        // it onto a huge page and shrinking moves it back
+        reset();
+        unsafe {
+            let p = a.alloc(small);
+            p.write_bytes(0xab, small.size());
+            assert!(enable(HUGE_PAGE));
+            assert!(!enable(HUGE_PAGE));
+            let p = a.realloc(p, small, big.size());
+            assert!(!p.is_null());
+            assert_eq!(p as usize % HUGE_PAGE, 0);
+            assert_eq!(*p.add(small.size() - 1), 0xab);
+            let p = a.realloc(p, big, small.size());
+            assert_eq!(*p, 0xab);
+            a.dealloc(p, small);
+
+            let q = a.alloc_zeroed(big);
+            assert_eq!(q as usize % HUGE_PAGE, 0);
+            a.dealloc(q, big);
+        }
+        reset();
+    }
+}
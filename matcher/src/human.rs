@@ -0,0 +1,63 @@
+//! Sizes and durations as log lines and summaries print them. `Bytes` uses
+//! binary units (B, KiB, MiB, GiB, TiB) with two decimals past bytes;
+//! `Secs` picks µs / ms below a second, seconds below a minute, then
+//! `4m 05s` and `2h 03m`. Machine-read output (the per-pair report lines,
+//! `Omega total`, JSON, CSV) keeps plain numbers instead.
+
+use std::fmt;
+
+const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+/// A byte count, displayed as `512 B`, `1.50 KiB`, ..., `3.20 TiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut v = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while v >= 1024.0 && unit + 1 < UNITS.len() {
+            v /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.2} {}", v, UNITS[unit])
+    }
+}
+
+/// A duration in seconds, displayed as `850 µs`, `42 ms`, `7.31 s`, `4m 05s`
+/// or `2h 03m`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Secs(pub f64);
+
+impl fmt::Display for Secs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = self.0;
+        if !s.is_finite() || s < 0.0 {
+            return write!(f, "{} s", s);
+        }
+        if s < 1e-3 {
+            return write!(f, "{:.0} µs", s * 1e6);
+        }
+        if s < 1.0 {
+            return write!(f, "{:.0} ms", s * 1e3);
+        }
+        if s < 60.0 {
+            return write!(f, "{:.2} s", s);
+        }
+        let whole = s.round() as u64;
+        if whole < 3600 {
+            write!(f, "{}m {:02}s", whole / 60, whole % 60)
+        } else {
+            write!(f, "{}h {:02}m", whole / 3600, whole % 3600 / 60)
+        }
+    }
+}
+
+impl From<std::time::Duration> for Secs {
+    fn from(d: std::time::Duration) -> Self {
+        Secs(d.as_secs_f64())
+    }
+}
@@ -0,0 +1,31 @@
+//! Enumeration and bucket-pair matching for the cjpt grid partition count, as
+//! a library. The `matcher` binary is a command line over this crate; to embed
+//! a run without shelling out to it, start from `MatcherEngine`:
+//!
+//! ```no_run
+//! use matcher::MatcherEngine;
+//!
+//! let engine = MatcherEngine::from_snapshot("cjpt8_snapshot.npz").build()?;
+//! let outcome = engine.run();
+//! println!("Omega = {}", outcome.omega());
+//! # anyhow::Ok(())
+//! ```
+//!
+//! Tunables are read from the `ENUM_*` / `MATCHER_*` environment variables (see
+//! `settings`) the first time a run needs them.
+
+#[cfg(feature = "capi")]
+mod capi;
+pub mod chaos;
+mod engine;
+pub mod enumeration;
+pub mod hugepages;
+pub mod human;
+pub mod matching;
+#[cfg(feature = "python")]
+mod python;
+pub mod runtime;
+pub mod settings;
+pub mod telemetry;
+
+pub use engine::{EngineBuilder, MatchOutcome, MatcherEngine};
@@ -1,99 +1,641 @@
 use anyhow::{Context, Result, bail};
-use std::env;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
-mod enumeration;
-mod matching;
-mod runtime;
-
-enum RunMode {
-    Enumerate {
-        input: PathBuf,
-        snapshot_out: PathBuf,
-    },
-    Resume {
-        snapshot: PathBuf,
-    },
-}
+mod check;
+mod clean;
+mod cli;
+mod compare;
+mod results_db;
+mod tune;
+mod watch;
+mod why;
 
-fn usage() -> ! {
-    eprintln!(
-        "usage: matcher <inputs.npz> [snapshot_out.npz]\n       matcher --resume <snapshot.npz>"
-    );
-    std::process::exit(1);
-}
+use matcher::matching::report::{exact_omega, fmt_value};
+use matcher::{chaos, enumeration, hugepages, human, matching, runtime, settings, telemetry};
+
+use cli::{Cli, RunMode};
 
-fn default_snapshot_path(input: &Path) -> PathBuf {
-    let parent = input
-        .parent()
-        .filter(|p| !p.as_os_str().is_empty())
-        .unwrap_or_else(|| Path::new("."));
-    let stem = input
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("snapshot");
-    parent.join(format!("{stem}_snapshot.npz"))
+#[global_allocator]
+static GLOBAL: hugepages::HugePageAlloc = hugepages::HugePageAlloc;
+
+/// Exit status when the input or snapshot holds nothing to enumerate or match.
+const EXIT_EMPTY: i32 = 2;
+/// Exit status of `matcher compare` when the result sets disagree, and of
+/// `matcher check` when a fixture drifts or fails.
+const EXIT_MISMATCH: i32 = 3;
+/// Exit status when an input or snapshot is malformed (`SchemaError`).
+const EXIT_SCHEMA: i32 = 4;
+/// Exit status after Ctrl-C stopped a run (128 + SIGINT, as a shell reports it).
+const EXIT_CANCELLED: i32 = 130;
+/// Exit status when `--column-block` checkpointed with roots left (EX_TEMPFAIL:
+/// run again with `--resume-enumeration` to continue).
+const EXIT_BLOCK_DONE: i32 = 75;
+
+fn main() -> Result<()> {
+    let res = run();
+    hugepages::report();
+    enumeration::spill::report();
+    telemetry::finish(res.as_ref().err());
+    match res {
+        Err(err) if err.downcast_ref::<matching::EmptyDataError>().is_some() => {
+            eprintln!("[empty] {err:#}");
+            std::process::exit(EXIT_EMPTY);
+        }
+        Err(err) if err.downcast_ref::<matching::SchemaError>().is_some() => {
+            eprintln!("[schema] {err:#}");
+            std::process::exit(EXIT_SCHEMA);
+        }
+        Err(err) if err.downcast_ref::<runtime::Cancelled>().is_some() => {
+            eprintln!("[cancel] {err:#}");
+            std::process::exit(EXIT_CANCELLED);
+        }
+        Err(err) if err.downcast_ref::<enumeration::BlockDone>().is_some() => {
+            eprintln!("[block] {err:#}");
+            std::process::exit(EXIT_BLOCK_DONE);
+        }
+        other => other,
+    }
 }
 
-fn parse_args() -> Result<RunMode> {
-    let mut args = env::args().skip(1);
-    let first = args.next().unwrap_or_else(|| usage());
-    if first == "--resume" {
-        let snap = args.next().unwrap_or_else(|| usage());
-        return Ok(RunMode::Resume {
-            snapshot: PathBuf::from(snap),
-        });
+fn run() -> Result<()> {
+    let args = cli::Args::parse();
+    let (config, sets) = args.settings_sources();
+    if let Some(path) = config {
+        runtime::apply_config_file(path)?;
     }
+    for spec in sets {
+        settings::apply_set_flag(spec)?;
+    }
+    settings::init()?;
+    runtime::configure_thread_pool();
+
+    let Cli {
+        mode,
+        require_done,
+        input_wait,
+        pairs_file,
+        results_db,
+        results_json,
+        csv_out,
+        cooccurrence_out,
+        plans,
+        force_enumerate,
+        checkpointing,
+        skip_match,
+        hugepages,
+        tmpdir,
+        max_disk_bytes,
+        resume_results,
+        shard_history,
+        mut match_cfg,
+    } = args.into_cli()?;
 
-    let input = PathBuf::from(first);
-    if !input.exists() {
-        bail!("input {:?} does not exist", input);
+    if hugepages {
+        hugepages::enable(settings::get().hugepage_min_mb << 20);
+    }
+    if settings::get().alloc_stats {
+        hugepages::count_allocations();
+    }
+    enumeration::spill::configure(tmpdir.as_deref(), max_disk_bytes)?;
+    // watch mode keeps the default SIGINT: a cancelled input would land in failed/
+    if matches!(mode, RunMode::Enumerate { .. } | RunMode::Resume { .. }) {
+        match_cfg.cancel.cancel_on_sigint();
+    }
+    // a batch scheduler's SIGTERM then leaves a checkpoint to resume from
+    if checkpointing.path.is_some() {
+        match_cfg.cancel.cancel_on_sigterm();
+    }
+    let host = runtime::HostInfo::detect();
+    if matches!(
+        mode,
+        RunMode::Enumerate { .. } | RunMode::Resume { .. } | RunMode::Watch { .. }
+    ) {
+        host.log();
+    }
+    telemetry::set_resource(&host);
+    if let Some(shard) = match_cfg.shard {
+        telemetry::run_attr("matcher.shard", shard.to_string());
     }
 
-    let snapshot_out = if let Some(explicit) = args.next() {
-        PathBuf::from(explicit)
-    } else if let Ok(from_env) = env::var("ENUM_SNAPSHOT_PATH") {
-        PathBuf::from(from_env)
-    } else {
-        default_snapshot_path(&input)
-    };
+    let mut db = results_db
+        .as_deref()
+        .map(results_db::ResultsDb::open)
+        .transpose()?;
 
-    Ok(RunMode::Enumerate {
-        input,
-        snapshot_out,
-    })
-}
+    if let Some(path) = &pairs_file {
+        let pairs = matching::load_pair_whitelist(&path.to_string_lossy())?;
+        eprintln!(
+            "[pairs] loaded {} pairs from {}",
+            pairs.len(),
+            path.display()
+        );
+        match_cfg.pair_whitelist = Some(pairs);
+    }
 
-fn main() -> Result<()> {
-    runtime::configure_thread_pool();
+    if let Some(path) = &shard_history {
+        let pairs = matching::load_results_json(&path.to_string_lossy())?;
+        let seconds: BTreeMap<matching::PairId, f64> = pairs
+            .iter()
+            .filter(|r| r.t_total > 0.0)
+            .map(|r| (matching::pair_id(r), r.t_total))
+            .collect();
+        eprintln!(
+            "[shard] {} recorded pair times from {}",
+            seconds.len(),
+            path.display()
+        );
+        match_cfg.pair_seconds = Some(seconds);
+    }
 
-    let mode = parse_args()?;
-    let snapshot = match &mode {
+    let (snapshot, source, t_enumerate, run_kind) = match &mode {
+        RunMode::History { run_id, limit } => {
+            let db = db.as_ref().context("history needs --results-db")?;
+            return match run_id {
+                Some(id) => db.print_run(*id),
+                None => db.print_history(*limit),
+            };
+        }
+        RunMode::Compare {
+            a,
+            b,
+            rel_tol,
+            abs_tol,
+        } => {
+            let same = compare::compare_results(
+                &a.to_string_lossy(),
+                &b.to_string_lossy(),
+                *rel_tol,
+                *abs_tol,
+            )?;
+            if !same {
+                std::process::exit(EXIT_MISMATCH);
+            }
+            return Ok(());
+        }
+        RunMode::Why { a, b } => {
+            return why::why(&a.to_string_lossy(), &b.to_string_lossy());
+        }
+        RunMode::Check {
+            baseline,
+            tol,
+            bless,
+        } => {
+            if !check::check(baseline, *tol, *bless)? {
+                std::process::exit(EXIT_MISMATCH);
+            }
+            return Ok(());
+        }
+        RunMode::Merge { inputs } => {
+            let paths: Vec<&Path> = inputs.iter().map(PathBuf::as_path).collect();
+            return matching::checkpoint::merge(&paths, results_json.as_deref());
+        }
+        RunMode::Estimate { input } => {
+            watch::wait_for_input(input, require_done, input_wait)?;
+            let est = enumeration::estimate::estimate_from_npz(&input.to_string_lossy())?;
+            est.print();
+            return Ok(());
+        }
+        RunMode::Tune {
+            input,
+            slice,
+            config_out,
+        } => {
+            watch::wait_for_input(input, require_done, input_wait)?;
+            tune::tune(input, *slice, config_out)?;
+            return Ok(());
+        }
+        RunMode::Scavenge { spill } => {
+            let found = enumeration::spill::scavenge(spill)?;
+            println!(
+                "{}: {} intact records, {} bytes kept, {} torn bytes dropped",
+                spill.display(),
+                found.records,
+                found.bytes,
+                found.torn
+            );
+            return Ok(());
+        }
+        RunMode::Clean { dirs, dry_run } => {
+            let dirs = clean::dirs_to_scan(dirs.clone());
+            let (files, bytes) = clean::clean(&dirs, *dry_run)?;
+            eprintln!(
+                "[clean] {} leftover files, {}{}",
+                files,
+                human::Bytes(bytes),
+                if *dry_run {
+                    " (dry run: nothing removed)"
+                } else {
+                    " removed"
+                }
+            );
+            return Ok(());
+        }
+        RunMode::VerifySnapshot { snapshot } => {
+            return matching::verify::verify_snapshot(&snapshot.to_string_lossy());
+        }
+        RunMode::PackView { snapshot, out } => {
+            return matching::view::pack_view(&snapshot.to_string_lossy(), out);
+        }
+        RunMode::Export {
+            snapshot,
+            format,
+            dir,
+        } => {
+            return matching::export::export(&snapshot.to_string_lossy(), *format, dir);
+        }
+        RunMode::Reweight { snapshot, weights } => {
+            matching::reweight::reweight_snapshot(
+                &snapshot.to_string_lossy(),
+                &weights.to_string_lossy(),
+            )?;
+            return Ok(());
+        }
+        RunMode::PlanShards { snapshot, count } => {
+            let snap = matching::load_snapshot(&snapshot.to_string_lossy())?;
+            matching::shard::print_plan(&snap, &match_cfg, *count);
+            return Ok(());
+        }
+        RunMode::SavePlans { snapshot, out } => {
+            let snap = matching::load_snapshot(&snapshot.to_string_lossy())?;
+            matching::plans::build_plans(&snap, &match_cfg).save(out)?;
+            eprintln!("[plans] written to {}", out.display());
+            return Ok(());
+        }
         RunMode::Resume { snapshot } => {
+            if skip_match {
+                bail!("--skip-match with --resume leaves nothing to do");
+            }
             eprintln!("[resume] loading snapshot from {}", snapshot.display());
             let snap_path = snapshot.to_string_lossy().into_owned();
-            matching::load_snapshot(&snap_path)?
+            (
+                matching::load_snapshot(&snap_path)?,
+                snapshot,
+                None,
+                "resume",
+            )
         }
         RunMode::Enumerate {
             input,
+            parts,
             snapshot_out,
         } => {
-            eprintln!("[enumerate] reading inputs from {}", input.display());
-            let input_path = input.to_string_lossy().into_owned();
-            let snap = enumeration::enumerate_to_snapshot_from_npz(&input_path)?;
-            if let Some(parent) = snapshot_out.parent() {
-                if !parent.as_os_str().is_empty() {
-                    std::fs::create_dir_all(parent)
-                        .with_context(|| format!("create dir {}", parent.display()))?;
+            let all_inputs: Vec<&Path> = std::iter::once(input.as_path())
+                .chain(parts.iter().map(PathBuf::as_path))
+                .collect();
+            if !force_enumerate
+                && checkpointing.resume.is_none()
+                && snapshot_is_fresh(&all_inputs, snapshot_out)
+            {
+                eprintln!(
+                    "[pipeline] {} is up to date with {}; skipping enumeration (--force-enumerate to redo)",
+                    snapshot_out.display(),
+                    input.display()
+                );
+                if skip_match {
+                    return Ok(());
                 }
+                let snap_path = snapshot_out.to_string_lossy().into_owned();
+                (matching::load_snapshot(&snap_path)?, input, None, "cached")
+            } else {
+                for part in &all_inputs {
+                    watch::wait_for_input(part, require_done, input_wait)?;
+                }
+                let t0 = Instant::now();
+                let snap = enumerate_and_cache(
+                    &all_inputs,
+                    snapshot_out,
+                    &checkpointing,
+                    &match_cfg.cancel,
+                    !skip_match,
+                )?;
+                if skip_match {
+                    eprintln!(
+                        "[pipeline] enumeration only; match later with: matcher match {}",
+                        snapshot_out.display()
+                    );
+                    return Ok(());
+                }
+                (snap, input, Some(t0.elapsed().as_secs_f64()), "enumerate")
+            }
+        }
+        RunMode::Watch { dir, out, poll } => {
+            if plans.is_some() {
+                bail!("--plans does not apply to watch: every input has its own rows");
             }
-            let snapshot_path = snapshot_out.to_string_lossy().into_owned();
-            matching::save_snapshot(&snapshot_path, &snap)?;
-            eprintln!("[enumerate] snapshot cached at {}", snapshot_out.display());
-            snap
+            return watch::watch_dir(dir, out, *poll, require_done, |input| {
+                let stem = input
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("input");
+                let t0 = Instant::now();
+                let snap = enumerate_and_cache(
+                    &[input],
+                    &out.join(format!("{stem}_snapshot.npz")),
+                    &Default::default(),
+                    &match_cfg.cancel,
+                    !skip_match,
+                )?;
+                let t_enumerate = t0.elapsed().as_secs_f64();
+                if skip_match {
+                    return Ok(());
+                }
+                if telemetry::enabled() {
+                    telemetry::run_attr(
+                        "matcher.config_sha256",
+                        matching::checkpoint::config_sha256(&snap, &match_cfg),
+                    );
+                }
+                matching::check_given(&snap, &match_cfg)?;
+                let (results, wall) = matching::run_all_pairs_parallel(&snap, &match_cfg);
+                if let Some(db) = db.as_mut() {
+                    db.record_run(&results_db::RunRecord {
+                        mode: "watch",
+                        input,
+                        cfg: &match_cfg,
+                        t_enumerate: Some(t_enumerate),
+                        t_match: wall,
+                        pairs: &results,
+                    })?;
+                }
+                let omega = matching::omega_sum(&results);
+                let json_path = out.join(format!("{stem}_results.json"));
+                matching::save_results_json(
+                    &json_path.to_string_lossy(),
+                    &results,
+                    wall,
+                    &why::run_info(&match_cfg, &host),
+                    &matching::checkpoint::Checkpoint::new(&snap, &match_cfg),
+                )?;
+                let omega_path = out.join(format!("{stem}_omega.txt"));
+                std::fs::write(
+                    &omega_path,
+                    format!(
+                        "Omega total: {} (pairs={}, wall={:.3}s)\n",
+                        fmt_value(omega, exact_omega(&results).as_ref()),
+                        results.len(),
+                        wall
+                    ),
+                )
+                .with_context(|| format!("write {}", omega_path.display()))?;
+                eprintln!("[watch] Omega written to {}", omega_path.display());
+                Ok(())
+            });
         }
     };
 
-    let _ = matching::run_all_pairs_parallel(&snapshot, true);
+    if telemetry::enabled() {
+        telemetry::run_attr(
+            "matcher.config_sha256",
+            matching::checkpoint::config_sha256(&snapshot, &match_cfg),
+        );
+    }
+    matching::check_given(&snapshot, &match_cfg)?;
+    if let Some(path) = &plans {
+        let set = matching::plans::PlanSet::load(path, &snapshot, match_cfg.complement)?;
+        eprintln!(
+            "[plans] loaded {} pair plans from {}",
+            set.pairs.len(),
+            path.display()
+        );
+        match_cfg.plans = Some(Arc::new(set));
+    }
+    let resumed = match &resume_results {
+        Some(path) => resume_partial(path, &snapshot, &mut match_cfg)?,
+        None => Vec::new(),
+    };
+    let (mut results, wall) = matching::run_all_pairs_parallel(&snapshot, &match_cfg);
+    let cancelled = match_cfg.cancel.is_cancelled();
+    if !resumed.is_empty() {
+        let n_new = results.len();
+        results.splice(0..0, resumed);
+        eprintln!(
+            "[resume] Omega with the resumed pairs: {} ({} resumed + {} new pairs)",
+            fmt_value(
+                matching::omega_sum(&results),
+                exact_omega(&results).as_ref()
+            ),
+            results.len() - n_new,
+            n_new
+        );
+    }
+    if let Some(t) = &snapshot.truncated {
+        eprintln!("[warn] Omega above is partial: snapshot {}", t);
+    }
+    if let Some(path) = &results_json {
+        matching::save_results_json(
+            &path.to_string_lossy(),
+            &results,
+            wall,
+            &why::run_info(&match_cfg, &host),
+            &matching::checkpoint::Checkpoint::new(&snapshot, &match_cfg),
+        )?;
+        eprintln!("[results] written to {}", path.display());
+    }
+    if let Some(path) = &csv_out {
+        matching::report::save_results_csv(path, &results, wall, match_cfg.normalize)?;
+        eprintln!("[results] CSV written to {}", path.display());
+    }
+    if let Some(path) = &cooccurrence_out
+        && !cancelled
+    {
+        matching::cooccurrence::cooccurrence(&snapshot, &match_cfg).save_npz(path)?;
+        eprintln!("[cooc] matrix written to {}", path.display());
+    }
+    if let Some(db) = db.as_mut() {
+        db.record_run(&results_db::RunRecord {
+            mode: if cancelled { "cancelled" } else { run_kind },
+            input: source,
+            cfg: &match_cfg,
+            t_enumerate,
+            t_match: wall,
+            pairs: &results,
+        })?;
+    }
+    if cancelled {
+        let hint = results_json.as_ref().map_or(String::new(), |p| {
+            format!("; finish with --resume-results {}", p.display())
+        });
+        return Err(runtime::Cancelled(format!(
+            "matching interrupted; results above cover the {} pairs that finished{}",
+            results.len(),
+            hint
+        ))
+        .into());
+    }
+    // the finished run's --results-json holds every resumed pair, so the
+    // partial file is folded into it
+    if let (Some(partial), Some(path)) = (&resume_results, &results_json)
+        && !same_file(partial, path)
+    {
+        std::fs::remove_file(partial).with_context(|| format!("remove {}", partial.display()))?;
+        eprintln!(
+            "[resume] {} folded into {}; removed",
+            partial.display(),
+            path.display()
+        );
+    }
     Ok(())
 }
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Load the partial results at `path` for `--resume-results`, check they come
+/// from this snapshot and configuration, and mark their pairs done in `cfg`.
+fn resume_partial(
+    path: &Path,
+    snapshot: &matching::types::Snapshot,
+    cfg: &mut matching::MatchConfig,
+) -> Result<Vec<matching::PairResult>> {
+    let part = matching::checkpoint::load_partial(&path.to_string_lossy())?;
+    let current = matching::checkpoint::Checkpoint::new(snapshot, cfg);
+    if part.checkpoint.config_sha256 != current.config_sha256 {
+        bail!(
+            "{} comes from a different snapshot or configuration (config_sha256 {} vs {})",
+            path.display(),
+            part.checkpoint.config_sha256,
+            current.config_sha256
+        );
+    }
+    if part.checkpoint.shard != current.shard {
+        let shard = |s: Option<matching::Shard>| {
+            s.map_or("the whole run".to_string(), |s| format!("shard {}", s))
+        };
+        bail!(
+            "{} covers {}, this run {}; resume with the same --shard",
+            path.display(),
+            shard(part.checkpoint.shard),
+            shard(current.shard)
+        );
+    }
+    if part.checkpoint.shard_history_sha256 != current.shard_history_sha256 {
+        bail!(
+            "{} was balanced with other --shard-history times, so its shard holds other pairs; resume with the same --shard-history",
+            path.display()
+        );
+    }
+    // pairs skipped as too large are solved again, under this run's limits
+    let done: Vec<matching::PairResult> = part
+        .pairs
+        .into_iter()
+        .filter(|r| r.skipped.is_none())
+        .collect();
+    cfg.skip_pairs = done
+        .iter()
+        .map(|r| (r.key_left.clone(), r.key_right.clone()))
+        .collect();
+    Ok(done)
+}
+
+/// A snapshot can stand in for enumeration when it exists, was written after
+/// every input was last modified, is complete (not cut short by a cancelled
+/// save), and records the fingerprint that enumerating `inputs` under the
+/// current settings (`ENUM_FIRST_LIMIT`, `ENUM_MIN_WEIGHT`,
+/// `ENUM_WEIGHT_BITS`, ...) would give it. A snapshot without one is stale.
+fn snapshot_is_fresh(inputs: &[&Path], snapshot: &Path) -> bool {
+    let mtime = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    let Some(t_snap) = mtime(snapshot) else {
+        return false;
+    };
+    let snap_path = snapshot.to_string_lossy();
+    if !inputs
+        .iter()
+        .all(|input| mtime(input).is_some_and(|t_in| t_snap >= t_in))
+        || matching::snapshot_is_truncated(&snap_path)
+    {
+        return false;
+    }
+    let paths: Vec<String> = inputs
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    // an input that fails to load is reported by the enumeration that follows
+    let Ok(expected) = enumeration::snapshot_fingerprint_for(&paths) else {
+        return false;
+    };
+    if matching::snapshot_fingerprint(&snap_path).as_deref() != Some(expected.as_str()) {
+        eprintln!(
+            "[pipeline] {} was built from other inputs or settings; enumerating again",
+            snapshot.display()
+        );
+        return false;
+    }
+    true
+}
+
+/// Enumerate `inputs` and save the snapshot at `snapshot_out`, each bucket
+/// written as soon as it is built. The buckets are also returned when `keep`
+/// (to match them next); otherwise the snapshot returned has none.
+fn enumerate_and_cache(
+    inputs: &[&Path],
+    snapshot_out: &Path,
+    checkpointing: &enumeration::Checkpointing,
+    cancel: &runtime::CancelToken,
+    keep: bool,
+) -> Result<matching::types::Snapshot> {
+    let paths: Vec<String> = inputs
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    eprintln!("[enumerate] reading inputs from {}", paths.join(", "));
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    if let Some(parent) = snapshot_out.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create dir {}", parent.display()))?;
+    }
+    let snapshot_path = snapshot_out.to_string_lossy().into_owned();
+    if matching::zst::is_zst(&snapshot_path) {
+        // the binary codec writes its header from the whole snapshot
+        let mut snap = enumeration::enumerate_to_snapshot_from_npzs(&paths, checkpointing, cancel)?;
+        let width = settings::get().index_width;
+        matching::save_snapshot(&snapshot_path, &snap, width, cancel)?;
+        if !keep {
+            snap.buckets = Vec::new();
+        }
+        return cached(snap, snapshot_out, checkpointing);
+    }
+    let mut writer = matching::SnapshotWriter::create(&snapshot_path, settings::get().index_width)?;
+    let mut kept = Vec::new();
+    let mut total = 0;
+    let mut snap =
+        enumeration::enumerate_npzs_into(&paths, checkpointing, cancel, &mut |bucket| {
+            total += 1;
+            // once cancelled, the buckets saved so far stay a loadable prefix
+            if writer.buckets() + 1 == total && !cancel.is_cancelled() {
+                writer.add_bucket(&bucket)?;
+            }
+            if keep {
+                kept.push(bucket);
+            }
+            Ok(())
+        })?;
+    writer.finish(&snap, total)?;
+    snap.buckets = kept;
+    cached(snap, snapshot_out, checkpointing)
+}
+
+/// Log the cached snapshot and remove the checkpoint it supersedes.
+fn cached(
+    snap: matching::types::Snapshot,
+    snapshot_out: &Path,
+    checkpointing: &enumeration::Checkpointing,
+) -> Result<matching::types::Snapshot> {
+    eprintln!("[enumerate] snapshot cached at {}", snapshot_out.display());
+    // the snapshot supersedes the checkpoint
+    if let Some(path) = &checkpointing.path {
+        for removed in enumeration::checkpoint::remove(path)? {
+            eprintln!("[checkpoint] removed {}", removed.display());
+        }
+    }
+    Ok(snap)
+}
@@ -0,0 +1,272 @@
+//! Snapshots as Arrow record batches (feature "arrow"): `Snapshot::to_arrow`
+//! and `Snapshot::from_arrow` for handing a snapshot to Arrow tools in memory,
+//! and Arrow IPC files (`matcher export --format arrow`) that pyarrow maps
+//! without copying and `load_snapshot` reads back.
+//!
+//! One batch per bucket, in bucket order, with the columns `bucket_id` (u32),
+//! `jbts` (LargeList<i32>: the bucket's CSR, `indptr` as the list offsets and
+//! `rows_data` as the values) and `weight` (f64). The schema metadata
+//! `cjpt.snapshot` holds the rest as JSON, laid out like a view header: N,
+//! `jbt_ref_pop`, the compat table, the labels and, per bucket, its key, row
+//! and entry counts and exact weights.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Leading bytes of an Arrow IPC file.
+const IPC_MAGIC: &[u8; 6] = b"ARROW1";
+
+/// Whether the open file `f` is an Arrow IPC file, leaving it rewound.
+pub(super) fn starts_as_ipc(f: &mut File) -> std::io::Result<bool> {
+    let mut head = Vec::with_capacity(IPC_MAGIC.len());
+    Read::take(&mut *f, IPC_MAGIC.len() as u64).read_to_end(&mut head)?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok(head == IPC_MAGIC)
+}
+
+#[cfg(feature = "arrow")]
+pub use batches::METADATA_KEY;
+pub(super) use batches::{read_ipc, write_ipc};
+
+/// Without the "arrow" feature there are no batches; files are refused.
+#[cfg(not(feature = "arrow"))]
+mod batches {
+    use anyhow::{Result, bail};
+    use std::fs::File;
+    use std::path::Path;
+
+    use super::super::types::Snapshot;
+
+    pub(in super::super) fn read_ipc(_f: File, path: &str) -> Result<Snapshot> {
+        bail!(
+            "{} is an Arrow IPC file, and this matcher was built without the \"arrow\" feature",
+            path
+        )
+    }
+
+    pub(in super::super) fn write_ipc(_snap: &Snapshot, _out: &Path) -> Result<()> {
+        bail!(
+            "this matcher was built without the \"arrow\" feature; rebuild with --features arrow to export Arrow"
+        )
+    }
+}
+
+#[cfg(feature = "arrow")]
+mod batches {
+    use anyhow::{Context, Result, bail};
+    use arrow_array::{Array, Float64Array, Int32Array, LargeListArray, RecordBatch, UInt32Array};
+    use arrow_buffer::OffsetBuffer;
+    use arrow_ipc::reader::FileReader;
+    use arrow_ipc::writer::FileWriter;
+    use arrow_schema::{DataType, Field, Schema, SchemaRef};
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::super::io::{check_bucket, replace_file, temp_beside, warn_snapshot_labels};
+    use super::super::types::{Bucket, Indptr, SchemaError, Snapshot};
+    use super::super::view::{exact_weights, header, snapshot_meta};
+
+    /// Schema metadata key of the snapshot header.
+    pub const METADATA_KEY: &str = "cjpt.snapshot";
+
+    fn item() -> Arc<Field> {
+        Arc::new(Field::new("item", DataType::Int32, false))
+    }
+
+    fn bucket_batch(schema: &SchemaRef, b: usize, bucket: &Bucket) -> Result<RecordBatch> {
+        let mut offsets = bucket.indptr.to_i64();
+        if offsets.is_empty() {
+            offsets.push(0);
+        }
+        let jbts = LargeListArray::try_new(
+            item(),
+            OffsetBuffer::new(offsets.into()),
+            Arc::new(Int32Array::from(bucket.rows_data.to_vec())),
+            None,
+        )?;
+        Ok(RecordBatch::try_new(
+            Arc::clone(schema),
+            vec![
+                Arc::new(UInt32Array::from(vec![b as u32; bucket.n_rows()])),
+                Arc::new(jbts),
+                Arc::new(Float64Array::from(bucket.weights.to_vec())),
+            ],
+        )?)
+    }
+
+    /// The batches' schema, with the header of `snap` as its metadata.
+    fn snapshot_schema(snap: &Snapshot) -> Result<SchemaRef> {
+        let entries: Vec<Value> = snap
+            .buckets
+            .iter()
+            .map(|b| {
+                let exact: Vec<Value> = b
+                    .exact
+                    .iter()
+                    .map(|(r, x)| json!([r, x.to_string()]))
+                    .collect();
+                json!({
+                    "key": b.key,
+                    "rows": b.n_rows(),
+                    "entries": b.rows_data.len(),
+                    "exact": exact,
+                })
+            })
+            .collect();
+        let text = serde_json::to_string(&header(snap, entries))?;
+        Ok(Arc::new(Schema::new_with_metadata(
+            vec![
+                Field::new("bucket_id", DataType::UInt32, false),
+                Field::new("jbts", DataType::LargeList(item()), false),
+                Field::new("weight", DataType::Float64, false),
+            ],
+            HashMap::from([(METADATA_KEY.to_string(), text)]),
+        )))
+    }
+
+    impl Snapshot {
+        /// The snapshot as one record batch per bucket, with the schema that
+        /// carries the header (see the module docs). The arrays are copies;
+        /// the snapshot is left as it was.
+        pub fn to_arrow(&self) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+            let schema = snapshot_schema(self)?;
+            let batches = self
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(b, bucket)| bucket_batch(&schema, b, bucket))
+                .collect::<Result<_>>()?;
+            Ok((schema, batches))
+        }
+
+        /// The snapshot `to_arrow` made into `schema` and `batches`, checked
+        /// as `load_snapshot` checks a file; `label` names it in messages.
+        /// Stats are not carried and come back `None`.
+        pub fn from_arrow(
+            schema: &Schema,
+            batches: &[RecordBatch],
+            label: &str,
+        ) -> Result<Snapshot> {
+            let place = &format!("arrow {}", label);
+            let bad = |what: String| -> anyhow::Error {
+                SchemaError(format!("{}: {}", place, what)).into()
+            };
+            let text = schema
+                .metadata()
+                .get(METADATA_KEY)
+                .ok_or_else(|| bad(format!("schema has no {:?} metadata", METADATA_KEY)))?;
+            let head: Value =
+                serde_json::from_str(text).map_err(|e| bad(format!("header: {}", e)))?;
+            let mut snap = snapshot_meta(&head, place)?;
+            let entries = head
+                .get("buckets")
+                .and_then(Value::as_array)
+                .ok_or_else(|| bad("header has no bucket list".into()))?;
+            if entries.len() != batches.len() {
+                return Err(bad(format!(
+                    "header lists {} buckets, {} batches given",
+                    entries.len(),
+                    batches.len()
+                )));
+            }
+            for (b, (e, batch)) in entries.iter().zip(batches).enumerate() {
+                let column = |name: &str| {
+                    batch
+                        .column_by_name(name)
+                        .ok_or_else(|| bad(format!("batch {} has no {:?} column", b, name)))
+                };
+                let (ids, jbts, weights) =
+                    (column("bucket_id")?, column("jbts")?, column("weight")?);
+                let ids = ids.as_any().downcast_ref::<UInt32Array>();
+                let jbts = jbts.as_any().downcast_ref::<LargeListArray>();
+                let weights = weights.as_any().downcast_ref::<Float64Array>();
+                let (Some(ids), Some(jbts), Some(weights)) = (ids, jbts, weights) else {
+                    return Err(bad(format!(
+                        "batch {}: column types differ from to_arrow's",
+                        b
+                    )));
+                };
+                if ids.iter().any(|id| id != Some(b as u32)) {
+                    return Err(bad(format!("batch {} holds rows of another bucket", b)));
+                }
+                let values = jbts
+                    .values()
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .ok_or_else(|| bad(format!("batch {}: jbts values are not i32", b)))?;
+                if jbts.null_count() + values.null_count() + weights.null_count() > 0 {
+                    return Err(bad(format!("batch {} has nulls", b)));
+                }
+                // a sliced batch starts its offsets past 0; rebase them
+                let offsets = jbts.value_offsets();
+                let (lo, hi) = (offsets[0] as usize, offsets[offsets.len() - 1] as usize);
+                let bucket = Bucket {
+                    rows_data: values.values()[lo..hi].to_vec().into(),
+                    indptr: Indptr::from_i64(offsets.iter().map(|&o| o - lo as i64).collect()),
+                    weights: weights.values().to_vec().into(),
+                    exact: exact_weights(e, b, place)?,
+                    key: e
+                        .get("key")
+                        .and_then(Value::as_array)
+                        .and_then(|k| {
+                            k.iter()
+                                .map(|x| x.as_i64().and_then(|x| i32::try_from(x).ok()))
+                                .collect()
+                        })
+                        .ok_or_else(|| bad(format!("bucket {}: key is not a list of i32", b)))?,
+                };
+                check_bucket(&bucket, b, snap.jbt_ref_pop.len()).context(place.clone())?;
+                if let Some((r, w)) = bucket.first_non_finite_weight() {
+                    bail!(
+                        "{}: bucket {} {:?} row {} has weight {}; weights must be finite",
+                        place,
+                        b,
+                        bucket.key,
+                        r,
+                        w
+                    );
+                }
+                snap.buckets.push(bucket);
+            }
+            warn_snapshot_labels(label, &snap);
+            Ok(snap)
+        }
+    }
+
+    /// Read the Arrow IPC file `f` (`write_ipc`'s) as a snapshot.
+    pub(in super::super) fn read_ipc(f: File, path: &str) -> Result<Snapshot> {
+        let reader = FileReader::try_new(f, None).with_context(|| format!("read {}", path))?;
+        let schema = reader.schema();
+        let batches = reader
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("read {}", path))?;
+        Snapshot::from_arrow(&schema, &batches, path)
+    }
+
+    /// Write `snap` to `out` as an Arrow IPC file, one batch at a time. It is
+    /// written beside `out` and renamed over it when complete.
+    pub(in super::super) fn write_ipc(snap: &Snapshot, out: &Path) -> Result<()> {
+        let schema = snapshot_schema(snap)?;
+        let tmp = temp_beside(out);
+        let file = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+        let res = (|| -> Result<()> {
+            let mut w = FileWriter::try_new(BufWriter::new(file), &schema)?;
+            for (b, bucket) in snap.buckets.iter().enumerate() {
+                w.write(&bucket_batch(&schema, b, bucket)?)?;
+            }
+            w.finish()?;
+            let file = w.into_inner()?.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            replace_file(&tmp, out)?;
+            Ok(())
+        })();
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        res.with_context(|| format!("write {}", out.display()))
+    }
+}
@@ -0,0 +1,248 @@
+//! Partial results shared by every way of splitting a matching run. A sharded
+//! run (`--shard k/n`), an interrupted one and a resumed one
+//! (`--resume-results`) all write the same container: the `--results-json`
+//! file, whose `checkpoint` object holds a hash of everything that decides the
+//! planned pairs and their subtotals, the number of planned pairs, and the
+//! shard with the hash of any recorded times it was balanced with. Its `pairs`
+//! array holds the finished pairs' keys and subtotals, and the pairs skipped as
+//! too large, which count as not done.
+//! `matcher merge` reduces any set of such files whose hashes agree.
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::config::{MatchConfig, Shard, complement_overrides};
+use super::driver::{PairResult, omega_sum, planned_pair_count};
+use super::io::{load_results_json, save_results_json};
+use super::report::{exact_omega, fmt_value};
+use super::shard::history_sha256;
+use super::types::Snapshot;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub config_sha256: String,
+    pub pairs_planned: usize,
+    pub shard: Option<Shard>,
+    /// `shard::history_sha256` of a sharded run's `--shard-history`.
+    pub shard_history_sha256: Option<String>,
+}
+
+impl Checkpoint {
+    pub fn new(snap: &Snapshot, cfg: &MatchConfig) -> Self {
+        Checkpoint {
+            config_sha256: config_sha256(snap, cfg),
+            pairs_planned: planned_pair_count(snap, cfg),
+            shard: cfg.shard,
+            shard_history_sha256: cfg.shard.and_then(|_| history_sha256(cfg)),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "config_sha256": self.config_sha256,
+            "pairs_planned": self.pairs_planned,
+            "shard": self.shard.map(|s| s.to_string()),
+            "shard_history_sha256": self.shard_history_sha256,
+        })
+    }
+
+    fn from_json(v: &Value) -> Option<Self> {
+        Some(Checkpoint {
+            config_sha256: v.get("config_sha256")?.as_str()?.to_string(),
+            pairs_planned: v.get("pairs_planned")?.as_u64()? as usize,
+            shard: match v.get("shard")? {
+                Value::Null => None,
+                s => Some(s.as_str()?.parse().ok()?),
+            },
+            shard_history_sha256: v
+                .get("shard_history_sha256")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        })
+    }
+}
+
+/// Hash of what decides the planned pairs and each subtotal: the snapshot
+/// (N, M, and every bucket's key, row count and weight sum), the neutral mode,
+/// the pair whitelist, `--given` and a non-default complement rule. Sharding
+/// and resuming leave it unchanged.
+pub fn config_sha256(snap: &Snapshot, cfg: &MatchConfig) -> String {
+    let mut h = Sha256::new();
+    h.update(format!(
+        "N={};M={};neutral={:?};pairs={:?};",
+        snap.n_total,
+        snap.jbt_ref_pop.len(),
+        cfg.neutral,
+        cfg.pair_whitelist
+    ));
+    if let Some(given) = cfg.given {
+        h.update(format!("given={};", given));
+    }
+    let overrides = complement_overrides(cfg.complement, snap.n_total);
+    if !overrides.is_empty() {
+        h.update(format!("complement={:?};", overrides));
+    }
+    for b in &snap.buckets {
+        let weight: f64 = b.weights.iter().sum();
+        h.update(format!(
+            "{:?}:{}:{:x};",
+            b.key,
+            b.n_rows(),
+            weight.to_bits()
+        ));
+    }
+    format!("{:x}", h.finalize())
+}
+
+/// One results file read back as partial results.
+pub struct Partial {
+    pub checkpoint: Checkpoint,
+    pub pairs: Vec<PairResult>,
+    pub wall: f64,
+}
+
+/// Read a results file as partial results. Files written before checkpoints
+/// were recorded cannot be resumed or merged.
+pub fn load_partial(path: &str) -> Result<Partial> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path))?;
+    let doc: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", path))?;
+    let Some(checkpoint) = doc.get("checkpoint").and_then(Checkpoint::from_json) else {
+        bail!(
+            "{}: no checkpoint recorded (written by an older matcher); it cannot be resumed or merged",
+            path
+        );
+    };
+    Ok(Partial {
+        checkpoint,
+        pairs: load_results_json(path)?,
+        wall: doc.get("wall").and_then(Value::as_f64).unwrap_or(0.0),
+    })
+}
+
+/// `matcher merge a.json b.json ...`: combine partial results from shards and
+/// resumed or interrupted runs of one configuration. A pair present in several
+/// files must have the same subtotal bits in each; one skipped as too large is
+/// taken from a file that solved it, if any. Prints Omega and how many
+/// planned pairs are covered; `out` receives the merged container.
+pub fn merge(paths: &[&Path], out: Option<&Path>) -> Result<()> {
+    let mut first: Option<(String, Checkpoint)> = None;
+    let mut merged: BTreeMap<(Vec<i32>, Vec<i32>), (PairResult, String)> = BTreeMap::new();
+    let mut skipped: BTreeMap<(Vec<i32>, Vec<i32>), PairResult> = BTreeMap::new();
+    let mut shards = Vec::new();
+    let mut histories = std::collections::BTreeSet::new();
+    let mut duplicates = 0;
+    let mut wall = 0.0f64;
+    for path in paths {
+        let path = path.to_string_lossy().into_owned();
+        let Partial {
+            checkpoint: cp,
+            pairs,
+            wall: part_wall,
+        } = load_partial(&path)?;
+        wall = wall.max(part_wall);
+        match &first {
+            None => first = Some((path.clone(), cp.clone())),
+            Some((p0, cp0)) if cp0.config_sha256 != cp.config_sha256 => bail!(
+                "{} and {} come from different configurations (config_sha256 {} vs {})",
+                p0,
+                path,
+                cp0.config_sha256,
+                cp.config_sha256
+            ),
+            Some(_) => {}
+        }
+        shards.push(cp.shard.map_or("all".to_string(), |s| s.to_string()));
+        if cp.shard.is_some() {
+            histories.insert(cp.shard_history_sha256.clone());
+        }
+        for r in pairs {
+            let key = (r.key_left.clone(), r.key_right.clone());
+            if r.skipped.is_some() {
+                skipped.entry(key).or_insert(r);
+                continue;
+            }
+            match merged.get(&key) {
+                Some((prev, prev_path)) => {
+                    if prev.subtotal.to_bits() != r.subtotal.to_bits() {
+                        bail!(
+                            "pair {:?} vs {:?}: {} has subtotal {:e}, {} has {:e}",
+                            key.0,
+                            key.1,
+                            prev_path,
+                            prev.subtotal,
+                            path,
+                            r.subtotal
+                        );
+                    }
+                    duplicates += 1;
+                }
+                None => {
+                    merged.insert(key, (r, path.clone()));
+                }
+            }
+        }
+    }
+    let Some((_, cp)) = first else {
+        bail!("merge needs at least one results file");
+    };
+
+    skipped.retain(|key, _| !merged.contains_key(key));
+    let mut results: Vec<PairResult> = merged.into_values().map(|(r, _)| r).collect();
+    let omega = omega_sum(&results);
+    println!(
+        "merged {} files (shards {}): {} of {} planned pairs, {} duplicates",
+        paths.len(),
+        shards.join(", "),
+        results.len(),
+        cp.pairs_planned,
+        duplicates
+    );
+    let exact = exact_omega(&results);
+    println!(
+        "Omega total: {} (pairs={})",
+        fmt_value(omega, exact.as_ref()),
+        results.len()
+    );
+    if let Some(x) = exact {
+        println!("Omega exact: {}", x);
+    }
+    if results.len() < cp.pairs_planned {
+        eprintln!(
+            "[merge] warn: {} planned pairs missing; Omega is partial",
+            cp.pairs_planned - results.len()
+        );
+        if !skipped.is_empty() {
+            eprintln!(
+                "[merge] warn: {} of them were skipped as too large in every file",
+                skipped.len()
+            );
+        }
+        if histories.len() > 1 {
+            eprintln!(
+                "[merge] warn: the shards were balanced with different --shard-history files, so they may not cover the plan"
+            );
+        }
+    }
+    if let Some(out) = out {
+        let checkpoint = Checkpoint {
+            shard: None,
+            shard_history_sha256: None,
+            ..cp
+        };
+        // keep the skipped pairs on record for a later --resume-results
+        results.extend(skipped.into_values());
+        let sources: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+        save_results_json(
+            &out.to_string_lossy(),
+            &results,
+            wall,
+            &json!({ "merged_from": sources }),
+            &checkpoint,
+        )?;
+        eprintln!("[merge] written to {}", out.display());
+    }
+    Ok(())
+}
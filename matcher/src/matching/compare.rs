@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use super::driver::{PairResult, omega_sum};
+use super::types::key_sorted_vec;
+
+/// Order-independent pair identity: both keys sorted, smaller key first.
+pub type PairId = (Vec<i32>, Vec<i32>);
+
+pub fn pair_id(r: &PairResult) -> PairId {
+    pair_id_of(&r.key_left, &r.key_right)
+}
+
+pub fn pair_id_of(left: &[i32], right: &[i32]) -> PairId {
+    let (a, b) = (key_sorted_vec(left), key_sorted_vec(right));
+    if a <= b { (a, b) } else { (b, a) }
+}
+
+/// A pair present in both sets whose subtotals differ beyond tolerance.
+#[derive(Debug, Clone)]
+pub struct PairDiff {
+    pub pair: PairId,
+    pub a: f64,
+    pub b: f64,
+    pub abs: f64,
+    pub rel: f64,
+}
+
+/// A pair present in only one set. `significant` is false when its subtotal is
+/// within `abs_tol` of zero, i.e. indistinguishable from the pair being absent.
+#[derive(Debug, Clone)]
+pub struct OneSided {
+    pub pair: PairId,
+    pub subtotal: f64,
+    pub significant: bool,
+}
+
+/// Outcome of `results_equivalent`.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Pairs present in both sets.
+    pub aligned: usize,
+    pub diffs: Vec<PairDiff>,
+    pub only_a: Vec<OneSided>,
+    pub only_b: Vec<OneSided>,
+    /// Largest per-pair differences over aligned pairs, within tolerance or not.
+    pub max_abs: f64,
+    pub max_rel: f64,
+    pub omega_a: f64,
+    pub omega_b: f64,
+}
+
+impl Report {
+    /// No aligned pair beyond tolerance and no significant one-sided pair.
+    pub fn is_equivalent(&self) -> bool {
+        self.diffs.is_empty()
+            && self.only_a.iter().all(|p| !p.significant)
+            && self.only_b.iter().all(|p| !p.significant)
+    }
+
+    pub fn omega_delta(&self) -> f64 {
+        self.omega_b - self.omega_a
+    }
+}
+
+/// Compare two result sets pair by pair (keys aligned regardless of left/right
+/// order). Subtotals agree when `|a - b| <= abs_tol + rel_tol * max(|a|, |b|)`;
+/// a pair missing from one set, or skipped as too large there, is compared
+/// against zero using `abs_tol`.
+pub fn results_equivalent(
+    a: &[PairResult],
+    b: &[PairResult],
+    rel_tol: f64,
+    abs_tol: f64,
+) -> Report {
+    let mut aligned: BTreeMap<PairId, (Option<f64>, Option<f64>)> = BTreeMap::new();
+    for r in a.iter().filter(|r| r.skipped.is_none()) {
+        aligned.entry(pair_id(r)).or_default().0 = Some(r.subtotal);
+    }
+    for r in b.iter().filter(|r| r.skipped.is_none()) {
+        aligned.entry(pair_id(r)).or_default().1 = Some(r.subtotal);
+    }
+
+    let mut report = Report {
+        omega_a: omega_sum(a),
+        omega_b: omega_sum(b),
+        ..Default::default()
+    };
+    for (pair, (sa, sb)) in aligned {
+        match (sa, sb) {
+            (Some(x), Some(y)) => {
+                report.aligned += 1;
+                let abs = (x - y).abs();
+                let scale = x.abs().max(y.abs());
+                let rel = if scale > 0.0 { abs / scale } else { 0.0 };
+                report.max_abs = report.max_abs.max(abs);
+                report.max_rel = report.max_rel.max(rel);
+                if abs > abs_tol + rel_tol * scale {
+                    report.diffs.push(PairDiff {
+                        pair,
+                        a: x,
+                        b: y,
+                        abs,
+                        rel,
+                    });
+                }
+            }
+            (Some(s), None) | (None, Some(s)) => {
+                let one = OneSided {
+                    pair,
+                    subtotal: s,
+                    significant: s.abs() > abs_tol,
+                };
+                if sa.is_some() {
+                    report.only_a.push(one);
+                } else {
+                    report.only_b.push(one);
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    report
+}
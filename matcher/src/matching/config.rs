@@ -0,0 +1,222 @@
+use anyhow::bail;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::compare::PairId;
+use super::plans::PlanSet;
+use super::report::ReportFormat;
+use super::types::{compat_key_sorted, key_sorted_vec};
+use crate::runtime::CancelToken;
+
+/// Maps a sorted bucket key (and N) to the sorted key of the bucket it pairs with.
+pub type ComplementFn = fn(&[i32], i32) -> Vec<i32>;
+
+/// Complement rules by name, for `--complement`: `n-minus` (`p -> N - p`, the
+/// default) and `same` (each bucket pairs with its own key).
+pub const COMPLEMENT_RULES: [(&str, ComplementFn); 2] =
+    [("n-minus", compat_key_sorted), ("same", same_key)];
+
+fn same_key(key: &[i32], _n_total: i32) -> Vec<i32> {
+    key_sorted_vec(key)
+}
+
+/// The complement rule named `name` (see `COMPLEMENT_RULES`).
+pub fn complement_rule(name: &str) -> anyhow::Result<ComplementFn> {
+    match COMPLEMENT_RULES.iter().find(|(n, _)| *n == name) {
+        Some(&(_, f)) => Ok(f),
+        None => bail!("unknown complement rule {:?} (expected n-minus|same)", name),
+    }
+}
+
+/// The pop `complement` pairs `pop` with: its image as the one-pop key `[pop]`.
+pub fn partner_pop(complement: ComplementFn, pop: i32, n_total: i32) -> i32 {
+    match complement(&[pop], n_total)[..] {
+        [p] => p,
+        ref key => panic!("complement of key [{}] is {:?}, not one pop", pop, key),
+    }
+}
+
+/// `(pop, partner)` for the pops 1..=N whose partner under `complement`
+/// differs from `N - pop`. Empty for the default rule, so hashes that include
+/// it do not change for runs that keep the default.
+pub fn complement_overrides(complement: ComplementFn, n_total: i32) -> Vec<(i32, i32)> {
+    (1..=n_total)
+        .map(|p| (p, partner_pop(complement, p, n_total)))
+        .filter(|&(p, q)| q != n_total - p)
+        .collect()
+}
+
+/// What to do with the neutral (empty-key) bucket when scheduling pairs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NeutralMode {
+    /// Pair it like any other bucket (s1*s2 fast path).
+    #[default]
+    Pair,
+    /// Drop every pair that involves the neutral bucket.
+    Exclude,
+    /// Only allow neutral x neutral; drop its cross terms with keyed buckets.
+    SelfOnly,
+}
+
+impl FromStr for NeutralMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "pair" => Ok(NeutralMode::Pair),
+            "exclude" => Ok(NeutralMode::Exclude),
+            "self" => Ok(NeutralMode::SelfOnly),
+            other => bail!(
+                "unknown neutral mode {:?} (expected pair|exclude|self)",
+                other
+            ),
+        }
+    }
+}
+
+/// Divisor for the normalized Omega reported next to the raw value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalize {
+    /// Sum over scheduled pairs of factor x total left weight x total right
+    /// weight, i.e. Omega if every row pair were compatible.
+    PairWeights,
+    /// A fixed constant (e.g. a known total count).
+    Constant(f64),
+}
+
+impl FromStr for Normalize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "weights" {
+            return Ok(Normalize::PairWeights);
+        }
+        match s.parse::<f64>() {
+            Ok(c) if c.is_finite() && c != 0.0 => Ok(Normalize::Constant(c)),
+            _ => bail!(
+                "bad normalization {:?} (expected weights or a finite non-zero number)",
+                s
+            ),
+        }
+    }
+}
+
+/// `--shard k/n`: this run solves the k-th (0-based) of n parts of the planned
+/// pairs, balanced by predicted runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parsed = s
+            .split_once('/')
+            .and_then(|(k, n)| Some((k.parse::<usize>().ok()?, n.parse::<usize>().ok()?)));
+        match parsed {
+            Some((index, count)) if index < count => Ok(Shard { index, count }),
+            _ => bail!("bad shard {:?} (expected k/n with 0 <= k < n)", s),
+        }
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// `--given j=x`: count only the matchings that pair jbt `j` of the left state
+/// with jbt `x` of the right one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Given {
+    pub j: i32,
+    pub x: i32,
+}
+
+impl FromStr for Given {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parsed = s
+            .split_once('=')
+            .and_then(|(j, x)| Some((j.trim().parse().ok()?, x.trim().parse().ok()?)));
+        match parsed {
+            Some((j, x)) if j >= 0 && x >= 0 => Ok(Given { j, x }),
+            _ => bail!(
+                "bad condition {:?} (expected j=x with jbt ids j, x >= 0)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Given {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.j, self.x)
+    }
+}
+
+/// Knobs for `run_all_pairs_parallel`. `MatchConfig::default()` reproduces the
+/// automatic compat-key pairing with no console output.
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    pub verbose: bool,
+    /// Explicit (left key, right key) pairs to run; overrides automatic pairing.
+    pub pair_whitelist: Option<Vec<(Vec<i32>, Vec<i32>)>>,
+    /// Key-pairing relation for automatic pairing (default: `p -> N - p`).
+    pub complement: ComplementFn,
+    pub neutral: NeutralMode,
+    /// Report a partial subtotal every this many bucket1 rows of each pair.
+    pub report_every_rows: Option<usize>,
+    /// Pairs not yet started when this is cancelled are skipped, so the run
+    /// returns the pairs finished so far.
+    pub cancel: CancelToken,
+    /// Re-run every pair's chunks in order on one thread and report any
+    /// subtotal that is not bit-identical to the parallel run.
+    pub audit_determinism: bool,
+    /// Also count every pair in exact integers (`PairResult::exact`) and report
+    /// the exact Omega next to the f64 one.
+    pub exact: bool,
+    /// Count only the matchings with this jbt assignment (`Given`): Omega
+    /// becomes the weight of the configurations where it holds.
+    pub given: Option<Given>,
+    /// Stored plans (`PlanSet::load`): pairs that have one skip indexing and
+    /// candidate search and only accumulate weights. Ignored under `given`.
+    pub plans: Option<Arc<PlanSet>>,
+    /// How the per-pair lines and Omega summary are printed (with `verbose`).
+    pub report_format: ReportFormat,
+    /// Also report Omega divided by this.
+    pub normalize: Option<Normalize>,
+    /// Solve only this shard of the planned pairs.
+    pub shard: Option<Shard>,
+    /// Recorded seconds per pair from an earlier run, used instead of the cost
+    /// model when balancing shards.
+    pub pair_seconds: Option<BTreeMap<PairId, f64>>,
+    /// (left key, right key) pairs already solved, e.g. by an interrupted run;
+    /// they are not scheduled again.
+    pub skip_pairs: HashSet<(Vec<i32>, Vec<i32>)>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            verbose: false,
+            pair_whitelist: None,
+            complement: compat_key_sorted,
+            neutral: NeutralMode::Pair,
+            report_every_rows: None,
+            cancel: CancelToken::default(),
+            audit_determinism: false,
+            exact: false,
+            given: None,
+            plans: None,
+            report_format: ReportFormat::Text,
+            normalize: None,
+            shard: None,
+            pair_seconds: None,
+            skip_pairs: HashSet::new(),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Weighted co-occurrence of jbt assignments (`--cooccurrence-out`): for every
+//! (j, x), the weight of the matchings that pair jbt j of the left state with
+//! jbt x of the right one, summed over the planned pairs. Entry (j, x) is the
+//! Omega `--given j=x` reports, and is counted the same way: one solve per
+//! pair orientation and (j, x), of the left rows holding j against the right
+//! rows holding x, with x as j's only candidate.
+
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+#[cfg(feature = "npz")]
+use {
+    anyhow::{Context, Result},
+    ndarray::Array1,
+    ndarray_npy::NpzWriter,
+    std::fs::File,
+    std::path::Path,
+};
+
+use super::config::{ComplementFn, MatchConfig};
+use super::driver::{plan_pairs, too_large};
+use super::solve::{
+    Compensated, RowIndex, build_rows_by_jbt, precompute_candidates_for_bucket1, subtotal_for_pair,
+};
+use super::types::Snapshot;
+use crate::human::Secs;
+use crate::runtime::resumable_progress_bar;
+
+/// Nonzero entries of the co-occurrence matrix, sorted by (j, x).
+pub struct Cooccurrence {
+    /// Matrix side: the snapshot's jbt count.
+    pub m: usize,
+    pub entries: Vec<(i32, i32, f64)>,
+}
+
+/// One (j, x) entry of one pair.
+type Cell = ((i32, i32), f64);
+
+/// The nonzero (j, x) entries of `task`, both orientations of a pair of
+/// distinct buckets included (see `--given`). Candidates are found once per
+/// orientation and the right rows holding x cut out once per x; j's candidate
+/// list is narrowed to x for each solve and put back. Candidates missing from
+/// the cut-down right bucket only match no row, so they can stay listed.
+fn task_cells<R: RowIndex>(
+    snap: &Snapshot,
+    (left, right, factor): (usize, usize, f64),
+    complement: ComplementFn,
+) -> Vec<Cell> {
+    let mut orientations = vec![(left, right)];
+    if factor != 1.0 {
+        orientations.push((right, left));
+    }
+    let mut cells = Vec::new();
+    for (a, b) in orientations {
+        let (left_rows, right_rows) = (
+            build_rows_by_jbt::<R>(&snap.buckets[a]),
+            build_rows_by_jbt::<R>(&snap.buckets[b]),
+        );
+        let rows = |index: &HashMap<i32, Vec<R>>, v: i32| -> Vec<usize> {
+            index[&v].iter().map(|r| r.row()).collect()
+        };
+        let mut cand_map = precompute_candidates_for_bucket1(
+            &snap.buckets[a],
+            &right_rows,
+            &snap.jbt_ref_pop,
+            snap.n_total,
+            &snap.compat,
+            complement,
+        );
+        let mut by_x: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+        for (&j, cands) in &cand_map {
+            for &x in cands {
+                by_x.entry(x).or_default().push(j);
+            }
+        }
+        for (x, mut js) in by_x {
+            js.sort_unstable();
+            let bucket2 = snap.buckets[b].select_rows(&rows(&right_rows, x));
+            let rows_by_jbt = build_rows_by_jbt::<R>(&bucket2);
+            for j in js {
+                let bucket1 = snap.buckets[a].select_rows(&rows(&left_rows, j));
+                let cands = cand_map.insert(j, vec![x]).unwrap_or_default();
+                let s = subtotal_for_pair(
+                    &bucket1,
+                    &bucket2,
+                    &snap.jbt_ref_pop,
+                    &rows_by_jbt,
+                    &cand_map,
+                    None,
+                    true,
+                );
+                cand_map.insert(j, cands);
+                if s.value != 0.0 {
+                    cells.push(((j, x), s.value));
+                }
+            }
+        }
+    }
+    cells
+}
+
+/// The matrix over the pairs `cfg` plans (whole run: `shard` and `skip_pairs`
+/// do not apply). Pairs are solved on the rayon pool and summed in plan order,
+/// so the entries do not depend on scheduling. Pairs left when `cfg.cancel`
+/// fires are skipped, with a `[cooc]` warning.
+pub fn cooccurrence(snap: &Snapshot, cfg: &MatchConfig) -> Cooccurrence {
+    let t0 = Instant::now();
+    let tasks = plan_pairs(snap, cfg, false);
+    let pb = resumable_progress_bar(tasks.len() as u64, "cooc pairs", 0);
+    let per_task: Vec<Option<Vec<Cell>>> = tasks
+        .par_iter()
+        .map(|&task| {
+            if cfg.cancel.is_cancelled() {
+                return None;
+            }
+            if let Some(reason) = too_large(snap, task) {
+                let (b1, b2) = (&snap.buckets[task.0], &snap.buckets[task.1]);
+                eprintln!(
+                    "[cooc] warn: pair {:?} vs {:?} left out: {}",
+                    b1.key, b2.key, reason
+                );
+                return None;
+            }
+            // either orientation indexes one of the two buckets
+            let rows = snap.buckets[task.0]
+                .n_rows()
+                .max(snap.buckets[task.1].n_rows());
+            let cells = if rows <= u32::MAX as usize {
+                task_cells::<u32>(snap, task, cfg.complement)
+            } else {
+                task_cells::<usize>(snap, task, cfg.complement)
+            };
+            pb.inc(1);
+            Some(cells)
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let missed = per_task.iter().filter(|t| t.is_none()).count();
+    if missed > 0 {
+        eprintln!(
+            "[cooc] warn: {} of {} pairs left out of the matrix (cancelled or too large)",
+            missed,
+            tasks.len()
+        );
+    }
+    let mut sums: BTreeMap<(i32, i32), Compensated> = BTreeMap::new();
+    for (cell, v) in per_task.into_iter().flatten().flatten() {
+        sums.entry(cell).or_default().add_f64(v);
+    }
+    let entries: Vec<(i32, i32, f64)> = sums
+        .into_iter()
+        .map(|((j, x), s)| (j, x, s.value()))
+        .collect();
+    eprintln!(
+        "[cooc] {} nonzero (j, x) entries over {} pairs in {}",
+        entries.len(),
+        tasks.len() - missed,
+        Secs::from(t0.elapsed())
+    );
+    Cooccurrence {
+        m: snap.jbt_ref_pop.len(),
+        entries,
+    }
+}
+
+#[cfg(feature = "npz")]
+impl Cooccurrence {
+    /// Write the matrix as COO arrays `j`, `x` (i32) and `weight` (f64), plus
+    /// `meta_M` (the side), for `scipy.sparse.coo_matrix((weight, (j, x)))`.
+    pub fn save_npz(&self, path: &Path) -> Result<()> {
+        let f = File::create(path).with_context(|| format!("create {}", path.display()))?;
+        let mut npz = NpzWriter::new(f);
+        let column = |pick: fn(&(i32, i32, f64)) -> i32| {
+            Array1::from_vec(self.entries.iter().map(pick).collect::<Vec<i32>>())
+        };
+        npz.add_array("meta_M.npy", &Array1::from_vec(vec![self.m as i64]))?;
+        npz.add_array("j.npy", &column(|e| e.0))?;
+        npz.add_array("x.npy", &column(|e| e.1))?;
+        npz.add_array(
+            "weight.npy",
+            &Array1::from_vec(self.entries.iter().map(|e| e.2).collect::<Vec<f64>>()),
+        )?;
+        npz.finish()
+            .with_context(|| format!("write {}", path.display()))?;
+        Ok(())
+    }
+}
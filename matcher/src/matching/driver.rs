@@ -1,9 +1,23 @@
+use num_bigint::BigUint;
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 use std::time::Instant;
 
-use super::solve::{build_rows_by_jbt, precompute_candidates_for_bucket1, subtotal_for_pair};
-use super::types::{Bucket, Snapshot, compat_key_sorted, key_sorted_vec};
+use super::compare::{PairId, pair_id};
+use super::config::{ComplementFn, Given, MatchConfig, NeutralMode};
+use super::report::{OmegaSummary, fmt_value};
+use super::shard::{ShardPlan, pair_cost_units};
+use super::solve::{
+    Accum, Compensated, ProgressHook, RowIndex, Subtotal, build_rows_by_jbt,
+    exact_subtotal_for_pair, exact_subtotal_from_plan, precompute_candidates_for_bucket1,
+    subtotal_for_pair, subtotal_from_plan,
+};
+use super::types::{Bucket, Snapshot, key_sorted_vec};
+use crate::hugepages::AllocPhase;
+use crate::human::{Bytes, Secs};
+use crate::runtime::{ProgressBar, UtilSampler, resumable_progress_bar};
 
 #[derive(Debug)]
 pub struct PairResult {
@@ -17,6 +31,34 @@ pub struct PairResult {
     pub t_solve: f64,
     pub t_total: f64,
     pub factor: f64,
+    /// factor x (sum of left weights) x (sum of right weights): the pair's
+    /// subtotal if every row pair were compatible. Sums to the
+    /// `Normalize::PairWeights` divisor.
+    pub weight_mass: f64,
+    /// Start offset (s) from the beginning of the parallel phase, and the rayon
+    /// worker that ran the pair; feed the load-balancing report.
+    pub t_start: f64,
+    pub worker: usize,
+    /// Why the pair was not solved (`MATCHER_MAX_PAIR_*`), with subtotal 0.
+    /// `--resume-results` and `matcher merge` do not count it as done.
+    pub skipped: Option<String>,
+    /// `subtotal` in exact integers (with `--exact`, when both buckets have
+    /// exact weights).
+    pub exact: Option<BigUint>,
+}
+
+/// Omega of `results`: the subtotals summed in pair order (`pair_id`) with
+/// Neumaier compensation, so the bits do not depend on the order the pairs
+/// were scheduled, finished, sharded or resumed in.
+pub fn omega_sum(results: &[PairResult]) -> f64 {
+    let mut subtotals: Vec<(PairId, f64)> =
+        results.iter().map(|r| (pair_id(r), r.subtotal)).collect();
+    subtotals.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    let mut sum = Compensated::default();
+    for (_, s) in subtotals {
+        sum.add_f64(s);
+    }
+    sum.value()
 }
 
 fn build_key_to_idx(buckets: &[Bucket]) -> HashMap<Vec<i32>, usize> {
@@ -27,121 +69,771 @@ fn build_key_to_idx(buckets: &[Bucket]) -> HashMap<Vec<i32>, usize> {
     map
 }
 
-pub fn run_all_pairs_parallel(snap: &Snapshot, verbose: bool) -> (Vec<PairResult>, f64) {
-    let t0 = Instant::now();
+/// Order a pair so the smaller bucket is on the left; distinct buckets count twice.
+fn make_task(snap: &Snapshot, i: usize, j: usize) -> (usize, usize, f64) {
+    let pair = if i <= j { (i, j) } else { (j, i) };
+    let (left, right) = if snap.buckets[pair.0].n_rows() <= snap.buckets[pair.1].n_rows() {
+        (pair.0, pair.1)
+    } else {
+        (pair.1, pair.0)
+    };
+    let factor = if pair.0 != pair.1 { 2.0 } else { 1.0 };
+    (left, right, factor)
+}
 
-    // build unordered tasks
-    let key_to_idx = build_key_to_idx(&snap.buckets);
+/// Cap on per-bucket pairing warnings; the summary line still counts all of them.
+const MAX_PAIRING_WARNINGS: usize = 20;
+
+fn auto_tasks(
+    snap: &Snapshot,
+    key_to_idx: &HashMap<Vec<i32>, usize>,
+    complement: ComplementFn,
+    log: bool,
+) -> Vec<(usize, usize, f64)> {
     let mut seen: HashSet<(usize, usize)> = HashSet::new();
     let mut tasks: Vec<(usize, usize, f64)> = Vec::new(); // (left,right,factor)
+    let mut unmatched = 0usize;
+    let mut asymmetric = 0usize;
 
     for (i, bi) in snap.buckets.iter().enumerate() {
-        let compat_sorted = compat_key_sorted(&key_sorted_vec(&bi.key), snap.n_total);
-        if let Some(&j) = key_to_idx.get(&compat_sorted) {
-            let pair = if i <= j { (i, j) } else { (j, i) };
-            if seen.insert(pair) {
-                let (left, right) =
-                    if snap.buckets[pair.0].n_rows() <= snap.buckets[pair.1].n_rows() {
-                        (pair.0, pair.1)
-                    } else {
-                        (pair.1, pair.0)
-                    };
-                let factor = if pair.0 != pair.1 { 2.0 } else { 1.0 };
-                tasks.push((left, right, factor));
+        let compat_sorted = complement(&key_sorted_vec(&bi.key), snap.n_total);
+        let Some(&j) = key_to_idx.get(&compat_sorted) else {
+            unmatched += 1;
+            if log && unmatched <= MAX_PAIRING_WARNINGS {
+                eprintln!(
+                    "[pairs] warn: bucket {:?} (rows={}) has no complement bucket {:?}",
+                    bi.key,
+                    bi.n_rows(),
+                    compat_sorted
+                );
+            }
+            continue;
+        };
+
+        // the complement of the partner must lead back to us
+        let back = complement(&key_sorted_vec(&snap.buckets[j].key), snap.n_total);
+        if key_to_idx.get(&back) != Some(&i) {
+            asymmetric += 1;
+            if log && asymmetric <= MAX_PAIRING_WARNINGS {
+                eprintln!(
+                    "[pairs] warn: asymmetric pairing {:?} -> {:?} -> {:?}",
+                    bi.key, snap.buckets[j].key, back
+                );
+            }
+        }
+
+        let pair = if i <= j { (i, j) } else { (j, i) };
+        if seen.insert(pair) {
+            tasks.push(make_task(snap, i, j));
+        }
+    }
+
+    if log && (unmatched > 0 || asymmetric > 0) {
+        eprintln!(
+            "[pairs] warn: {} of {} buckets have no complement bucket, {} asymmetric pairings (enumeration may be incomplete)",
+            unmatched,
+            snap.buckets.len(),
+            asymmetric
+        );
+    }
+    tasks
+}
+
+fn whitelist_tasks(
+    snap: &Snapshot,
+    key_to_idx: &HashMap<Vec<i32>, usize>,
+    pairs: &[(Vec<i32>, Vec<i32>)],
+    log: bool,
+) -> Vec<(usize, usize, f64)> {
+    let mut seen: HashSet<(usize, usize)> = HashSet::new();
+    let mut tasks: Vec<(usize, usize, f64)> = Vec::new();
+
+    for (kl, kr) in pairs {
+        let (Some(&i), Some(&j)) = (
+            key_to_idx.get(&key_sorted_vec(kl)),
+            key_to_idx.get(&key_sorted_vec(kr)),
+        ) else {
+            if log {
+                eprintln!(
+                    "[pairs] warn: whitelist pair {:?} vs {:?} has no matching bucket; skipped",
+                    kl, kr
+                );
             }
+            continue;
+        };
+        let pair = if i <= j { (i, j) } else { (j, i) };
+        if seen.insert(pair) {
+            tasks.push(make_task(snap, i, j));
         }
     }
+    tasks
+}
+
+fn apply_neutral_mode(
+    snap: &Snapshot,
+    tasks: &mut Vec<(usize, usize, f64)>,
+    mode: NeutralMode,
+    log: bool,
+) {
+    let is_neutral = |b: usize| snap.buckets[b].key.is_empty();
+    let before = tasks.len();
+    match mode {
+        NeutralMode::Pair => return,
+        NeutralMode::Exclude => tasks.retain(|&(l, r, _)| !is_neutral(l) && !is_neutral(r)),
+        NeutralMode::SelfOnly => tasks.retain(|&(l, r, _)| is_neutral(l) == is_neutral(r)),
+    }
+    if log && tasks.len() != before {
+        eprintln!(
+            "[pairs] neutral mode {:?}: dropped {} pairs",
+            mode,
+            before - tasks.len()
+        );
+    }
+}
+
+/// Every pair task `cfg` plans as (left bucket, right bucket, factor), heaviest
+/// first, before `cfg.shard` and `cfg.skip_pairs` select from them. `log`
+/// prints the pairing warnings.
+pub(super) fn plan_pairs(
+    snap: &Snapshot,
+    cfg: &MatchConfig,
+    log: bool,
+) -> Vec<(usize, usize, f64)> {
+    let key_to_idx = build_key_to_idx(&snap.buckets);
+    let mut tasks = match &cfg.pair_whitelist {
+        Some(pairs) => {
+            let tasks = whitelist_tasks(snap, &key_to_idx, pairs, log);
+            if log {
+                eprintln!(
+                    "[pairs] whitelist: {} pairs listed, {} scheduled",
+                    pairs.len(),
+                    tasks.len()
+                );
+            }
+            tasks
+        }
+        None => auto_tasks(snap, &key_to_idx, cfg.complement, log),
+    };
+    apply_neutral_mode(snap, &mut tasks, cfg.neutral, log);
+    if log && tasks.is_empty() {
+        eprintln!(
+            "[pairs] warn: no bucket pairs scheduled out of {} buckets; Omega is trivially 0",
+            snap.buckets.len()
+        );
+    }
 
     // cost sort heavy first
-    tasks.sort_by_key(|&(l, r, _)| {
-        use std::cmp::Reverse;
-        let cost = (snap.buckets[l].n_rows() as u64)
-            * (snap.buckets[r].n_rows() as u64)
-            * (std::cmp::max(1, snap.buckets[l].key.len()) as u64);
-        Reverse(cost)
+    tasks.sort_by_key(|&(l, r, _)| std::cmp::Reverse(pair_cost_units(snap, l, r)));
+    tasks
+}
+
+/// Number of pairs `cfg` plans over the whole run, ignoring `shard` and
+/// `skip_pairs`: what a merge of partial results must cover.
+pub fn planned_pair_count(snap: &Snapshot, cfg: &MatchConfig) -> usize {
+    plan_pairs(snap, cfg, false).len()
+}
+
+/// The planned pairs this run solves: for a shard, its part of a plan balanced
+/// by predicted runtime (see `shard`), minus those already done.
+fn schedule_pairs(snap: &Snapshot, cfg: &MatchConfig) -> Vec<(usize, usize, f64)> {
+    let mut tasks = plan_pairs(snap, cfg, true);
+    if let Some(shard) = cfg.shard {
+        let plan = ShardPlan::new(snap, cfg, &tasks, shard.count);
+        plan.print(shard.count, rayon::current_num_threads(), Some(shard.index));
+        tasks = plan
+            .tasks_of(shard.index)
+            .into_iter()
+            .map(|i| tasks[i])
+            .collect();
+    }
+    if !cfg.skip_pairs.is_empty() {
+        let before = tasks.len();
+        tasks.retain(|&(l, r, _)| {
+            !cfg.skip_pairs
+                .contains(&(snap.buckets[l].key.clone(), snap.buckets[r].key.clone()))
+        });
+        eprintln!(
+            "[resume] {} of {} pairs already done; {} left",
+            before - tasks.len(),
+            before,
+            tasks.len()
+        );
+    }
+    tasks
+}
+
+/// Counts one run's pairs add to as they finish, for its summary lines. Each
+/// run has its own, so concurrent runs in one process do not mix them.
+#[derive(Default)]
+struct RunCounters {
+    /// Pairs whose parallel subtotal differed from the sequential re-run (audit mode).
+    audit_mismatches: AtomicUsize,
+    /// Pairs counted in each `Accum`, in declaration order, for the `[accum]` line.
+    accum_pairs: [AtomicUsize; 3],
+    /// Pairs counted from a stored plan, for the `[plans]` line.
+    plan_pairs: AtomicUsize,
+}
+
+/// Solve one scheduled pair. `t_par0` is the start of the parallel phase and
+/// `omega_done` the running sum of finished pairs (for progress lines).
+fn solve_pair(
+    snap: &Snapshot,
+    cfg: &MatchConfig,
+    task: (usize, usize, f64),
+    t_par0: Instant,
+    omega_done: &Mutex<f64>,
+    counters: &RunCounters,
+) -> PairResult {
+    if let Some(reason) = too_large(snap, task) {
+        return skipped_pair(snap, task, reason, t_par0);
+    }
+    // u32 row lists whenever bucket2 is small enough, which is nearly always
+    if snap.buckets[task.1].n_rows() <= u32::MAX as usize {
+        solve_pair_with::<u32>(snap, cfg, task, t_par0, omega_done, counters)
+    } else {
+        solve_pair_with::<usize>(snap, cfg, task, t_par0, omega_done, counters)
+    }
+}
+
+/// Why `task` is over the `MATCHER_MAX_PAIR_*` ceilings, if it is. The index
+/// size is that of the right bucket's row lists, the structure that grows with
+/// the pair before any solving starts.
+pub(super) fn too_large(snap: &Snapshot, (left, right, _): (usize, usize, f64)) -> Option<String> {
+    const MIB: u64 = 1 << 20;
+    let settings = crate::settings::get();
+    let (rows1, rows2) = (snap.buckets[left].n_rows(), snap.buckets[right].n_rows());
+    let cells = (rows1 as u64).saturating_mul(rows2 as u64);
+    if let Some(max) = settings.max_pair_cells
+        && cells > max
+    {
+        return Some(format!(
+            "too large: rows1 x rows2 = {} > MATCHER_MAX_PAIR_CELLS={}",
+            cells, max
+        ));
+    }
+    let width = if rows2 <= u32::MAX as usize { 4 } else { 8 };
+    let index = snap.buckets[right].rows_data.len() as u64 * width;
+    if let Some(max) = settings.max_pair_index_bytes
+        && index > max
+    {
+        return Some(format!(
+            "too large: row index {} > MATCHER_MAX_PAIR_INDEX_MB={} ({})",
+            Bytes(index),
+            max / MIB,
+            Bytes(max)
+        ));
+    }
+    None
+}
+
+/// The result of a pair `too_large` turned away: no subtotal, only why.
+fn skipped_pair(
+    snap: &Snapshot,
+    (left, right, factor): (usize, usize, f64),
+    reason: String,
+    t_par0: Instant,
+) -> PairResult {
+    let (b1, b2) = (&snap.buckets[left], &snap.buckets[right]);
+    eprintln!(
+        "[skip] pair {:?} vs {:?} (rows1={}, rows2={}): {}",
+        b1.key,
+        b2.key,
+        b1.n_rows(),
+        b2.n_rows(),
+        reason
+    );
+    PairResult {
+        key_left: b1.key.clone(),
+        key_right: b2.key.clone(),
+        rows1: b1.n_rows(),
+        rows2: b2.n_rows(),
+        subtotal: 0.0,
+        t_index: 0.0,
+        t_cands: 0.0,
+        t_solve: 0.0,
+        t_total: 0.0,
+        factor,
+        weight_mass: factor * b1.weights.iter().sum::<f64>() * b2.weights.iter().sum::<f64>(),
+        t_start: t_par0.elapsed().as_secs_f64(),
+        worker: rayon::current_thread_index().unwrap_or(0),
+        skipped: Some(reason),
+        exact: None,
+    }
+}
+
+/// Omega leaves out the pairs `too_large` skipped; say so once, with the way
+/// to finish them.
+fn warn_skipped(results: &[PairResult]) {
+    let skipped = results.iter().filter(|r| r.skipped.is_some()).count();
+    if skipped > 0 {
+        eprintln!(
+            "[skip] warn: {} of {} pairs skipped as too large; Omega is partial. Solve them on a larger host with higher MATCHER_MAX_PAIR_* limits and --resume-results",
+            skipped,
+            results.len()
+        );
+    }
+}
+
+/// A NaN or inf subtotal would carry into Omega unnoticed; name the pair and
+/// the first offending weight, if a weight is the cause rather than overflow.
+fn warn_non_finite(snap: &Snapshot, left: usize, right: usize, subtotal: f64) {
+    let (b1, b2) = (&snap.buckets[left], &snap.buckets[right]);
+    eprintln!(
+        "[warn] pair {:?} vs {:?}: subtotal {} is not finite; Omega will be too",
+        b1.key, b2.key, subtotal
+    );
+    for (idx, b) in [(left, b1), (right, b2)] {
+        if let Some((r, w)) = b.first_non_finite_weight() {
+            eprintln!(
+                "[warn]   bucket {} {:?} row {} has weight {}",
+                idx, b.key, r, w
+            );
+        }
+    }
+}
+
+fn solve_pair_with<R: RowIndex>(
+    snap: &Snapshot,
+    cfg: &MatchConfig,
+    (left, right, factor): (usize, usize, f64),
+    t_par0: Instant,
+    omega_done: &Mutex<f64>,
+    counters: &RunCounters,
+) -> PairResult {
+    let key_left = snap.buckets[left].key.clone();
+    let key_right = snap.buckets[right].key.clone();
+
+    let t_pair0 = Instant::now();
+    let t_start = t_pair0.duration_since(t_par0).as_secs_f64();
+    let mut span = crate::telemetry::span("pair");
+
+    // a stored plan already holds what the index and candidates are for
+    let plan = match (&cfg.plans, cfg.given) {
+        (Some(plans), None) => plans.get(left, right),
+        _ => None,
+    };
+
+    let t_index0 = Instant::now();
+    let rows_by_jbt = match plan {
+        Some(_) => HashMap::new(),
+        None => build_rows_by_jbt::<R>(&snap.buckets[right]),
+    };
+    let t_index = t_index0.elapsed().as_secs_f64();
+
+    let t_cands0 = Instant::now();
+    let cand_map = match plan {
+        Some(_) => HashMap::new(),
+        None => precompute_candidates_for_bucket1(
+            &snap.buckets[left],
+            &rows_by_jbt,
+            &snap.jbt_ref_pop,
+            snap.n_total,
+            &snap.compat,
+            cfg.complement,
+        ),
+    };
+    let t_cands = t_cands0.elapsed().as_secs_f64();
+
+    let report = |done: usize, total: usize, partial: f64| {
+        eprintln!(
+            "[progress] pair {:?} vs {:?}: rows {}/{} partial={} (finished pairs={})",
+            key_left,
+            key_right,
+            done,
+            total,
+            fmt_value(partial * factor, None),
+            fmt_value(*omega_done.lock().unwrap(), None)
+        );
+    };
+    let hook = cfg.report_every_rows.map(|every| ProgressHook {
+        every: every.max(1),
+        report: &report,
+    });
+
+    let t_solve0 = Instant::now();
+    let count = |progress: Option<&ProgressHook>, parallel: bool| match (cfg.given, plan) {
+        (Some(given), _) => {
+            given_subtotal::<R>(snap, (left, right, factor), given, cfg.complement, parallel)
+        }
+        (None, Some(plan)) => subtotal_from_plan(plan, &snap.buckets[left], &snap.buckets[right]),
+        (None, None) => subtotal_for_pair(
+            &snap.buckets[left],
+            &snap.buckets[right],
+            &snap.jbt_ref_pop,
+            &rows_by_jbt,
+            &cand_map,
+            progress,
+            parallel,
+        ),
+    };
+    let counted = count(hook.as_ref(), true);
+    counters.accum_pairs[counted.accum as usize].fetch_add(1, Ordering::Relaxed);
+    if plan.is_some() {
+        counters.plan_pairs.fetch_add(1, Ordering::Relaxed);
+    }
+    let mut subtotal = counted.value;
+    if cfg.audit_determinism {
+        let sequential = count(None, false).value;
+        if sequential.to_bits() != subtotal.to_bits() {
+            counters.audit_mismatches.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "[audit] pair {:?} vs {:?}: chunked parallel {:e} != sequential {:e}",
+                key_left, key_right, subtotal, sequential
+            );
+        }
+    }
+    if !subtotal.is_finite() {
+        warn_non_finite(snap, left, right, subtotal);
+    }
+    // a conditioned count already holds both orientations of the pair
+    let scale = if cfg.given.is_some() { 1.0 } else { factor };
+    // an integer count is already exact; only f64 pairs need the second pass
+    let exact = cfg
+        .exact
+        .then(|| match counted.exact {
+            Some(x) => Some(BigUint::from(x)),
+            None if cfg.given.is_some() => None,
+            None => exact_pair(snap, left, right, |w1, w2| match plan {
+                Some(plan) => exact_subtotal_from_plan(plan, w1, w2),
+                None => exact_subtotal_for_pair(
+                    &snap.buckets[left],
+                    w1,
+                    &snap.buckets[right],
+                    w2,
+                    &snap.jbt_ref_pop,
+                    &rows_by_jbt,
+                    &cand_map,
+                    true,
+                ),
+            }),
+        })
+        .flatten()
+        .map(|x| x * scale as u64);
+    subtotal *= scale;
+    *omega_done.lock().unwrap() += subtotal;
+    let t_solve = t_solve0.elapsed().as_secs_f64();
+
+    let t_total = t_pair0.elapsed().as_secs_f64();
+    span.attr("matcher.key_left", format!("{:?}", key_left));
+    span.attr("matcher.key_right", format!("{:?}", key_right));
+    span.attr("matcher.rows1", snap.buckets[left].n_rows() as u64);
+    span.attr("matcher.rows2", snap.buckets[right].n_rows() as u64);
+    span.attr("matcher.subtotal", subtotal);
+
+    PairResult {
+        key_left,
+        key_right,
+        rows1: snap.buckets[left].n_rows(),
+        rows2: snap.buckets[right].n_rows(),
+        subtotal,
+        t_index,
+        t_cands,
+        t_solve,
+        t_total,
+        factor,
+        weight_mass: factor
+            * snap.buckets[left].weights.iter().sum::<f64>()
+            * snap.buckets[right].weights.iter().sum::<f64>(),
+        t_start,
+        worker: rayon::current_thread_index().unwrap_or(0),
+        skipped: None,
+        exact,
+    }
+}
+
+/// Reject a `--given` whose jbt ids the snapshot does not have, or whose `j`
+/// has pop 0 (never matched, so it cannot be conditioned on).
+pub fn check_given(snap: &Snapshot, cfg: &MatchConfig) -> anyhow::Result<()> {
+    let Some(Given { j, x }) = cfg.given else {
+        return Ok(());
+    };
+    let m = snap.jbt_ref_pop.len();
+    if j as usize >= m || x as usize >= m {
+        anyhow::bail!("--given {}={}: the snapshot has jbt ids 0..{}", j, x, m);
+    }
+    if snap.jbt_ref_pop[j as usize] == 0 {
+        anyhow::bail!(
+            "--given {}={}: jbt {} has pop 0 and is never matched",
+            j,
+            x,
+            j
+        );
+    }
+    Ok(())
+}
+
+/// Subtotal of `task` over the matchings that pair `given.j` with `given.x`:
+/// bucket1 cut down to the rows holding j, bucket2 to those holding x, and j's
+/// candidates to x alone (injectivity then keeps x from the other positions).
+/// The pair factor
+/// stands for the mirrored orientation, which conditions on another set of
+/// states, so a pair of distinct buckets is solved both ways and not scaled.
+fn given_subtotal<R: RowIndex>(
+    snap: &Snapshot,
+    (left, right, factor): (usize, usize, f64),
+    given: Given,
+    complement: ComplementFn,
+    parallel: bool,
+) -> Subtotal {
+    let mut orientations = vec![(left, right)];
+    if factor != 1.0 {
+        orientations.push((right, left));
+    }
+    let mut value = Compensated::default();
+    let mut exact = Some(0u128);
+    let mut accum = Accum::U64;
+    for (a, b) in orientations {
+        let bucket1 = snap.buckets[a].select_rows(&snap.buckets[a].rows_with_jbt(given.j));
+        let bucket2 = snap.buckets[b].select_rows(&snap.buckets[b].rows_with_jbt(given.x));
+        let rows_by_jbt = build_rows_by_jbt::<R>(&bucket2);
+        let mut cand_map = precompute_candidates_for_bucket1(
+            &bucket1,
+            &rows_by_jbt,
+            &snap.jbt_ref_pop,
+            snap.n_total,
+            &snap.compat,
+            complement,
+        );
+        if let Some(cands) = cand_map.get_mut(&given.j) {
+            cands.retain(|&x| x == given.x);
+        }
+        let s = subtotal_for_pair(
+            &bucket1,
+            &bucket2,
+            &snap.jbt_ref_pop,
+            &rows_by_jbt,
+            &cand_map,
+            None,
+            parallel,
+        );
+        value.add_f64(s.value);
+        exact = exact.zip(s.exact).and_then(|(x, y)| x.checked_add(y));
+        accum = accum.max(s.accum);
+    }
+    Subtotal {
+        value: value.value(),
+        exact,
+        accum,
+    }
+}
+
+/// The pair's subtotal in exact integers, counted by `count` from the two
+/// buckets' exact weights, or `None` (with an `[exact]` line) when a bucket
+/// has a weight with no exact value.
+fn exact_pair(
+    snap: &Snapshot,
+    left: usize,
+    right: usize,
+    count: impl FnOnce(&[BigUint], &[BigUint]) -> BigUint,
+) -> Option<BigUint> {
+    let (b1, b2) = (&snap.buckets[left], &snap.buckets[right]);
+    let weights = |b: &Bucket| {
+        b.exact_weights()
+            .map_err(|(r, w)| {
+                eprintln!(
+                    "[exact] pair {:?} vs {:?}: bucket {:?} row {} has weight {}, which is not exact; no exact subtotal",
+                    b1.key, b2.key, b.key, r, w
+                );
+            })
+            .ok()
+    };
+    let (w1, w2) = (weights(b1)?, weights(b2)?);
+    Some(count(&w1, &w2))
+}
+
+/// Library mode: run the pairs on the rayon pool from a background thread and
+/// yield each `PairResult` as it finishes (completion order, not schedule
+/// order); iterate the receiver directly. Dropping the receiver cancels the
+/// pairs not yet started, through `cfg.cancel`.
+#[allow(dead_code)] // for embedders; the binary collects with `run_all_pairs_parallel`
+pub fn run_all_pairs_streaming(
+    snap: Arc<Snapshot>,
+    cfg: MatchConfig,
+) -> mpsc::Receiver<PairResult> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let tasks = schedule_pairs(&snap, &cfg);
+        let mut phase = crate::telemetry::phase("match");
+        phase.attr("matcher.pairs", tasks.len() as u64);
+        let t_par0 = Instant::now();
+        let omega_done = Mutex::new(0.0f64);
+        let counters = RunCounters::default();
+        tasks
+            .par_iter()
+            .enumerate()
+            .for_each_with(tx, |tx, (k, &task)| {
+                if cfg.cancel.is_cancelled() {
+                    return;
+                }
+                crate::chaos::at_pair(k);
+                let result = solve_pair(&snap, &cfg, task, t_par0, &omega_done, &counters);
+                if tx.send(result).is_err() {
+                    cfg.cancel.cancel();
+                }
+            });
     });
+    rx
+}
+
+pub fn run_all_pairs_parallel(snap: &Snapshot, cfg: &MatchConfig) -> (Vec<PairResult>, f64) {
+    let t0 = Instant::now();
+    let verbose = cfg.verbose;
+    let tasks = schedule_pairs(snap, cfg);
+    let mut phase = crate::telemetry::phase("match");
+    phase.attr("matcher.pairs", tasks.len() as u64);
+
+    // sum of finished pairs, for omega-so-far in progress reports
+    let omega_done = Mutex::new(0.0f64);
+    let counters = RunCounters::default();
+
+    let pb = if verbose {
+        resumable_progress_bar(tasks.len() as u64, "pairs", 0)
+    } else {
+        ProgressBar::hidden()
+    };
 
     // parallel run
+    let t_par0 = Instant::now();
+    let util = verbose.then(|| UtilSampler::start("matching"));
+    let allocs = AllocPhase::start("matching");
     let results: Vec<PairResult> = tasks
         .par_iter()
-        .map(|&(left, right, factor)| {
-            let key_left = snap.buckets[left].key.clone();
-            let key_right = snap.buckets[right].key.clone();
-
-            let t_pair0 = Instant::now();
-
-            let t_index0 = Instant::now();
-            let rows_by_jbt = build_rows_by_jbt(&snap.buckets[right]);
-            let t_index = t_index0.elapsed().as_secs_f64();
-
-            let t_cands0 = Instant::now();
-            let cand_map = precompute_candidates_for_bucket1(
-                &snap.buckets[left],
-                &rows_by_jbt,
-                &snap.jbt_ref_pop,
-                snap.n_total,
-                &snap.compat,
-            );
-            let t_cands = t_cands0.elapsed().as_secs_f64();
-
-            let t_solve0 = Instant::now();
-            let mut subtotal = subtotal_for_pair(
-                &snap.buckets[left],
-                &snap.buckets[right],
-                &snap.jbt_ref_pop,
-                snap.n_total,
-                &snap.compat,
-                &rows_by_jbt,
-                &cand_map,
-            );
-            subtotal *= factor;
-            let t_solve = t_solve0.elapsed().as_secs_f64();
-
-            let t_total = t_pair0.elapsed().as_secs_f64();
-
-            PairResult {
-                key_left,
-                key_right,
-                rows1: snap.buckets[left].n_rows(),
-                rows2: snap.buckets[right].n_rows(),
-                subtotal,
-                t_index,
-                t_cands,
-                t_solve,
-                t_total,
-                factor,
+        .enumerate()
+        .filter_map(|(k, &task)| {
+            if cfg.cancel.is_cancelled() {
+                return None;
             }
+            crate::chaos::at_pair(k);
+            let _busy = util.as_ref().map(|u| u.busy());
+            let result = solve_pair(snap, cfg, task, t_par0, &omega_done, &counters);
+            pb.inc(1);
+            Some(result)
         })
         .collect();
+    pb.finish_and_clear();
+    if cfg.audit_determinism {
+        eprintln!(
+            "[audit] {} pairs re-run sequentially, {} bit mismatches",
+            results.len(),
+            counters.audit_mismatches.load(Ordering::Relaxed)
+        );
+    }
+    let [u64s, u128s, f64s] = counters
+        .accum_pairs
+        .each_ref()
+        .map(|n| n.load(Ordering::Relaxed));
+    if u64s + u128s + f64s > 0 {
+        eprintln!(
+            "[accum] pairs counted in u64={} u128={} f64={}",
+            u64s, u128s, f64s
+        );
+    }
+    if cfg.plans.is_some() {
+        eprintln!(
+            "[plans] {} of {} pairs counted from stored plans",
+            counters.plan_pairs.load(Ordering::Relaxed),
+            results.len()
+        );
+    }
+    if cfg.cancel.is_cancelled() {
+        eprintln!(
+            "[cancel] matching stopped: {} of {} pairs finished",
+            results.len(),
+            tasks.len()
+        );
+    }
+    let par_wall = t_par0.elapsed().as_secs_f64();
+    if let Some(a) = allocs {
+        a.finish();
+    }
 
     let wall = t0.elapsed().as_secs_f64();
+    warn_skipped(&results);
 
     if verbose {
-        for r in &results {
-            println!(
-                "[pair {:?} vs {:?}{}] rows1={}, rows2={} | index={:.3}s, cands={:.3}s, solve={:.3}s → total={:.3}s | subtotal={:.6}",
-                r.key_left,
-                r.key_right,
-                if r.factor == 2.0 { " x2" } else { "" },
-                r.rows1,
-                r.rows2,
-                r.t_index,
-                r.t_cands,
-                r.t_solve,
-                r.t_total,
-                r.subtotal
-            );
+        let mut reporter = cfg.report_format.reporter(std::io::stdout().lock());
+        let written = results
+            .iter()
+            .try_for_each(|r| reporter.pair(r))
+            .and_then(|_| {
+                reporter.omega(&OmegaSummary::from_results(&results, wall, cfg.normalize))
+            });
+        drop(reporter);
+        if let Err(err) = written {
+            eprintln!("[report] warn: writing the pair report failed: {err}");
+        }
+        report_load_balance(&results, par_wall);
+        if let Some(util) = util {
+            util.finish();
         }
-        let omega: f64 = results.iter().map(|r| r.subtotal).sum();
-        println!(
-            "Omega total: {:.6} (pairs={}, wall={:.3}s, sum_pair_total={:.3}s, sum_pair_solve={:.3}s)",
-            omega,
-            results.len(),
-            wall,
-            results.iter().map(|r| r.t_total).sum::<f64>(),
-            results.iter().map(|r| r.t_solve).sum::<f64>(),
-        );
     }
 
     (results, wall)
 }
+
+/// `run_all_pairs_parallel` on a pool of `threads` threads of its own, or on
+/// the global pool when `None`; for embedders that size each run themselves.
+pub fn run_all_pairs_on(
+    snap: &Snapshot,
+    cfg: &MatchConfig,
+    threads: Option<usize>,
+) -> anyhow::Result<(Vec<PairResult>, f64)> {
+    Ok(match threads {
+        Some(n) => rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build()?
+            .install(|| run_all_pairs_parallel(snap, cfg)),
+        None => run_all_pairs_parallel(snap, cfg),
+    })
+}
+
+/// Pairs listed as responsible for the idle tail.
+const TAIL_TOP_PAIRS: usize = 5;
+
+/// Scheduler efficiency of the parallel phase: ideal time (sum of pair times /
+/// threads) vs. actual wall, the idle tail after the first worker ran out of
+/// pairs, and the longest pairs still running during that tail.
+fn report_load_balance(results: &[PairResult], par_wall: f64) {
+    let threads = rayon::current_num_threads().max(1);
+    if results.is_empty() || par_wall <= 0.0 {
+        return;
+    }
+    let busy: f64 = results.iter().map(|r| r.t_total).sum();
+    let ideal = busy / threads as f64;
+
+    // per-worker finish time; the tail starts when the earliest one goes idle
+    let mut finish = vec![0.0f64; threads];
+    for r in results {
+        if let Some(f) = finish.get_mut(r.worker) {
+            *f = f.max(r.t_start + r.t_total);
+        }
+    }
+    let tail_start = finish.iter().copied().fold(f64::INFINITY, f64::min);
+    let tail = (par_wall - tail_start).max(0.0);
+
+    eprintln!(
+        "[balance] threads={} wall={} sum_pairs={} ideal={} efficiency={:.1}% idle_tail={}",
+        threads,
+        Secs(par_wall),
+        Secs(busy),
+        Secs(ideal),
+        100.0 * ideal / par_wall,
+        Secs(tail)
+    );
+    let mut in_tail: Vec<&PairResult> = results
+        .iter()
+        .filter(|r| r.t_start + r.t_total > tail_start)
+        .collect();
+    in_tail.sort_by(|a, b| b.t_total.total_cmp(&a.t_total));
+    for r in in_tail.iter().take(TAIL_TOP_PAIRS) {
+        eprintln!(
+            "[balance]   tail pair {:?} vs {:?}: {} (started at {}, rows1={}, rows2={})",
+            r.key_left,
+            r.key_right,
+            Secs(r.t_total),
+            Secs(r.t_start),
+            r.rows1,
+            r.rows2
+        );
+    }
+}
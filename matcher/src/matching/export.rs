@@ -0,0 +1,220 @@
+//! `matcher export`: snapshot buckets as tables for analytics tools. With
+//! `--format parquet` (feature "parquet") the output directory gets
+//! `rows.parquet`, one row per state (bucket_id, row_id, jbts, weight and,
+//! for weights past 2^53, weight_exact as decimal text), and
+//! `buckets.parquet`, one row per bucket (bucket_id, key, rows, weight). NPZ
+//! snapshots are read one bucket at a time. With `--format arrow` (feature
+//! "arrow") it gets `snapshot.arrow`, the whole snapshot as an Arrow IPC file
+//! (see `arrow`), for which the snapshot is loaded whole.
+
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+
+use super::arrow::write_ipc;
+use super::io::load_snapshot;
+use super::types::Bucket;
+use crate::human::Secs;
+#[cfg(feature = "npz")]
+use {super::io::read_snapshot_with, super::view::is_view, super::zst::is_zst};
+
+/// Table format of `matcher export`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Parquet,
+    Arrow,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "arrow" => Ok(ExportFormat::Arrow),
+            other => bail!(
+                "unknown export format {:?} (expected parquet or arrow)",
+                other
+            ),
+        }
+    }
+}
+
+/// Hand every bucket of the snapshot or view at `path` to `on_bucket`, in
+/// snapshot order, without holding an NPZ snapshot's buckets all at once.
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+fn for_each_bucket(
+    path: &str,
+    mut on_bucket: impl FnMut(usize, &Bucket) -> Result<()>,
+) -> Result<()> {
+    #[cfg(feature = "npz")]
+    if !is_view(path) && !is_zst(path) {
+        let f = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+        read_snapshot_with(f, path, |b, bucket| on_bucket(b, &bucket))?;
+        return Ok(());
+    }
+    let snap = load_snapshot(path)?;
+    snap.buckets
+        .iter()
+        .enumerate()
+        .try_for_each(|(b, bucket)| on_bucket(b, bucket))
+}
+
+/// Export the snapshot at `path` into the directory `dir` (created if needed).
+pub fn export(path: &str, format: ExportFormat, dir: &Path) -> Result<()> {
+    let t0 = Instant::now();
+    std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    let (buckets, rows) = match format {
+        ExportFormat::Parquet => parquet_tables::write(path, dir)?,
+        ExportFormat::Arrow => {
+            let snap = load_snapshot(path)?;
+            write_ipc(&snap, &dir.join("snapshot.arrow"))?;
+            let rows = snap.buckets.iter().map(|b| b.n_rows() as u64).sum();
+            (snap.buckets.len(), rows)
+        }
+    };
+    eprintln!(
+        "[export] {} buckets, {} rows of {} written to {} in {}",
+        buckets,
+        rows,
+        path,
+        dir.display(),
+        Secs::from(t0.elapsed())
+    );
+    Ok(())
+}
+
+/// Without the "parquet" feature there is no writer; `write` says so.
+#[cfg(not(feature = "parquet"))]
+mod parquet_tables {
+    use anyhow::{Result, bail};
+    use std::path::Path;
+
+    pub(super) fn write(_path: &str, _dir: &Path) -> Result<(usize, u64)> {
+        bail!(
+            "this matcher was built without the \"parquet\" feature; rebuild with --features parquet to export Parquet"
+        )
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_tables {
+    use anyhow::{Context, Result};
+    use arrow_array::builder::{Int32Builder, ListBuilder, StringBuilder};
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, UInt32Array, UInt64Array};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use parquet::basic::Compression;
+    use parquet::file::properties::WriterProperties;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::super::types::Bucket;
+    use super::for_each_bucket;
+
+    /// Rows per record batch (and at most per row group) of `rows.parquet`.
+    const BATCH_ROWS: usize = 1 << 16;
+
+    /// A list-of-i32 column, as `ListBuilder<Int32Builder>` builds it.
+    fn i32_list(name: &str) -> Field {
+        Field::new(
+            name,
+            DataType::List(Arc::new(Field::new("item", DataType::Int32, true))),
+            false,
+        )
+    }
+
+    fn writer(dir: &Path, name: &str, schema: &Arc<Schema>) -> Result<ArrowWriter<File>> {
+        let path = dir.join(name);
+        let f = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(BATCH_ROWS)
+            .build();
+        Ok(ArrowWriter::try_new(f, Arc::clone(schema), Some(props))?)
+    }
+
+    /// Rows `rows` of bucket `b` as one record batch.
+    fn rows_batch(
+        schema: &Arc<Schema>,
+        b: usize,
+        bucket: &Bucket,
+        rows: std::ops::Range<usize>,
+    ) -> Result<RecordBatch> {
+        let mut jbts = ListBuilder::new(Int32Builder::new());
+        let mut exact = StringBuilder::new();
+        let mut overrides = bucket
+            .exact
+            .iter()
+            .skip_while(|(r, _)| *r < rows.start)
+            .peekable();
+        for r in rows.clone() {
+            jbts.values().append_slice(bucket.row_slice(r));
+            jbts.append(true);
+            match overrides.next_if(|(e, _)| *e == r) {
+                Some((_, x)) => exact.append_value(x.to_string()),
+                None => exact.append_null(),
+            }
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt32Array::from(vec![b as u32; rows.len()])),
+            Arc::new(UInt64Array::from_iter_values(
+                rows.clone().map(|r| r as u64),
+            )),
+            Arc::new(jbts.finish()),
+            Arc::new(Float64Array::from(bucket.weights[rows].to_vec())),
+            Arc::new(exact.finish()),
+        ];
+        Ok(RecordBatch::try_new(Arc::clone(schema), columns)?)
+    }
+
+    /// Write both tables; returns the bucket and row counts.
+    pub(super) fn write(path: &str, dir: &Path) -> Result<(usize, u64)> {
+        let rows_schema = Arc::new(Schema::new(vec![
+            Field::new("bucket_id", DataType::UInt32, false),
+            Field::new("row_id", DataType::UInt64, false),
+            i32_list("jbts"),
+            Field::new("weight", DataType::Float64, false),
+            Field::new("weight_exact", DataType::Utf8, true),
+        ]));
+        let mut rows_out = writer(dir, "rows.parquet", &rows_schema)?;
+        let mut keys: Vec<(Vec<i32>, u64, f64)> = Vec::new();
+        for_each_bucket(path, |b, bucket| {
+            let n = bucket.n_rows();
+            for start in (0..n).step_by(BATCH_ROWS) {
+                let batch = rows_batch(&rows_schema, b, bucket, start..n.min(start + BATCH_ROWS))?;
+                rows_out.write(&batch)?;
+            }
+            keys.push((bucket.key.clone(), n as u64, bucket.weights.iter().sum()));
+            Ok(())
+        })?;
+        rows_out.close().context("finish rows.parquet")?;
+
+        let buckets_schema = Arc::new(Schema::new(vec![
+            Field::new("bucket_id", DataType::UInt32, false),
+            i32_list("key"),
+            Field::new("rows", DataType::UInt64, false),
+            Field::new("weight", DataType::Float64, false),
+        ]));
+        let mut key_list = ListBuilder::new(Int32Builder::new());
+        for (key, _, _) in &keys {
+            key_list.values().append_slice(key);
+            key_list.append(true);
+        }
+        let batch = RecordBatch::try_new(
+            Arc::clone(&buckets_schema),
+            vec![
+                Arc::new(UInt32Array::from_iter_values(0..keys.len() as u32)),
+                Arc::new(key_list.finish()),
+                Arc::new(UInt64Array::from_iter_values(keys.iter().map(|k| k.1))),
+                Arc::new(Float64Array::from_iter_values(keys.iter().map(|k| k.2))),
+            ],
+        )?;
+        let mut buckets_out = writer(dir, "buckets.parquet", &buckets_schema)?;
+        buckets_out.write(&batch)?;
+        buckets_out.close().context("finish buckets.parquet")?;
+        Ok((keys.len(), keys.iter().map(|k| k.1).sum()))
+    }
+}
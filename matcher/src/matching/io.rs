@@ -1,64 +1,361 @@
-use anyhow::{Context, Result};
-use ndarray::Array1;
-use ndarray_npy::{NpzReader, NpzWriter};
+use anyhow::{Context, Result, bail};
+use num_traits::ToPrimitive;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 
-use super::types::{Bucket, Snapshot};
+use super::checkpoint::Checkpoint;
+use super::driver::PairResult;
+use super::report::exact_omega;
+use super::types::{Bucket, Snapshot, warn_saturation_sites};
+use super::zst;
+use crate::runtime::{CancelToken, Cancelled};
 
-fn read_i32<R: std::io::Read + std::io::Seek>(
+#[cfg(feature = "npz")]
+use {
+    super::npz_check::{check_member_sizes, check_offsets},
+    super::stats::{EnumCounts, SnapshotStats},
+    super::types::{EmptyDataError, Indptr, Pruning, Saturation, SchemaError, Truncation},
+    crate::human::Secs,
+    crate::runtime::resumable_progress_bar,
+    ndarray::{Array1, Array2, ArrayView1},
+    ndarray_npy::{NpzReader, NpzWriter},
+    num_bigint::BigUint,
+    std::collections::HashSet,
+    std::time::{Duration, Instant},
+};
+
+#[cfg(feature = "npz")]
+pub(super) fn read_i32<R: std::io::Read + std::io::Seek>(
     npz: &mut NpzReader<R>,
     name: &str,
 ) -> Result<Array1<i32>> {
     let arr: Array1<i32> = npz
         .by_name(name)
-        .with_context(|| format!("missing {}", name))?;
+        .with_context(|| format!("read {}", name))?;
     Ok(arr)
 }
-fn read_i64<R: std::io::Read + std::io::Seek>(
+#[cfg(feature = "npz")]
+pub(super) fn read_i64<R: std::io::Read + std::io::Seek>(
     npz: &mut NpzReader<R>,
     name: &str,
 ) -> Result<Array1<i64>> {
     let arr: Array1<i64> = npz
         .by_name(name)
-        .with_context(|| format!("missing {}", name))?;
+        .with_context(|| format!("read {}", name))?;
     Ok(arr)
 }
-fn read_f64<R: std::io::Read + std::io::Seek>(
+#[cfg(feature = "npz")]
+pub(super) fn read_f64<R: std::io::Read + std::io::Seek>(
     npz: &mut NpzReader<R>,
     name: &str,
 ) -> Result<Array1<f64>> {
     let arr: Array1<f64> = npz
         .by_name(name)
-        .with_context(|| format!("missing {}", name))?;
+        .with_context(|| format!("read {}", name))?;
     Ok(arr)
 }
 
+/// Row offsets as saved with either index width.
+#[cfg(feature = "npz")]
+pub(super) fn read_indptr<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+    name: &str,
+) -> Result<Indptr> {
+    if let Ok(wide) = npz.by_name::<ndarray::OwnedRepr<i64>, ndarray::Ix1>(name) {
+        if let Some(bad) = wide.iter().find(|&&o| o < 0) {
+            bail!("{} has a negative offset {}", name, bad);
+        }
+        return Ok(Indptr::from_i64(wide.into_raw_vec()));
+    }
+    let narrow: Array1<u32> = npz
+        .by_name(name)
+        .with_context(|| format!("read {} (as i64 or u32)", name))?;
+    Ok(Indptr::Narrow(narrow.into_raw_vec().into()))
+}
+
+/// Exact weights of bucket `b` (`b{b}_exact_rows`, and `b{b}_exact_limbs` with
+/// one row of little-endian u64 limbs each), if it has any. `names` are the
+/// snapshot's members.
+#[cfg(feature = "npz")]
+pub(super) fn read_exact<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+    b: usize,
+    names: &HashSet<String>,
+) -> Result<Vec<(usize, BigUint)>> {
+    let rows_name = format!("b{}_exact_rows.npy", b);
+    if !names.contains(&rows_name) {
+        return Ok(Vec::new());
+    }
+    let rows = read_i64(npz, &rows_name)?;
+    let limbs_name = format!("b{}_exact_limbs.npy", b);
+    let limbs: Array2<u64> = npz
+        .by_name(&limbs_name)
+        .with_context(|| format!("read {}", limbs_name))?;
+    if limbs.nrows() != rows.len() {
+        bail!(
+            "{} has {} rows for {} exact weights",
+            limbs_name,
+            limbs.nrows(),
+            rows.len()
+        );
+    }
+    rows.iter()
+        .zip(limbs.outer_iter())
+        .map(|(&r, l)| {
+            let r = usize::try_from(r).with_context(|| format!("{} has row {}", rows_name, r))?;
+            let bytes: Vec<u8> = l.iter().flat_map(|d| d.to_le_bytes()).collect();
+            Ok((r, BigUint::from_bytes_le(&bytes)))
+        })
+        .collect()
+}
+
+/// Integer width of the row offsets written by `save_snapshot`
+/// (`ENUM_INDEX_WIDTH=32|64`, default 64 for existing readers).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexWidth {
+    U32,
+    #[default]
+    I64,
+}
+
+impl std::str::FromStr for IndexWidth {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "64" => Ok(IndexWidth::I64),
+            "32" => Ok(IndexWidth::U32),
+            other => bail!("unknown index width {:?} (expected 32|64)", other),
+        }
+    }
+}
+
+/// Bucket `b` must be a well-formed CSR over `jbt_ref_pop`, so that
+/// `row_slice` and the solver's `jbt_ref_pop[j]` lookups cannot go out of range.
+#[cfg_attr(
+    not(any(feature = "npz", feature = "zstd", feature = "arrow")),
+    allow(dead_code)
+)]
+pub(super) fn check_bucket(bucket: &Bucket, b: usize, m: usize) -> Result<()> {
+    let p = &bucket.indptr;
+    if p.len() != bucket.weights.len() + 1 {
+        bail!(
+            "bucket {}: {} row offsets for {} weights (expected weights + 1)",
+            b,
+            p.len(),
+            bucket.weights.len()
+        );
+    }
+    if p.get(0) != 0 || p.get(p.len() - 1) != bucket.rows_data.len() {
+        bail!(
+            "bucket {}: row offsets span {}..{}, expected 0..{}",
+            b,
+            p.get(0),
+            p.get(p.len() - 1),
+            bucket.rows_data.len()
+        );
+    }
+    if let Some(r) = (1..p.len()).find(|&r| p.get(r) < p.get(r - 1)) {
+        bail!("bucket {}: row offsets decrease at row {}", b, r);
+    }
+    if let Some(&j) = bucket.rows_data.iter().find(|&&j| j < 0 || j as usize >= m) {
+        bail!("bucket {}: j index {} outside jbt_ref_pop (M={})", b, j, m);
+    }
+    if bucket.exact.windows(2).any(|w| w[1].0 <= w[0].0) {
+        bail!("bucket {}: exact weight rows are not ascending", b);
+    }
+    for (r, x) in &bucket.exact {
+        if *r >= bucket.weights.len() {
+            bail!(
+                "bucket {}: exact weight for row {} of {}",
+                b,
+                r,
+                bucket.weights.len()
+            );
+        }
+        if x.to_f64() != Some(bucket.weights[*r]) {
+            bail!(
+                "bucket {}: row {} has exact weight {} but weight {}",
+                b,
+                r,
+                x,
+                bucket.weights[*r]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Load an NPZ or binary (`.snap.zst`) snapshot, map a snapshot view
+/// (`view::pack_view`), or read an Arrow IPC file (`arrow::write_ipc`,
+/// feature "arrow").
+///
+/// Readers take no locks and open the file once, read-only, so any number of
+/// processes may load one snapshot at a time, also over NFS; writers
+/// (`save_snapshot`, `pack-view`, `reweight`) rename a finished file over it,
+/// so a reader sees the old snapshot or the new one, never a partial one.
 pub fn load_snapshot(path: &str) -> Result<Snapshot> {
-    let f = File::open(path).with_context(|| format!("open {}", path))?;
-    let mut npz = NpzReader::new(f).context("read npz")?;
+    let _phase = crate::telemetry::phase("load");
+    let mut f = File::open(path).with_context(|| format!("open {}", path))?;
+    if super::view::starts_as_view(&mut f).with_context(|| format!("read {}", path))? {
+        return super::view::map_view(&f, path);
+    }
+    if zst::is_zst(path) {
+        return zst::read(f, path);
+    }
+    if super::arrow::starts_as_ipc(&mut f).with_context(|| format!("read {}", path))? {
+        return super::arrow::read_ipc(f, path);
+    }
+    load_snapshot_from(f, path)
+}
+
+/// Where a file meant for `path` is written before it is renamed over it: a
+/// dotfile beside it (same filesystem, skipped by `watch`), named by process
+/// so that two writers of one path do not share it.
+pub(crate) fn temp_beside(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
 
-    let n_total = read_i32(&mut npz, "meta_N.npy")?[0];
+/// Rename the finished file `tmp` over `path`, then sync the directory that
+/// holds them so the rename itself survives a crash (the file's contents
+/// must already be synced).
+pub(crate) fn replace_file(tmp: &Path, path: &Path) -> Result<()> {
+    std::fs::rename(tmp, path)
+        .with_context(|| format!("rename {} to {}", tmp.display(), path.display()))?;
+    sync_dir(path)
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("sync {}", dir.display()))
+}
+
+/// Directories cannot be opened for syncing here; the rename is left to the OS.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// `load_snapshot` over any seekable reader; `path` only labels messages.
+/// Malformed contents are reported as errors, never panics (see `fuzz/`).
+#[cfg(feature = "npz")]
+pub fn load_snapshot_from<R: std::io::Read + std::io::Seek>(
+    reader: R,
+    path: &str,
+) -> Result<Snapshot> {
+    let mut buckets = Vec::new();
+    let mut snap = read_snapshot_with(reader, path, |_, bucket| {
+        buckets.push(bucket);
+        Ok(())
+    })?;
+    snap.buckets = buckets;
+    Ok(snap)
+}
+
+/// Without the "npz" feature there is no NPZ reader; every file is refused.
+#[cfg(not(feature = "npz"))]
+pub fn load_snapshot_from<R: std::io::Read + std::io::Seek>(
+    _reader: R,
+    path: &str,
+) -> Result<Snapshot> {
+    bail!(
+        "{} is read as an NPZ snapshot, and this matcher was built without the \"npz\" feature",
+        path
+    )
+}
+
+/// `load_snapshot_from`, handing each checked bucket to `on_bucket` as it is
+/// read instead of keeping it: the returned snapshot has no buckets.
+#[cfg(feature = "npz")]
+pub(super) fn read_snapshot_with<R: std::io::Read + std::io::Seek>(
+    reader: R,
+    path: &str,
+    mut on_bucket: impl FnMut(usize, Bucket) -> Result<()>,
+) -> Result<Snapshot> {
+    let reader = check_member_sizes(reader, path)?;
+    let mut npz = NpzReader::new(reader).context("read npz")?;
+
+    let n_total = *read_i32(&mut npz, "meta_N.npy")?
+        .first()
+        .context("meta_N is empty")?;
+    if n_total <= 0 {
+        bail!("snapshot {}: meta_N={} must be positive", path, n_total);
+    }
     let jbt_ref_pop = read_i32(&mut npz, "meta_jbt_ref_pop.npy")?.to_vec();
 
+    let keys_data = read_i32(&mut npz, "meta_bucket_keys_data.npy")?;
     let keys_indptr = read_i64(&mut npz, "meta_bucket_keys_indptr.npy")?;
-    let num_buckets = if keys_indptr.len() == 0 {
+    let num_buckets = if keys_indptr.is_empty() {
         0
     } else {
         keys_indptr.len() - 1
     };
+    if num_buckets == 0 {
+        return Err(EmptyDataError(format!("snapshot {} has zero buckets", path)).into());
+    }
+    check_offsets(
+        "meta_bucket_keys_indptr",
+        keys_indptr.as_slice().unwrap_or(&[]),
+        keys_data.len(),
+    )
+    .with_context(|| format!("snapshot {}", path))?;
+    // every bucket is four members; more buckets than that is a corrupt count
+    let names: HashSet<String> = npz
+        .names()
+        .context("list snapshot members")?
+        .into_iter()
+        .collect();
+    let members = names.len();
+    if num_buckets > members / 4 {
+        return Err(SchemaError(format!(
+            "snapshot {}: meta_bucket_keys_indptr lists {} buckets, the file has {} members",
+            path, num_buckets, members
+        ))
+        .into());
+    }
 
-    let mut buckets = Vec::with_capacity(num_buckets);
     for b in 0..num_buckets {
-        let rows_data = read_i32(&mut npz, &format!("b{}_rows_data.npy", b))?.to_vec();
-        let indptr = read_i64(&mut npz, &format!("b{}_rows_indptr.npy", b))?.to_vec();
-        let weights = read_f64(&mut npz, &format!("b{}_weights.npy", b))?.to_vec();
+        let rows_data = read_i32(&mut npz, &format!("b{}_rows_data.npy", b))?.into_raw_vec();
+        let indptr = read_indptr(&mut npz, &format!("b{}_rows_indptr.npy", b))?;
+        let weights = read_f64(&mut npz, &format!("b{}_weights.npy", b))?.into_raw_vec();
         let key = read_i32(&mut npz, &format!("b{}_key.npy", b))?.to_vec();
-        buckets.push(Bucket {
-            rows_data,
+        let exact = read_exact(&mut npz, b, &names)?;
+        let listed = &keys_data.as_slice().unwrap_or(&[])
+            [keys_indptr[b] as usize..keys_indptr[b + 1] as usize];
+        if key != listed {
+            return Err(SchemaError(format!(
+                "snapshot {}: b{}_key {:?} differs from meta_bucket_keys {:?}",
+                path, b, key, listed
+            ))
+            .into());
+        }
+        let bucket = Bucket {
+            rows_data: rows_data.into(),
             indptr,
-            weights,
+            weights: weights.into(),
+            exact,
             key,
-        });
+        };
+        check_bucket(&bucket, b, jbt_ref_pop.len())
+            .with_context(|| format!("snapshot {}", path))?;
+        // one NaN or inf would silently poison every pair the bucket is in
+        if let Some((r, w)) = bucket.first_non_finite_weight() {
+            bail!(
+                "snapshot {}: bucket {} {:?} row {} has weight {}; weights must be finite",
+                path,
+                b,
+                bucket.key,
+                r,
+                w
+            );
+        }
+        on_bucket(b, bucket)?;
     }
 
     // compat tables (pop -> (key1, key2))
@@ -67,76 +364,976 @@ pub fn load_snapshot(path: &str) -> Result<Snapshot> {
     for p in compat_pops.iter() {
         let k1 = read_i32(&mut npz, &format!("compat_p{}_key1.npy", p))?.to_vec();
         let k2 = read_i32(&mut npz, &format!("compat_p{}_key2.npy", p))?.to_vec();
+        if k1.len() != k2.len() {
+            bail!(
+                "snapshot {}: compat_p{} key1/key2 lengths differ ({} vs {})",
+                path,
+                p,
+                k1.len(),
+                k2.len()
+            );
+        }
         compat.insert(*p, (k1, k2));
     }
 
-    Ok(Snapshot {
-        buckets,
+    let saturations = read_saturations(&mut npz, n_total)?;
+    let stats = read_stats(&mut npz, num_buckets)?;
+    let counts = read_counts(&mut npz, num_buckets)?;
+    let pruning = read_pruning(&mut npz)?;
+    let truncated = read_truncation(&mut npz, num_buckets)?;
+    let fingerprint = read_fingerprint(&mut npz)?;
+    let snap = Snapshot {
+        buckets: Vec::new(),
         jbt_ref_pop,
         n_total,
         compat,
-    })
+        saturations,
+        stats,
+        counts,
+        pruning,
+        truncated,
+        fingerprint,
+    };
+    warn_snapshot_labels(path, &snap);
+    Ok(snap)
+}
+
+/// `[warn]` lines for a truncated, pruned or saturated snapshot.
+#[cfg_attr(
+    not(any(feature = "npz", feature = "zstd", feature = "arrow")),
+    allow(dead_code)
+)]
+pub(super) fn warn_snapshot_labels(path: &str, snap: &Snapshot) {
+    if let Some(t) = &snap.truncated {
+        eprintln!(
+            "[warn] snapshot {} is truncated ({}); Omega from it is partial",
+            path, t
+        );
+    }
+    if let Some(p) = &snap.pruning {
+        eprintln!(
+            "[warn] snapshot {} was pruned at build ({}); Omega is approximate",
+            path, p
+        );
+    }
+    if !snap.saturations.is_empty() {
+        let clamped: u64 = snap.saturations.iter().map(|s| s.count).sum();
+        eprintln!(
+            "[warn] snapshot {} has {} weights clamped at enumeration; affected counts are lower bounds:",
+            path, clamped
+        );
+        warn_saturation_sites(&snap.saturations);
+    }
+}
+
+/// `meta_truncated` = [written, total] buckets, if the save was cancelled.
+#[cfg(feature = "npz")]
+pub(super) fn read_truncation<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+    num_buckets: usize,
+) -> Result<Option<Truncation>> {
+    if !npz
+        .names()
+        .context("list snapshot members")?
+        .iter()
+        .any(|name| name == "meta_truncated.npy")
+    {
+        return Ok(None);
+    }
+    let v = read_i64(npz, "meta_truncated.npy")?;
+    let &[written, total] = v.as_slice().unwrap_or(&[]) else {
+        bail!("meta_truncated has {} entries, expected 2", v.len());
+    };
+    if written != num_buckets as i64 || total < written {
+        return Err(SchemaError(format!(
+            "meta_truncated says {} of {} buckets, the file lists {}",
+            written, total, num_buckets
+        ))
+        .into());
+    }
+    Ok(Some(Truncation {
+        written: written as usize,
+        total: total as usize,
+    }))
+}
+
+/// Whether the snapshot at `path` is labeled truncated by a cancelled save.
+/// Unreadable files count as not truncated; loading them reports the error.
+pub fn snapshot_is_truncated(path: &str) -> bool {
+    if zst::is_zst(path) {
+        return zst::is_truncated(path);
+    }
+    #[cfg(not(feature = "npz"))]
+    return false;
+    #[cfg(feature = "npz")]
+    File::open(path)
+        .ok()
+        .and_then(|f| NpzReader::new(f).ok())
+        .and_then(|mut npz| npz.names().ok())
+        .is_some_and(|names| names.iter().any(|n| n == "meta_truncated.npy"))
+}
+
+/// `meta_fingerprint` = the fingerprint's ASCII bytes, if recorded.
+#[cfg(feature = "npz")]
+fn read_fingerprint<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+) -> Result<Option<String>> {
+    if !npz
+        .names()
+        .context("list snapshot members")?
+        .iter()
+        .any(|name| name == "meta_fingerprint.npy")
+    {
+        return Ok(None);
+    }
+    let bytes: Array1<u8> = npz
+        .by_name("meta_fingerprint.npy")
+        .context("read meta_fingerprint.npy")?;
+    let text = String::from_utf8(bytes.to_vec())
+        .map_err(|_| SchemaError("meta_fingerprint is not ASCII".into()))?;
+    Ok(Some(text))
+}
+
+/// The fingerprint recorded in the snapshot at `path` (see
+/// `Snapshot::fingerprint`), read without loading its buckets. `None` when
+/// there is none or the file cannot be read; loading it reports the error.
+pub fn snapshot_fingerprint(path: &str) -> Option<String> {
+    if zst::is_zst(path) {
+        return zst::read_fingerprint(path);
+    }
+    #[cfg(not(feature = "npz"))]
+    return None;
+    #[cfg(feature = "npz")]
+    {
+        let f = File::open(path).ok()?;
+        let mut npz = NpzReader::new(f).ok()?;
+        read_fingerprint(&mut npz).ok().flatten()
+    }
 }
 
-pub fn save_snapshot(path: &str, snap: &Snapshot) -> Result<()> {
-    let f = File::create(path).with_context(|| format!("create {}", path))?;
-    let mut npz = NpzWriter::new(f);
+/// `meta_prune` = [min_weight, states, weight, total_weight], if pruned.
+#[cfg(feature = "npz")]
+pub(super) fn read_pruning<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+) -> Result<Option<Pruning>> {
+    if !npz
+        .names()
+        .context("list snapshot members")?
+        .iter()
+        .any(|name| name == "meta_prune.npy")
+    {
+        return Ok(None);
+    }
+    let v = read_f64(npz, "meta_prune.npy")?;
+    let [min_weight, states, weight, total_weight] = v.as_slice().unwrap_or(&[]) else {
+        bail!("meta_prune has {} entries, expected 4", v.len());
+    };
+    Ok(Some(Pruning {
+        min_weight: *min_weight,
+        states: *states as u64,
+        weight: *weight,
+        total_weight: *total_weight,
+    }))
+}
 
-    npz.add_array("meta_N.npy", &Array1::from_vec(vec![snap.n_total]))?;
-    npz.add_array(
-        "meta_jbt_ref_pop.npy",
-        &Array1::from_vec(snap.jbt_ref_pop.clone()),
+/// `meta_hist_k_*` / `meta_bucket_{states,weight}`, if the snapshot has them.
+#[cfg(feature = "npz")]
+pub(super) fn read_stats<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+    num_buckets: usize,
+) -> Result<Option<SnapshotStats>> {
+    if !npz
+        .names()
+        .context("list snapshot members")?
+        .iter()
+        .any(|name| name == "meta_hist_k_states.npy")
+    {
+        return Ok(None);
+    }
+    let k_states = read_i64(npz, "meta_hist_k_states.npy")?;
+    let k_weight = read_f64(npz, "meta_hist_k_weight.npy")?;
+    let bucket_states = read_i64(npz, "meta_bucket_states.npy")?;
+    let bucket_weight = read_f64(npz, "meta_bucket_weight.npy")?;
+    if k_weight.len() != k_states.len()
+        || bucket_states.len() != num_buckets
+        || bucket_weight.len() != num_buckets
+    {
+        bail!("snapshot statistics disagree in length with the buckets");
+    }
+    Ok(Some(SnapshotStats {
+        k_states: k_states.iter().map(|&s| s as u64).collect(),
+        k_weight: k_weight.to_vec(),
+        bucket_states: bucket_states.iter().map(|&s| s as u64).collect(),
+        bucket_weight: bucket_weight.to_vec(),
+    }))
+}
+
+/// `meta_frontier_states` / `meta_bucket_completed`, if the snapshot has them.
+#[cfg(feature = "npz")]
+pub(super) fn read_counts<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+    num_buckets: usize,
+) -> Result<Option<EnumCounts>> {
+    if !npz
+        .names()
+        .context("list snapshot members")?
+        .iter()
+        .any(|name| name == "meta_frontier_states.npy")
+    {
+        return Ok(None);
+    }
+    let frontier = read_i64(npz, "meta_frontier_states.npy")?;
+    let completed = read_i64(npz, "meta_bucket_completed.npy")?;
+    let [frontier_states] = frontier.as_slice().unwrap_or(&[]) else {
+        bail!(
+            "meta_frontier_states has {} entries, expected 1",
+            frontier.len()
+        );
+    };
+    if completed.len() != num_buckets {
+        bail!(
+            "meta_bucket_completed has {} entries for {} buckets",
+            completed.len(),
+            num_buckets
+        );
+    }
+    Ok(Some(EnumCounts {
+        frontier_states: *frontier_states as u64,
+        bucket_completed: completed.iter().map(|&c| c as u64).collect(),
+    }))
+}
+
+/// Saturation provenance (`meta_saturation_*`); absent in snapshots whose
+/// weights never clamped and in snapshots written before it was recorded.
+#[cfg(feature = "npz")]
+pub(super) fn read_saturations<R: std::io::Read + std::io::Seek>(
+    npz: &mut NpzReader<R>,
+    n_total: i32,
+) -> Result<Vec<Saturation>> {
+    if !npz
+        .names()
+        .context("list snapshot members")?
+        .iter()
+        .any(|name| name == "meta_saturation_roots.npy")
+    {
+        return Ok(Vec::new());
+    }
+    let roots = read_i32(npz, "meta_saturation_roots.npy")?;
+    let counts = read_i64(npz, "meta_saturation_counts.npy")?;
+    let in_frontier = read_i32(npz, "meta_saturation_in_frontier.npy")?;
+    let key_data = read_i32(npz, "meta_saturation_keys_data.npy")?;
+    let key_indptr = read_i64(npz, "meta_saturation_keys_indptr.npy")?;
+    if counts.len() != roots.len()
+        || in_frontier.len() != roots.len()
+        || key_indptr.len() != roots.len() + 1
+    {
+        bail!("meta_saturation_* arrays disagree in length");
+    }
+    check_offsets(
+        "meta_saturation_keys_indptr",
+        key_indptr.as_slice().unwrap_or(&[]),
+        key_data.len(),
     )?;
+    let n = n_total.max(1) as usize;
+    (0..roots.len())
+        .map(|i| {
+            let (lo, hi) = (key_indptr[i] as usize, key_indptr[i + 1] as usize);
+            let key = key_data
+                .as_slice()
+                .and_then(|d| d.get(lo..hi))
+                .context("meta_saturation_keys_indptr out of range")?
+                .to_vec();
+            let root = roots[i] as usize;
+            Ok(Saturation {
+                root: (root / n, root % n),
+                key: (in_frontier[i] == 0).then_some(key),
+                count: counts[i] as u64,
+            })
+        })
+        .collect()
+}
 
-    let mut key_data: Vec<i32> = Vec::new();
-    let mut key_indptr: Vec<i64> = Vec::with_capacity(snap.buckets.len() + 1);
-    key_indptr.push(0);
+/// Write `snap` to `path` (binary when it ends in `.snap.zst`, see `zst`,
+/// NPZ otherwise), with a progress bar over buckets. If `cancel`
+/// fires, the bucket being written is finished and the rest are left out: the
+/// file is still a loadable snapshot, labeled with `meta_truncated` (statistics
+/// cover the saved buckets only), and the call returns `Cancelled`. Any other
+/// failure removes the partly written file.
+pub fn save_snapshot(
+    path: &str,
+    snap: &Snapshot,
+    width: IndexWidth,
+    cancel: &CancelToken,
+) -> Result<()> {
+    if width == IndexWidth::U32
+        && let Some((idx, b)) = snap
+            .buckets
+            .iter()
+            .enumerate()
+            .find(|(_, b)| b.rows_data.len() > u32::MAX as usize)
+    {
+        bail!(
+            "ENUM_INDEX_WIDTH=32: bucket {} {:?} has {} row entries, past the u32 range; save with 64",
+            idx,
+            b.key,
+            b.rows_data.len()
+        );
+    }
+    let mut phase = crate::telemetry::phase("save");
+    phase.attr("matcher.buckets", snap.buckets.len() as u64);
+    if zst::is_zst(path) {
+        return save_binary(path, snap, cancel);
+    }
+    save_npz(path, snap, width, cancel)
+}
+
+/// `save_snapshot` for an NPZ path, through a `SnapshotWriter`.
+#[cfg(feature = "npz")]
+fn save_npz(path: &str, snap: &Snapshot, width: IndexWidth, cancel: &CancelToken) -> Result<()> {
+    let mut w = SnapshotWriter::create(path, width)?;
+    let pb = resumable_progress_bar(snap.buckets.len() as u64, "buckets", 0);
     for bucket in &snap.buckets {
-        key_data.extend(bucket.key.iter().copied());
-        let last = *key_indptr.last().unwrap();
-        key_indptr.push(last + bucket.key.len() as i64);
+        if cancel.is_cancelled() {
+            break;
+        }
+        w.add_bucket(bucket)?;
+        pb.inc(1);
     }
-    npz.add_array("meta_bucket_keys_data.npy", &Array1::from_vec(key_data))?;
-    npz.add_array("meta_bucket_keys_indptr.npy", &Array1::from_vec(key_indptr))?;
+    pb.finish_and_clear();
+    w.finish(snap, snap.buckets.len())
+}
 
-    for (idx, bucket) in snap.buckets.iter().enumerate() {
+/// Without the "npz" feature there is no NPZ writer; the save is refused.
+#[cfg(not(feature = "npz"))]
+fn save_npz(path: &str, _snap: &Snapshot, _width: IndexWidth, _cancel: &CancelToken) -> Result<()> {
+    bail!(
+        "{} is saved as an NPZ snapshot, and this matcher was built without the \"npz\" feature",
+        path
+    )
+}
+
+/// `save_snapshot` for a `.snap.zst` path, written beside it and renamed over
+/// it as `SnapshotWriter` does.
+fn save_binary(path: &str, snap: &Snapshot, cancel: &CancelToken) -> Result<()> {
+    let tmp = temp_beside(Path::new(path));
+    let f = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+    let res = zst::write(f, path, snap, cancel);
+    // a cancelled save is complete and labeled truncated, and is kept
+    let keep = match &res {
+        Ok(()) => true,
+        Err(err) => err.downcast_ref::<Cancelled>().is_some(),
+    };
+    if !keep {
+        if std::fs::remove_file(&tmp).is_ok() {
+            eprintln!("[save] removed the incomplete {}", tmp.display());
+        }
+        return res;
+    }
+    replace_file(&tmp, Path::new(path))?;
+    res
+}
+
+/// A snapshot file written one bucket at a time, straight from the bucket's
+/// arrays: `add_bucket` as each bucket is ready, then `finish` with the rest
+/// of the snapshot. The file is written beside `path` and renamed over it by
+/// `finish`, since a half-written zip at `path` would fail concurrent readers
+/// and pass for a fresh snapshot; dropped unfinished, the partial file is
+/// removed.
+#[cfg(feature = "npz")]
+pub struct SnapshotWriter {
+    npz: Option<NpzWriter<File>>,
+    tmp: PathBuf,
+    path: String,
+    width: IndexWidth,
+    /// Keys of the buckets added so far, in order.
+    keys: Vec<Vec<i32>>,
+    /// Statistics of the buckets added so far.
+    stats: SnapshotStats,
+    kept: bool,
+    /// Time spent writing, apart from building what is written.
+    spent: Duration,
+}
+
+#[cfg(feature = "npz")]
+impl SnapshotWriter {
+    pub fn create(path: &str, width: IndexWidth) -> Result<Self> {
+        let tmp = temp_beside(Path::new(path));
+        let f = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+        Ok(SnapshotWriter {
+            npz: Some(NpzWriter::new(f)),
+            tmp,
+            path: path.to_string(),
+            width,
+            keys: Vec::new(),
+            stats: SnapshotStats::default(),
+            kept: false,
+            spent: Duration::ZERO,
+        })
+    }
+
+    /// Buckets added so far.
+    pub fn buckets(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn npz(&mut self) -> &mut NpzWriter<File> {
+        self.npz
+            .as_mut()
+            .expect("snapshot writer used after finish")
+    }
+
+    /// Write `bucket` as the next bucket. Only a row-offset column stored at
+    /// the other index width is converted (copied); the rest is written from
+    /// the bucket's own slices.
+    pub fn add_bucket(&mut self, bucket: &Bucket) -> Result<()> {
+        let idx = self.keys.len();
+        let width = self.width;
+        if width == IndexWidth::U32 && bucket.rows_data.len() > u32::MAX as usize {
+            bail!(
+                "ENUM_INDEX_WIDTH=32: bucket {} {:?} has {} row entries, past the u32 range; save with 64",
+                idx,
+                bucket.key,
+                bucket.rows_data.len()
+            );
+        }
+        crate::chaos::before_save_bucket(idx).with_context(|| format!("write {}", self.path))?;
+        let t0 = Instant::now();
+        let npz = self.npz();
         npz.add_array(
-            &format!("b{}_rows_data.npy", idx),
-            &Array1::from_vec(bucket.rows_data.clone()),
+            format!("b{}_rows_data.npy", idx),
+            &ArrayView1::from(&bucket.rows_data[..]),
         )?;
+        let name = format!("b{}_rows_indptr.npy", idx);
+        match (width, &bucket.indptr) {
+            (IndexWidth::I64, Indptr::Wide(v)) => npz.add_array(name, &ArrayView1::from(&v[..]))?,
+            (IndexWidth::U32, Indptr::Narrow(v)) => {
+                npz.add_array(name, &ArrayView1::from(&v[..]))?
+            }
+            (IndexWidth::I64, Indptr::Narrow(_)) => {
+                npz.add_array(name, &Array1::from_vec(bucket.indptr.to_i64()))?
+            }
+            (IndexWidth::U32, Indptr::Wide(_)) => {
+                let narrow = bucket.indptr.to_u32().context("row offset past u32")?;
+                npz.add_array(name, &Array1::from_vec(narrow))?
+            }
+        }
         npz.add_array(
-            &format!("b{}_rows_indptr.npy", idx),
-            &Array1::from_vec(bucket.indptr.clone()),
+            format!("b{}_weights.npy", idx),
+            &ArrayView1::from(&bucket.weights[..]),
         )?;
+        if !bucket.exact.is_empty() {
+            let rows: Vec<i64> = bucket.exact.iter().map(|&(r, _)| r as i64).collect();
+            let digits: Vec<Vec<u64>> = bucket
+                .exact
+                .iter()
+                .map(|(_, x)| x.to_u64_digits())
+                .collect();
+            let width = digits.iter().map(Vec::len).max().unwrap_or(0);
+            let limbs = Array2::from_shape_fn((digits.len(), width), |(i, k)| {
+                digits[i].get(k).copied().unwrap_or(0)
+            });
+            npz.add_array(format!("b{}_exact_rows.npy", idx), &Array1::from_vec(rows))?;
+            npz.add_array(format!("b{}_exact_limbs.npy", idx), &limbs)?;
+        }
         npz.add_array(
-            &format!("b{}_weights.npy", idx),
-            &Array1::from_vec(bucket.weights.clone()),
+            format!("b{}_key.npy", idx),
+            &ArrayView1::from(&bucket.key[..]),
         )?;
+        self.keys.push(bucket.key.clone());
+        self.stats.add(bucket);
+        self.spent += t0.elapsed();
+        Ok(())
+    }
+
+    /// Write the rest of `meta` (its buckets are not read: they are the ones
+    /// added) and move the file to its path. `total` is the number of buckets
+    /// the snapshot has; with fewer added, the file is labeled truncated, kept,
+    /// and `Cancelled` returned.
+    pub fn finish(mut self, meta: &Snapshot, total: usize) -> Result<()> {
+        let res = self.write_meta(meta, total);
+        // a cancelled save is complete and labeled truncated, and is kept
+        if res.is_ok()
+            || res
+                .as_ref()
+                .is_err_and(|e| e.downcast_ref::<Cancelled>().is_some())
+        {
+            replace_file(&self.tmp, Path::new(&self.path))?;
+            self.kept = true;
+        }
+        res
+    }
+
+    fn write_meta(&mut self, snap: &Snapshot, total: usize) -> Result<()> {
+        let t0 = Instant::now();
+        let path = self.path.clone();
+        let written = self.keys.len();
+        let mut npz = self.npz.take().expect("snapshot writer used after finish");
+        npz.add_array("meta_N.npy", &Array1::from_vec(vec![snap.n_total]))?;
         npz.add_array(
-            &format!("b{}_key.npy", idx),
-            &Array1::from_vec(bucket.key.clone()),
+            "meta_jbt_ref_pop.npy",
+            &ArrayView1::from(&snap.jbt_ref_pop[..]),
         )?;
-    }
 
-    let mut compat_pops: Vec<i32> = snap.compat.keys().copied().collect();
-    compat_pops.sort_unstable();
-    npz.add_array(
-        "meta_compat_pops.npy",
-        &Array1::from_vec(compat_pops.clone()),
-    )?;
-    for p in compat_pops {
-        if let Some((key1, key2)) = snap.compat.get(&p) {
+        // the key list covers exactly the buckets that made it into the file
+        let mut key_data: Vec<i32> = Vec::new();
+        let mut key_indptr: Vec<i64> = Vec::with_capacity(written + 1);
+        key_indptr.push(0);
+        for key in &self.keys {
+            key_data.extend(key.iter().copied());
+            key_indptr.push(key_data.len() as i64);
+        }
+        npz.add_array("meta_bucket_keys_data.npy", &Array1::from_vec(key_data))?;
+        npz.add_array("meta_bucket_keys_indptr.npy", &Array1::from_vec(key_indptr))?;
+
+        let mut compat_pops: Vec<i32> = snap.compat.keys().copied().collect();
+        compat_pops.sort_unstable();
+        npz.add_array("meta_compat_pops.npy", &ArrayView1::from(&compat_pops[..]))?;
+        for p in compat_pops {
+            if let Some((key1, key2)) = snap.compat.get(&p) {
+                npz.add_array(
+                    format!("compat_p{}_key1.npy", p),
+                    &ArrayView1::from(&key1[..]),
+                )?;
+                npz.add_array(
+                    format!("compat_p{}_key2.npy", p),
+                    &ArrayView1::from(&key2[..]),
+                )?;
+            }
+        }
+
+        let truncated = (written < total).then_some(Truncation { written, total });
+        let stats = match (&snap.stats, truncated) {
+            (Some(_), Some(_)) => Some(&self.stats),
+            (stats, None) => stats.as_ref(),
+            (None, Some(_)) => None,
+        };
+        if let Some(stats) = stats {
+            let as_i64 = |v: &[u64]| Array1::from_vec(v.iter().map(|&s| s as i64).collect());
+            npz.add_array("meta_hist_k_states.npy", &as_i64(&stats.k_states))?;
+            npz.add_array(
+                "meta_hist_k_weight.npy",
+                &ArrayView1::from(&stats.k_weight[..]),
+            )?;
+            npz.add_array("meta_bucket_states.npy", &as_i64(&stats.bucket_states))?;
+            npz.add_array(
+                "meta_bucket_weight.npy",
+                &ArrayView1::from(&stats.bucket_weight[..]),
+            )?;
+        }
+
+        if let Some(c) = &snap.counts {
+            npz.add_array(
+                "meta_frontier_states.npy",
+                &Array1::from_vec(vec![c.frontier_states as i64]),
+            )?;
+            let completed = c.bucket_completed.iter().take(written);
+            npz.add_array(
+                "meta_bucket_completed.npy",
+                &Array1::from_iter(completed.map(|&c| c as i64)),
+            )?;
+        }
+
+        if let Some(p) = &snap.pruning {
+            npz.add_array(
+                "meta_prune.npy",
+                &Array1::from_vec(vec![
+                    p.min_weight,
+                    p.states as f64,
+                    p.weight,
+                    p.total_weight,
+                ]),
+            )?;
+        }
+
+        if !snap.saturations.is_empty() {
+            let n = snap.n_total as usize;
+            let mut roots = Vec::with_capacity(snap.saturations.len());
+            let mut counts = Vec::with_capacity(snap.saturations.len());
+            let mut in_frontier = Vec::with_capacity(snap.saturations.len());
+            let mut key_data: Vec<i32> = Vec::new();
+            let mut key_indptr: Vec<i64> = vec![0];
+            for s in &snap.saturations {
+                roots.push((s.root.0 * n + s.root.1) as i32);
+                counts.push(s.count as i64);
+                in_frontier.push(s.key.is_none() as i32);
+                key_data.extend(s.key.iter().flatten().copied());
+                key_indptr.push(key_data.len() as i64);
+            }
+            npz.add_array("meta_saturation_roots.npy", &Array1::from_vec(roots))?;
+            npz.add_array("meta_saturation_counts.npy", &Array1::from_vec(counts))?;
+            npz.add_array(
+                "meta_saturation_in_frontier.npy",
+                &Array1::from_vec(in_frontier),
+            )?;
+            npz.add_array("meta_saturation_keys_data.npy", &Array1::from_vec(key_data))?;
             npz.add_array(
-                &format!("compat_p{}_key1.npy", p),
-                &Array1::from_vec(key1.clone()),
+                "meta_saturation_keys_indptr.npy",
+                &Array1::from_vec(key_indptr),
             )?;
+        }
+
+        if let Some(fp) = &snap.fingerprint {
+            npz.add_array("meta_fingerprint.npy", &ArrayView1::from(fp.as_bytes()))?;
+        }
+
+        if let Some(t) = truncated {
             npz.add_array(
-                &format!("compat_p{}_key2.npy", p),
-                &Array1::from_vec(key2.clone()),
+                "meta_truncated.npy",
+                &Array1::from_vec(vec![t.written as i64, t.total as i64]),
             )?;
         }
+
+        npz.finish()?
+            .sync_all()
+            .with_context(|| format!("sync {}", path))?;
+        if let Some(t) = truncated {
+            return Err(Cancelled(format!(
+                "snapshot {}: {}; the file is labeled truncated",
+                path, t
+            ))
+            .into());
+        }
+        eprintln!(
+            "[save] {} buckets written to {} in {}",
+            total,
+            path,
+            Secs::from(self.spent + t0.elapsed())
+        );
+        Ok(())
     }
+}
 
-    npz.finish()?;
-    Ok(())
+#[cfg(feature = "npz")]
+impl Drop for SnapshotWriter {
+    fn drop(&mut self) {
+        if !self.kept && std::fs::remove_file(&self.tmp).is_ok() {
+            eprintln!("[save] removed the incomplete {}", self.tmp.display());
+        }
+    }
+}
+
+fn parse_key_text(text: &str) -> Result<Vec<i32>> {
+    let inner = text.trim().trim_start_matches('(').trim_end_matches(')');
+    inner
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            t.parse::<i32>()
+                .ok()
+                .filter(|p| *p >= 0)
+                .with_context(|| format!("bad pop {:?} in key {:?}", t, text))
+        })
+        .collect()
+}
+
+fn parse_key_json(v: &serde_json::Value) -> Result<Vec<i32>> {
+    let arr = v
+        .as_array()
+        .with_context(|| format!("key must be an array of pops, got {}", v))?;
+    arr.iter()
+        .map(|p| {
+            p.as_i64()
+                .and_then(|p| i32::try_from(p).ok())
+                .filter(|p| *p >= 0)
+                .with_context(|| format!("bad pop {} in key {}", p, v))
+        })
+        .collect()
+}
+
+/// Load explicit (left key, right key) pairs for `MatchConfig::pair_whitelist`.
+/// - `.json`: `[[[1,2],[8,9]], ...]` or `[{"left": [1,2], "right": [8,9]}, ...]`
+/// - otherwise one pair per line as `left | right`, e.g. `1,2 | 8,9`;
+///   `()` or an empty side is the neutral key, `#` starts a comment.
+pub fn load_pair_whitelist(path: &str) -> Result<Vec<(Vec<i32>, Vec<i32>)>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path))?;
+    let mut pairs = Vec::new();
+
+    if path.ends_with(".json") {
+        let doc: serde_json::Value =
+            serde_json::from_str(&text).with_context(|| format!("parse {}", path))?;
+        let entries = doc
+            .as_array()
+            .with_context(|| format!("{}: expected a top-level array of pairs", path))?;
+        for e in entries {
+            let (l, r) = match e {
+                serde_json::Value::Array(a) if a.len() == 2 => (&a[0], &a[1]),
+                serde_json::Value::Object(o) => (
+                    o.get("left").context("pair object missing \"left\"")?,
+                    o.get("right").context("pair object missing \"right\"")?,
+                ),
+                other => bail!("{}: bad pair entry {}", path, other),
+            };
+            pairs.push((parse_key_json(l)?, parse_key_json(r)?));
+        }
+        return Ok(pairs);
+    }
+
+    for (lineno, raw) in text.lines().enumerate() {
+        let line = raw.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((l, r)) = line.split_once('|') else {
+            bail!(
+                "{}:{}: expected `left | right`, got {:?}",
+                path,
+                lineno + 1,
+                raw
+            );
+        };
+        let left = parse_key_text(l).with_context(|| format!("{}:{}", path, lineno + 1))?;
+        let right = parse_key_text(r).with_context(|| format!("{}:{}", path, lineno + 1))?;
+        pairs.push((left, right));
+    }
+    Ok(pairs)
+}
+
+/// Write per-pair results and Omega as JSON (`--results-json`), for `matcher compare`.
+/// `run` describes the run (`why::run_info`) for `matcher why`.
+pub fn save_results_json(
+    path: &str,
+    results: &[PairResult],
+    wall: f64,
+    run: &serde_json::Value,
+    checkpoint: &Checkpoint,
+) -> Result<()> {
+    let pairs: Vec<serde_json::Value> = results
+        .iter()
+        .map(|r| {
+            serde_json::json!({
+                "key_left": r.key_left,
+                "key_right": r.key_right,
+                "rows1": r.rows1,
+                "rows2": r.rows2,
+                "factor": r.factor,
+                "weight_mass": r.weight_mass,
+                "subtotal": r.subtotal,
+                "t_index": r.t_index,
+                "t_cands": r.t_cands,
+                "t_solve": r.t_solve,
+                "t_total": r.t_total,
+                "t_start": r.t_start,
+                "worker": r.worker,
+                "skipped": r.skipped,
+                "subtotal_exact": r.exact.as_ref().map(|x| x.to_string()),
+            })
+        })
+        .collect();
+    let doc = serde_json::json!({
+        "omega": super::driver::omega_sum(results),
+        "omega_exact": exact_omega(results).map(|x| x.to_string()),
+        "wall": wall,
+        "run": run,
+        "checkpoint": checkpoint.to_json(),
+        "pairs": pairs,
+    });
+    let text = serde_json::to_string_pretty(&doc)?;
+    std::fs::write(path, text + "\n").with_context(|| format!("write {}", path))
+}
+
+/// Read back a `save_results_json` file. Timings default to 0 when absent.
+pub fn load_results_json(path: &str) -> Result<Vec<PairResult>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path))?;
+    let doc: serde_json::Value =
+        serde_json::from_str(&text).with_context(|| format!("parse {}", path))?;
+    let pairs = doc
+        .get("pairs")
+        .and_then(|p| p.as_array())
+        .with_context(|| format!("{}: expected an object with a \"pairs\" array", path))?;
+    let num = |e: &serde_json::Value, k: &str| e.get(k).and_then(|v| v.as_f64()).unwrap_or(0.0);
+    pairs
+        .iter()
+        .map(|e| {
+            let subtotal = e
+                .get("subtotal")
+                .and_then(|v| v.as_f64())
+                .with_context(|| format!("{}: pair without subtotal: {}", path, e))?;
+            Ok(PairResult {
+                key_left: parse_key_json(e.get("key_left").unwrap_or(&serde_json::Value::Null))?,
+                key_right: parse_key_json(e.get("key_right").unwrap_or(&serde_json::Value::Null))?,
+                rows1: num(e, "rows1") as usize,
+                rows2: num(e, "rows2") as usize,
+                subtotal,
+                t_index: num(e, "t_index"),
+                t_cands: num(e, "t_cands"),
+                t_solve: num(e, "t_solve"),
+                t_total: num(e, "t_total"),
+                factor: e.get("factor").and_then(|v| v.as_f64()).unwrap_or(1.0),
+                weight_mass: num(e, "weight_mass"),
+                t_start: num(e, "t_start"),
+                worker: num(e, "worker") as usize,
+                skipped: e
+                    .get("skipped")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string),
+                exact: match e.get("subtotal_exact").and_then(|v| v.as_str()) {
+                    Some(s) => {
+                        Some(s.parse().with_context(|| {
+                            format!("{}: pair with subtotal_exact {:?}", path, s)
+                        })?)
+                    }
+                    None => None,
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "npz")]
+    use {crate::matching::types::Indptr, std::collections::HashMap, std::path::PathBuf};
+
+    #[cfg(feature = "npz")]
+    fn snapshot(weights: [f64; 2]) -> Snapshot {
+        Snapshot {
+            buckets: vec![Bucket {
+                rows_data: vec![0, 1].into(),
+                indptr: Indptr::from_i64(vec![0, 1, 2]),
+                weights: weights.to_vec().into(),
+                exact: Vec::new(),
+                key: vec![1],
+            }],
+            jbt_ref_pop: vec![1, 1],
+            n_total: 2,
+            compat: HashMap::new(),
+            saturations: Vec::new(),
+            stats: None,
+            counts: None,
+            pruning: None,
+            truncated: None,
+            fingerprint: None,
+        }
+    }
+
+    #[cfg(feature = "npz")]
+    fn weights(snap: &Snapshot) -> Vec<f64> {
+        snap.buckets[0].weights.to_vec()
+    }
+
+    #[cfg(feature = "npz")]
+    #[test]
+    fn readers_keep_the_snapshot_they_opened_when_it_is_replaced() {
+        let dir = std::env::temp_dir().join(format!("matcher-io-readers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snap.npz");
+        let path_str = path.to_string_lossy().into_owned();
+        let save = |w| {
+            save_snapshot(
+                &path_str,
+                &snapshot(w),
+                IndexWidth::default(),
+                &CancelToken::default(),
+            )
+            .unwrap()
+        };
+        let (old, new) = ([1.0, 2.0], [3.0, 4.0]);
+
+        // a reader holding the old file open still reads it whole after the rename
+        save(old);
+        let held = File::open(&path).unwrap();
+        save(new);
+        assert_eq!(weights(&load_snapshot_from(held, &path_str).unwrap()), old);
+        assert_eq!(weights(&load_snapshot(&path_str).unwrap()), new);
+
+        // readers racing a writer see one snapshot or the other, never a partial one
+        std::thread::scope(|s| {
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        for _ in 0..50 {
+                            let w = weights(&load_snapshot(&path_str).unwrap());
+                            assert!(w == old || w == new, "read {:?}", w);
+                        }
+                    })
+                })
+                .collect();
+            for i in 0..50 {
+                save(if i % 2 == 0 { old } else { new });
+            }
+            for r in readers {
+                r.join().unwrap();
+            }
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "npz")]
+    fn saved(snap: &Snapshot, name: &str) -> (PathBuf, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!("matcher-io-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        save_snapshot(
+            &path.to_string_lossy(),
+            snap,
+            IndexWidth::default(),
+            &CancelToken::default(),
+        )
+        .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (path, bytes)
+    }
+
+    #[cfg(feature = "npz")]
+    #[test]
+    fn snapshots_load_back_as_saved() {
+        let mut snap = snapshot([0.5, 3.0]);
+        snap.compat.insert(2, (vec![1], vec![1]));
+        let (path, bytes) = saved(&snap, "round_trip.npz");
+        let back =
+            load_snapshot_from(std::io::Cursor::new(bytes), &path.to_string_lossy()).unwrap();
+        assert_eq!(back.n_total, snap.n_total);
+        assert_eq!(back.jbt_ref_pop, snap.jbt_ref_pop);
+        assert_eq!(back.compat, snap.compat);
+        let (a, b) = (&back.buckets[0], &snap.buckets[0]);
+        assert_eq!(a.rows_data.to_vec(), b.rows_data.to_vec());
+        assert_eq!(a.indptr.to_i64(), b.indptr.to_i64());
+        assert_eq!(a.weights.to_vec(), b.weights.to_vec());
+        assert_eq!(a.key, b.key);
+    }
+
+    #[cfg(feature = "npz")]
+    #[test]
+    fn malformed_snapshots_are_refused_without_panicking() {
+        let mut bad = snapshot([1.0, 2.0]);
+        bad.buckets[0].rows_data = vec![0, 7].into();
+        let (path, bytes) = saved(&bad, "bad_j.npz");
+        let err = load_snapshot_from(std::io::Cursor::new(bytes), &path.to_string_lossy())
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("outside jbt_ref_pop"),
+            "{:#}",
+            err
+        );
+
+        // every truncation and many single-byte corruptions load or fail cleanly
+        let (_, good) = saved(&snapshot([1.0, 2.0]), "mutated.npz");
+        for len in (0..good.len()).step_by(97) {
+            let _ = load_snapshot_from(std::io::Cursor::new(&good[..len]), "cut");
+        }
+        for at in (0..good.len()).step_by(13) {
+            let mut bytes = good.clone();
+            bytes[at] ^= 0xa5;
+            let _ = load_snapshot_from(std::io::Cursor::new(bytes), "flipped");
+        }
+    }
+
+    #[test]
+    fn pair_keys_must_hold_pops_that_fit_an_i32() {
+        let json = serde_json::json!([[2, 4], [6, 8]]);
+        assert_eq!(parse_key_json(&json[0]).unwrap(), [2, 4]);
+        for bad in [
+            serde_json::json!([2, 4294967300i64]),
+            serde_json::json!([-1]),
+            serde_json::json!([1.5]),
+        ] {
+            let err = parse_key_json(&bad).err().unwrap();
+            assert!(err.to_string().starts_with("bad pop"), "{:#}", err);
+        }
+        assert_eq!(parse_key_text("(4, 4)").unwrap(), [4, 4]);
+        assert!(parse_key_text("4,-4").is_err());
+    }
 }
@@ -1,7 +1,28 @@
+pub mod arrow;
+pub mod checkpoint;
+pub mod compare;
+pub mod config;
+pub mod cooccurrence;
 pub mod driver;
+pub mod export;
 pub mod io;
+#[cfg(feature = "npz")]
+pub mod npz_check;
+pub mod plans;
+pub mod report;
+#[cfg(feature = "npz")]
+pub mod reweight;
+pub mod shard;
 pub mod solve;
+pub mod stats;
 pub mod types;
+#[cfg(feature = "npz")]
+pub mod verify;
+pub mod view;
+pub mod zst;
 
+pub use compare::*;
+pub use config::*;
 pub use driver::*;
 pub use io::*;
+pub use types::{EmptyDataError, SchemaError};
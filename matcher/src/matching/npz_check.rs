@@ -0,0 +1,266 @@
+//! Pre-flight check of NPZ members against their `.npy` headers. ndarray-npy
+//! allocates the whole array from the header's shape before reading any data,
+//! so a corrupt or hostile header (shape `(10**13,)` in a 1 KiB file) aborts
+//! the process instead of failing the load. Each member's header must fit in
+//! the member, and the shape times the item size must not exceed it. The
+//! member sizes in the zip directory are in turn held to the file size, and
+//! `check_offsets` vets the CSR offset arrays whose values size later
+//! allocations and slices. Failures are `SchemaError`s.
+
+use anyhow::{Context, Result};
+use std::io::{Read, Seek, SeekFrom};
+use zip::{CompressionMethod, ZipArchive};
+
+use super::types::SchemaError;
+
+/// Leading bytes of an HDF5 file, refused with a pointer to NPZ rather than
+/// as a broken zip.
+const HDF5_SIGNATURE: &[u8; 8] = b"\x89HDF\r\n\x1a\n";
+
+/// Magic string, version, and the longest length field (v2/v3: u32).
+const NPY_PREFIX: usize = 6 + 2 + 4;
+
+/// Deflate cannot expand data by more than about 1032:1, so a member claiming
+/// more than this times its compressed size is corrupt.
+const MAX_INFLATE_RATIO: u64 = 1032;
+
+macro_rules! schema {
+    ($($arg:tt)*) => {
+        return Err(SchemaError(format!($($arg)*)).into())
+    };
+}
+
+/// Refuse an HDF5 file up front, leaving `reader` rewound.
+pub fn check_not_hdf5<R: Read + Seek>(reader: &mut R, path: &str) -> Result<()> {
+    let mut head = Vec::with_capacity(HDF5_SIGNATURE.len());
+    Read::take(&mut *reader, HDF5_SIGNATURE.len() as u64).read_to_end(&mut head)?;
+    reader.seek(SeekFrom::Start(0))?;
+    if head == HDF5_SIGNATURE {
+        schema!(
+            "{} is an HDF5 file; this build reads NPZ only (convert it with numpy.savez, same member names)",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Check every member of the NPZ in `reader`, then hand the reader back
+/// rewound for `NpzReader`. `path` only labels messages.
+pub fn check_member_sizes<R: Read + Seek>(mut reader: R, path: &str) -> Result<R> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    check_not_hdf5(&mut reader, path)?;
+    let mut zip = ZipArchive::new(reader).with_context(|| format!("read npz {}", path))?;
+    for i in 0..zip.len() {
+        let mut member = zip
+            .by_index(i)
+            .with_context(|| format!("read member {} of {}", i, path))?;
+        let name = member.name().to_string();
+        let (size, packed) = (member.size(), member.compressed_size());
+        let max_size = match member.compression() {
+            CompressionMethod::Stored => packed,
+            _ => packed.saturating_mul(MAX_INFLATE_RATIO),
+        };
+        if packed > file_len || size > max_size {
+            schema!(
+                "{}: member {} claims {} bytes ({} stored) in a {}-byte file",
+                path,
+                name,
+                size,
+                packed,
+                file_len
+            );
+        }
+        check_npy_header(&mut member, size)
+            .with_context(|| format!("{}: member {}", path, name))?;
+    }
+    let mut reader = zip.into_inner();
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(reader)
+}
+
+/// `size` is the member's uncompressed length from the archive directory.
+fn check_npy_header(member: &mut impl Read, size: u64) -> Result<()> {
+    let mut prefix = [0u8; NPY_PREFIX];
+    let got = read_up_to(member, &mut prefix)?;
+    if got < 10 || &prefix[..6] != b"\x93NUMPY" {
+        // not an .npy; the array reader reports it if it is ever asked for
+        return Ok(());
+    }
+    let (header_len, data_start) = match prefix[6] {
+        1 => (u16::from_le_bytes([prefix[8], prefix[9]]) as u64, 10u64),
+        _ if got == NPY_PREFIX => (
+            u32::from_le_bytes([prefix[8], prefix[9], prefix[10], prefix[11]]) as u64,
+            12u64,
+        ),
+        _ => schema!("truncated .npy header"),
+    };
+    if data_start + header_len > size {
+        schema!(
+            ".npy header claims {} bytes, member holds {}",
+            header_len,
+            size
+        );
+    }
+    let mut header = prefix[data_start as usize..got].to_vec();
+    member
+        .take(header_len.saturating_sub(header.len() as u64))
+        .read_to_end(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+    // leave headers we cannot parse to ndarray-npy, which rejects them cheaply
+    let (Some(item), Some(count)) = (item_size(&header), element_count(&header)) else {
+        return Ok(());
+    };
+    let Some(data) = count.checked_mul(item) else {
+        schema!("array shape overflows");
+    };
+    if data > size - data_start - header_len {
+        schema!(
+            "array header declares {} elements of {} bytes, member holds {} bytes",
+            count,
+            item,
+            size
+        );
+    }
+    Ok(())
+}
+
+fn read_up_to(r: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..])? {
+            0 => break,
+            n => got += n,
+        }
+    }
+    Ok(got)
+}
+
+/// Value of `'key': ` in the header dict, up to the closing delimiter.
+fn field<'a>(header: &'a str, key: &str, close: char) -> Option<&'a str> {
+    let start = header.find(&format!("'{}':", key))? + key.len() + 3;
+    let rest = header[start..].trim_start();
+    let open = rest.chars().next()?;
+    let rest = &rest[open.len_utf8()..];
+    Some(&rest[..rest.find(close)?])
+}
+
+/// `'descr': '<f8'` -> 8. Only plain scalar types; anything else is `None`.
+fn item_size(header: &str) -> Option<u64> {
+    let descr = field(header, "descr", '\'')?;
+    descr.get(2..)?.parse().ok()
+}
+
+/// Product of `'shape': (a, b, ...)`; `()` is a scalar (1 element).
+fn element_count(header: &str) -> Option<u64> {
+    field(header, "shape", ')')?
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .try_fold(1u64, |acc, d| Some(acc.saturating_mul(d.parse().ok()?)))
+}
+
+/// CSR offsets must start at 0, never decrease, and end at `data_len`, the
+/// length of the array they index; anything else would slice out of range or
+/// size allocations from garbage.
+pub fn check_offsets(name: &str, offsets: &[i64], data_len: usize) -> Result<()> {
+    let (Some(&first), Some(&last)) = (offsets.first(), offsets.last()) else {
+        schema!("{} is empty", name);
+    };
+    if first != 0 || last != data_len as i64 {
+        schema!(
+            "{} spans {}..{}, expected 0..{}",
+            name,
+            first,
+            last,
+            data_len
+        );
+    }
+    if let Some(i) = offsets.windows(2).position(|w| w[1] < w[0]) {
+        schema!(
+            "{} decreases at entry {} ({} -> {})",
+            name,
+            i + 1,
+            offsets[i],
+            offsets[i + 1]
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    /// A v1 `.npy` with header dict `dict` followed by `data`.
+    fn npy(dict: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = format!("{{{}}}", dict);
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+        let mut out = b"\x93NUMPY\x01\x00".to_vec();
+        out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn npz(members: &[(&str, Vec<u8>)]) -> Cursor<Vec<u8>> {
+        let mut out = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, bytes) in members {
+            out.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            out.write_all(bytes).unwrap();
+        }
+        out.finish().unwrap()
+    }
+
+    fn refused(members: &[(&str, Vec<u8>)]) -> String {
+        let err = check_member_sizes(npz(members), "t.npz").err().unwrap();
+        assert!(err.downcast_ref::<SchemaError>().is_some(), "{:#}", err);
+        format!("{:#}", err)
+    }
+
+    #[test]
+    fn headers_must_fit_their_member() {
+        let ok = npy(
+            "'descr': '<f8', 'fortran_order': False, 'shape': (2,), ",
+            &[0; 16],
+        );
+        check_member_sizes(npz(&[("a.npy", ok.clone())]), "t.npz").unwrap();
+        let scalar = npy(
+            "'descr': '<i4', 'fortran_order': False, 'shape': (), ",
+            &[0; 4],
+        );
+        check_member_sizes(npz(&[("a.npy", ok), ("n.npy", scalar)]), "t.npz").unwrap();
+
+        let huge = npy(
+            "'descr': '<f8', 'fortran_order': False, 'shape': (10000000000000,), ",
+            &[0; 16],
+        );
+        assert!(refused(&[("a.npy", huge)]).contains("declares 10000000000000 elements"));
+        let overflow = npy(
+            "'descr': '<f8', 'fortran_order': False, 'shape': (4294967296, 4294967296), ",
+            &[],
+        );
+        assert!(refused(&[("a.npy", overflow)]).contains("overflows"));
+        let mut cut = npy(
+            "'descr': '<f8', 'fortran_order': False, 'shape': (0,), ",
+            &[],
+        );
+        cut.truncate(40);
+        assert!(refused(&[("a.npy", cut)]).contains("header claims"));
+    }
+
+    #[test]
+    fn offsets_must_cover_their_data() {
+        check_offsets("p", &[0, 2, 2, 5], 5).unwrap();
+        assert!(check_offsets("p", &[], 0).is_err());
+        assert!(check_offsets("p", &[1, 5], 5).is_err());
+        assert!(check_offsets("p", &[0, 4], 5).is_err());
+        let err = check_offsets("p", &[0, 3, 2, 5], 5).err().unwrap();
+        assert!(err.to_string().contains("decreases at entry 2"), "{}", err);
+    }
+}
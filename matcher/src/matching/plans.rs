@@ -0,0 +1,275 @@
+//! Stored solver plans (`matcher save-plans`, `--plans`): the weight-free part
+//! of every planned pair (`PairPlan`), built once and reloaded so that later
+//! runs over the same rows with other weights only redo the accumulation. A
+//! plan file is tied to the snapshot's structure (keys, rows, pops, compat)
+//! by a hash that leaves the weights out.
+
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::config::{ComplementFn, MatchConfig, complement_overrides};
+use super::driver::{plan_pairs, too_large};
+use super::solve::{
+    PairPlan, RowIndex, build_pair_plan, build_rows_by_jbt, precompute_candidates_for_bucket1,
+};
+use super::types::Snapshot;
+use crate::human::Secs;
+#[cfg(feature = "npz")]
+use {
+    super::io::read_i64,
+    super::npz_check::{check_member_sizes, check_offsets},
+    super::types::SchemaError,
+    anyhow::{Context, Result, bail},
+    ndarray::{Array1, Array2},
+    ndarray_npy::{NpzReader, NpzWriter},
+    std::fs::File,
+    std::path::Path,
+};
+
+/// Plans by (left, right) bucket of the scheduled pair.
+#[derive(Debug, Default)]
+pub struct PlanSet {
+    pub structure_sha256: String,
+    pub pairs: HashMap<(usize, usize), PairPlan>,
+}
+
+impl PlanSet {
+    pub fn get(&self, left: usize, right: usize) -> Option<&PairPlan> {
+        self.pairs.get(&(left, right))
+    }
+}
+
+/// Hash of everything a plan depends on: N, the pops, the compat table, a
+/// complement rule other than the default, and each bucket's key and rows.
+/// Weights are left out, so a snapshot that only reweights the same states
+/// keeps its plans.
+pub fn structure_sha256(snap: &Snapshot, complement: ComplementFn) -> String {
+    let mut h = Sha256::new();
+    h.update(format!("N={};pops={:?};", snap.n_total, snap.jbt_ref_pop));
+    let overrides = complement_overrides(complement, snap.n_total);
+    if !overrides.is_empty() {
+        h.update(format!("complement={:?};", overrides));
+    }
+    let mut compat: Vec<_> = snap.compat.iter().collect();
+    compat.sort_by_key(|(pop, _)| **pop);
+    h.update(format!("compat={:?};", compat));
+    for b in &snap.buckets {
+        h.update(format!("{:?}:{};", b.key, b.n_rows()));
+        for r in 0..=b.n_rows() {
+            h.update((b.indptr.get(r) as u64).to_le_bytes());
+        }
+        for v in &b.rows_data {
+            h.update(v.to_le_bytes());
+        }
+    }
+    format!("{:x}", h.finalize())
+}
+
+fn pair_plan<R: RowIndex>(
+    snap: &Snapshot,
+    left: usize,
+    right: usize,
+    complement: ComplementFn,
+) -> Option<PairPlan> {
+    let rows_by_jbt = build_rows_by_jbt::<R>(&snap.buckets[right]);
+    let cand_map = precompute_candidates_for_bucket1(
+        &snap.buckets[left],
+        &rows_by_jbt,
+        &snap.jbt_ref_pop,
+        snap.n_total,
+        &snap.compat,
+        complement,
+    );
+    build_pair_plan(
+        &snap.buckets[left],
+        &snap.buckets[right],
+        &snap.jbt_ref_pop,
+        &rows_by_jbt,
+        &cand_map,
+    )
+}
+
+/// Plans of every pair `cfg` plans (whole run: `shard` and `skip_pairs` do not
+/// apply). Pairs over the `MATCHER_MAX_PAIR_*` limits, and pairs a plan cannot
+/// hold (see `build_pair_plan`), get none and are solved in full later.
+pub fn build_plans(snap: &Snapshot, cfg: &MatchConfig) -> PlanSet {
+    let t0 = Instant::now();
+    let tasks = plan_pairs(snap, cfg, false);
+    let plans: Vec<Option<((usize, usize), PairPlan)>> = tasks
+        .par_iter()
+        .map(|&task| {
+            let (left, right, _) = task;
+            if too_large(snap, task).is_some() {
+                return None;
+            }
+            let plan = if snap.buckets[right].n_rows() <= u32::MAX as usize {
+                pair_plan::<u32>(snap, left, right, cfg.complement)
+            } else {
+                pair_plan::<usize>(snap, left, right, cfg.complement)
+            };
+            plan.map(|p| ((left, right), p))
+        })
+        .collect();
+    let pairs: HashMap<(usize, usize), PairPlan> = plans.into_iter().flatten().collect();
+    let cells: usize = pairs.values().map(|p| p.cell_rows.len()).sum();
+    eprintln!(
+        "[plans] {} of {} pairs planned, {} groups, {} cells in {}",
+        pairs.len(),
+        tasks.len(),
+        pairs.values().map(PairPlan::n_groups).sum::<usize>(),
+        cells,
+        Secs::from(t0.elapsed())
+    );
+    PlanSet {
+        structure_sha256: structure_sha256(snap, cfg.complement),
+        pairs,
+    }
+}
+
+#[cfg(feature = "npz")]
+impl PlanSet {
+    /// Write the plans as NPZ: `meta_structure_sha256` (hex bytes), `meta_pairs`
+    /// (left, right, neutral) and, per pair i, the `PairPlan` arrays as
+    /// `p{i}_member_indptr`, `p{i}_members`, `p{i}_cell_indptr`, `p{i}_cell_rows`
+    /// and `p{i}_cell_counts`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let f = File::create(path).with_context(|| format!("create {}", path.display()))?;
+        let mut npz = NpzWriter::new(f);
+        npz.add_array(
+            "meta_structure_sha256.npy",
+            &Array1::from_vec(self.structure_sha256.as_bytes().to_vec()),
+        )?;
+        let mut keys: Vec<&(usize, usize)> = self.pairs.keys().collect();
+        keys.sort();
+        let meta: Vec<i64> = keys
+            .iter()
+            .flat_map(|&&(l, r)| [l as i64, r as i64, self.pairs[&(l, r)].neutral as i64])
+            .collect();
+        npz.add_array(
+            "meta_pairs.npy",
+            &Array2::from_shape_vec((keys.len(), 3), meta)?,
+        )?;
+        let offsets = |v: &[u64]| Array1::from_vec(v.iter().map(|&o| o as i64).collect());
+        for (i, key) in keys.iter().enumerate() {
+            let plan = &self.pairs[key];
+            npz.add_array(
+                &format!("p{}_member_indptr.npy", i),
+                &offsets(&plan.member_indptr),
+            )?;
+            npz.add_array(
+                &format!("p{}_members.npy", i),
+                &Array1::from_vec(plan.members.clone()),
+            )?;
+            npz.add_array(
+                &format!("p{}_cell_indptr.npy", i),
+                &offsets(&plan.cell_indptr),
+            )?;
+            npz.add_array(
+                &format!("p{}_cell_rows.npy", i),
+                &Array1::from_vec(plan.cell_rows.clone()),
+            )?;
+            npz.add_array(
+                &format!("p{}_cell_counts.npy", i),
+                &Array1::from_vec(plan.cell_counts.clone()),
+            )?;
+        }
+        npz.finish()
+            .with_context(|| format!("write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Read plans written by `save` for `snap`, which must have the structure
+    /// (and complement rule) they were built from. Every index is checked against the buckets, so a
+    /// damaged file fails here with a `SchemaError` rather than in a pair.
+    pub fn load(path: &Path, snap: &Snapshot, complement: ComplementFn) -> Result<Self> {
+        let label = path.display().to_string();
+        let f = File::open(path).with_context(|| format!("open {}", label))?;
+        let mut npz = NpzReader::new(check_member_sizes(f, &label)?).context("read npz")?;
+        let hash: Array1<u8> = npz
+            .by_name("meta_structure_sha256.npy")
+            .context("read meta_structure_sha256.npy")?;
+        let current = structure_sha256(snap, complement);
+        let structure_sha256 = String::from_utf8_lossy(hash.as_slice().unwrap_or(&[])).into_owned();
+        if structure_sha256 != current {
+            bail!(
+                "{} was built for other snapshot rows (structure_sha256 {} vs {}); rebuild it with matcher save-plans",
+                label,
+                structure_sha256,
+                current
+            );
+        }
+        let meta: Array2<i64> = npz
+            .by_name("meta_pairs.npy")
+            .context("read meta_pairs.npy")?;
+        if meta.ncols() != 3 {
+            return Err(SchemaError(format!(
+                "meta_pairs has {} columns, expected 3",
+                meta.ncols()
+            ))
+            .into());
+        }
+        let mut pairs = HashMap::with_capacity(meta.nrows());
+        for (i, row) in meta.outer_iter().enumerate() {
+            let bucket = |v: i64| {
+                usize::try_from(v)
+                    .ok()
+                    .filter(|&b| b < snap.buckets.len())
+                    .ok_or_else(|| SchemaError(format!("pair {} names bucket {}", i, v)))
+            };
+            let (left, right) = (bucket(row[0])?, bucket(row[1])?);
+            let (rows1, rows2) = (snap.buckets[left].n_rows(), snap.buckets[right].n_rows());
+            let name = |field: &str| format!("p{}_{}.npy", i, field);
+            let member_indptr = read_i64(&mut npz, &name("member_indptr"))?.to_vec();
+            let members: Array1<u32> = npz
+                .by_name(&name("members"))
+                .with_context(|| format!("read {}", name("members")))?;
+            let cell_indptr = read_i64(&mut npz, &name("cell_indptr"))?.to_vec();
+            let cell_rows: Array1<u32> = npz
+                .by_name(&name("cell_rows"))
+                .with_context(|| format!("read {}", name("cell_rows")))?;
+            let cell_counts: Array1<u64> = npz
+                .by_name(&name("cell_counts"))
+                .with_context(|| format!("read {}", name("cell_counts")))?;
+            check_offsets(&name("member_indptr"), &member_indptr, members.len())?;
+            check_offsets(&name("cell_indptr"), &cell_indptr, cell_rows.len())?;
+            if member_indptr.len() != cell_indptr.len() || cell_counts.len() != cell_rows.len() {
+                return Err(SchemaError(format!(
+                    "pair {}: {} member groups, {} cell groups, {} cell rows, {} counts",
+                    i,
+                    member_indptr.len() - 1,
+                    cell_indptr.len() - 1,
+                    cell_rows.len(),
+                    cell_counts.len()
+                ))
+                .into());
+            }
+            if members.iter().any(|&r| r as usize >= rows1)
+                || cell_rows.iter().any(|&r| r as usize >= rows2)
+            {
+                return Err(SchemaError(format!(
+                    "pair {}: a row past the buckets' {} x {} rows",
+                    i, rows1, rows2
+                ))
+                .into());
+            }
+            let offsets = |v: Vec<i64>| v.into_iter().map(|o| o as u64).collect();
+            pairs.insert(
+                (left, right),
+                PairPlan {
+                    neutral: row[2] != 0,
+                    member_indptr: offsets(member_indptr),
+                    members: members.to_vec(),
+                    cell_indptr: offsets(cell_indptr),
+                    cell_rows: cell_rows.to_vec(),
+                    cell_counts: cell_counts.to_vec(),
+                },
+            );
+        }
+        Ok(PlanSet {
+            structure_sha256,
+            pairs,
+        })
+    }
+}
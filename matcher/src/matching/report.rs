@@ -0,0 +1,350 @@
+//! Renderers for the per-pair lines and the Omega summary printed after
+//! matching. `--report-format` picks one; diagnostics (`[pairs]`, `[balance]`,
+//! `[util]`, ...) stay on stderr in every format.
+
+use anyhow::{Context, Result, bail};
+use num_bigint::BigUint;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::config::Normalize;
+use super::driver::{PairResult, omega_sum};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Human-readable lines (`[pair ...]`, `Omega total: ...`).
+    #[default]
+    Text,
+    /// One JSON object per line, tagged `"record": "pair"` or `"omega"`.
+    Jsonl,
+    /// A header, one row per pair, then an `omega` row.
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "jsonl" => Ok(ReportFormat::Jsonl),
+            "csv" => Ok(ReportFormat::Csv),
+            other => bail!(
+                "unknown report format {:?} (expected text|jsonl|csv)",
+                other
+            ),
+        }
+    }
+}
+
+impl ReportFormat {
+    pub fn reporter<'a>(self, out: impl Write + 'a) -> Box<dyn Reporter + 'a> {
+        match self {
+            ReportFormat::Text => Box::new(TextReporter { out }),
+            ReportFormat::Jsonl => Box::new(JsonlReporter { out }),
+            ReportFormat::Csv => Box::new(CsvReporter { out, header: false }),
+        }
+    }
+}
+
+/// How Omega and pair subtotals print (`MATCHER_FLOAT_FORMAT`), in the
+/// reports, the `Omega total` lines and the results CSV. JSON keeps plain
+/// numbers, with exact values as strings, in every format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FloatFormat {
+    /// Six decimals, `4292655082.000000`. Past 2^53 the digits are those of
+    /// the nearest f64, not of Omega.
+    #[default]
+    Fixed,
+    /// Scientific with `MATCHER_FLOAT_DIGITS` significant digits, `4.292655082e9`.
+    Sci,
+    /// The exact integer where `--exact` counted one; else fixed below 2^53,
+    /// where an f64 still holds every integer, and scientific past it.
+    Auto,
+}
+
+impl FromStr for FloatFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "fixed" => Ok(FloatFormat::Fixed),
+            "sci" => Ok(FloatFormat::Sci),
+            "auto" => Ok(FloatFormat::Auto),
+            other => bail!("unknown float format {:?} (expected fixed|sci|auto)", other),
+        }
+    }
+}
+
+impl FloatFormat {
+    /// `x` in this format with `digits` significant digits; `exact` is its
+    /// exact integer, when one was counted.
+    pub fn render(self, x: f64, exact: Option<&BigUint>, digits: usize) -> String {
+        let sci = || format!("{:.*e}", digits.saturating_sub(1), x);
+        match (self, exact) {
+            (FloatFormat::Fixed, _) => format!("{:.6}", x),
+            (FloatFormat::Sci, _) => sci(),
+            (FloatFormat::Auto, Some(e)) => e.to_string(),
+            (FloatFormat::Auto, None) if x.abs() < 2f64.powi(53) => format!("{:.6}", x),
+            (FloatFormat::Auto, None) => sci(),
+        }
+    }
+}
+
+/// `x` as `MATCHER_FLOAT_FORMAT` / `MATCHER_FLOAT_DIGITS` print it.
+pub fn fmt_value(x: f64, exact: Option<&BigUint>) -> String {
+    let s = crate::settings::get();
+    s.float_format.render(x, exact, s.float_digits)
+}
+
+/// Write the `ReportFormat::Csv` report of `results` to `path` (`--csv-out`).
+pub fn save_results_csv(
+    path: &Path,
+    results: &[PairResult],
+    wall: f64,
+    normalize: Option<Normalize>,
+) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    let mut reporter = ReportFormat::Csv.reporter(&mut out);
+    results.iter().try_for_each(|r| reporter.pair(r))?;
+    reporter.omega(&OmegaSummary::from_results(results, wall, normalize))?;
+    drop(reporter);
+    out.flush()
+        .with_context(|| format!("write {}", path.display()))
+}
+
+/// Totals of a matching run, reported once after all pairs.
+pub struct OmegaSummary {
+    pub omega: f64,
+    pub pairs: usize,
+    /// Pairs among `pairs` skipped as too large, left out of `omega`.
+    pub skipped: usize,
+    pub wall: f64,
+    pub sum_pair_total: f64,
+    pub sum_pair_solve: f64,
+    /// (divisor, Omega / divisor) when normalization was requested.
+    pub normalized: Option<(f64, f64)>,
+    /// Omega in exact integers (`--exact`), see `exact_omega`.
+    pub exact: Option<BigUint>,
+}
+
+/// Sum of the exact subtotals, when every pair that was solved has one.
+pub fn exact_omega(results: &[PairResult]) -> Option<BigUint> {
+    let mut solved = results.iter().filter(|r| r.skipped.is_none()).peekable();
+    solved.peek()?;
+    solved.map(|r| r.exact.as_ref()).sum()
+}
+
+impl OmegaSummary {
+    pub fn from_results(results: &[PairResult], wall: f64, normalize: Option<Normalize>) -> Self {
+        let omega = omega_sum(results);
+        let divisor = normalize.map(|n| match n {
+            Normalize::PairWeights => results.iter().map(|r| r.weight_mass).sum(),
+            Normalize::Constant(c) => c,
+        });
+        OmegaSummary {
+            omega,
+            pairs: results.len(),
+            skipped: results.iter().filter(|r| r.skipped.is_some()).count(),
+            wall,
+            sum_pair_total: results.iter().map(|r| r.t_total).sum(),
+            sum_pair_solve: results.iter().map(|r| r.t_solve).sum(),
+            normalized: divisor.map(|d| (d, omega / d)),
+            exact: exact_omega(results),
+        }
+    }
+}
+
+pub trait Reporter {
+    fn pair(&mut self, r: &PairResult) -> io::Result<()>;
+    fn omega(&mut self, s: &OmegaSummary) -> io::Result<()>;
+}
+
+struct TextReporter<W> {
+    out: W,
+}
+
+impl<W: Write> Reporter for TextReporter<W> {
+    fn pair(&mut self, r: &PairResult) -> io::Result<()> {
+        if let Some(reason) = &r.skipped {
+            return writeln!(
+                self.out,
+                "[pair {:?} vs {:?}{}] rows1={}, rows2={} | skipped: {}",
+                r.key_left,
+                r.key_right,
+                if r.factor == 2.0 { " x2" } else { "" },
+                r.rows1,
+                r.rows2,
+                reason
+            );
+        }
+        write!(
+            self.out,
+            "[pair {:?} vs {:?}{}] rows1={}, rows2={} | index={:.3}s, cands={:.3}s, solve={:.3}s → total={:.3}s | subtotal={}",
+            r.key_left,
+            r.key_right,
+            if r.factor == 2.0 { " x2" } else { "" },
+            r.rows1,
+            r.rows2,
+            r.t_index,
+            r.t_cands,
+            r.t_solve,
+            r.t_total,
+            fmt_value(r.subtotal, r.exact.as_ref())
+        )?;
+        match &r.exact {
+            Some(x) => writeln!(self.out, " | exact={}", x),
+            None => writeln!(self.out),
+        }
+    }
+
+    fn omega(&mut self, s: &OmegaSummary) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "Omega total: {} (pairs={}, wall={:.3}s, sum_pair_total={:.3}s, sum_pair_solve={:.3}s)",
+            fmt_value(s.omega, s.exact.as_ref()),
+            s.pairs,
+            s.wall,
+            s.sum_pair_total,
+            s.sum_pair_solve
+        )?;
+        if let Some(x) = &s.exact {
+            writeln!(self.out, "Omega exact: {}", x)?;
+        }
+        if s.skipped > 0 {
+            writeln!(
+                self.out,
+                "Omega is partial: {} pairs skipped as too large",
+                s.skipped
+            )?;
+        }
+        if let Some((divisor, value)) = s.normalized {
+            writeln!(
+                self.out,
+                "Omega normalized: {:e} (Omega / {:e})",
+                value, divisor
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct JsonlReporter<W> {
+    out: W,
+}
+
+impl<W: Write> Reporter for JsonlReporter<W> {
+    fn pair(&mut self, r: &PairResult) -> io::Result<()> {
+        let line = serde_json::json!({
+            "record": "pair",
+            "key_left": r.key_left,
+            "key_right": r.key_right,
+            "factor": r.factor,
+            "rows1": r.rows1,
+            "rows2": r.rows2,
+            "t_index": r.t_index,
+            "t_cands": r.t_cands,
+            "t_solve": r.t_solve,
+            "t_total": r.t_total,
+            "subtotal": r.subtotal,
+            "subtotal_exact": r.exact.as_ref().map(|x| x.to_string()),
+            "skipped": r.skipped,
+        });
+        writeln!(self.out, "{}", line)
+    }
+
+    fn omega(&mut self, s: &OmegaSummary) -> io::Result<()> {
+        let line = serde_json::json!({
+            "record": "omega",
+            "omega": s.omega,
+            "pairs": s.pairs,
+            "skipped": s.skipped,
+            "wall": s.wall,
+            "sum_pair_total": s.sum_pair_total,
+            "sum_pair_solve": s.sum_pair_solve,
+            "norm_divisor": s.normalized.map(|(d, _)| d),
+            "omega_normalized": s.normalized.map(|(_, v)| v),
+            "omega_exact": s.exact.as_ref().map(|x| x.to_string()),
+        });
+        writeln!(self.out, "{}", line)
+    }
+}
+
+/// Keys are space-separated pops (empty for the neutral key), the form
+/// `--pairs` text files accept. `skipped` holds the reason a pair was skipped
+/// as too large (its subtotal is then 0). The `omega` row carries Omega in `subtotal`,
+/// the pair count in `rows1` and the wall time in `t_total`; an
+/// `omega_normalized` row carries the divisor in `factor`, and an `omega_exact`
+/// row the exact Omega in `subtotal`.
+struct CsvReporter<W> {
+    out: W,
+    header: bool,
+}
+
+impl<W: Write> CsvReporter<W> {
+    fn header(&mut self) -> io::Result<()> {
+        if !self.header {
+            self.header = true;
+            writeln!(
+                self.out,
+                "record,key_left,key_right,factor,rows1,rows2,t_index,t_cands,t_solve,t_total,subtotal,skipped"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A CSV number: shortest round-trip digits under the fixed format, else as
+/// `fmt_value` prints it.
+fn csv_value(x: f64, exact: Option<&BigUint>) -> String {
+    match crate::settings::get().float_format {
+        FloatFormat::Fixed => x.to_string(),
+        _ => fmt_value(x, exact),
+    }
+}
+
+fn csv_key(key: &[i32]) -> String {
+    key.iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl<W: Write> Reporter for CsvReporter<W> {
+    fn pair(&mut self, r: &PairResult) -> io::Result<()> {
+        self.header()?;
+        writeln!(
+            self.out,
+            "pair,{},{},{},{},{},{},{},{},{},{},{}",
+            csv_key(&r.key_left),
+            csv_key(&r.key_right),
+            r.factor,
+            r.rows1,
+            r.rows2,
+            r.t_index,
+            r.t_cands,
+            r.t_solve,
+            r.t_total,
+            csv_value(r.subtotal, r.exact.as_ref()),
+            r.skipped.as_deref().unwrap_or("")
+        )
+    }
+
+    fn omega(&mut self, s: &OmegaSummary) -> io::Result<()> {
+        self.header()?;
+        writeln!(
+            self.out,
+            "omega,,,,{},,,,,{},{},",
+            s.pairs,
+            s.wall,
+            csv_value(s.omega, s.exact.as_ref())
+        )?;
+        if let Some((divisor, value)) = s.normalized {
+            writeln!(self.out, "omega_normalized,,,{},,,,,,,{},", divisor, value)?;
+        }
+        if let Some(x) = &s.exact {
+            writeln!(self.out, "omega_exact,,,,,,,,,,{},", x)?;
+        }
+        Ok(())
+    }
+}
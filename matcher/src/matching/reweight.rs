@@ -0,0 +1,144 @@
+//! `matcher reweight`: replace the row weights of a saved snapshot without
+//! enumerating again. The weights file holds `b{b}_weights` (f64, one per row
+//! of bucket b) for each bucket to replace, optionally with that bucket's
+//! `b{b}_exact_rows` / `b{b}_exact_limbs` as a snapshot stores them, and
+//! `b{b}_key` to check it is meant for that bucket. Buckets it does not list
+//! keep their weights.
+
+use anyhow::{Context, Result, bail};
+use ndarray_npy::NpzReader;
+use std::collections::HashSet;
+use std::fs::File;
+use std::time::Instant;
+
+use super::io::{check_bucket, load_snapshot, read_exact, read_f64, read_i32, save_snapshot};
+use super::npz_check::check_member_sizes;
+use super::stats::SnapshotStats;
+use super::types::SchemaError;
+use super::view::is_view;
+use crate::human::Secs;
+use crate::runtime::CancelToken;
+use crate::settings;
+
+/// Replace the weights of the snapshot at `path` with those in `weights_path`
+/// and save it back in place (`save_snapshot` writes beside it, then renames
+/// over it, so concurrent readers never see a partial file).
+/// Returns how many buckets were reweighted.
+pub fn reweight_snapshot(path: &str, weights_path: &str) -> Result<usize> {
+    if is_view(path) {
+        bail!(
+            "{} is a snapshot view; reweight the NPZ snapshot and pack it again",
+            path
+        );
+    }
+    let t0 = Instant::now();
+    let mut snap = load_snapshot(path)?;
+    let f = File::open(weights_path).with_context(|| format!("open {}", weights_path))?;
+    let mut npz =
+        NpzReader::new(check_member_sizes(f, weights_path)?).context("read weights npz")?;
+    let names: HashSet<String> = npz
+        .names()
+        .context("list weight members")?
+        .into_iter()
+        .collect();
+    let known: HashSet<String> = (0..snap.buckets.len())
+        .flat_map(|b| {
+            ["weights", "exact_rows", "exact_limbs", "key"].map(|m| format!("b{}_{}.npy", b, m))
+        })
+        .collect();
+    if let Some(stray) = names.iter().find(|n| !known.contains(*n)) {
+        return Err(SchemaError(format!(
+            "{}: member {} names no bucket of {} ({} buckets)",
+            weights_path,
+            stray,
+            path,
+            snap.buckets.len()
+        ))
+        .into());
+    }
+
+    let mut replaced = 0;
+    for (b, bucket) in snap.buckets.iter_mut().enumerate() {
+        let name = format!("b{}_weights.npy", b);
+        if !names.contains(&name) {
+            if names.iter().any(|n| n.starts_with(&format!("b{}_", b))) {
+                bail!(
+                    "{}: bucket {} has exact weights or a key but no {}",
+                    weights_path,
+                    b,
+                    name
+                );
+            }
+            continue;
+        }
+        let key_name = format!("b{}_key.npy", b);
+        if names.contains(&key_name) {
+            let key = read_i32(&mut npz, &key_name)?;
+            if key.as_slice() != Some(bucket.key.as_slice()) {
+                bail!(
+                    "{}: {} is {:?}, but bucket {} of {} is {:?}",
+                    weights_path,
+                    key_name,
+                    key.to_vec(),
+                    b,
+                    path,
+                    bucket.key
+                );
+            }
+        }
+        let weights = read_f64(&mut npz, &name)?.into_raw_vec();
+        if weights.len() != bucket.n_rows() {
+            return Err(SchemaError(format!(
+                "{}: {} has {} weights, bucket {} {:?} has {} rows",
+                weights_path,
+                name,
+                weights.len(),
+                b,
+                bucket.key,
+                bucket.n_rows()
+            ))
+            .into());
+        }
+        if let Some((r, w)) = weights
+            .iter()
+            .enumerate()
+            .find(|(_, w)| !w.is_finite() || **w < 0.0)
+        {
+            bail!(
+                "{}: bucket {} row {} has weight {}; weights must be finite and non-negative",
+                weights_path,
+                b,
+                r,
+                w
+            );
+        }
+        bucket.weights = weights.into();
+        bucket.exact = read_exact(&mut npz, b, &names)?;
+        check_bucket(bucket, b, snap.jbt_ref_pop.len())
+            .with_context(|| format!("reweight {} with {}", path, weights_path))?;
+        replaced += 1;
+    }
+    if snap.stats.is_some() {
+        snap.stats = Some(SnapshotStats::from_buckets(&snap.buckets));
+    }
+    // the weights no longer come from enumerating the inputs it was built from
+    if replaced > 0 {
+        snap.fingerprint = None;
+    }
+
+    save_snapshot(
+        path,
+        &snap,
+        settings::get().index_width,
+        &CancelToken::default(),
+    )?;
+    eprintln!(
+        "[reweight] {} of {} buckets of {} reweighted from {} in {}",
+        replaced,
+        snap.buckets.len(),
+        path,
+        weights_path,
+        Secs::from(t0.elapsed())
+    );
+    Ok(replaced)
+}
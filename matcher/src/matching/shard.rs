@@ -0,0 +1,163 @@
+//! Splitting the planned pairs into `--shard` parts that finish together. Each
+//! pair gets a predicted runtime: its recorded time from `--shard-history` when
+//! the history has it, otherwise the heaviest-first cost heuristic (rows1 x
+//! rows2 x key length) scaled by seconds per unit. The pairs are then dealt
+//! longest first to whichever shard is predicted to finish earliest. Every
+//! shard job computes the same plan independently, so all of them must see the
+//! same snapshot, configuration and history.
+
+use sha2::{Digest, Sha256};
+
+use super::compare::pair_id_of;
+use super::config::{MatchConfig, Shard};
+use super::driver::plan_pairs;
+use super::types::Snapshot;
+use crate::human::Secs;
+
+/// Cost-model time per heuristic unit, calibrated on n = 8 (one core). Override
+/// with `MATCHER_NS_PER_PAIR_COST`; a `--shard-history` file recalibrates it
+/// from the pairs it shares with the plan.
+const DEFAULT_NS_PER_PAIR_COST: f64 = 1.2;
+
+/// The heaviest-first heuristic `plan_pairs` orders by.
+pub(super) fn pair_cost_units(snap: &Snapshot, left: usize, right: usize) -> u64 {
+    (snap.buckets[left].n_rows() as u64)
+        * (snap.buckets[right].n_rows() as u64)
+        * (std::cmp::max(1, snap.buckets[left].key.len()) as u64)
+}
+
+/// Predicted seconds for each task, and how many came from the history.
+fn predict(snap: &Snapshot, cfg: &MatchConfig, tasks: &[(usize, usize, f64)]) -> (Vec<f64>, usize) {
+    let units: Vec<u64> = tasks
+        .iter()
+        .map(|&(l, r, _)| pair_cost_units(snap, l, r))
+        .collect();
+    let recorded: Vec<Option<f64>> = tasks
+        .iter()
+        .map(|&(l, r, _)| {
+            let id = pair_id_of(&snap.buckets[l].key, &snap.buckets[r].key);
+            cfg.pair_seconds.as_ref()?.get(&id).copied()
+        })
+        .collect();
+    // seconds per unit over the recorded pairs, else the calibrated default
+    let (rec_secs, rec_units) = recorded
+        .iter()
+        .zip(&units)
+        .filter_map(|(s, &u)| Some((s.as_ref()?, u)))
+        .fold((0.0, 0u64), |(s, u), (&ts, tu)| (s + ts, u + tu));
+    let rate = if rec_units > 0 && rec_secs > 0.0 {
+        rec_secs / rec_units as f64
+    } else {
+        crate::settings::get()
+            .ns_per_pair_cost
+            .unwrap_or(DEFAULT_NS_PER_PAIR_COST)
+            * 1e-9
+    };
+    let seconds = recorded
+        .iter()
+        .zip(&units)
+        .map(|(s, &u)| s.unwrap_or(u as f64 * rate))
+        .collect();
+    (seconds, recorded.iter().flatten().count())
+}
+
+/// Which shard each planned task goes to, and its predicted runtime.
+pub struct ShardPlan {
+    /// Shard of each planned task, in plan order.
+    pub shard_of: Vec<usize>,
+    /// Predicted seconds of each planned task.
+    pub seconds: Vec<f64>,
+    /// Planned tasks whose prediction is a recorded time.
+    pub from_history: usize,
+}
+
+impl ShardPlan {
+    /// Longest predicted task first, each to the shard with the least predicted
+    /// time so far (the lowest index on ties).
+    pub fn new(
+        snap: &Snapshot,
+        cfg: &MatchConfig,
+        tasks: &[(usize, usize, f64)],
+        count: usize,
+    ) -> Self {
+        let (seconds, from_history) = predict(snap, cfg, tasks);
+        let mut order: Vec<usize> = (0..tasks.len()).collect();
+        order.sort_by(|&a, &b| seconds[b].total_cmp(&seconds[a]));
+        let mut load = vec![0.0f64; count];
+        let mut shard_of = vec![0; tasks.len()];
+        for i in order {
+            let k = (0..count)
+                .min_by(|&a, &b| load[a].total_cmp(&load[b]))
+                .unwrap_or(0);
+            shard_of[i] = k;
+            load[k] += seconds[i];
+        }
+        ShardPlan {
+            shard_of,
+            seconds,
+            from_history,
+        }
+    }
+
+    /// Task indices of shard `k`, longest predicted first.
+    pub fn tasks_of(&self, k: usize) -> Vec<usize> {
+        let mut mine: Vec<usize> = (0..self.shard_of.len())
+            .filter(|&i| self.shard_of[i] == k)
+            .collect();
+        mine.sort_by(|&a, &b| self.seconds[b].total_cmp(&self.seconds[a]));
+        mine
+    }
+
+    /// One line per shard: pairs, predicted pair time, and predicted wall time
+    /// on `threads` (the summed time spread over the threads, but no less than
+    /// the longest pair). `mark` flags the shard this run solves.
+    pub fn print(&self, count: usize, threads: usize, mark: Option<usize>) {
+        let source = if self.from_history == 0 {
+            "cost model".to_string()
+        } else {
+            format!(
+                "{} recorded pairs, cost model for {}",
+                self.from_history,
+                self.shard_of.len() - self.from_history
+            )
+        };
+        eprintln!(
+            "[shard] {} planned pairs over {} shards ({}); predicted on {} threads:",
+            self.shard_of.len(),
+            count,
+            source,
+            threads
+        );
+        for k in 0..count {
+            let mine = self.tasks_of(k);
+            let sum: f64 = mine.iter().map(|&i| self.seconds[i]).sum();
+            let longest = mine.first().map_or(0.0, |&i| self.seconds[i]);
+            eprintln!(
+                "[shard]   {}{}: {} pairs, {} of pair time, ~{} wall",
+                Shard { index: k, count },
+                if mark == Some(k) { " (this run)" } else { "" },
+                mine.len(),
+                Secs(sum),
+                Secs((sum / threads.max(1) as f64).max(longest))
+            );
+        }
+    }
+}
+
+/// `matcher plan-shards <snapshot> <n>`: the shard plan a run with `--shard k/n`
+/// and the same options would use, without matching.
+pub fn print_plan(snap: &Snapshot, cfg: &MatchConfig, count: usize) {
+    let tasks = plan_pairs(snap, cfg, true);
+    ShardPlan::new(snap, cfg, &tasks, count).print(count, rayon::current_num_threads(), None);
+}
+
+/// Hash of the recorded pair times shard plans are balanced with, so a resumed
+/// shard can check it is planned like the partial run; `None` without history.
+pub fn history_sha256(cfg: &MatchConfig) -> Option<String> {
+    let history = cfg.pair_seconds.as_ref()?;
+    let mut h = Sha256::new();
+    for ((left, right), secs) in history {
+        h.update(format!("{:?}:{:?}:{:x};", left, right, secs.to_bits()));
+    }
+    Some(format!("{:x}", h.finalize()))
+}
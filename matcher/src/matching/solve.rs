@@ -1,28 +1,321 @@
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use rayon::prelude::*;
+use smallvec::SmallVec;
 use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
+use super::config::{ComplementFn, partner_pop};
 use super::types::Bucket;
 
-// x -> sorted Vec<row_idx>
-pub fn build_rows_by_jbt(bucket: &Bucket) -> HashMap<i32, Vec<usize>> {
-    let mut m: HashMap<i32, Vec<usize>> = HashMap::new();
+/// What a pair is counted in: `u64` or `u128` when its bound allows (see
+/// `Accum`), `Compensated` f64 otherwise, and `Exact` for the exact Omega of
+/// `--exact`.
+/// Weights are whole numbers, so the counts folded into them are exact
+/// integers in any of them.
+pub trait Tally: Clone + Send + Sync {
+    fn zero() -> Self;
+    fn add(&mut self, x: &Self);
+    fn mul(&self, x: &Self) -> Self;
+    fn mul_count(&mut self, c: u64);
+    fn is_finite(&self) -> bool {
+        true
+    }
+    /// For progress reports.
+    fn approx(&self) -> f64;
+}
+
+impl Tally for f64 {
+    #[inline]
+    fn zero() -> Self {
+        0.0
+    }
+    #[inline]
+    fn add(&mut self, x: &Self) {
+        *self += *x;
+    }
+    #[inline]
+    fn mul(&self, x: &Self) -> Self {
+        self * x
+    }
+    #[inline]
+    fn mul_count(&mut self, c: u64) {
+        *self *= c as f64;
+    }
+    #[inline]
+    fn is_finite(&self) -> bool {
+        f64::is_finite(*self)
+    }
+    #[inline]
+    fn approx(&self) -> f64 {
+        *self
+    }
+}
+
+/// An f64 sum with Neumaier compensation: `comp` collects the low bits each
+/// addition rounds away, so long sums of products of very different sizes do
+/// not drift. Used where weights force f64 counting.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Compensated {
+    sum: f64,
+    comp: f64,
+}
+
+impl Compensated {
+    #[inline]
+    pub fn add_f64(&mut self, x: f64) {
+        let t = self.sum + x;
+        if self.sum.abs() >= x.abs() {
+            self.comp += (self.sum - t) + x;
+        } else {
+            self.comp += (x - t) + self.sum;
+        }
+        self.sum = t;
+    }
+
+    #[inline]
+    pub fn value(&self) -> f64 {
+        self.sum + self.comp
+    }
+
+    #[inline]
+    fn of(x: f64) -> Self {
+        Compensated { sum: x, comp: 0.0 }
+    }
+}
+
+impl Tally for Compensated {
+    #[inline]
+    fn zero() -> Self {
+        Compensated::default()
+    }
+    #[inline]
+    fn add(&mut self, x: &Self) {
+        self.add_f64(x.sum);
+        self.comp += x.comp;
+    }
+    #[inline]
+    fn mul(&self, x: &Self) -> Self {
+        Compensated::of(self.value() * x.value())
+    }
+    #[inline]
+    fn mul_count(&mut self, c: u64) {
+        *self = Compensated::of(self.value() * c as f64);
+    }
+    #[inline]
+    fn is_finite(&self) -> bool {
+        self.value().is_finite()
+    }
+    #[inline]
+    fn approx(&self) -> f64 {
+        self.value()
+    }
+}
+
+macro_rules! impl_int_tally {
+    ($($t:ty),*) => {$(
+        impl Tally for $t {
+            #[inline]
+            fn zero() -> Self {
+                0
+            }
+            #[inline]
+            fn add(&mut self, x: &Self) {
+                *self += *x;
+            }
+            #[inline]
+            fn mul(&self, x: &Self) -> Self {
+                self * x
+            }
+            #[inline]
+            fn mul_count(&mut self, c: u64) {
+                *self *= c as $t;
+            }
+            #[inline]
+            fn approx(&self) -> f64 {
+                *self as f64
+            }
+        }
+    )*};
+}
+impl_int_tally!(u64, u128);
+
+/// The accumulator a pair was counted in. Integers are exact and the pair's
+/// bound (total left weight x total right weight x the most assignments any
+/// left row can have) rules out overflow; f64 is the fallback for weights that
+/// are not whole numbers or bounds past u128, summed with compensation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Accum {
+    U64,
+    U128,
+    F64,
+}
+
+/// A pair's subtotal and how it was counted.
+#[derive(Debug, Clone, Copy)]
+pub struct Subtotal {
+    pub value: f64,
+    /// The subtotal itself when counted in integers.
+    pub exact: Option<u128>,
+    pub accum: Accum,
+}
+
+/// Upper bound on the pair's subtotal: sum1 x sum2 x the largest product of
+/// candidate counts over the nonzero-pop positions of a bucket1 row; `None`
+/// past u128.
+fn pair_bound(
+    bucket1: &Bucket,
+    weights1: &[u128],
+    weights2: &[u128],
+    jbt_ref_pop: &[i32],
+    cand_map: &HashMap<i32, Vec<i32>>,
+) -> Option<u128> {
+    let sum = |ws: &[u128]| ws.iter().try_fold(0u128, |s, &w| s.checked_add(w));
+    sum(weights1)?
+        .checked_mul(sum(weights2)?)?
+        .checked_mul(max_assignments(bucket1, jbt_ref_pop, cand_map)?)
+}
+
+/// The largest product of candidate counts over the nonzero-pop positions of
+/// a bucket1 row (1 for the neutral bucket): a bound on the assignments of
+/// one row pair. `None` past u128.
+fn max_assignments(
+    bucket1: &Bucket,
+    jbt_ref_pop: &[i32],
+    cand_map: &HashMap<i32, Vec<i32>>,
+) -> Option<u128> {
+    let mut most = 1u128;
+    if !bucket1.key.is_empty() {
+        for r in 0..bucket1.n_rows() {
+            let mut ways = 1u128;
+            for &j in bucket1.row_slice(r) {
+                if jbt_ref_pop[j as usize] != 0 {
+                    let c = cand_map.get(&j).map_or(0, Vec::len);
+                    ways = ways.checked_mul(c as u128)?;
+                }
+            }
+            most = most.max(ways);
+        }
+    }
+    Some(most)
+}
+
+/// An exact count: u128 while it fits (no allocation on the hot path), a
+/// `BigUint` once it does not.
+#[derive(Clone)]
+enum Exact {
+    Small(u128),
+    Big(BigUint),
+}
+
+impl Exact {
+    fn from_big(x: &BigUint) -> Self {
+        x.to_u128()
+            .map_or_else(|| Exact::Big(x.clone()), Exact::Small)
+    }
+    fn to_big(&self) -> BigUint {
+        match self {
+            Exact::Small(a) => BigUint::from(*a),
+            Exact::Big(a) => a.clone(),
+        }
+    }
+}
+
+impl Tally for Exact {
+    fn zero() -> Self {
+        Exact::Small(0)
+    }
+    fn add(&mut self, x: &Self) {
+        if let (Exact::Small(a), Exact::Small(b)) = (&*self, x)
+            && let Some(s) = a.checked_add(*b)
+        {
+            *self = Exact::Small(s);
+            return;
+        }
+        *self = Exact::Big(self.to_big() + x.to_big());
+    }
+    fn mul(&self, x: &Self) -> Self {
+        if let (Exact::Small(a), Exact::Small(b)) = (self, x)
+            && let Some(p) = a.checked_mul(*b)
+        {
+            return Exact::Small(p);
+        }
+        Exact::Big(self.to_big() * x.to_big())
+    }
+    fn mul_count(&mut self, c: u64) {
+        match self {
+            Exact::Small(a) => match a.checked_mul(c as u128) {
+                Some(p) => *a = p,
+                None => *self = Exact::Big(BigUint::from(*a) * c),
+            },
+            Exact::Big(a) => *a *= c,
+        }
+    }
+    fn approx(&self) -> f64 {
+        match self {
+            Exact::Small(a) => *a as f64,
+            Exact::Big(a) => a.to_f64().unwrap_or(f64::INFINITY),
+        }
+    }
+}
+
+/// Bucket2 row index held in the x -> rows lists. `u32` halves the index
+/// memory (and the cache traffic of the binary searches) whenever bucket2 has
+/// fewer than 2^32 rows; `usize` covers the rest.
+pub trait RowIndex: Copy + Ord + Send + Sync {
+    fn from_row(r: usize) -> Self;
+    fn row(self) -> usize;
+}
+
+impl RowIndex for u32 {
+    #[inline]
+    fn from_row(r: usize) -> Self {
+        r as u32
+    }
+    #[inline]
+    fn row(self) -> usize {
+        self as usize
+    }
+}
+
+impl RowIndex for usize {
+    #[inline]
+    fn from_row(r: usize) -> Self {
+        r
+    }
+    #[inline]
+    fn row(self) -> usize {
+        self
+    }
+}
+
+// x -> sorted Vec<row_idx>; a counting pass sizes every list exactly
+pub fn build_rows_by_jbt<R: RowIndex>(bucket: &Bucket) -> HashMap<i32, Vec<R>> {
+    let mut counts: HashMap<i32, usize> = HashMap::new();
+    for &v in &bucket.rows_data {
+        *counts.entry(v).or_insert(0) += 1;
+    }
+    let mut m: HashMap<i32, Vec<R>> = counts
+        .into_iter()
+        .map(|(v, n)| (v, Vec::with_capacity(n)))
+        .collect();
+    // rows are visited in order, so each list comes out sorted
     for r in 0..bucket.n_rows() {
         for &v in bucket.row_slice(r) {
-            m.entry(v).or_default().push(r);
+            m.get_mut(&v).unwrap().push(R::from_row(r));
         }
     }
-    for rows in m.values_mut() {
-        rows.sort_unstable();
-    }
     m
 }
 
 // candidates per j (filtered to x present in bucket2)
-pub fn precompute_candidates_for_bucket1(
+pub fn precompute_candidates_for_bucket1<R: RowIndex>(
     bucket1: &Bucket,
-    rows_by_jbt: &HashMap<i32, Vec<usize>>,
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
     jbt_ref_pop: &[i32],
     n_total: i32,
     compat: &HashMap<i32, (Vec<i32>, Vec<i32>)>,
+    complement: ComplementFn,
 ) -> HashMap<i32, Vec<i32>> {
     let mut all_j: HashSet<i32> = HashSet::new();
     for r in 0..bucket1.n_rows() {
@@ -35,22 +328,23 @@ pub fn precompute_candidates_for_bucket1(
     let mut out: HashMap<i32, Vec<i32>> = HashMap::with_capacity(all_j.len());
     for j in all_j {
         let pop = jbt_ref_pop[j as usize];
-        let (k1, k2): (&Vec<i32>, &Vec<i32>) = if pop > n_total / 2 {
-            let pair = compat.get(&(n_total - pop)).expect("compat missing pop");
+        let partner = partner_pop(complement, pop, n_total);
+        // compat holds each pop pair once, under the smaller pop
+        let (k1, k2): (&Vec<i32>, &Vec<i32>) = if pop > partner {
+            let pair = compat.get(&partner).expect("compat missing pop");
             (&pair.1, &pair.0) // swapped
         } else {
             let pair = compat.get(&pop).expect("compat missing pop");
             (&pair.0, &pair.1)
         };
-        let mut cands = Vec::<i32>::new();
-        for (i, &v) in k1.iter().enumerate() {
-            if v == j {
-                let x = k2[i];
-                if rows_by_jbt.contains_key(&x) {
-                    cands.push(x);
-                }
-            }
-        }
+        let matches = || {
+            k1.iter()
+                .zip(k2)
+                .filter(move |&(&v, x)| v == j && rows_by_jbt.contains_key(x))
+                .map(|(_, &x)| x)
+        };
+        let mut cands = Vec::<i32>::with_capacity(matches().count());
+        cands.extend(matches());
         cands.sort_unstable();
         cands.dedup();
         out.insert(j, cands);
@@ -58,39 +352,658 @@ pub fn precompute_candidates_for_bucket1(
     out
 }
 
-// per-pair subtotal (same logic you’re running now)
-pub fn subtotal_for_pair(
+/// Partial-subtotal callback for long pairs: fires as bucket1 rows complete,
+/// roughly every `every` rows, with (rows_done, rows_total, subtotal_so_far).
+pub struct ProgressHook<'a> {
+    pub every: usize,
+    pub report: &'a (dyn Fn(usize, usize, f64) + Sync),
+}
+
+/// Bucket1 row groups per chunk. Chunk boundaries depend only on this constant
+/// (not on the thread count), and chunk subtotals are added in chunk order, so
+/// a pair's f64 subtotal is bit-identical however the chunks were scheduled.
+pub const PAIR_CHUNK_GROUPS: usize = 2048;
+
+/// Bucket1 rows the solver cannot tell apart: position for position the same
+/// candidate set and the same unique/colliding class. One is solved for all.
+struct RowGroup<T> {
+    /// First row of the group; its j's stand in for the others'.
+    rep: usize,
+    rows: usize,
+    /// Sum of the rows' weights, in row order.
+    weight: T,
+}
+
+/// Intern the candidate sets of `cand_map`: j -> id shared by every j with the
+/// same candidate list.
+fn intern_candidates(cand_map: &HashMap<i32, Vec<i32>>) -> HashMap<i32, u32> {
+    let mut ids: HashMap<&[i32], u32> = HashMap::with_capacity(cand_map.len());
+    cand_map
+        .iter()
+        .map(|(&j, cands)| {
+            let next = ids.len() as u32;
+            (j, *ids.entry(cands.as_slice()).or_insert(next))
+        })
+        .collect()
+}
+
+/// Group the rows of `bucket1` by signature (the sorted interned candidate set
+/// and class of each position), in order of first row: the first row of each
+/// group, and each row's group. Rows with a position that has no candidate
+/// count for nothing and get no group.
+fn row_groups(
+    bucket1: &Bucket,
+    jbt_ref_pop: &[i32],
+    cand_map: &HashMap<i32, Vec<i32>>,
+    cand_id: &HashMap<i32, u32>,
+    pop_mult: &HashMap<i32, i32>,
+) -> (Vec<usize>, Vec<Option<usize>>) {
+    let mut reps: Vec<usize> = Vec::new();
+    let mut group_of: Vec<Option<usize>> = vec![None; bucket1.n_rows()];
+    let mut index: HashMap<SmallVec<[u64; 8]>, usize> = HashMap::new();
+    'rows: for (r, group) in group_of.iter_mut().enumerate() {
+        let mut sig: SmallVec<[u64; 8]> = SmallVec::new();
+        for &j in bucket1.row_slice(r) {
+            let pop = jbt_ref_pop[j as usize];
+            if pop == 0 {
+                continue;
+            }
+            if cand_map.get(&j).is_none_or(|c| c.is_empty()) {
+                continue 'rows;
+            }
+            let colliding = *pop_mult.get(&pop).unwrap_or(&0) > 1;
+            sig.push(((cand_id[&j] as u64) << 1) | colliding as u64);
+        }
+        sig.sort_unstable();
+        *group = Some(*index.entry(sig).or_insert_with(|| {
+            reps.push(r);
+            reps.len() - 1
+        }));
+    }
+    (reps, group_of)
+}
+
+/// The `row_groups` of `bucket1`, each with its row count and the sum of its
+/// rows' weights in row order.
+fn group_rows<T: Tally>(
+    bucket1: &Bucket,
+    weights1: &[T],
+    jbt_ref_pop: &[i32],
+    cand_map: &HashMap<i32, Vec<i32>>,
+    cand_id: &HashMap<i32, u32>,
+    pop_mult: &HashMap<i32, i32>,
+) -> Vec<RowGroup<T>> {
+    let (reps, group_of) = row_groups(bucket1, jbt_ref_pop, cand_map, cand_id, pop_mult);
+    let mut groups: Vec<RowGroup<T>> = reps
+        .into_iter()
+        .map(|rep| RowGroup {
+            rep,
+            rows: 0,
+            weight: T::zero(),
+        })
+        .collect();
+    for (w, g) in weights1.iter().zip(group_of) {
+        if let Some(g) = g {
+            groups[g].rows += 1;
+            groups[g].weight.add(w);
+        }
+    }
+    groups
+}
+
+/// Per-pair subtotal. Bucket1 rows are grouped (see `RowGroup`), and the groups
+/// split into `PAIR_CHUNK_GROUPS` chunks that run on the rayon pool when
+/// `parallel` (so one dominant pair can use idle workers), or in order on the
+/// calling thread otherwise; either way the result is the in-order sum of the
+/// chunk subtotals.
+pub fn subtotal_for_pair<R: RowIndex>(
+    bucket1: &Bucket,
+    bucket2: &Bucket,
+    jbt_ref_pop: &[i32],
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
+    cand_map: &HashMap<i32, Vec<i32>>,
+    progress: Option<&ProgressHook>,
+    parallel: bool,
+) -> Subtotal {
+    for b in [bucket1, bucket2] {
+        debug_assert!(
+            b.first_non_finite_weight().is_none(),
+            "bucket {:?}: non-finite weight (row, weight) {:?}",
+            b.key,
+            b.first_non_finite_weight()
+        );
+    }
+    let ints = bucket1.int_weights().zip(bucket2.int_weights());
+    let bound = ints
+        .as_ref()
+        .and_then(|(w1, w2)| pair_bound(bucket1, w1, w2, jbt_ref_pop, cand_map));
+    macro_rules! run {
+        ($w1:expr, $w2:expr) => {
+            pair_subtotal(
+                bucket1,
+                $w1,
+                bucket2,
+                $w2,
+                jbt_ref_pop,
+                rows_by_jbt,
+                cand_map,
+                progress,
+                parallel,
+            )
+        };
+    }
+    match (ints, bound) {
+        (Some((w1, w2)), Some(bound)) if bound <= u64::MAX as u128 => {
+            let narrow = |ws: Vec<u128>| ws.into_iter().map(|w| w as u64).collect::<Vec<_>>();
+            let x: u64 = run!(&narrow(w1), &narrow(w2));
+            let x = x as u128;
+            Subtotal {
+                value: x as f64,
+                exact: Some(x),
+                accum: Accum::U64,
+            }
+        }
+        (Some((w1, w2)), Some(_)) => {
+            let x: u128 = run!(&w1, &w2);
+            Subtotal {
+                value: x as f64,
+                exact: Some(x),
+                accum: Accum::U128,
+            }
+        }
+        _ => Subtotal {
+            value: {
+                let compensated =
+                    |ws: &[f64]| ws.iter().map(|&w| Compensated::of(w)).collect::<Vec<_>>();
+                let x: Compensated = run!(
+                    &compensated(&bucket1.weights),
+                    &compensated(&bucket2.weights)
+                );
+                x.value()
+            },
+            exact: None,
+            accum: Accum::F64,
+        },
+    }
+}
+
+/// `subtotal_for_pair` in exact integers, from the weights `Bucket::exact_weights`
+/// gives each bucket (`--exact`).
+#[allow(clippy::too_many_arguments)]
+pub fn exact_subtotal_for_pair<R: RowIndex>(
+    bucket1: &Bucket,
+    weights1: &[BigUint],
+    bucket2: &Bucket,
+    weights2: &[BigUint],
+    jbt_ref_pop: &[i32],
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
+    cand_map: &HashMap<i32, Vec<i32>>,
+    parallel: bool,
+) -> BigUint {
+    let exact = |ws: &[BigUint]| ws.iter().map(Exact::from_big).collect::<Vec<_>>();
+    pair_subtotal(
+        bucket1,
+        &exact(weights1),
+        bucket2,
+        &exact(weights2),
+        jbt_ref_pop,
+        rows_by_jbt,
+        cand_map,
+        None,
+        parallel,
+    )
+    .to_big()
+}
+
+/// The weight-free part of a pair (`--plans`): which bucket1 rows share a
+/// group, and for each group the number of assignments it has into each
+/// bucket2 row. The subtotal is then sum over groups of (group weight) x
+/// sum over rows of (count x bucket2 weight), for any weights on the same rows.
+#[derive(Debug, Clone, Default)]
+pub struct PairPlan {
+    /// Neutral pair: every row pair counts once (no groups or cells).
+    pub neutral: bool,
+    /// Group g holds bucket1 rows `members[member_indptr[g]..member_indptr[g + 1]]`.
+    pub member_indptr: Vec<u64>,
+    pub members: Vec<u32>,
+    /// Group g has `cell_counts[i]` assignments into bucket2 row `cell_rows[i]`
+    /// for i in `cell_indptr[g]..cell_indptr[g + 1]` (ascending rows, nonzero).
+    pub cell_indptr: Vec<u64>,
+    pub cell_rows: Vec<u32>,
+    pub cell_counts: Vec<u64>,
+}
+
+impl PairPlan {
+    pub fn n_groups(&self) -> usize {
+        self.member_indptr.len().saturating_sub(1)
+    }
+    /// The most assignments of one row pair (1 for a neutral pair).
+    pub fn max_count(&self) -> u64 {
+        self.cell_counts.iter().copied().max().unwrap_or(1)
+    }
+    fn group_range(indptr: &[u64], g: usize) -> std::ops::Range<usize> {
+        indptr[g] as usize..indptr[g + 1] as usize
+    }
+}
+
+/// The tally `chunk_subtotal` runs in to build a `PairPlan`: bucket2 weights
+/// are unit vectors `Rows([(r, 1)])`, group weights `Group(g)`, and a group's
+/// total comes out as the cells (g, r, count). Only the operations the solver
+/// applies to each kind are defined.
+#[derive(Clone)]
+enum PlanTally {
+    Group(usize),
+    /// Counts per bucket2 row, ascending.
+    Rows(SmallVec<[(usize, u64); 1]>),
+    /// (group, bucket2 row, count), ascending.
+    Cells(Vec<(usize, usize, u64)>),
+}
+
+/// Merge two ascending (key, count) lists, adding the counts of equal keys.
+fn merge_counts<K: Copy + Ord>(a: &[(K, u64)], b: &[(K, u64)]) -> Vec<(K, u64)> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut k) = (0, 0);
+    while i < a.len() && k < b.len() {
+        match a[i].0.cmp(&b[k].0) {
+            std::cmp::Ordering::Less => {
+                out.push(a[i]);
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                out.push(b[k]);
+                k += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                out.push((a[i].0, a[i].1 + b[k].1));
+                i += 1;
+                k += 1;
+            }
+        }
+    }
+    out.extend_from_slice(&a[i..]);
+    out.extend_from_slice(&b[k..]);
+    out
+}
+
+impl PlanTally {
+    fn is_empty(&self) -> bool {
+        match self {
+            PlanTally::Group(_) => false,
+            PlanTally::Rows(v) => v.is_empty(),
+            PlanTally::Cells(v) => v.is_empty(),
+        }
+    }
+}
+
+impl Tally for PlanTally {
+    fn zero() -> Self {
+        PlanTally::Rows(SmallVec::new())
+    }
+    fn add(&mut self, x: &Self) {
+        match (&mut *self, x) {
+            (_, x) if x.is_empty() => {}
+            (a, _) if a.is_empty() => *self = x.clone(),
+            (PlanTally::Rows(a), PlanTally::Rows(b)) => {
+                // rows mostly arrive in order: append when they do
+                if a.last().is_some_and(|l| l.0 < b[0].0) {
+                    a.extend_from_slice(b);
+                } else {
+                    *a = merge_counts(a, b).into();
+                }
+            }
+            (PlanTally::Cells(a), PlanTally::Cells(b)) => {
+                if a.last().is_some_and(|l| (l.0, l.1) < (b[0].0, b[0].1)) {
+                    a.extend_from_slice(b);
+                } else {
+                    let key = |c: &[(usize, usize, u64)]| -> Vec<((usize, usize), u64)> {
+                        c.iter().map(|&(g, r, n)| ((g, r), n)).collect()
+                    };
+                    *a = merge_counts(&key(a), &key(b))
+                        .into_iter()
+                        .map(|((g, r), n)| (g, r, n))
+                        .collect();
+                }
+            }
+            _ => unreachable!("plan tally: adding a group weight"),
+        }
+    }
+    fn mul(&self, x: &Self) -> Self {
+        match (self, x) {
+            (PlanTally::Group(g), PlanTally::Rows(v))
+            | (PlanTally::Rows(v), PlanTally::Group(g)) => {
+                PlanTally::Cells(v.iter().map(|&(r, n)| (*g, r, n)).collect())
+            }
+            _ => unreachable!("plan tally: product of two row vectors"),
+        }
+    }
+    fn mul_count(&mut self, c: u64) {
+        match self {
+            PlanTally::Rows(v) => v.iter_mut().for_each(|e| e.1 *= c),
+            PlanTally::Cells(v) => v.iter_mut().for_each(|e| e.2 *= c),
+            PlanTally::Group(_) => unreachable!("plan tally: scaling a group weight"),
+        }
+    }
+    fn approx(&self) -> f64 {
+        match self {
+            PlanTally::Group(_) => 1.0,
+            PlanTally::Rows(v) => v.iter().map(|e| e.1 as f64).sum(),
+            PlanTally::Cells(v) => v.iter().map(|e| e.2 as f64).sum(),
+        }
+    }
+}
+
+/// Build the `PairPlan` of a pair: the solver's own grouping and assignment
+/// counting, run once without weights. `None` when a bucket has 2^32 rows or
+/// more, or a row pair can have more than u64::MAX assignments.
+pub fn build_pair_plan<R: RowIndex>(
     bucket1: &Bucket,
     bucket2: &Bucket,
     jbt_ref_pop: &[i32],
-    _n_total: i32,
-    _compat: &std::collections::HashMap<i32, (Vec<i32>, Vec<i32>)>,
-    rows_by_jbt: &HashMap<i32, Vec<usize>>,
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
     cand_map: &HashMap<i32, Vec<i32>>,
-) -> f64 {
+) -> Option<PairPlan> {
+    let (n1, n2) = (bucket1.n_rows(), bucket2.n_rows());
+    if n1 > u32::MAX as usize || n2 > u32::MAX as usize {
+        return None;
+    }
     if bucket1.key.is_empty() {
-        let s1: f64 = bucket1.weights.iter().copied().sum();
-        let s2: f64 = bucket2.weights.iter().copied().sum();
-        return s1 * s2;
+        return Some(PairPlan {
+            neutral: true,
+            ..Default::default()
+        });
     }
+    max_assignments(bucket1, jbt_ref_pop, cand_map).filter(|&m| m <= u64::MAX as u128)?;
 
-    let n_rows2 = bucket2.n_rows();
-    let mut subtotal = 0.0f64;
+    let mut pop_mult: HashMap<i32, i32> = HashMap::new();
+    for &p in &bucket1.key {
+        *pop_mult.entry(p).or_insert(0) += 1;
+    }
+    let cand_id = intern_candidates(cand_map);
+    let (reps, group_of) = row_groups(bucket1, jbt_ref_pop, cand_map, &cand_id, &pop_mult);
+
+    let mut member_indptr = vec![0u64; reps.len() + 1];
+    for g in group_of.iter().flatten() {
+        member_indptr[g + 1] += 1;
+    }
+    for g in 0..reps.len() {
+        member_indptr[g + 1] += member_indptr[g];
+    }
+    let mut members = vec![0u32; member_indptr[reps.len()] as usize];
+    let mut next: Vec<u64> = member_indptr[..reps.len()].to_vec();
+    for (r, g) in group_of.iter().enumerate() {
+        if let Some(g) = *g {
+            members[next[g] as usize] = r as u32;
+            next[g] += 1;
+        }
+    }
+
+    let groups: Vec<RowGroup<PlanTally>> = reps
+        .iter()
+        .enumerate()
+        .map(|(g, &rep)| RowGroup {
+            rep,
+            rows: 1,
+            weight: PlanTally::Group(g),
+        })
+        .collect();
+    let weights2: Vec<PlanTally> = (0..n2)
+        .map(|r| PlanTally::Rows(SmallVec::from_buf([(r, 1)])))
+        .collect();
+    let chunks: Vec<PlanTally> = groups
+        .par_chunks(PAIR_CHUNK_GROUPS)
+        .map(|chunk| {
+            chunk_subtotal(
+                bucket1,
+                bucket2,
+                &weights2,
+                chunk,
+                jbt_ref_pop,
+                rows_by_jbt,
+                cand_map,
+                &cand_id,
+                &pop_mult,
+            )
+        })
+        .collect();
+
+    let mut cell_indptr = vec![0u64; reps.len() + 1];
+    let (mut cell_rows, mut cell_counts) = (Vec::new(), Vec::new());
+    for chunk in chunks {
+        let PlanTally::Cells(cells) = chunk else {
+            continue; // a chunk with no compatible row pair
+        };
+        for (g, r, n) in cells {
+            if n > 0 {
+                cell_indptr[g + 1] += 1;
+                cell_rows.push(r as u32);
+                cell_counts.push(n);
+            }
+        }
+    }
+    for g in 0..reps.len() {
+        cell_indptr[g + 1] += cell_indptr[g];
+    }
+    Some(PairPlan {
+        neutral: false,
+        member_indptr,
+        members,
+        cell_indptr,
+        cell_rows,
+        cell_counts,
+    })
+}
+
+/// A pair's subtotal from its plan and the buckets' current weights.
+fn plan_subtotal<T: Tally>(plan: &PairPlan, weights1: &[T], weights2: &[T]) -> T {
+    let sum = |ws: &mut dyn Iterator<Item = T>| {
+        let mut s = T::zero();
+        for w in ws {
+            s.add(&w);
+        }
+        s
+    };
+    if plan.neutral {
+        return sum(&mut weights1.iter().cloned()).mul(&sum(&mut weights2.iter().cloned()));
+    }
+    let mut total = T::zero();
+    for g in 0..plan.n_groups() {
+        let members = &plan.members[PairPlan::group_range(&plan.member_indptr, g)];
+        let cells = PairPlan::group_range(&plan.cell_indptr, g);
+        let w1 = sum(&mut members.iter().map(|&r| weights1[r as usize].clone()));
+        let s = sum(&mut cells.map(|i| {
+            let mut w = weights2[plan.cell_rows[i] as usize].clone();
+            w.mul_count(plan.cell_counts[i]);
+            w
+        }));
+        total.add(&w1.mul(&s));
+    }
+    total
+}
+
+/// `subtotal_for_pair` from a `PairPlan`, in the accumulator the same bound
+/// picks (total left weight x total right weight x the plan's largest count).
+pub fn subtotal_from_plan(plan: &PairPlan, bucket1: &Bucket, bucket2: &Bucket) -> Subtotal {
+    let ints = bucket1.int_weights().zip(bucket2.int_weights());
+    let bound = ints.as_ref().and_then(|(w1, w2)| {
+        let sum = |ws: &[u128]| ws.iter().try_fold(0u128, |s, &w| s.checked_add(w));
+        sum(w1)?
+            .checked_mul(sum(w2)?)?
+            .checked_mul(plan.max_count() as u128)
+    });
+    match (ints, bound) {
+        (Some((w1, w2)), Some(bound)) if bound <= u64::MAX as u128 => {
+            let narrow = |ws: Vec<u128>| ws.into_iter().map(|w| w as u64).collect::<Vec<_>>();
+            let x = plan_subtotal(plan, &narrow(w1), &narrow(w2)) as u128;
+            Subtotal {
+                value: x as f64,
+                exact: Some(x),
+                accum: Accum::U64,
+            }
+        }
+        (Some((w1, w2)), Some(_)) => {
+            let x = plan_subtotal(plan, &w1, &w2);
+            Subtotal {
+                value: x as f64,
+                exact: Some(x),
+                accum: Accum::U128,
+            }
+        }
+        _ => {
+            let compensated =
+                |ws: &[f64]| ws.iter().map(|&w| Compensated::of(w)).collect::<Vec<_>>();
+            Subtotal {
+                value: plan_subtotal(
+                    plan,
+                    &compensated(&bucket1.weights),
+                    &compensated(&bucket2.weights),
+                )
+                .value(),
+                exact: None,
+                accum: Accum::F64,
+            }
+        }
+    }
+}
+
+/// `exact_subtotal_for_pair` from a `PairPlan`.
+pub fn exact_subtotal_from_plan(
+    plan: &PairPlan,
+    weights1: &[BigUint],
+    weights2: &[BigUint],
+) -> BigUint {
+    let exact = |ws: &[BigUint]| ws.iter().map(Exact::from_big).collect::<Vec<_>>();
+    plan_subtotal(plan, &exact(weights1), &exact(weights2)).to_big()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn pair_subtotal<R: RowIndex, T: Tally>(
+    bucket1: &Bucket,
+    weights1: &[T],
+    bucket2: &Bucket,
+    weights2: &[T],
+    jbt_ref_pop: &[i32],
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
+    cand_map: &HashMap<i32, Vec<i32>>,
+    progress: Option<&ProgressHook>,
+    parallel: bool,
+) -> T {
+    if bucket1.key.is_empty() {
+        let sum = |ws: &[T]| {
+            let mut s = T::zero();
+            for w in ws {
+                s.add(w);
+            }
+            s
+        };
+        return sum(weights1).mul(&sum(weights2));
+    }
 
     let mut pop_mult: HashMap<i32, i32> = HashMap::new();
     for &p in &bucket1.key {
         *pop_mult.entry(p).or_insert(0) += 1;
     }
 
-    let mut union_cache: HashMap<i32, Vec<bool>> = HashMap::new();
-    let mut count_cache: HashMap<i32, Vec<i32>> = HashMap::new();
+    let cand_id = intern_candidates(cand_map);
+    let groups = group_rows(
+        bucket1,
+        weights1,
+        jbt_ref_pop,
+        cand_map,
+        &cand_id,
+        &pop_mult,
+    );
+    let n_rows1: usize = groups.iter().map(|g| g.rows).sum();
+    let n_chunks = groups.len().div_ceil(PAIR_CHUNK_GROUPS);
+    // (rows done, sum of finished chunks, rows at the last report)
+    let done = Mutex::new((0usize, 0.0f64, 0usize));
+    let run_chunk = |c: usize| -> T {
+        let chunk = &groups[c * PAIR_CHUNK_GROUPS..((c + 1) * PAIR_CHUNK_GROUPS).min(groups.len())];
+        let n: usize = chunk.iter().map(|g| g.rows).sum();
+        let sub = chunk_subtotal(
+            bucket1,
+            bucket2,
+            weights2,
+            chunk,
+            jbt_ref_pop,
+            rows_by_jbt,
+            cand_map,
+            &cand_id,
+            &pop_mult,
+        );
+        debug_assert!(
+            sub.is_finite(),
+            "pair {:?} vs {:?}: chunk {} subtotal {}",
+            bucket1.key,
+            bucket2.key,
+            c,
+            sub.approx()
+        );
+        if let Some(hook) = progress {
+            let mut d = done.lock().unwrap();
+            d.0 += n;
+            d.1 += sub.approx();
+            if d.0 < n_rows1 && d.0 - d.2 >= hook.every {
+                d.2 = d.0;
+                (hook.report)(d.0, n_rows1, d.1);
+            }
+        }
+        sub
+    };
+    let chunk_subtotals: Vec<T> = if parallel && n_chunks > 1 {
+        (0..n_chunks).into_par_iter().map(run_chunk).collect()
+    } else {
+        (0..n_chunks).map(run_chunk).collect()
+    };
+    let mut total = T::zero();
+    for sub in &chunk_subtotals {
+        total.add(sub);
+    }
+    total
+}
+
+/// Subtotal over the row groups `groups`, with chunk-local union/count caches
+/// keyed by interned candidate set.
+#[allow(clippy::too_many_arguments)]
+fn chunk_subtotal<R: RowIndex, T: Tally>(
+    bucket1: &Bucket,
+    bucket2: &Bucket,
+    weights2: &[T],
+    groups: &[RowGroup<T>],
+    jbt_ref_pop: &[i32],
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
+    cand_map: &HashMap<i32, Vec<i32>>,
+    cand_id: &HashMap<i32, u32>,
+    pop_mult: &HashMap<i32, i32>,
+) -> T {
+    let n_rows2 = bucket2.n_rows();
+    let mut subtotal = T::zero();
+
+    let mut union_cache: HashMap<u32, Vec<bool>> = HashMap::new();
+    let mut count_cache: HashMap<u32, Vec<i32>> = HashMap::new();
 
-    'rowloop: for r1 in 0..bucket1.n_rows() {
-        let row = bucket1.row_slice(r1);
-        let w1 = bucket1.weights[r1] as f64;
+    // per-row scratch, sized once per chunk and refilled for each row
+    let max_row = groups
+        .iter()
+        .map(|g| bucket1.row_slice(g.rep).len())
+        .max()
+        .unwrap_or(0);
+    let mut unique_positions: Vec<usize> = Vec::with_capacity(max_row);
+    let mut colliding_positions: Vec<usize> = Vec::with_capacity(max_row);
+    let mut mask = vec![true; n_rows2];
+    let mut eff = weights2.to_vec();
+    let mut rem: Vec<i32> = Vec::with_capacity(max_row);
+    let mut cand_lists: Vec<&[i32]> = Vec::with_capacity(max_row);
+    let mut cluster_of: Vec<usize> = Vec::with_capacity(max_row);
+    let mut entangled: Vec<usize> = Vec::with_capacity(max_row);
+    let mut cluster: Vec<i32> = Vec::with_capacity(max_row);
+    let mut assignments = vec![0u64; n_rows2];
 
-        let mut unique_positions = Vec::new();
-        let mut colliding_positions = Vec::new();
+    'rowloop: for g in groups {
+        let row = bucket1.row_slice(g.rep);
+        let w1 = &g.weight;
+
+        unique_positions.clear();
+        colliding_positions.clear();
 
         for (i, &j) in row.iter().enumerate() {
             let pop = jbt_ref_pop[j as usize];
@@ -108,34 +1021,35 @@ pub fn subtotal_for_pair(
             }
         }
 
-        let mut mask = vec![true; n_rows2];
-        let mut eff = bucket2.weights.clone();
+        mask.fill(true);
+        eff.clone_from_slice(weights2);
 
         // unique-pop fast path
         for &i in &unique_positions {
             let j = row[i];
-            if !union_cache.contains_key(&j) {
+            let id = cand_id[&j];
+            if let std::collections::hash_map::Entry::Vacant(e) = union_cache.entry(id) {
                 let cands = cand_map.get(&j).map(|v| v.as_slice()).unwrap_or(&[]);
                 let mut union = vec![false; n_rows2];
                 let mut counts = vec![0i32; n_rows2];
                 for &x in cands {
                     if let Some(rows) = rows_by_jbt.get(&x) {
                         for &r in rows {
-                            union[r] = true;
-                            counts[r] += 1;
+                            union[r.row()] = true;
+                            counts[r.row()] += 1;
                         }
                     }
                 }
-                union_cache.insert(j, union);
-                count_cache.insert(j, counts);
+                e.insert(union);
+                count_cache.insert(id, counts);
             }
-            let union = union_cache.get(&j).unwrap();
-            let counts = count_cache.get(&j).unwrap();
+            let union = union_cache.get(&id).unwrap();
+            let counts = count_cache.get(&id).unwrap();
             let mut any = false;
             for r in 0..n_rows2 {
                 mask[r] = mask[r] && union[r];
                 if mask[r] {
-                    eff[r] *= counts[r] as f64;
+                    eff[r].mul_count(counts[r] as u64);
                     any = true;
                 }
             }
@@ -144,159 +1058,721 @@ pub fn subtotal_for_pair(
             }
         }
 
-        let rem: Vec<i32> = colliding_positions.iter().map(|&i| row[i]).collect();
+        rem.clear();
+        rem.extend(colliding_positions.iter().map(|&i| row[i]));
         if rem.is_empty() {
-            let mut s = 0.0f64;
+            let mut s = T::zero();
             for r in 0..n_rows2 {
                 if mask[r] {
-                    s += eff[r];
+                    s.add(&eff[r]);
                 }
             }
-            subtotal += w1 * s;
+            subtotal.add(&w1.mul(&s));
             continue;
         }
 
-        // disjoint fast path
-        let mut seen = std::collections::HashSet::new();
-        let mut overlap = false;
-        let mut cand_lists: Vec<&[i32]> = Vec::with_capacity(rem.len());
-        for &j in &rem {
-            let cands = cand_map.get(&j).map(|v| v.as_slice()).unwrap_or(&[]);
-            for &x in cands {
-                if !seen.insert(x) {
-                    overlap = true;
-                    break;
+        // split the colliding positions into clusters whose candidate sets
+        // overlap (transitively); injectivity only ties positions within a
+        // cluster, so per bucket2 row the count is the product over clusters
+        cand_lists.clear();
+        cand_lists.extend(
+            rem.iter()
+                .map(|j| cand_map.get(j).map(|v| v.as_slice()).unwrap_or(&[])),
+        );
+        cluster_of.clear();
+        cluster_of.extend(0..rem.len());
+        for a in 0..rem.len() {
+            for b in a + 1..rem.len() {
+                if sorted_overlap(cand_lists[a], cand_lists[b]) {
+                    let (ca, cb) = (cluster_of[a], cluster_of[b]);
+                    for c in cluster_of.iter_mut() {
+                        if *c == cb {
+                            *c = ca;
+                        }
+                    }
                 }
             }
-            cand_lists.push(cands);
-            if overlap {
-                break;
-            }
         }
-        if !overlap {
-            let mut s = 0.0f64;
+
+        // single-position clusters (the disjoint case): fold the number of
+        // candidates present in each bucket2 row into eff
+        entangled.clear();
+        for (i, &cands) in cand_lists.iter().enumerate() {
+            if cluster_of.iter().filter(|&&c| c == cluster_of[i]).count() > 1 {
+                if !entangled.contains(&cluster_of[i]) {
+                    entangled.push(cluster_of[i]);
+                }
+                continue;
+            }
+            let mut any = false;
             for r in 0..n_rows2 {
                 if !mask[r] {
                     continue;
                 }
-                let mut mult = eff[r];
-                for &cands in &cand_lists {
-                    let mut cnt = 0i32;
-                    for &x in cands {
-                        if let Some(rows) = rows_by_jbt.get(&x) {
-                            if rows.binary_search(&r).is_ok() {
-                                cnt += 1;
-                            }
-                        }
+                let mut cnt = 0i32;
+                for &x in cands {
+                    if let Some(rows) = rows_by_jbt.get(&x)
+                        && rows.binary_search(&R::from_row(r)).is_ok()
+                    {
+                        cnt += 1;
                     }
-                    mult *= cnt as f64;
                 }
-                s += mult;
+                eff[r].mul_count(cnt as u64);
+                mask[r] = cnt > 0;
+                any |= cnt > 0;
+            }
+            if !any {
+                continue 'rowloop;
             }
-            subtotal += w1 * s;
-            continue;
         }
-
-        // fallback recursion with injectivity
-        fn intersect_in_place(dst: &mut [bool], rows: &[usize]) -> bool {
-            let mut any = false;
-            for (i, v) in dst.iter_mut().enumerate() {
-                if *v {
-                    *v = rows.binary_search(&i).is_ok();
-                }
-                if *v {
-                    any = true;
+        let Some((&last, others)) = entangled.split_last() else {
+            let mut s = T::zero();
+            for r in 0..n_rows2 {
+                if mask[r] {
+                    s.add(&eff[r]);
                 }
             }
-            any
-        }
-        fn rec(
-            idxs: &[i32],
-            mask: &[bool],
-            eff: &[f64],
-            rows_by_jbt: &HashMap<i32, Vec<usize>>,
-            cand_map: &HashMap<i32, Vec<i32>>,
-            used_x: &mut std::collections::HashSet<i32>,
-        ) -> f64 {
-            for &j in idxs {
-                let cands = cand_map.get(&j).map(|v| v.as_slice()).unwrap_or(&[]);
-                let mut ok = false;
-                'outer: for &x in cands {
-                    if used_x.contains(&x) {
-                        continue;
-                    }
-                    if let Some(rows) = rows_by_jbt.get(&x) {
-                        for &r in rows {
-                            if mask[r] {
-                                ok = true;
-                                break 'outer;
-                            }
+            subtotal.add(&w1.mul(&s));
+            continue;
+        };
+
+        // entangled clusters but the last: count their injective assignments
+        // per bucket2 row and fold the counts into eff
+        for &c in others {
+            cluster.clear();
+            cluster.extend(
+                (0..rem.len())
+                    .filter(|&i| cluster_of[i] == c)
+                    .map(|i| rem[i]),
+            );
+            assignments.fill(0);
+            let mut used = HashSet::<i32>::new();
+            rec(
+                &cluster,
+                &mask,
+                rows_by_jbt,
+                cand_map,
+                &mut used,
+                &mut |m| {
+                    for (r, &on) in m.iter().enumerate() {
+                        if on {
+                            assignments[r] += 1;
                         }
                     }
-                }
-                if !ok {
-                    return 0.0;
-                }
+                    0.0
+                },
+            );
+            let mut any = false;
+            for r in 0..n_rows2 {
+                eff[r].mul_count(assignments[r]);
+                mask[r] = mask[r] && assignments[r] > 0;
+                any |= mask[r];
             }
-            if idxs.is_empty() {
-                let mut s = 0.0f64;
-                for (r, &m) in mask.iter().enumerate() {
-                    if m {
-                        s += eff[r];
-                    }
-                }
-                return s;
+            if !any {
+                continue 'rowloop;
             }
-            // pivot
-            let mut best_j = idxs[0];
-            let mut best_list: Vec<i32> = Vec::new();
-            let mut best_cnt = usize::MAX;
-            for &j in idxs {
-                let cands = cand_map.get(&j).map(|v| v.as_slice()).unwrap_or(&[]);
-                let mut viable: Vec<i32> = Vec::new();
-                for &x in cands {
-                    if used_x.contains(&x) {
-                        continue;
-                    }
-                    if let Some(rows) = rows_by_jbt.get(&x) {
-                        if rows.iter().any(|&r| mask[r]) {
-                            viable.push(x);
+        }
+
+        // the last cluster sums eff over the rows each assignment leaves
+        cluster.clear();
+        cluster.extend(
+            (0..rem.len())
+                .filter(|&i| cluster_of[i] == last)
+                .map(|i| rem[i]),
+        );
+        let add = {
+            let mut used = HashSet::<i32>::new();
+            rec(
+                &cluster,
+                &mask,
+                rows_by_jbt,
+                cand_map,
+                &mut used,
+                &mut |m| {
+                    let mut s = T::zero();
+                    for (r, &on) in m.iter().enumerate() {
+                        if on {
+                            s.add(&eff[r]);
                         }
                     }
-                }
-                if viable.is_empty() {
-                    return 0.0;
-                }
-                if viable.len() < best_cnt {
-                    best_cnt = viable.len();
-                    best_j = j;
-                    best_list = viable;
-                    if best_cnt == 1 {
-                        break;
-                    }
-                }
+                    s
+                },
+            )
+        };
+        subtotal.add(&w1.mul(&add));
+    }
+
+    subtotal
+}
+
+/// Whether two sorted candidate lists share an x.
+fn sorted_overlap(a: &[i32], b: &[i32]) -> bool {
+    let (mut i, mut k) = (0, 0);
+    while i < a.len() && k < b.len() {
+        match a[i].cmp(&b[k]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => k += 1,
+            std::cmp::Ordering::Equal => return true,
+        }
+    }
+    false
+}
+
+fn intersect_in_place<R: RowIndex>(dst: &mut [bool], rows: &[R]) -> bool {
+    let mut any = false;
+    for (i, v) in dst.iter_mut().enumerate() {
+        if *v {
+            *v = rows.binary_search(&R::from_row(i)).is_ok();
+        }
+        if *v {
+            any = true;
+        }
+    }
+    any
+}
+
+/// Recursion with injectivity over the positions `idxs`: assigns each j a
+/// distinct candidate x present in some bucket2 row still in `mask`, narrowing
+/// the mask to the rows holding every x chosen so far. `leaf` gets the mask of
+/// each complete assignment; the sum of its results is returned.
+fn rec<R: RowIndex, T: Tally>(
+    idxs: &[i32],
+    mask: &[bool],
+    rows_by_jbt: &HashMap<i32, Vec<R>>,
+    cand_map: &HashMap<i32, Vec<i32>>,
+    used_x: &mut HashSet<i32>,
+    leaf: &mut dyn FnMut(&[bool]) -> T,
+) -> T {
+    for &j in idxs {
+        let cands = cand_map.get(&j).map(|v| v.as_slice()).unwrap_or(&[]);
+        let mut ok = false;
+        'outer: for &x in cands {
+            if used_x.contains(&x) {
+                continue;
             }
-            let mut total = 0.0f64;
-            let rest: Vec<i32> = idxs.iter().copied().filter(|&x| x != best_j).collect();
-            for x in best_list {
-                if let Some(rows) = rows_by_jbt.get(&x) {
-                    let mut new_mask = mask.to_vec();
-                    if !intersect_in_place(&mut new_mask, rows) {
-                        continue;
+            if let Some(rows) = rows_by_jbt.get(&x) {
+                for &r in rows {
+                    if mask[r.row()] {
+                        ok = true;
+                        break 'outer;
                     }
-                    used_x.insert(x);
-                    total += rec(&rest, &new_mask, eff, rows_by_jbt, cand_map, used_x);
-                    used_x.remove(&x);
                 }
             }
-            total
         }
-        let add = {
-            let mut used = HashSet::<i32>::new();
-            rec(&rem, &mask, &eff, rows_by_jbt, cand_map, &mut used)
+        if !ok {
+            return T::zero();
+        }
+    }
+    if idxs.is_empty() {
+        return leaf(mask);
+    }
+    // pivot
+    let mut best_j = idxs[0];
+    let mut best_list: Vec<i32> = Vec::new();
+    let mut best_cnt = usize::MAX;
+    for &j in idxs {
+        let cands = cand_map.get(&j).map(|v| v.as_slice()).unwrap_or(&[]);
+        let mut viable: Vec<i32> = Vec::with_capacity(cands.len());
+        for &x in cands {
+            if used_x.contains(&x) {
+                continue;
+            }
+            if let Some(rows) = rows_by_jbt.get(&x)
+                && rows.iter().any(|&r| mask[r.row()])
+            {
+                viable.push(x);
+            }
+        }
+        if viable.is_empty() {
+            return T::zero();
+        }
+        if viable.len() < best_cnt {
+            best_cnt = viable.len();
+            best_j = j;
+            best_list = viable;
+            if best_cnt == 1 {
+                break;
+            }
+        }
+    }
+    let mut total = T::zero();
+    let mut rest: Vec<i32> = Vec::with_capacity(idxs.len() - 1);
+    rest.extend(idxs.iter().copied().filter(|&x| x != best_j));
+    for x in best_list {
+        if let Some(rows) = rows_by_jbt.get(&x) {
+            let mut new_mask = mask.to_vec();
+            if !intersect_in_place(&mut new_mask, rows) {
+                continue;
+            }
+            used_x.insert(x);
+            total.add(&rec(&rest, &new_mask, rows_by_jbt, cand_map, used_x, leaf));
+            used_x.remove(&x);
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching::types::{Indptr, compat_key_sorted};
+
+    /// Deterministic pseudo-random stream (64-bit LCG).
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+    }
+
+    fn bucket(key: Vec<i32>, rows: &[Vec<i32>], rng: &mut Lcg) -> Bucket {
+        let mut indptr = vec![0i64];
+        for r in rows {
+            indptr.push(indptr.last().unwrap() + r.len() as i64);
+        }
+        Bucket {
+            rows_data: rows.concat().into(),
+            indptr: Indptr::from_i64(indptr),
+            // fractional, so the pair is counted in f64 and summation order shows
+            weights: (0..rows.len())
+                .map(|_| 1.0 + rng.next() as f64 / 3.0e5)
+                .collect::<Vec<_>>()
+                .into(),
+            exact: Vec::new(),
+            key,
+        }
+    }
+
+    /// N=4: bucket1 rows hold two pop-1 j's, bucket2 rows two pop-3 x's, and
+    /// each j is compatible with its own few x's, so bucket1 falls into more
+    /// row groups than one chunk holds.
+    #[test]
+    fn pair_subtotal_is_bit_identical_across_thread_counts() {
+        const J: i32 = 400;
+        const X: i32 = 40;
+        let mut rng = Lcg(7);
+        let jbt_ref_pop: Vec<i32> = (0..J).map(|_| 1).chain((0..X).map(|_| 3)).collect();
+        let (mut k1, mut k2) = (Vec::new(), Vec::new());
+        for j in 0..J {
+            for _ in 0..4 {
+                k1.push(j);
+                k2.push(J + (rng.next() % X as u64) as i32);
+            }
+        }
+        let compat = HashMap::from([(1, (k1, k2))]);
+        let pick2 = |rng: &mut Lcg, base: i32, n: i32| {
+            let a = (rng.next() % n as u64) as i32;
+            let b = (a + 1 + (rng.next() % (n - 1) as u64) as i32) % n;
+            vec![base + a.min(b), base + a.max(b)]
+        };
+        let rows1: Vec<Vec<i32>> = (0..6000).map(|_| pick2(&mut rng, 0, J)).collect();
+        let rows2: Vec<Vec<i32>> = (0..100).map(|_| pick2(&mut rng, J, X)).collect();
+        let bucket1 = bucket(vec![1, 1], &rows1, &mut rng);
+        let bucket2 = bucket(vec![3, 3], &rows2, &mut rng);
+
+        let rows_by_jbt = build_rows_by_jbt::<u32>(&bucket2);
+        let cand_map = precompute_candidates_for_bucket1(
+            &bucket1,
+            &rows_by_jbt,
+            &jbt_ref_pop,
+            4,
+            &compat,
+            compat_key_sorted,
+        );
+        let cand_id = intern_candidates(&cand_map);
+        let pop_mult = HashMap::from([(1, 2)]);
+        let groups = group_rows(
+            &bucket1,
+            &bucket1.weights,
+            &jbt_ref_pop,
+            &cand_map,
+            &cand_id,
+            &pop_mult,
+        );
+        assert!(
+            groups.len() > 2 * PAIR_CHUNK_GROUPS,
+            "{} groups",
+            groups.len()
+        );
+        let run = |threads: usize, parallel: bool| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                subtotal_for_pair(
+                    &bucket1,
+                    &bucket2,
+                    &jbt_ref_pop,
+                    &rows_by_jbt,
+                    &cand_map,
+                    None,
+                    parallel,
+                )
+            })
         };
-        subtotal += w1 * add;
+        let one = run(1, true);
+        assert_eq!(one.accum, Accum::F64);
+        assert!(one.value > 0.0);
+        for threads in [2, 8] {
+            assert_eq!(run(threads, true).value.to_bits(), one.value.to_bits());
+        }
+        assert_eq!(run(8, false).value.to_bits(), one.value.to_bits());
     }
 
-    subtotal
+    #[test]
+    fn tallies_agree_and_compensation_keeps_the_low_bits() {
+        let mut rng = Lcg(7);
+        let (mut a, mut b, mut c) = (0u64, 0u128, Compensated::zero());
+        for _ in 0..1000 {
+            let (x, k) = (rng.next() % 1000, rng.next() % 5 + 1);
+            let mut ta = x.mul(&3);
+            ta.mul_count(k);
+            a.add(&ta);
+            let mut tb = (x as u128).mul(&3);
+            tb.mul_count(k);
+            b.add(&tb);
+            let mut tc = Compensated::of(x as f64).mul(&Compensated::of(3.0));
+            tc.mul_count(k);
+            c.add(&tc);
+        }
+        assert_eq!(a as u128, b);
+        assert_eq!(c.value(), a as f64);
+
+        // 1.0 vanishes next to 2^53 in a plain f64 sum, but not here
+        let (mut plain, mut comp) = (0.0f64, Compensated::zero());
+        for x in [9007199254740992.0, 1.0, 1.0, 1.0, 1.0, -9007199254740992.0] {
+            plain.add(&x);
+            comp.add_f64(x);
+        }
+        assert_eq!(plain, 0.0);
+        assert_eq!(comp.value(), 4.0);
+    }
+
+    /// Hand-built pair for the grouping tests. Bucket1 j's 0..=3 and 9 have
+    /// pop 1 and j's 4..=7 pop 2; x's 10..=15 partner pop 1 and 20..=23 pop 2.
+    /// j 9 has j 0's candidates (so their rows share a group), j 7 has none.
+    struct Fixture {
+        jbt_ref_pop: Vec<i32>,
+        cand_map: HashMap<i32, Vec<i32>>,
+    }
+
+    fn fixture() -> Fixture {
+        let mut jbt_ref_pop = vec![0; 24];
+        for (j, pop) in [
+            (0, 1),
+            (1, 1),
+            (2, 1),
+            (3, 1),
+            (9, 1),
+            (4, 2),
+            (5, 2),
+            (6, 2),
+            (7, 2),
+        ] {
+            jbt_ref_pop[j] = pop;
+        }
+        let cand_map = HashMap::from([
+            (0, vec![10, 11]),
+            (1, vec![11, 12]),
+            (2, vec![13, 14]),
+            (3, vec![15]),
+            (9, vec![10, 11]),
+            (4, vec![20, 21]),
+            (5, vec![21, 22]),
+            (6, vec![23]),
+            (7, vec![]),
+        ]);
+        Fixture {
+            jbt_ref_pop,
+            cand_map,
+        }
+    }
+
+    fn int_bucket(key: Vec<i32>, rows: &[Vec<i32>], weights: &[u64]) -> Bucket {
+        let mut indptr = vec![0i64];
+        for r in rows {
+            indptr.push(indptr.last().unwrap() + r.len() as i64);
+        }
+        Bucket {
+            rows_data: rows.concat().into(),
+            indptr: Indptr::from_i64(indptr),
+            weights: weights.iter().map(|&w| w as f64).collect::<Vec<_>>().into(),
+            exact: Vec::new(),
+            key,
+        }
+    }
+
+    /// Every bucket2 row holding `take` of the x's 10..=15 and, when `pop2`,
+    /// one of 20..=23.
+    fn all_rows2(take: usize, pop2: bool) -> Vec<Vec<i32>> {
+        let mut rows: Vec<Vec<i32>> = vec![Vec::new()];
+        for _ in 0..take {
+            rows = rows
+                .into_iter()
+                .flat_map(|r| {
+                    let from = r.last().map_or(10, |&x| x + 1);
+                    (from..=15).map(move |x| [r.as_slice(), &[x]].concat())
+                })
+                .collect();
+        }
+        if pop2 {
+            rows = rows
+                .into_iter()
+                .flat_map(|r| (20..=23).map(move |x| [r.as_slice(), &[x]].concat()))
+                .collect();
+        }
+        rows
+    }
+
+    /// Assignments of `row1` into `row2` by brute force: each distinct j a
+    /// distinct x of `row2` among its candidates. A j the row repeats is
+    /// assigned once, as the reference solver (`old.rs`) does.
+    fn brute_count(row1: &[i32], row2: &[i32], cand_map: &HashMap<i32, Vec<i32>>) -> u64 {
+        fn go(
+            row1: &[i32],
+            row2: &[i32],
+            cand_map: &HashMap<i32, Vec<i32>>,
+            used: &mut Vec<i32>,
+        ) -> u64 {
+            let Some((&j, rest)) = row1.split_first() else {
+                return 1;
+            };
+            let mut n = 0;
+            for &x in row2 {
+                if cand_map[&j].contains(&x) && !used.contains(&x) {
+                    used.push(x);
+                    n += go(rest, row2, cand_map, used);
+                    used.pop();
+                }
+            }
+            n
+        }
+        let mut js = row1.to_vec();
+        js.sort_unstable();
+        js.dedup();
+        go(&js, row2, cand_map, &mut Vec::new())
+    }
+
+    fn brute_subtotal(
+        rows1: &[Vec<i32>],
+        w1: &[u64],
+        rows2: &[Vec<i32>],
+        w2: &[u64],
+        cand_map: &HashMap<i32, Vec<i32>>,
+    ) -> u64 {
+        let mut total = 0;
+        for (r1, a) in rows1.iter().zip(w1) {
+            for (r2, b) in rows2.iter().zip(w2) {
+                total += a * b * brute_count(r1, r2, cand_map);
+            }
+        }
+        total
+    }
+
+    /// The subtotal three ways: the solver's grouped path, `chunk_subtotal`
+    /// with every bucket1 row its own group, and the plan.
+    fn subtotals(
+        f: &Fixture,
+        bucket1: &Bucket,
+        w1: &[u64],
+        bucket2: &Bucket,
+        w2: &[u64],
+    ) -> (u64, u64, u64) {
+        let rows_by_jbt = build_rows_by_jbt::<u32>(bucket2);
+        let mut pop_mult: HashMap<i32, i32> = HashMap::new();
+        for &p in &bucket1.key {
+            *pop_mult.entry(p).or_insert(0) += 1;
+        }
+        let cand_id = intern_candidates(&f.cand_map);
+        let grouped: u64 = pair_subtotal(
+            bucket1,
+            w1,
+            bucket2,
+            w2,
+            &f.jbt_ref_pop,
+            &rows_by_jbt,
+            &f.cand_map,
+            None,
+            false,
+        );
+        let singles: Vec<RowGroup<u64>> = w1
+            .iter()
+            .enumerate()
+            .map(|(rep, &weight)| RowGroup {
+                rep,
+                rows: 1,
+                weight,
+            })
+            .collect();
+        let ungrouped = chunk_subtotal(
+            bucket1,
+            bucket2,
+            w2,
+            &singles,
+            &f.jbt_ref_pop,
+            &rows_by_jbt,
+            &f.cand_map,
+            &cand_id,
+            &pop_mult,
+        );
+        let plan =
+            build_pair_plan(bucket1, bucket2, &f.jbt_ref_pop, &rows_by_jbt, &f.cand_map).unwrap();
+        (grouped, ungrouped, plan_subtotal(&plan, w1, w2))
+    }
+
+    /// Key [1, 1, 2]: the pop-1 positions collide, the pop-2 one is unique.
+    /// Rows repeat a j, overlap or not in candidates, or have a j with none.
+    #[test]
+    fn grouped_subtotal_matches_row_by_row() {
+        let f = fixture();
+        let rows1 = vec![
+            vec![0, 1, 4],
+            vec![0, 0, 4],
+            vec![0, 2, 5],
+            vec![2, 3, 6],
+            vec![3, 3, 4],
+            vec![0, 1, 7],
+            vec![1, 9, 4],
+            vec![1, 1, 5],
+            vec![2, 2, 4],
+            vec![0, 1, 4],
+        ];
+        let rows2 = all_rows2(2, true);
+        let w1: Vec<u64> = (1..=rows1.len() as u64).collect();
+        let w2: Vec<u64> = (0..rows2.len() as u64).map(|r| 2 * r + 1).collect();
+        let bucket1 = int_bucket(vec![1, 1, 2], &rows1, &w1);
+        let bucket2 = int_bucket(vec![1, 1, 2], &rows2, &w2);
+
+        let cand_id = intern_candidates(&f.cand_map);
+        let pop_mult = HashMap::from([(1, 2), (2, 1)]);
+        let (reps, group_of) =
+            row_groups(&bucket1, &f.jbt_ref_pop, &f.cand_map, &cand_id, &pop_mult);
+        // [0, 1, 4] twice and [1, 9, 4] share a group; [0, 1, 7] has none
+        assert_eq!(reps.len(), 7);
+        assert_eq!(group_of[0], group_of[6]);
+        assert_eq!(group_of[0], group_of[9]);
+        assert_eq!(group_of[5], None);
+        let groups = group_rows(
+            &bucket1,
+            &w1,
+            &f.jbt_ref_pop,
+            &f.cand_map,
+            &cand_id,
+            &pop_mult,
+        );
+        let g = group_of[0].unwrap();
+        assert_eq!(
+            (groups[g].rep, groups[g].rows, groups[g].weight),
+            (0, 3, 1 + 7 + 10)
+        );
+
+        let want = brute_subtotal(&rows1, &w1, &rows2, &w2, &f.cand_map);
+        assert!(want > 0);
+        assert_eq!(
+            subtotals(&f, &bucket1, &w1, &bucket2, &w2),
+            (want, want, want)
+        );
+    }
+
+    /// Key [1, 1, 1, 1]: rows whose positions fall into two entangled
+    /// clusters, so one is counted per bucket2 row and folded into the other.
+    #[test]
+    fn overlapping_clusters_count_injective_assignments() {
+        let f = fixture();
+        let rows1 = vec![
+            vec![0, 1, 2, 2],
+            vec![0, 0, 1, 3],
+            vec![0, 9, 2, 3],
+            vec![0, 1, 2, 3],
+            vec![0, 1, 9, 2],
+        ];
+        let rows2 = all_rows2(4, false);
+        let w1 = vec![1; rows1.len()];
+        let w2 = vec![1; rows2.len()];
+        let bucket1 = int_bucket(vec![1; 4], &rows1, &w1);
+        let bucket2 = int_bucket(vec![1; 4], &rows2, &w2);
+
+        // {0, 1} only as 11, 12; the repeated 2 once, as 13 or 14
+        assert_eq!(brute_count(&rows1[0], &[11, 12, 13, 14], &f.cand_map), 2);
+        let first = int_bucket(vec![1; 4], &rows1[..1], &[1]);
+        let single = int_bucket(vec![1; 4], &[vec![11, 12, 13, 14]], &[1]);
+        assert_eq!(subtotals(&f, &first, &[1], &single, &[1]), (2, 2, 2));
+
+        let want = brute_subtotal(&rows1, &w1, &rows2, &w2, &f.cand_map);
+        assert!(want > 0);
+        assert_eq!(
+            subtotals(&f, &bucket1, &w1, &bucket2, &w2),
+            (want, want, want)
+        );
+    }
+
+    /// A plan built once gives the subtotal for any weights on the same rows,
+    /// and `subtotal_from_plan` picks the accumulator `subtotal_for_pair` does.
+    #[test]
+    fn pair_plan_reproduces_subtotals_for_new_weights() {
+        let f = fixture();
+        let rows1 = vec![vec![0, 1, 4], vec![1, 9, 4], vec![0, 0, 5], vec![0, 1, 7]];
+        let rows2 = all_rows2(2, true);
+        let w1 = vec![1; rows1.len()];
+        let w2 = vec![1; rows2.len()];
+        let bucket1 = int_bucket(vec![1, 1, 2], &rows1, &w1);
+        let bucket2 = int_bucket(vec![1, 1, 2], &rows2, &w2);
+        let rows_by_jbt = build_rows_by_jbt::<u32>(&bucket2);
+        let plan = build_pair_plan(
+            &bucket1,
+            &bucket2,
+            &f.jbt_ref_pop,
+            &rows_by_jbt,
+            &f.cand_map,
+        )
+        .unwrap();
+        assert!(!plan.neutral);
+        assert_eq!(plan.n_groups(), 2);
+        assert_eq!(plan.members, vec![0, 1, 2]);
+        assert_eq!(plan.member_indptr, vec![0, 2, 3]);
+        assert!(plan.cell_counts.iter().all(|&n| n > 0));
+        // [0, 0, 5] needs 10 and 11 both
+        assert_eq!(plan.max_count(), 2);
+
+        let mut rng = Lcg(11);
+        for _ in 0..3 {
+            let w1: Vec<u64> = w1.iter().map(|_| rng.next() % 100).collect();
+            let w2: Vec<u64> = w2.iter().map(|_| rng.next() % 100).collect();
+            let want = brute_subtotal(&rows1, &w1, &rows2, &w2, &f.cand_map);
+            assert_eq!(plan_subtotal(&plan, &w1, &w2), want);
+
+            let bucket1 = int_bucket(bucket1.key.clone(), &rows1, &w1);
+            let bucket2 = int_bucket(bucket2.key.clone(), &rows2, &w2);
+            let direct = subtotal_for_pair(
+                &bucket1,
+                &bucket2,
+                &f.jbt_ref_pop,
+                &rows_by_jbt,
+                &f.cand_map,
+                None,
+                false,
+            );
+            let planned = subtotal_from_plan(&plan, &bucket1, &bucket2);
+            assert_eq!(planned.exact, Some(want as u128));
+            assert_eq!((planned.exact, planned.accum), (direct.exact, direct.accum));
+        }
+
+        let neutral = int_bucket(Vec::new(), &[vec![], vec![]], &[3, 4]);
+        let plan = build_pair_plan(
+            &neutral,
+            &neutral,
+            &f.jbt_ref_pop,
+            &rows_by_jbt,
+            &f.cand_map,
+        )
+        .unwrap();
+        assert!(plan.neutral);
+        assert_eq!(plan_subtotal(&plan, &[3u64, 4], &[3, 4]), 49);
+    }
 }
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use super::types::Bucket;
+
+/// Pop multisets listed in the summary, most states first.
+const TOP_KEYS: usize = 10;
+
+/// Headline statistics of a snapshot: the distribution of signature length k
+/// (j entries per state; every row of a bucket has k = key length) and of pop
+/// multisets. Stored as `meta_hist_k_*` / `meta_bucket_{states,weight}`.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotStats {
+    /// Indexed by k.
+    pub k_states: Vec<u64>,
+    pub k_weight: Vec<f64>,
+    /// Per bucket, in snapshot order.
+    pub bucket_states: Vec<u64>,
+    pub bucket_weight: Vec<f64>,
+}
+
+impl SnapshotStats {
+    pub fn from_buckets(buckets: &[Bucket]) -> Self {
+        let mut s = SnapshotStats::default();
+        for b in buckets {
+            s.add(b);
+        }
+        s
+    }
+
+    /// Count `b` as the next bucket.
+    pub fn add(&mut self, b: &Bucket) {
+        let k = b.key.len();
+        if self.k_states.len() <= k {
+            self.k_states.resize(k + 1, 0);
+            self.k_weight.resize(k + 1, 0.0);
+        }
+        let weight: f64 = b.weights.iter().sum();
+        self.k_states[k] += b.n_rows() as u64;
+        self.k_weight[k] += weight;
+        self.bucket_states.push(b.n_rows() as u64);
+        self.bucket_weight.push(weight);
+    }
+
+    /// `[stats]` lines: k histogram, pop occurrence counts, largest multisets.
+    /// `keys` are the bucket keys, in snapshot order.
+    pub fn print(&self, keys: &[Vec<i32>]) {
+        let states: u64 = self.k_states.iter().sum();
+        let weight: f64 = self.k_weight.iter().sum();
+        let pct = |part: f64, whole: f64| 100.0 * part / whole.max(f64::MIN_POSITIVE);
+        eprintln!(
+            "[stats] {} states, total weight {}, {} pop multisets",
+            states,
+            weight,
+            keys.len()
+        );
+        for (k, (&st, &w)) in self.k_states.iter().zip(&self.k_weight).enumerate() {
+            if st == 0 {
+                continue;
+            }
+            eprintln!(
+                "[stats]   k={:>2}: {:>12} states ({:5.1}%), weight {} ({:5.1}%)",
+                k,
+                st,
+                pct(st as f64, states as f64),
+                w,
+                pct(w, weight)
+            );
+        }
+
+        // how many states carry each pop, counting repeats within a multiset
+        let mut pop_states: BTreeMap<i32, u64> = BTreeMap::new();
+        for (key, &st) in keys.iter().zip(&self.bucket_states) {
+            for &p in key {
+                *pop_states.entry(p).or_insert(0) += st;
+            }
+        }
+        let pops: Vec<String> = pop_states
+            .iter()
+            .map(|(p, st)| format!("{}:{}", p, st))
+            .collect();
+        eprintln!("[stats]   pop occurrences (pop:states) {}", pops.join(" "));
+
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.bucket_states[i]));
+        for &i in order.iter().take(TOP_KEYS) {
+            eprintln!(
+                "[stats]   multiset {:?}: {} states ({:.1}%), weight {}",
+                keys[i],
+                self.bucket_states[i],
+                pct(self.bucket_states[i] as f64, states as f64),
+                self.bucket_weight[i]
+            );
+        }
+    }
+}
+
+/// Unweighted counts kept by enumeration, for cross-checks against theory: the
+/// frontier states vacated over all roots and, per bucket, the completed states
+/// that reached it before equal signatures were merged (its rows are the
+/// distinct ones). Stored as `meta_frontier_states` / `meta_bucket_completed`.
+#[derive(Debug, Clone, Default)]
+pub struct EnumCounts {
+    pub frontier_states: u64,
+    /// Per bucket, in snapshot order.
+    pub bucket_completed: Vec<u64>,
+}
+
+impl EnumCounts {
+    /// The `[counts]` line; `distinct` is the snapshot's row count.
+    pub fn print(&self, distinct: u64) {
+        eprintln!(
+            "[counts] {} frontier states vacated; {} completed states reached {} pop-keys, {} distinct",
+            self.frontier_states,
+            self.bucket_completed.iter().sum::<u64>(),
+            self.bucket_completed.len(),
+            distinct
+        );
+    }
+}
@@ -1,10 +1,200 @@
+#[cfg(feature = "npz")]
+use memmap2::Mmap;
+use num_bigint::BigUint;
+use num_traits::{FromPrimitive, ToPrimitive};
 use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "npz")]
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "npz")]
+use std::sync::Arc;
+
+use super::stats::{EnumCounts, SnapshotStats};
+
+/// Input or snapshot that is structurally valid but has nothing to enumerate or
+/// match (no pre_jbt entries, no completed states, zero buckets). Callers can
+/// `downcast_ref` it out of an `anyhow::Error` to report a distinct status.
+#[derive(Debug)]
+pub struct EmptyDataError(pub String);
+impl fmt::Display for EmptyDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nothing to do: {}", self.0)
+    }
+}
+impl std::error::Error for EmptyDataError {}
+
+/// Input or snapshot whose contents contradict the file format: offsets out of
+/// order, arrays larger than the file could hold. Raised before anything is
+/// sized from the bad values.
+#[derive(Debug)]
+pub struct SchemaError(pub String);
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "schema error: {}", self.0)
+    }
+}
+impl std::error::Error for SchemaError {}
+
+/// Element types a `Column` may map: plain little-endian numbers, valid for
+/// every bit pattern.
+pub trait Plain: Copy + fmt::Debug + 'static {
+    /// Append the little-endian bytes.
+    fn put_le(self, out: &mut Vec<u8>);
+}
+macro_rules! plain {
+    ($($t:ty),*) => {$(
+        impl Plain for $t {
+            fn put_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+plain!(i32, u32, i64, f64);
+
+/// One bucket array: owned, or `len` elements at byte `offset` of a mapped
+/// snapshot view (`view`, feature "npz"), which stays mapped while any column
+/// uses it.
+/// Reads go through `Deref<[T]>` either way; a write copies a mapped column
+/// into an owned one first.
+#[derive(Clone)]
+pub enum Column<T: Plain> {
+    Owned(Vec<T>),
+    #[cfg(feature = "npz")]
+    Mapped {
+        map: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+        elem: PhantomData<T>,
+    },
+}
+impl<T: Plain> Column<T> {
+    /// `len` elements at `offset` of `map`; `None` unless they lie inside the
+    /// map at `T`'s alignment (and the target is little-endian, as the file).
+    #[cfg(feature = "npz")]
+    pub fn mapped(map: &Arc<Mmap>, offset: usize, len: usize) -> Option<Self> {
+        let end = len
+            .checked_mul(std::mem::size_of::<T>())?
+            .checked_add(offset)?;
+        let aligned = || (map.as_ptr() as usize + offset).is_multiple_of(std::mem::align_of::<T>());
+        (cfg!(target_endian = "little") && end <= map.len() && aligned()).then(|| Column::Mapped {
+            map: Arc::clone(map),
+            offset,
+            len,
+            elem: PhantomData,
+        })
+    }
+    /// The owned vector, copying a mapped column out first.
+    pub fn to_mut(&mut self) -> &mut Vec<T> {
+        #[cfg(feature = "npz")]
+        if let Column::Mapped { .. } = self {
+            *self = Column::Owned(self.to_vec());
+        }
+        match self {
+            Column::Owned(v) => v,
+            #[cfg(feature = "npz")]
+            Column::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+impl<T: Plain> Deref for Column<T> {
+    type Target = [T];
+    #[inline]
+    fn deref(&self) -> &[T] {
+        match self {
+            Column::Owned(v) => v,
+            // SAFETY: `mapped` checked the range and alignment, `T: Plain`
+            // accepts any bits, and the Arc keeps the read-only map alive.
+            #[cfg(feature = "npz")]
+            Column::Mapped {
+                map, offset, len, ..
+            } => unsafe { std::slice::from_raw_parts(map.as_ptr().add(*offset).cast(), *len) },
+        }
+    }
+}
+impl<T: Plain> DerefMut for Column<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.to_mut()
+    }
+}
+impl<'a, T: Plain> IntoIterator for &'a Column<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+impl<T: Plain> From<Vec<T>> for Column<T> {
+    fn from(v: Vec<T>) -> Self {
+        Column::Owned(v)
+    }
+}
+impl<T: Plain> fmt::Debug for Column<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Row offsets of a bucket into `rows_data`. Held as u32 whenever the bucket
+/// has fewer than 2^32 entries (half the memory of i64); `Wide` otherwise.
+#[derive(Debug, Clone)]
+pub enum Indptr {
+    Narrow(Column<u32>),
+    Wide(Column<i64>),
+}
+impl Indptr {
+    pub fn from_i64(offsets: Vec<i64>) -> Self {
+        if offsets.last().is_none_or(|&last| last <= u32::MAX as i64) {
+            Indptr::Narrow(Column::Owned(offsets.iter().map(|&o| o as u32).collect()))
+        } else {
+            Indptr::Wide(offsets.into())
+        }
+    }
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            Indptr::Narrow(v) => v.len(),
+            Indptr::Wide(v) => v.len(),
+        }
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    #[inline]
+    pub fn get(&self, i: usize) -> usize {
+        match self {
+            Indptr::Narrow(v) => v[i] as usize,
+            Indptr::Wide(v) => v[i] as usize,
+        }
+    }
+    pub fn to_i64(&self) -> Vec<i64> {
+        match self {
+            Indptr::Narrow(v) => v.iter().map(|&o| o as i64).collect(),
+            Indptr::Wide(v) => v.to_vec(),
+        }
+    }
+    /// `None` if some offset does not fit in u32.
+    pub fn to_u32(&self) -> Option<Vec<u32>> {
+        match self {
+            Indptr::Narrow(v) => Some(v.to_vec()),
+            Indptr::Wide(v) => v.iter().map(|&o| u32::try_from(o).ok()).collect(),
+        }
+    }
+}
+
+/// Past this, consecutive integers are not all f64s.
+const EXACT_F64_MAX: f64 = (1u64 << f64::MANTISSA_DIGITS) as f64;
 
 #[derive(Debug, Clone)]
 pub struct Bucket {
-    pub rows_data: Vec<i32>,
-    pub indptr: Vec<i64>, // ok to switch to u32 later if you like
-    pub weights: Vec<f64>,
+    pub rows_data: Column<i32>,
+    pub indptr: Indptr,
+    pub weights: Column<f64>,
+    /// Rows whose weight is past 2^53, where `weights` holds only the nearest
+    /// f64, with their exact weight (ascending rows; usually empty).
+    pub exact: Vec<(usize, BigUint)>,
     pub key: Vec<i32>, // empty [] means neutral ()
 }
 impl Bucket {
@@ -14,10 +204,169 @@ impl Bucket {
     }
     #[inline]
     pub fn row_slice(&self, r: usize) -> &[i32] {
-        let lo = self.indptr[r] as usize;
-        let hi = self.indptr[r + 1] as usize;
+        let lo = self.indptr.get(r);
+        let hi = self.indptr.get(r + 1);
         &self.rows_data[lo..hi]
     }
+    /// First row whose weight is NaN or infinite, with that weight.
+    pub fn first_non_finite_weight(&self) -> Option<(usize, f64)> {
+        let r = self.weights.iter().position(|w| !w.is_finite())?;
+        Some((r, self.weights[r]))
+    }
+    /// Every row's weight as a u128, when each is a whole number `exact_weights`
+    /// accepts and fits; `None` otherwise.
+    pub fn int_weights(&self) -> Option<Vec<u128>> {
+        let mut overrides = self.exact.iter().peekable();
+        let mut out = Vec::with_capacity(self.weights.len());
+        for (r, &w) in self.weights.iter().enumerate() {
+            if let Some((_, x)) = overrides.next_if(|(e, _)| *e == r) {
+                out.push(x.to_u128()?);
+                continue;
+            }
+            if !(0.0..=EXACT_F64_MAX).contains(&w) || w.fract() != 0.0 {
+                return None;
+            }
+            out.push(w as u128);
+        }
+        Some(out)
+    }
+    /// Every row's weight as an exact integer: `exact` where it has the row,
+    /// else the f64 weight. Errs with the first other row whose f64 weight is
+    /// not a whole number up to 2^53 (a hand-made snapshot, or one written
+    /// before `exact` was), which has no exact value.
+    pub fn exact_weights(&self) -> Result<Vec<BigUint>, (usize, f64)> {
+        let mut overrides = self.exact.iter().peekable();
+        let mut out = Vec::with_capacity(self.weights.len());
+        for (r, &w) in self.weights.iter().enumerate() {
+            if let Some((_, x)) = overrides.next_if(|(e, _)| *e == r) {
+                out.push(x.clone());
+                continue;
+            }
+            match BigUint::from_f64(w) {
+                Some(x) if w <= EXACT_F64_MAX && x.to_f64() == Some(w) => out.push(x),
+                _ => return Err((r, w)),
+            }
+        }
+        Ok(out)
+    }
+    /// Rows whose pre_jbt list contains `jbt`, ascending: a ready-made
+    /// `select_rows` list.
+    pub fn rows_with_jbt(&self, jbt: i32) -> Vec<usize> {
+        (0..self.n_rows())
+            .filter(|&r| self.row_slice(r).contains(&jbt))
+            .collect()
+    }
+    /// The bucket cut down to `rows` (ascending, in range), with their weights
+    /// and exact weights; same key.
+    pub fn select_rows(&self, rows: &[usize]) -> Bucket {
+        let mut rows_data = Vec::new();
+        let mut offsets = Vec::with_capacity(rows.len() + 1);
+        offsets.push(0i64);
+        for &r in rows {
+            rows_data.extend_from_slice(self.row_slice(r));
+            offsets.push(rows_data.len() as i64);
+        }
+        let exact = self
+            .exact
+            .iter()
+            .filter_map(|(r, x)| Some((rows.binary_search(r).ok()?, x.clone())))
+            .collect();
+        Bucket {
+            rows_data: rows_data.into(),
+            indptr: Indptr::from_i64(offsets),
+            weights: Column::Owned(rows.iter().map(|&r| self.weights[r]).collect()),
+            exact,
+            key: self.key.clone(),
+        }
+    }
+}
+
+/// Weights clamped to u32::MAX during enumeration at one site: in the
+/// frontier of `root` (`key == None`), or in the completed bucket `key` while
+/// `root` was vacated (the last root for the final flush). Counts in the
+/// affected buckets, and in everything downstream of a frontier clamp, are
+/// lower bounds.
+#[derive(Debug, Clone)]
+pub struct Saturation {
+    pub root: (usize, usize),
+    pub key: Option<Vec<i32>>,
+    pub count: u64,
+}
+impl fmt::Display for Saturation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (u, v) = self.root;
+        match &self.key {
+            Some(key) => write!(
+                f,
+                "{} weights clamped in bucket {:?} while vacating root ({},{})",
+                self.count, key, u, v
+            ),
+            None => write!(
+                f,
+                "{} weights clamped in the frontier of root ({},{})",
+                self.count, u, v
+            ),
+        }
+    }
+}
+
+/// States dropped at snapshot build for weighing less than `min_weight`
+/// (`ENUM_MIN_WEIGHT`); Omega from such a snapshot is approximate.
+#[derive(Debug, Clone, Copy)]
+pub struct Pruning {
+    pub min_weight: f64,
+    pub states: u64,
+    pub weight: f64,
+    /// Weight before pruning, for the dropped share.
+    pub total_weight: f64,
+}
+impl fmt::Display for Pruning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dropped {} states below weight {} (weight {}, {:.4}% of {})",
+            self.states,
+            self.min_weight,
+            self.weight,
+            100.0 * self.weight / self.total_weight.max(f64::MIN_POSITIVE),
+            self.total_weight
+        )
+    }
+}
+
+/// Cap on the saturation sites listed in warnings.
+const MAX_SATURATION_LINES: usize = 10;
+
+/// Print the sites with the most clamps as `[warn]` lines.
+pub fn warn_saturation_sites(saturations: &[Saturation]) {
+    let mut sites: Vec<&Saturation> = saturations.iter().collect();
+    sites.sort_by_key(|s| std::cmp::Reverse(s.count));
+    for s in sites.iter().take(MAX_SATURATION_LINES) {
+        eprintln!("[warn]   {}", s);
+    }
+    if sites.len() > MAX_SATURATION_LINES {
+        eprintln!(
+            "[warn]   ... and {} more sites",
+            sites.len() - MAX_SATURATION_LINES
+        );
+    }
+}
+
+/// A snapshot whose save was cancelled (`meta_truncated`): only the first
+/// `written` of `total` buckets are present, so Omega from it is partial.
+#[derive(Debug, Clone, Copy)]
+pub struct Truncation {
+    pub written: usize,
+    pub total: usize,
+}
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "save cancelled after {} of {} buckets",
+            self.written, self.total
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -26,6 +375,49 @@ pub struct Snapshot {
     pub jbt_ref_pop: Vec<i32>,
     pub n_total: i32,
     pub compat: HashMap<i32, (Vec<i32>, Vec<i32>)>, // pop -> (key1, key2)
+    /// Weight saturations recorded at enumeration; empty when counts are exact.
+    pub saturations: Vec<Saturation>,
+    /// k and pop-multiset distributions; `None` for snapshots written before
+    /// they were recorded.
+    pub stats: Option<SnapshotStats>,
+    /// Unweighted enumeration counts; `None` for snapshots written before they
+    /// were recorded.
+    pub counts: Option<EnumCounts>,
+    /// Set when below-threshold states were dropped at build.
+    pub pruning: Option<Pruning>,
+    /// Set when the save was cancelled after only the first buckets.
+    pub truncated: Option<Truncation>,
+    /// Hash of the inputs and enumeration settings the snapshot was built
+    /// from (`enumeration::snapshot_fingerprint_for`); `None` for snapshots
+    /// written before it was recorded or whose rows or weights were changed
+    /// after enumeration.
+    pub fingerprint: Option<String>,
+}
+
+impl Snapshot {
+    /// The snapshot with bucket `i` cut down to `rows[i]` (see
+    /// `Bucket::select_rows`), for Omega over a subset of the states: those
+    /// holding a given pre_jbt, say. Pairing is unchanged, since it goes by
+    /// key. The stats describe the whole snapshot and are dropped.
+    pub fn restrict_rows(&self, rows: &[Vec<usize>]) -> Snapshot {
+        Snapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .zip(rows)
+                .map(|(b, rows)| b.select_rows(rows))
+                .collect(),
+            jbt_ref_pop: self.jbt_ref_pop.clone(),
+            n_total: self.n_total,
+            compat: self.compat.clone(),
+            saturations: self.saturations.clone(),
+            stats: None,
+            counts: None,
+            pruning: self.pruning,
+            truncated: self.truncated,
+            fingerprint: None,
+        }
+    }
 }
 
 #[inline]
@@ -0,0 +1,496 @@
+//! `matcher verify-snapshot`: a read-only audit of a saved snapshot. Where
+//! `load_snapshot` stops at the first problem, this keeps going and lists every
+//! defect it finds: zip members whose CRC does not match, malformed CSR offsets,
+//! keys that are not the pop multiset of their rows, non-finite or negative
+//! weights, compat entries that are missing, index past `jbt_ref_pop` or pair
+//! pops that do not sum to N, and metadata that disagrees with the buckets.
+//!
+//! It also checks the pairing the matcher will do: each bucket key's complement
+//! bucket (`compat_key_sorted`) must hold rows whose pops are the complements
+//! of the rows of the first, so labels and row contents pair buckets the same
+//! way. An upstream generator that mislabels a bucket shows up here instead of
+//! as a quietly wrong Omega.
+
+use anyhow::{Context, Result};
+use ndarray_npy::NpzReader;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use zip::ZipArchive;
+
+use super::io::{
+    check_bucket, load_snapshot, read_counts, read_exact, read_f64, read_i32, read_i64,
+    read_indptr, read_pruning, read_saturations, read_stats, read_truncation,
+};
+use super::npz_check::{check_member_sizes, check_offsets};
+use super::types::{Bucket, SchemaError, compat_key_sorted};
+use super::zst;
+use crate::human::Bytes;
+
+/// What a clean file holds, for the summary line.
+#[derive(Default)]
+struct Tally {
+    members: usize,
+    bytes: u64,
+    buckets: usize,
+    rows: u64,
+    weight: f64,
+}
+
+/// Verify `path`, print the findings, and return a `SchemaError` counting the
+/// defects if there were any.
+pub fn verify_snapshot(path: &str) -> Result<()> {
+    let mut defects = Vec::new();
+    let mut notes = Vec::new();
+    let mut tally = Tally::default();
+    if zst::is_zst(path) {
+        check_zst(path, &mut defects, &mut notes, &mut tally);
+    } else {
+        let f = File::open(path).with_context(|| format!("open {}", path))?;
+        check(f, path, &mut defects, &mut notes, &mut tally)?;
+    }
+
+    for note in &notes {
+        println!("note: {}", note);
+    }
+    println!(
+        "{}: {} members checksummed ({}), {} buckets, {} rows, total weight {}",
+        path,
+        tally.members,
+        Bytes(tally.bytes),
+        tally.buckets,
+        tally.rows,
+        tally.weight
+    );
+    if defects.is_empty() {
+        println!("{}: OK", path);
+        return Ok(());
+    }
+    for d in &defects {
+        println!("defect: {}", d);
+    }
+    Err(SchemaError(format!("snapshot {}: {} defects", path, defects.len())).into())
+}
+
+fn check<R: Read + Seek>(
+    mut reader: R,
+    path: &str,
+    defects: &mut Vec<String>,
+    notes: &mut Vec<String>,
+    tally: &mut Tally,
+) -> Result<()> {
+    // every member read to the end, which checks it against its CRC-32
+    let mut zip = ZipArchive::new(&mut reader).with_context(|| format!("read npz {}", path))?;
+    for i in 0..zip.len() {
+        let mut member = match zip.by_index(i) {
+            Ok(m) => m,
+            Err(err) => {
+                defects.push(format!("member {}: {}", i, err));
+                continue;
+            }
+        };
+        let name = member.name().to_string();
+        match io::copy(&mut member, &mut io::sink()) {
+            Ok(n) => tally.bytes += n,
+            Err(err) => defects.push(format!("{}: {}", name, err)),
+        }
+        tally.members += 1;
+    }
+    drop(zip);
+
+    // headers that would make the array reader over-allocate: nothing past
+    // this point is safe to read
+    let reader = match check_member_sizes(reader, path) {
+        Ok(r) => r,
+        Err(err) => {
+            defects.push(format!("{:#}", err));
+            return Ok(());
+        }
+    };
+    let mut npz = NpzReader::new(reader).context("read npz")?;
+    macro_rules! or_defect {
+        ($e:expr) => {
+            match $e {
+                Ok(v) => v,
+                Err(err) => {
+                    defects.push(format!("{:#}", err));
+                    return Ok(());
+                }
+            }
+        };
+    }
+
+    let n_total = or_defect!(read_i32(&mut npz, "meta_N.npy"))
+        .first()
+        .copied()
+        .unwrap_or(0);
+    if n_total <= 0 {
+        defects.push(format!("meta_N={} must be positive", n_total));
+        return Ok(());
+    }
+    let jbt_ref_pop = or_defect!(read_i32(&mut npz, "meta_jbt_ref_pop.npy")).to_vec();
+    if let Some(j) = jbt_ref_pop.iter().position(|&p| p < 0 || p > n_total) {
+        defects.push(format!(
+            "meta_jbt_ref_pop[{}]={} outside 0..={}",
+            j, jbt_ref_pop[j], n_total
+        ));
+    }
+    let keys_data = or_defect!(read_i32(&mut npz, "meta_bucket_keys_data.npy")).to_vec();
+    let keys_indptr = or_defect!(read_i64(&mut npz, "meta_bucket_keys_indptr.npy")).to_vec();
+    or_defect!(check_offsets(
+        "meta_bucket_keys_indptr",
+        &keys_indptr,
+        keys_data.len()
+    ));
+    let num_buckets = keys_indptr.len() - 1;
+    tally.buckets = num_buckets;
+    // compat entries the solver will look up: p for row pops up to N/2, N - p above
+    let mut needed = BTreeSet::new();
+    // (key, what its rows carry) per bucket, for the pairing check
+    let mut pairing = Vec::with_capacity(num_buckets);
+    let names: HashSet<String> = or_defect!(npz.names().context("list snapshot members"))
+        .into_iter()
+        .collect();
+
+    for b in 0..num_buckets {
+        let listed = &keys_data[keys_indptr[b] as usize..keys_indptr[b + 1] as usize];
+        let bucket = (|| -> Result<Bucket> {
+            Ok(Bucket {
+                rows_data: read_i32(&mut npz, &format!("b{}_rows_data.npy", b))?
+                    .into_raw_vec()
+                    .into(),
+                indptr: read_indptr(&mut npz, &format!("b{}_rows_indptr.npy", b))?,
+                weights: read_f64(&mut npz, &format!("b{}_weights.npy", b))?
+                    .into_raw_vec()
+                    .into(),
+                exact: read_exact(&mut npz, b, &names)?,
+                key: read_i32(&mut npz, &format!("b{}_key.npy", b))?.to_vec(),
+            })
+        })();
+        match bucket {
+            Ok(bucket) => {
+                let pops = check_one_bucket(&bucket, b, listed, &jbt_ref_pop, defects, tally);
+                if !matches!(pops, RowPops::Unreadable) {
+                    for &j in &bucket.rows_data {
+                        let p = jbt_ref_pop[j as usize];
+                        if p != 0 {
+                            needed.insert(if p > n_total / 2 { n_total - p } else { p });
+                        }
+                    }
+                }
+                pairing.push((bucket.key, pops));
+            }
+            Err(err) => defects.push(format!("bucket {}: {:#}", b, err)),
+        }
+    }
+
+    let compat_pops = or_defect!(read_i32(&mut npz, "meta_compat_pops.npy")).to_vec();
+    for p in needed {
+        if !compat_pops.contains(&p) {
+            defects.push(format!(
+                "compat has no entry for pop {}, which bucket rows carry",
+                p
+            ));
+        }
+    }
+    for &p in &compat_pops {
+        let keys = (|| -> Result<(Vec<i32>, Vec<i32>)> {
+            Ok((
+                read_i32(&mut npz, &format!("compat_p{}_key1.npy", p))?.to_vec(),
+                read_i32(&mut npz, &format!("compat_p{}_key2.npy", p))?.to_vec(),
+            ))
+        })();
+        match keys {
+            Ok((k1, k2)) => check_compat(p, &k1, &k2, &jbt_ref_pop, n_total, defects),
+            Err(err) => defects.push(format!("compat pop {}: {:#}", p, err)),
+        }
+    }
+    check_pairing(&pairing, n_total, defects, notes);
+
+    if let Err(err) = read_saturations(&mut npz, n_total) {
+        defects.push(format!("saturation records: {:#}", err));
+    }
+    if let Err(err) = read_stats(&mut npz, num_buckets) {
+        defects.push(format!("statistics: {:#}", err));
+    }
+    if let Err(err) = read_counts(&mut npz, num_buckets) {
+        defects.push(format!("enumeration counts: {:#}", err));
+    }
+    match read_pruning(&mut npz) {
+        Ok(Some(p)) => notes.push(format!("pruned at build ({}); Omega is approximate", p)),
+        Ok(None) => {}
+        Err(err) => defects.push(format!("{:#}", err)),
+    }
+    match read_truncation(&mut npz, num_buckets) {
+        Ok(Some(t)) => notes.push(format!("truncated ({}); Omega is partial", t)),
+        Ok(None) => {}
+        Err(err) => defects.push(format!("{:#}", err)),
+    }
+    Ok(())
+}
+
+/// `check` for a binary snapshot: its zstd frame is checksummed and its
+/// layout checked as it loads, so only the contents are left to audit.
+fn check_zst(path: &str, defects: &mut Vec<String>, notes: &mut Vec<String>, tally: &mut Tally) {
+    let snap = match load_snapshot(path) {
+        Ok(snap) => snap,
+        Err(err) => {
+            defects.push(format!("{:#}", err));
+            return;
+        }
+    };
+    tally.members = 1;
+    tally.bytes = std::fs::metadata(path).map_or(0, |m| m.len());
+    tally.buckets = snap.buckets.len();
+    let n_total = snap.n_total;
+    if let Some(j) = snap.jbt_ref_pop.iter().position(|&p| p < 0 || p > n_total) {
+        defects.push(format!(
+            "jbt_ref_pop[{}]={} outside 0..={}",
+            j, snap.jbt_ref_pop[j], n_total
+        ));
+        return;
+    }
+    let mut needed = BTreeSet::new();
+    let mut pairing = Vec::with_capacity(snap.buckets.len());
+    for (b, bucket) in snap.buckets.iter().enumerate() {
+        let pops = check_one_bucket(bucket, b, &bucket.key, &snap.jbt_ref_pop, defects, tally);
+        for &j in &bucket.rows_data {
+            let p = snap.jbt_ref_pop[j as usize];
+            if p != 0 {
+                needed.insert(if p > n_total / 2 { n_total - p } else { p });
+            }
+        }
+        pairing.push((bucket.key.clone(), pops));
+    }
+    for p in needed {
+        if !snap.compat.contains_key(&p) {
+            defects.push(format!(
+                "compat has no entry for pop {}, which bucket rows carry",
+                p
+            ));
+        }
+    }
+    let mut pops: Vec<&i32> = snap.compat.keys().collect();
+    pops.sort_unstable();
+    for p in pops {
+        let (k1, k2) = &snap.compat[p];
+        check_compat(*p, k1, k2, &snap.jbt_ref_pop, n_total, defects);
+    }
+    check_pairing(&pairing, n_total, defects, notes);
+    if let Some(p) = &snap.pruning {
+        notes.push(format!("pruned at build ({}); Omega is approximate", p));
+    }
+    if let Some(t) = &snap.truncated {
+        notes.push(format!("truncated ({}); Omega is partial", t));
+    }
+}
+
+/// The compat entry for pop `p`: key1 holds pop-p j's and key2 the pop-(N - p)
+/// x's they may pair with, all inside `jbt_ref_pop`.
+fn check_compat(
+    p: i32,
+    k1: &[i32],
+    k2: &[i32],
+    jbt_ref_pop: &[i32],
+    n_total: i32,
+    defects: &mut Vec<String>,
+) {
+    let m = jbt_ref_pop.len();
+    if k1.len() != k2.len() {
+        defects.push(format!(
+            "compat_p{}: key1/key2 lengths differ ({} vs {})",
+            p,
+            k1.len(),
+            k2.len()
+        ));
+    }
+    let out: Vec<i32> = k1
+        .iter()
+        .chain(k2)
+        .copied()
+        .filter(|&j| j < 0 || j as usize >= m)
+        .collect();
+    if let Some(j) = out.first() {
+        defects.push(format!(
+            "compat_p{}: {} j indices outside jbt_ref_pop (M={}), e.g. {}",
+            p,
+            out.len(),
+            m,
+            j
+        ));
+        return;
+    }
+    let pop = |j: i32| jbt_ref_pop[j as usize];
+    let off: Vec<(i32, i32)> = k1
+        .iter()
+        .zip(k2)
+        .map(|(&j, &x)| (j, x))
+        .filter(|&(j, x)| pop(j) != p || pop(x) != n_total - p)
+        .collect();
+    if let Some(&(j, x)) = off.first() {
+        defects.push(format!(
+            "compat_p{}: {} entries whose pops are not {} and {} (first: j {} pop {}, x {} pop {})",
+            p,
+            off.len(),
+            p,
+            n_total - p,
+            j,
+            pop(j),
+            x,
+            pop(x)
+        ));
+    }
+}
+
+/// The pops a bucket's rows carry, as far as `check_one_bucket` could tell.
+enum RowPops {
+    /// Malformed CSR offsets: the rows are not safe to index.
+    Unreadable,
+    /// No rows, or rows that disagree among themselves.
+    Mixed,
+    /// Every row carries these sorted nonzero pops (the key, when it is right).
+    Uniform(Vec<i32>),
+}
+
+/// Pair buckets twice, by key and by row contents, and report where the two
+/// disagree: a key whose complement bucket's rows do not carry the complement
+/// pops of its own rows. A key with no complement bucket at all is only noted;
+/// matching warns about it too, and an incomplete enumeration leaves some.
+fn check_pairing(
+    buckets: &[(Vec<i32>, RowPops)],
+    n_total: i32,
+    defects: &mut Vec<String>,
+    notes: &mut Vec<String>,
+) {
+    let by_key: HashMap<&[i32], usize> = buckets
+        .iter()
+        .enumerate()
+        .map(|(b, (key, _))| (key.as_slice(), b))
+        .collect();
+    let by_pops: HashMap<&[i32], usize> = buckets
+        .iter()
+        .enumerate()
+        .filter_map(|(b, (_, pops))| match pops {
+            RowPops::Uniform(p) => Some((p.as_slice(), b)),
+            _ => None,
+        })
+        .collect();
+    let mut unpaired = Vec::new();
+    for (b, (key, pops)) in buckets.iter().enumerate() {
+        let complement = compat_key_sorted(key, n_total);
+        let Some(&c) = by_key.get(complement.as_slice()) else {
+            unpaired.push(key);
+            continue;
+        };
+        // each pair once
+        if c < b {
+            continue;
+        }
+        let (RowPops::Uniform(mine), RowPops::Uniform(theirs)) = (pops, &buckets[c].1) else {
+            continue;
+        };
+        let wanted = compat_key_sorted(mine, n_total);
+        if *theirs == wanted {
+            continue;
+        }
+        let by_rows = match by_pops.get(wanted.as_slice()) {
+            Some(&d) => format!(
+                "; by row contents it pairs with bucket {} {:?}",
+                d, buckets[d].0
+            ),
+            None => "; no bucket holds those rows".to_string(),
+        };
+        defects.push(format!(
+            "bucket {} {:?} pairs by key with bucket {} {:?}, but its rows carry pops {:?} and the partner's {:?}, not their complements {:?}{}",
+            b, key, c, buckets[c].0, mine, theirs, wanted, by_rows
+        ));
+    }
+    if let Some(first) = unpaired.first() {
+        notes.push(format!(
+            "{} bucket keys have no complement bucket (first: {:?} lacks {:?}); matching skips them",
+            unpaired.len(),
+            first,
+            compat_key_sorted(first, n_total)
+        ));
+    }
+}
+
+/// CSR shape, key against the listing and against its rows, and weights.
+fn check_one_bucket(
+    bucket: &Bucket,
+    b: usize,
+    listed: &[i32],
+    jbt_ref_pop: &[i32],
+    defects: &mut Vec<String>,
+    tally: &mut Tally,
+) -> RowPops {
+    if bucket.key != listed {
+        defects.push(format!(
+            "b{}_key {:?} differs from meta_bucket_keys {:?}",
+            b, bucket.key, listed
+        ));
+    }
+    if bucket.key.windows(2).any(|w| w[1] < w[0]) {
+        defects.push(format!("b{}_key {:?} is not sorted", b, bucket.key));
+    }
+    let row_pops = if let Err(err) = check_bucket(bucket, b, jbt_ref_pop.len()) {
+        // rows cannot be sliced safely; weights are still worth a look
+        defects.push(format!("{:#}", err));
+        RowPops::Unreadable
+    } else {
+        // the key must decode from every row: the sorted nonzero pops of its j's
+        let mut pops = Vec::with_capacity(bucket.key.len());
+        let mut bad = (0usize, None);
+        let mut first: Option<Vec<i32>> = None;
+        let mut uniform = bucket.n_rows() > 0;
+        for r in 0..bucket.n_rows() {
+            pops.clear();
+            pops.extend(
+                bucket
+                    .row_slice(r)
+                    .iter()
+                    .map(|&j| jbt_ref_pop[j as usize])
+                    .filter(|&p| p != 0),
+            );
+            pops.sort_unstable();
+            match &first {
+                None => first = Some(pops.clone()),
+                Some(f) => uniform &= *f == pops,
+            }
+            if pops != bucket.key {
+                bad.0 += 1;
+                bad.1.get_or_insert((r, pops.clone()));
+            }
+        }
+        if let (n, Some((r, pops))) = bad {
+            defects.push(format!(
+                "bucket {} {:?}: {} rows whose pops are not the key (first: row {} has {:?})",
+                b, bucket.key, n, r, pops
+            ));
+        }
+        tally.rows += bucket.n_rows() as u64;
+        match first {
+            Some(pops) if uniform => RowPops::Uniform(pops),
+            _ => RowPops::Mixed,
+        }
+    };
+    let bad: Vec<(usize, f64)> = bucket
+        .weights
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|&(_, w)| !w.is_finite() || w < 0.0)
+        .collect();
+    if let Some(&(r, w)) = bad.first() {
+        defects.push(format!(
+            "bucket {} {:?}: {} weights NaN, infinite or negative (first: row {} = {})",
+            b,
+            bucket.key,
+            bad.len(),
+            r,
+            w
+        ));
+    }
+    tally.weight += bucket.weights.iter().filter(|w| w.is_finite()).sum::<f64>();
+    row_pops
+}
@@ -0,0 +1,429 @@
+//! Snapshot views (`matcher pack-view`): a snapshot laid out so that matching
+//! maps it instead of reading it. Every bucket array sits at an aligned offset
+//! of the file and becomes a `Column::Mapped` slice, so the buckets take page
+//! cache rather than heap and a snapshot larger than RAM can still be matched.
+//! `load_snapshot` opens a view wherever it takes an NPZ snapshot.
+//!
+//! Layout (little-endian): `MAGIC`, the format version (u32, then 4 zero
+//! bytes), and the offset and length (u64 each) of a JSON header at the end of
+//! the file; bucket arrays from byte `ALIGN` on, each starting on a multiple
+//! of `ALIGN`. The header holds N, `jbt_ref_pop`, the compat table, the
+//! saturation / pruning / truncation labels and, per bucket, its key, row and
+//! entry counts, exact weights, and the offsets of `rows_data`, `indptr` (u32
+//! or i64, `indptr_bits`) and `weights`.
+// the header and schema helpers are shared with `arrow`
+#![cfg_attr(not(any(feature = "npz", feature = "arrow")), allow(dead_code))]
+
+use anyhow::{Result, bail};
+use num_bigint::BigUint;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::types::{Pruning, Saturation, SchemaError, Snapshot, Truncation};
+#[cfg(feature = "npz")]
+use {
+    super::io::{
+        check_bucket, read_snapshot_with, replace_file, temp_beside, warn_snapshot_labels,
+    },
+    super::types::{Bucket, Column, Indptr, Plain},
+    crate::human::Secs,
+    anyhow::Context,
+    memmap2::Mmap,
+    std::io::{BufWriter, Write},
+    std::path::Path,
+    std::sync::Arc,
+    std::time::Instant,
+};
+
+const MAGIC: &[u8; 8] = b"CJPTVIEW";
+#[cfg(feature = "npz")]
+const VERSION: u32 = 1;
+/// Alignment of every array, and the offset of the first.
+#[cfg(feature = "npz")]
+const ALIGN: u64 = 64;
+
+/// Whether the file at `path` starts like a view (unreadable files do not).
+pub fn is_view(path: &str) -> bool {
+    File::open(path)
+        .and_then(|mut f| starts_as_view(&mut f))
+        .unwrap_or(false)
+}
+
+/// Whether the open file `f` is a view, leaving it rewound to its start.
+pub(super) fn starts_as_view(f: &mut File) -> std::io::Result<bool> {
+    let mut head = Vec::with_capacity(MAGIC.len());
+    Read::take(&mut *f, MAGIC.len() as u64).read_to_end(&mut head)?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok(head == MAGIC)
+}
+
+/// Writes arrays at aligned offsets, tracking the position.
+#[cfg(feature = "npz")]
+struct ArrayWriter<W: Write> {
+    out: W,
+    pos: u64,
+}
+
+#[cfg(feature = "npz")]
+impl<W: Write> ArrayWriter<W> {
+    fn pad(&mut self) -> Result<()> {
+        let pad = (ALIGN - self.pos % ALIGN) % ALIGN;
+        self.out.write_all(&vec![0u8; pad as usize])?;
+        self.pos += pad;
+        Ok(())
+    }
+    /// Write `values` at the next aligned offset and return that offset.
+    fn array<T: Plain>(&mut self, values: &[T]) -> Result<u64> {
+        self.pad()?;
+        let at = self.pos;
+        let mut buf = Vec::new();
+        for chunk in values.chunks(1 << 16) {
+            buf.clear();
+            chunk.iter().for_each(|&v| v.put_le(&mut buf));
+            self.out.write_all(&buf)?;
+            self.pos += buf.len() as u64;
+        }
+        Ok(at)
+    }
+    fn bucket(&mut self, b: &Bucket) -> Result<Value> {
+        let rows_data = self.array(&b.rows_data)?;
+        let (indptr, bits) = match &b.indptr {
+            Indptr::Narrow(v) => (self.array(v)?, 32),
+            Indptr::Wide(v) => (self.array(v)?, 64),
+        };
+        let weights = self.array(&b.weights)?;
+        let exact: Vec<Value> = b
+            .exact
+            .iter()
+            .map(|(r, x)| json!([r, x.to_string()]))
+            .collect();
+        Ok(json!({
+            "key": b.key,
+            "rows": b.n_rows(),
+            "entries": b.rows_data.len(),
+            "rows_data": rows_data,
+            "indptr": indptr,
+            "indptr_bits": bits,
+            "weights": weights,
+            "exact": exact,
+        }))
+    }
+}
+
+pub(super) fn header(snap: &Snapshot, buckets: Vec<Value>) -> Value {
+    let mut pops: Vec<&i32> = snap.compat.keys().collect();
+    pops.sort();
+    let compat: Vec<Value> = pops
+        .into_iter()
+        .map(|p| json!({"pop": p, "key1": snap.compat[p].0, "key2": snap.compat[p].1}))
+        .collect();
+    let saturations: Vec<Value> = snap
+        .saturations
+        .iter()
+        .map(|s| json!({"root": [s.root.0, s.root.1], "key": s.key, "count": s.count}))
+        .collect();
+    json!({
+        "N": snap.n_total,
+        "jbt_ref_pop": snap.jbt_ref_pop,
+        "compat": compat,
+        "saturations": saturations,
+        "pruning": snap.pruning.map(|p| json!([p.min_weight, p.states, p.weight, p.total_weight])),
+        "truncated": snap.truncated.map(|t| json!([t.written, t.total])),
+        "fingerprint": snap.fingerprint,
+        "buckets": buckets,
+    })
+}
+
+/// Rewrite the NPZ snapshot at `npz_path` as a view at `out`, one bucket at a
+/// time (peak memory is one bucket, not the snapshot). The snapshot is checked
+/// as `load_snapshot` checks it. The view is written beside `out` and renamed
+/// over it when complete; a failed pack removes the partial file.
+#[cfg(feature = "npz")]
+pub fn pack_view(npz_path: &str, out: &Path) -> Result<()> {
+    let t0 = Instant::now();
+    let f = File::open(npz_path).with_context(|| format!("open {}", npz_path))?;
+    let tmp = temp_beside(out);
+    let file = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+    let res = (|| -> Result<u64> {
+        let mut w = ArrayWriter {
+            out: BufWriter::new(file),
+            pos: 0,
+        };
+        w.out.write_all(&[0u8; ALIGN as usize])?;
+        w.pos = ALIGN;
+        let mut buckets = Vec::new();
+        let snap = read_snapshot_with(f, npz_path, |_, b| {
+            buckets.push(w.bucket(&b)?);
+            Ok(())
+        })?;
+        let n_buckets = buckets.len();
+        let text = serde_json::to_vec(&header(&snap, buckets))?;
+        let at = w.pos;
+        w.out.write_all(&text)?;
+        let mut file = w.out.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut preamble = MAGIC.to_vec();
+        preamble.extend_from_slice(&VERSION.to_le_bytes());
+        preamble.extend_from_slice(&[0u8; 4]);
+        preamble.extend_from_slice(&at.to_le_bytes());
+        preamble.extend_from_slice(&(text.len() as u64).to_le_bytes());
+        file.write_all(&preamble)?;
+        file.sync_all()?;
+        replace_file(&tmp, out)?;
+        Ok(n_buckets as u64)
+    })();
+    match res {
+        Ok(n) => {
+            eprintln!(
+                "[view] {} buckets of {} packed into {} in {}",
+                n,
+                npz_path,
+                out.display(),
+                Secs::from(t0.elapsed())
+            );
+            Ok(())
+        }
+        Err(err) => {
+            if std::fs::remove_file(&tmp).is_ok() {
+                eprintln!("[view] removed the incomplete {}", tmp.display());
+            }
+            Err(err).with_context(|| format!("pack {} into {}", npz_path, out.display()))
+        }
+    }
+}
+
+/// A `SchemaError` about `place` ("view <path>", say).
+fn schema(place: &str, what: String) -> anyhow::Error {
+    SchemaError(format!("{}: {}", place, what)).into()
+}
+
+fn field<'a>(v: &'a Value, name: &str, place: &str) -> Result<&'a Value> {
+    v.get(name)
+        .ok_or_else(|| schema(place, format!("header has no {:?}", name)))
+}
+
+fn uint(v: &Value, name: &str, place: &str) -> Result<u64> {
+    field(v, name, place)?
+        .as_u64()
+        .ok_or_else(|| schema(place, format!("{:?} is not a non-negative integer", name)))
+}
+
+fn list<'a>(v: &'a Value, name: &str, place: &str) -> Result<&'a Vec<Value>> {
+    field(v, name, place)?
+        .as_array()
+        .ok_or_else(|| schema(place, format!("{:?} is not a list", name)))
+}
+
+fn i32s(v: &Value, name: &str, place: &str) -> Result<Vec<i32>> {
+    list(v, name, place)?
+        .iter()
+        .map(|x| {
+            x.as_i64()
+                .and_then(|x| i32::try_from(x).ok())
+                .ok_or_else(|| schema(place, format!("{:?} holds {}", name, x)))
+        })
+        .collect()
+}
+
+/// Open the view at `path`: map it, check the header and every bucket as
+/// `load_snapshot` does, and return a `Snapshot` whose bucket arrays are
+/// slices of the map. The file must not change while it is mapped; `pack-view`
+/// replaces a view by renaming over it, which leaves open maps intact.
+#[cfg(feature = "npz")]
+pub fn open_view(path: &str) -> Result<Snapshot> {
+    let f = File::open(path).with_context(|| format!("open {}", path))?;
+    map_view(&f, path)
+}
+
+/// Without the "npz" feature views are not mapped; they are refused.
+#[cfg(not(feature = "npz"))]
+pub(super) fn map_view(_f: &File, path: &str) -> Result<Snapshot> {
+    bail!(
+        "{} is a snapshot view, and this matcher was built without the \"npz\" feature",
+        path
+    )
+}
+
+/// `open_view` over the already open `f`; `path` labels messages.
+#[cfg(feature = "npz")]
+pub(super) fn map_view(f: &File, path: &str) -> Result<Snapshot> {
+    let place = &format!("view {}", path);
+    // SAFETY: the map is read-only, and columns only read plain numbers from
+    // it; a file truncated underneath would fault, as a read would fail.
+    let map = Arc::new(unsafe { Mmap::map(f) }.with_context(|| format!("map {}", path))?);
+    if map.len() < ALIGN as usize || &map[..8] != MAGIC {
+        return Err(schema(place, "not a snapshot view".into()));
+    }
+    let word = |at: usize| u64::from_le_bytes(map[at..at + 8].try_into().unwrap());
+    let version = u32::from_le_bytes(map[8..12].try_into().unwrap());
+    if version != VERSION {
+        bail!(
+            "view {}: format version {}, this build reads {}; pack it again",
+            path,
+            version,
+            VERSION
+        );
+    }
+    let (at, len) = (word(16), word(24));
+    let text = at
+        .checked_add(len)
+        .filter(|&end| end <= map.len() as u64)
+        .map(|end| &map[at as usize..end as usize])
+        .ok_or_else(|| schema(place, format!("header {}+{} past the file end", at, len)))?;
+    let head: Value =
+        serde_json::from_slice(text).map_err(|e| schema(place, format!("header: {}", e)))?;
+    let mut snap = snapshot_meta(&head, place)?;
+
+    let entries = list(&head, "buckets", place)?;
+    let mut buckets = Vec::with_capacity(entries.len());
+    for (b, e) in entries.iter().enumerate() {
+        let (rows, n) = (
+            uint(e, "rows", place)? as usize,
+            uint(e, "entries", place)? as usize,
+        );
+        fn column<T: Plain>(
+            map: &Arc<Mmap>,
+            e: &Value,
+            name: &str,
+            len: usize,
+            place: &str,
+        ) -> Result<Column<T>> {
+            let at = uint(e, name, place)? as usize;
+            Column::mapped(map, at, len)
+                .ok_or_else(|| schema(place, format!("{} at {} is misplaced", name, at)))
+        }
+        let indptr = match uint(e, "indptr_bits", place)? {
+            32 => Indptr::Narrow(column(&map, e, "indptr", rows + 1, place)?),
+            64 => Indptr::Wide(column(&map, e, "indptr", rows + 1, place)?),
+            bits => return Err(schema(place, format!("bucket {}: indptr_bits {}", b, bits))),
+        };
+        let bucket = Bucket {
+            rows_data: column(&map, e, "rows_data", n, place)?,
+            indptr,
+            weights: column(&map, e, "weights", rows, place)?,
+            exact: exact_weights(e, b, place)?,
+            key: i32s(e, "key", place)?,
+        };
+        if let Indptr::Wide(v) = &bucket.indptr
+            && let Some(bad) = v.iter().find(|&&o| o < 0)
+        {
+            bail!("view {}: bucket {} has a negative offset {}", path, b, bad);
+        }
+        check_bucket(&bucket, b, snap.jbt_ref_pop.len())
+            .with_context(|| format!("view {}", path))?;
+        if let Some((r, w)) = bucket.first_non_finite_weight() {
+            bail!(
+                "view {}: bucket {} {:?} row {} has weight {}; weights must be finite",
+                path,
+                b,
+                bucket.key,
+                r,
+                w
+            );
+        }
+        buckets.push(bucket);
+    }
+    snap.buckets = buckets;
+    warn_snapshot_labels(path, &snap);
+    Ok(snap)
+}
+
+/// The bucket entry's `exact` weights: `[row, decimal text]` pairs.
+pub(super) fn exact_weights(e: &Value, b: usize, place: &str) -> Result<Vec<(usize, BigUint)>> {
+    list(e, "exact", place)?
+        .iter()
+        .map(|x| {
+            let pair = match x.as_array().map(Vec::as_slice) {
+                Some([r, x]) => r.as_u64().zip(x.as_str()),
+                _ => None,
+            };
+            pair.and_then(|(r, x)| Some((r as usize, x.parse::<BigUint>().ok()?)))
+                .ok_or_else(|| schema(place, format!("bucket {}: exact weight {}", b, x)))
+        })
+        .collect()
+}
+
+/// Everything but the buckets of the snapshot a `header` describes; the
+/// returned snapshot has none.
+pub(super) fn snapshot_meta(head: &Value, place: &str) -> Result<Snapshot> {
+    let n_total = field(head, "N", place)?
+        .as_i64()
+        .and_then(|n| i32::try_from(n).ok())
+        .ok_or_else(|| schema(place, "N is not an i32".into()))?;
+    if n_total <= 0 {
+        bail!("view {}: N={} must be positive", place, n_total);
+    }
+    let jbt_ref_pop = i32s(head, "jbt_ref_pop", place)?;
+    let mut compat = HashMap::new();
+    for c in list(head, "compat", place)? {
+        let (k1, k2) = (i32s(c, "key1", place)?, i32s(c, "key2", place)?);
+        if k1.len() != k2.len() {
+            return Err(schema(place, "compat key1/key2 lengths differ".into()));
+        }
+        let pop = i32::try_from(uint(c, "pop", place)?)
+            .map_err(|_| schema(place, "compat pop past i32".into()))?;
+        compat.insert(pop, (k1, k2));
+    }
+    let mut saturations = Vec::new();
+    for s in list(head, "saturations", place)? {
+        let root = match list(s, "root", place)?.as_slice() {
+            [u, v] => u.as_u64().zip(v.as_u64()),
+            _ => None,
+        }
+        .ok_or_else(|| schema(place, "saturation root is not [u, v]".into()))?;
+        saturations.push(Saturation {
+            root: (root.0 as usize, root.1 as usize),
+            key: match field(s, "key", place)? {
+                Value::Null => None,
+                _ => Some(i32s(s, "key", place)?),
+            },
+            count: uint(s, "count", place)?,
+        });
+    }
+    let numbers = |name: &str| -> Result<Option<Vec<f64>>> {
+        match field(head, name, place)? {
+            Value::Null => Ok(None),
+            v => v
+                .as_array()
+                .and_then(|a| a.iter().map(Value::as_f64).collect())
+                .map(Some)
+                .ok_or_else(|| schema(place, format!("{:?} is not a list of numbers", name))),
+        }
+    };
+    let pruning = match numbers("pruning")?.as_deref() {
+        None => None,
+        Some(&[min_weight, states, weight, total_weight]) => Some(Pruning {
+            min_weight,
+            states: states as u64,
+            weight,
+            total_weight,
+        }),
+        Some(_) => return Err(schema(place, "pruning is not 4 numbers".into())),
+    };
+    let truncated = match numbers("truncated")?.as_deref() {
+        None => None,
+        Some(&[written, total]) => Some(Truncation {
+            written: written as usize,
+            total: total as usize,
+        }),
+        Some(_) => return Err(schema(place, "truncated is not 2 numbers".into())),
+    };
+    Ok(Snapshot {
+        buckets: Vec::new(),
+        jbt_ref_pop,
+        n_total,
+        compat,
+        saturations,
+        stats: None,
+        counts: None,
+        pruning,
+        truncated,
+        // absent from views packed before it was recorded
+        fingerprint: head
+            .get("fingerprint")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
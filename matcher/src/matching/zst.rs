@@ -0,0 +1,476 @@
+//! Zstd-compressed binary snapshots (feature "zstd"), for paths ending in
+//! `.snap.zst`: `save_snapshot` and `load_snapshot` pick this codec by
+//! extension. Smaller and much faster to write than NPZ, but read only by the
+//! matcher itself.
+//!
+//! Layout: `MAGIC`, then one zstd frame holding bincode records (a header
+//! with N, `jbt_ref_pop` and the compat table; the fingerprint, an
+//! `Option<String>`, absent from version 1 files; each bucket as `Some(bucket)`;
+//! `None`; the statistics, counts, pruning and saturations), then a plain
+//! footer of two little-endian u64, buckets written and buckets total, so a
+//! cancelled save can be told apart without decompressing.
+
+/// Whether `path` names a binary snapshot.
+pub fn is_zst(path: &str) -> bool {
+    path.ends_with(".snap.zst")
+}
+
+pub(super) use frames::{is_truncated, read, read_fingerprint, write};
+
+/// Without the "zstd" feature there is no codec; binary snapshots are refused.
+#[cfg(not(feature = "zstd"))]
+mod frames {
+    use anyhow::{Result, bail};
+    use std::fs::File;
+
+    use super::super::types::Snapshot;
+    use crate::runtime::CancelToken;
+
+    pub(in super::super) fn write(
+        _f: File,
+        path: &str,
+        _snap: &Snapshot,
+        _cancel: &CancelToken,
+    ) -> Result<()> {
+        bail!(
+            "{} is a binary snapshot path, and this matcher was built without the \"zstd\" feature; rebuild with --features zstd or save as .npz",
+            path
+        )
+    }
+
+    pub(in super::super) fn read(_f: File, path: &str) -> Result<Snapshot> {
+        bail!(
+            "{} is a binary snapshot, and this matcher was built without the \"zstd\" feature",
+            path
+        )
+    }
+
+    pub(in super::super) fn is_truncated(_path: &str) -> bool {
+        false
+    }
+
+    pub(in super::super) fn read_fingerprint(_path: &str) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod frames {
+    use anyhow::{Context, Result, bail};
+    use num_bigint::BigUint;
+    use serde::{Deserialize, Serialize};
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+    use std::time::Instant;
+
+    use super::super::io::{check_bucket, warn_snapshot_labels};
+    use super::super::stats::{EnumCounts, SnapshotStats};
+    use super::super::types::{
+        Bucket, EmptyDataError, Indptr, Pruning, Saturation, SchemaError, Snapshot, Truncation,
+    };
+    use crate::human::Secs;
+    use crate::runtime::{CancelToken, Cancelled, resumable_progress_bar};
+
+    /// Leading bytes of a binary snapshot; the last byte is the format version.
+    const MAGIC: &[u8; 8] = b"CJPTSNP2";
+    /// Version 1: no fingerprint record after the header.
+    const MAGIC_V1: &[u8; 8] = b"CJPTSNP1";
+    /// Footer: buckets written, buckets total.
+    const FOOTER: usize = 16;
+    /// zstd level: fast, and still well under the size of a deflated NPZ.
+    const LEVEL: i32 = 3;
+
+    /// (pop, key1, key2).
+    type CompatRecord<'a> = (i32, Cow<'a, [i32]>, Cow<'a, [i32]>);
+    /// ((u, v), pop-key or `None` while still in the frontier, count).
+    type SaturationRecord = ((u64, u64), Option<Vec<i32>>, u64);
+
+    #[derive(Serialize, Deserialize)]
+    struct Header<'a> {
+        n_total: i32,
+        jbt_ref_pop: Cow<'a, [i32]>,
+        /// By pop.
+        compat: Vec<CompatRecord<'a>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum Offsets<'a> {
+        Narrow(Cow<'a, [u32]>),
+        Wide(Cow<'a, [i64]>),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct BucketRecord<'a> {
+        key: Cow<'a, [i32]>,
+        rows_data: Cow<'a, [i32]>,
+        indptr: Offsets<'a>,
+        weights: Cow<'a, [f64]>,
+        /// (row, little-endian bytes of the exact weight).
+        exact: Vec<(u64, Vec<u8>)>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Meta {
+        stats: Option<SnapshotStatsRecord>,
+        /// (frontier states, completed states per bucket).
+        counts: Option<(u64, Vec<u64>)>,
+        /// (min_weight, states, weight, total_weight).
+        pruning: Option<(f64, u64, f64, f64)>,
+        saturations: Vec<SaturationRecord>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct SnapshotStatsRecord {
+        k_states: Vec<u64>,
+        k_weight: Vec<f64>,
+        bucket_states: Vec<u64>,
+        bucket_weight: Vec<f64>,
+    }
+
+    impl From<&SnapshotStats> for SnapshotStatsRecord {
+        fn from(s: &SnapshotStats) -> Self {
+            SnapshotStatsRecord {
+                k_states: s.k_states.clone(),
+                k_weight: s.k_weight.clone(),
+                bucket_states: s.bucket_states.clone(),
+                bucket_weight: s.bucket_weight.clone(),
+            }
+        }
+    }
+
+    impl<'a> From<&'a Bucket> for BucketRecord<'a> {
+        fn from(b: &'a Bucket) -> Self {
+            BucketRecord {
+                key: Cow::Borrowed(&b.key),
+                rows_data: Cow::Borrowed(&b.rows_data),
+                indptr: match &b.indptr {
+                    Indptr::Narrow(v) => Offsets::Narrow(Cow::Borrowed(v)),
+                    Indptr::Wide(v) => Offsets::Wide(Cow::Borrowed(v)),
+                },
+                weights: Cow::Borrowed(&b.weights),
+                exact: b
+                    .exact
+                    .iter()
+                    .map(|(r, x)| (*r as u64, x.to_bytes_le()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl BucketRecord<'_> {
+        fn into_bucket(self) -> Result<Bucket> {
+            Ok(Bucket {
+                key: self.key.into_owned(),
+                rows_data: self.rows_data.into_owned().into(),
+                indptr: match self.indptr {
+                    Offsets::Narrow(v) => Indptr::Narrow(v.into_owned().into()),
+                    Offsets::Wide(v) => Indptr::Wide(v.into_owned().into()),
+                },
+                weights: self.weights.into_owned().into(),
+                exact: self
+                    .exact
+                    .into_iter()
+                    .map(|(r, bytes)| {
+                        let r = usize::try_from(r)
+                            .with_context(|| format!("exact weight row {}", r))?;
+                        Ok((r, BigUint::from_bytes_le(&bytes)))
+                    })
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+
+    /// Write `snap` to `f` as `save_snapshot` does for NPZ: cancelled after a
+    /// bucket, the file holds the buckets so far and the footer says so.
+    pub(in super::super) fn write(
+        f: File,
+        path: &str,
+        snap: &Snapshot,
+        cancel: &CancelToken,
+    ) -> Result<()> {
+        let t0 = Instant::now();
+        let mut f = BufWriter::new(f);
+        f.write_all(MAGIC)?;
+        let mut z = zstd::Encoder::new(f, LEVEL)?;
+        z.include_checksum(true)?;
+        z.multithread(rayon::current_num_threads() as u32)?;
+        let mut z = BufWriter::new(z);
+
+        let mut pops: Vec<&i32> = snap.compat.keys().collect();
+        pops.sort_unstable();
+        let header = Header {
+            n_total: snap.n_total,
+            jbt_ref_pop: Cow::Borrowed(&snap.jbt_ref_pop),
+            compat: pops
+                .into_iter()
+                .map(|p| {
+                    let (k1, k2) = &snap.compat[p];
+                    (*p, Cow::Borrowed(&k1[..]), Cow::Borrowed(&k2[..]))
+                })
+                .collect(),
+        };
+        bincode::serialize_into(&mut z, &header)?;
+        bincode::serialize_into(&mut z, &snap.fingerprint)?;
+
+        let total = snap.buckets.len();
+        let pb = resumable_progress_bar(total as u64, "buckets", 0);
+        let mut written = 0;
+        for (idx, bucket) in snap.buckets.iter().enumerate() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            crate::chaos::before_save_bucket(idx).with_context(|| format!("write {}", path))?;
+            bincode::serialize_into(&mut z, &Some(BucketRecord::from(bucket)))?;
+            written += 1;
+            pb.inc(1);
+        }
+        pb.finish_and_clear();
+        bincode::serialize_into(&mut z, &None::<BucketRecord>)?;
+
+        let truncated = (written < total).then_some(Truncation { written, total });
+        let partial_stats;
+        let stats = match (&snap.stats, truncated) {
+            (Some(_), Some(_)) => {
+                partial_stats = SnapshotStats::from_buckets(&snap.buckets[..written]);
+                Some(&partial_stats)
+            }
+            (stats, None) => stats.as_ref(),
+            (None, Some(_)) => None,
+        };
+        let meta = Meta {
+            stats: stats.map(SnapshotStatsRecord::from),
+            counts: snap.counts.as_ref().map(|c| {
+                let completed = c.bucket_completed.iter().take(written).copied();
+                (c.frontier_states, completed.collect())
+            }),
+            pruning: snap
+                .pruning
+                .map(|p| (p.min_weight, p.states, p.weight, p.total_weight)),
+            saturations: snap
+                .saturations
+                .iter()
+                .map(|s| ((s.root.0 as u64, s.root.1 as u64), s.key.clone(), s.count))
+                .collect(),
+        };
+        bincode::serialize_into(&mut z, &meta)?;
+
+        let z = z.into_inner().map_err(|e| e.into_error())?;
+        let mut f = z.finish()?;
+        f.write_all(&(written as u64).to_le_bytes())?;
+        f.write_all(&(total as u64).to_le_bytes())?;
+        f.flush().with_context(|| format!("write {}", path))?;
+        f.get_ref()
+            .sync_all()
+            .with_context(|| format!("sync {}", path))?;
+
+        if let Some(t) = truncated {
+            return Err(Cancelled(format!(
+                "snapshot {}: {}; the file is labeled truncated",
+                path, t
+            ))
+            .into());
+        }
+        eprintln!(
+            "[save] {} buckets written to {} in {}",
+            total,
+            path,
+            Secs::from(t0.elapsed())
+        );
+        Ok(())
+    }
+
+    /// Buckets written and total, from the footer.
+    fn read_footer(f: &mut File, path: &str) -> Result<(u64, u64)> {
+        let len = f.metadata()?.len();
+        if len < (MAGIC.len() + FOOTER) as u64 {
+            return Err(SchemaError(format!("{}: too short for a binary snapshot", path)).into());
+        }
+        let mut footer = [0u8; FOOTER];
+        f.seek(SeekFrom::Start(len - FOOTER as u64))?;
+        f.read_exact(&mut footer)?;
+        f.seek(SeekFrom::Start(0))?;
+        let (written, total) = footer.split_at(8);
+        Ok((
+            u64::from_le_bytes(written.try_into().unwrap()),
+            u64::from_le_bytes(total.try_into().unwrap()),
+        ))
+    }
+
+    /// Whether the binary snapshot at `path` was cut short by a cancelled save.
+    pub(in super::super) fn is_truncated(path: &str) -> bool {
+        File::open(path)
+            .ok()
+            .and_then(|mut f| read_footer(&mut f, path).ok())
+            .is_some_and(|(written, total)| written < total)
+    }
+
+    /// The decompressed records of the binary snapshot read by `r`, past the
+    /// magic, the header and the fingerprint.
+    fn open_frame(
+        mut r: BufReader<File>,
+        path: &str,
+    ) -> Result<(impl Read, Header<'static>, Option<String>)> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC && &magic != MAGIC_V1 {
+            return Err(SchemaError(format!(
+                "{}: not a binary snapshot (or written by another version)",
+                path
+            ))
+            .into());
+        }
+        let mut z = BufReader::new(zstd::Decoder::with_buffer(r)?.single_frame());
+        let header: Header = bincode::deserialize_from(&mut z)
+            .with_context(|| format!("snapshot {}: read header", path))?;
+        let fingerprint = if &magic == MAGIC {
+            bincode::deserialize_from(&mut z)
+                .with_context(|| format!("snapshot {}: read fingerprint", path))?
+        } else {
+            None
+        };
+        Ok((z, header, fingerprint))
+    }
+
+    /// The fingerprint of the binary snapshot at `path`, from the start of the
+    /// frame only.
+    pub(in super::super) fn read_fingerprint(path: &str) -> Option<String> {
+        let f = File::open(path).ok()?;
+        open_frame(BufReader::new(f), path).ok()?.2
+    }
+
+    /// Load the binary snapshot at `path`, with the checks `load_snapshot_from`
+    /// makes of an NPZ.
+    pub(in super::super) fn read(mut f: File, path: &str) -> Result<Snapshot> {
+        let (written, total) = read_footer(&mut f, path)?;
+        let (mut z, header, fingerprint) = open_frame(BufReader::new(f), path)?;
+        if header.n_total <= 0 {
+            bail!("snapshot {}: N={} must be positive", path, header.n_total);
+        }
+        let jbt_ref_pop = header.jbt_ref_pop.into_owned();
+        let mut compat = HashMap::new();
+        for (p, k1, k2) in header.compat {
+            if k1.len() != k2.len() {
+                bail!(
+                    "snapshot {}: compat for pop {} key1/key2 lengths differ ({} vs {})",
+                    path,
+                    p,
+                    k1.len(),
+                    k2.len()
+                );
+            }
+            compat.insert(p, (k1.into_owned(), k2.into_owned()));
+        }
+
+        let mut buckets = Vec::new();
+        while let Some(record) = bincode::deserialize_from::<_, Option<BucketRecord>>(&mut z)
+            .with_context(|| format!("snapshot {}: read bucket {}", path, buckets.len()))?
+        {
+            let b = buckets.len();
+            let bucket = record.into_bucket()?;
+            check_bucket(&bucket, b, jbt_ref_pop.len())
+                .with_context(|| format!("snapshot {}", path))?;
+            if let Some((r, w)) = bucket.first_non_finite_weight() {
+                bail!(
+                    "snapshot {}: bucket {} {:?} row {} has weight {}; weights must be finite",
+                    path,
+                    b,
+                    bucket.key,
+                    r,
+                    w
+                );
+            }
+            buckets.push(bucket);
+        }
+        if buckets.is_empty() {
+            return Err(EmptyDataError(format!("snapshot {} has zero buckets", path)).into());
+        }
+        if written != buckets.len() as u64 || total < written {
+            return Err(SchemaError(format!(
+                "snapshot {}: footer says {} of {} buckets, the file holds {}",
+                path,
+                written,
+                total,
+                buckets.len()
+            ))
+            .into());
+        }
+
+        let meta: Meta = bincode::deserialize_from(&mut z)
+            .with_context(|| format!("snapshot {}: read statistics", path))?;
+        // the frame checksum is only checked at its end
+        let mut rest = Vec::new();
+        z.read_to_end(&mut rest)
+            .with_context(|| format!("snapshot {}", path))?;
+        if !rest.is_empty() {
+            return Err(SchemaError(format!(
+                "snapshot {}: {} stray bytes after the statistics",
+                path,
+                rest.len()
+            ))
+            .into());
+        }
+        let stats = meta.stats.map(|s| SnapshotStats {
+            k_states: s.k_states,
+            k_weight: s.k_weight,
+            bucket_states: s.bucket_states,
+            bucket_weight: s.bucket_weight,
+        });
+        if let Some(s) = &stats
+            && (s.k_weight.len() != s.k_states.len()
+                || s.bucket_states.len() != buckets.len()
+                || s.bucket_weight.len() != buckets.len())
+        {
+            bail!("snapshot statistics disagree in length with the buckets");
+        }
+        let counts = meta
+            .counts
+            .map(|(frontier_states, bucket_completed)| EnumCounts {
+                frontier_states,
+                bucket_completed,
+            });
+        if let Some(c) = &counts
+            && c.bucket_completed.len() != buckets.len()
+        {
+            bail!(
+                "snapshot {}: {} completed-state counts for {} buckets",
+                path,
+                c.bucket_completed.len(),
+                buckets.len()
+            );
+        }
+        let snap = Snapshot {
+            buckets,
+            jbt_ref_pop,
+            n_total: header.n_total,
+            compat,
+            saturations: meta
+                .saturations
+                .into_iter()
+                .map(|((u, v), key, count)| Saturation {
+                    root: (u as usize, v as usize),
+                    key,
+                    count,
+                })
+                .collect(),
+            stats,
+            counts,
+            pruning: meta
+                .pruning
+                .map(|(min_weight, states, weight, total_weight)| Pruning {
+                    min_weight,
+                    states,
+                    weight,
+                    total_weight,
+                }),
+            truncated: (written < total).then_some(Truncation {
+                written: written as usize,
+                total: total as usize,
+            }),
+            fingerprint,
+        };
+        warn_snapshot_labels(path, &snap);
+        Ok(snap)
+    }
+}
@@ -0,0 +1,243 @@
+//! Python bindings (feature "python"): the `matcher_py` extension module, so
+//! a Python driver can enumerate, load and match without running the binary
+//! and parsing its output.
+//!
+//! ```python
+//! import matcher_py, pandas
+//!
+//! snap = matcher_py.enumerate_to_snapshot("pre_ref_compat_inputs8.npz")
+//! snap.save("cjpt8_snapshot.npz")
+//! pairs = pandas.DataFrame(matcher_py.run_all_pairs(snap, n_threads=8))
+//! omega = pairs.subtotal.sum()
+//! ```
+//!
+//! `Snapshot.rows_data(b)`, `indptr(b)` and `weights(b)` are read-only numpy
+//! views of bucket b's arrays, kept alive by the snapshot object; nothing is
+//! copied. Long calls release the GIL.
+
+use numpy::PyArray1;
+use numpy::ndarray::ArrayView1;
+use pyo3::exceptions::{PyIndexError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyTuple};
+use std::sync::Arc;
+
+use crate::enumeration::{self, Checkpointing};
+use crate::matching::types::{Bucket, Indptr, Snapshot};
+use crate::matching::{self, EmptyDataError, MatchConfig, SchemaError};
+use crate::runtime::CancelToken;
+use crate::settings;
+
+/// Malformed or empty inputs raise `ValueError`, anything else
+/// `RuntimeError`, with the whole context chain as the message.
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    let msg = format!("{:#}", e);
+    if e.downcast_ref::<SchemaError>().is_some() || e.downcast_ref::<EmptyDataError>().is_some() {
+        PyValueError::new_err(msg)
+    } else {
+        PyRuntimeError::new_err(msg)
+    }
+}
+
+/// A snapshot held by Rust; Python sees its metadata and numpy views of its
+/// bucket arrays.
+#[pyclass(frozen, name = "Snapshot", module = "matcher_py")]
+struct PySnapshot {
+    inner: Arc<Snapshot>,
+}
+
+impl PySnapshot {
+    fn bucket(&self, b: usize) -> PyResult<&Bucket> {
+        self.inner.buckets.get(b).ok_or_else(|| {
+            PyIndexError::new_err(format!(
+                "bucket {} out of range ({} buckets)",
+                b,
+                self.inner.buckets.len()
+            ))
+        })
+    }
+}
+
+/// `data` as a read-only numpy array borrowing from the snapshot `owner`.
+fn view<'py, T: numpy::Element>(
+    owner: &Bound<'py, PySnapshot>,
+    data: &[T],
+) -> PyResult<Bound<'py, PyAny>> {
+    // SAFETY: `data` lies in a bucket of `owner`'s snapshot, which is never
+    // mutated (the class is frozen and the Arc is not shared mutably) and
+    // lives as long as `owner`, which the array keeps a reference to.
+    let arr =
+        unsafe { PyArray1::borrow_from_array(&ArrayView1::from(data), owner.clone().into_any()) };
+    arr.call_method1("setflags", (false,))?;
+    Ok(arr.into_any())
+}
+
+#[pymethods]
+impl PySnapshot {
+    fn __len__(&self) -> usize {
+        self.inner.buckets.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Snapshot(N={}, {} buckets, {} rows)",
+            self.inner.n_total,
+            self.inner.buckets.len(),
+            self.inner.buckets.iter().map(Bucket::n_rows).sum::<usize>()
+        )
+    }
+
+    /// Board size N.
+    #[getter]
+    fn n_total(&self) -> i32 {
+        self.inner.n_total
+    }
+
+    #[getter]
+    fn jbt_ref_pop(&self) -> Vec<i32> {
+        self.inner.jbt_ref_pop.clone()
+    }
+
+    /// Pop-key of every bucket, as tuples (`()` for the neutral bucket).
+    #[getter]
+    fn keys<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        PyList::new(
+            py,
+            self.inner
+                .buckets
+                .iter()
+                .map(|b| PyTuple::new(py, &b.key))
+                .collect::<PyResult<Vec<_>>>()?,
+        )
+    }
+
+    fn key<'py>(&self, py: Python<'py>, b: usize) -> PyResult<Bound<'py, PyTuple>> {
+        PyTuple::new(py, &self.bucket(b)?.key)
+    }
+
+    fn n_rows(&self, b: usize) -> PyResult<usize> {
+        Ok(self.bucket(b)?.n_rows())
+    }
+
+    /// Bucket b's jbt entries, row after row (int32).
+    fn rows_data<'py>(slf: &Bound<'py, Self>, b: usize) -> PyResult<Bound<'py, PyAny>> {
+        view(slf, &slf.get().bucket(b)?.rows_data)
+    }
+
+    /// Row offsets into `rows_data(b)`, n_rows + 1 of them: uint32 unless the
+    /// bucket has 2^32 entries or more, int64 then.
+    fn indptr<'py>(slf: &Bound<'py, Self>, b: usize) -> PyResult<Bound<'py, PyAny>> {
+        match &slf.get().bucket(b)?.indptr {
+            Indptr::Narrow(v) => view(slf, v),
+            Indptr::Wide(v) => view(slf, v),
+        }
+    }
+
+    /// Row weights (float64; past 2^53 the nearest float).
+    fn weights<'py>(slf: &Bound<'py, Self>, b: usize) -> PyResult<Bound<'py, PyAny>> {
+        view(slf, &slf.get().bucket(b)?.weights)
+    }
+
+    /// Write the snapshot as `matcher enumerate --snapshot-out` would.
+    fn save(&self, py: Python<'_>, path: &str) -> PyResult<()> {
+        py.detach(|| {
+            matching::save_snapshot(
+                path,
+                &self.inner,
+                settings::get().index_width,
+                &CancelToken::default(),
+            )
+        })
+        .map_err(to_py_err)
+    }
+}
+
+/// Enumerate one input NPZ, or the parts of a split pre-CSR (a list of paths,
+/// as with `--merge-input`), into a snapshot held in memory.
+#[pyfunction]
+fn enumerate_to_snapshot(py: Python<'_>, path: Bound<'_, PyAny>) -> PyResult<PySnapshot> {
+    let paths: Vec<String> = match path.extract::<String>() {
+        Ok(p) => vec![p],
+        Err(_) => path.extract()?,
+    };
+    let snap = py
+        .detach(|| {
+            let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+            enumeration::enumerate_to_snapshot_from_npzs(
+                &paths,
+                &Checkpointing::default(),
+                &CancelToken::default(),
+            )
+        })
+        .map_err(to_py_err)?;
+    Ok(PySnapshot {
+        inner: Arc::new(snap),
+    })
+}
+
+/// Load a snapshot or snapshot view from disk.
+#[pyfunction]
+fn load_snapshot(py: Python<'_>, path: &str) -> PyResult<PySnapshot> {
+    let snap = py
+        .detach(|| matching::load_snapshot(path))
+        .map_err(to_py_err)?;
+    Ok(PySnapshot {
+        inner: Arc::new(snap),
+    })
+}
+
+/// Match every pair of `snapshot` on `n_threads` threads (default: the global
+/// pool). Returns one list per column, in pair order, ready for
+/// `pandas.DataFrame`: key_left, key_right, rows1, rows2, subtotal,
+/// weight_mass, t_total (s) and skipped (None, or why the pair was left out).
+#[pyfunction]
+#[pyo3(signature = (snapshot, n_threads = None))]
+fn run_all_pairs<'py>(
+    py: Python<'py>,
+    snapshot: &PySnapshot,
+    n_threads: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let snap = Arc::clone(&snapshot.inner);
+    let (pairs, _) = py
+        .detach(|| matching::run_all_pairs_on(&snap, &MatchConfig::default(), n_threads))
+        .map_err(to_py_err)?;
+    let out = PyDict::new(py);
+    let keys = |side: fn(&matching::PairResult) -> &Vec<i32>| {
+        pairs
+            .iter()
+            .map(|r| PyTuple::new(py, side(r)))
+            .collect::<PyResult<Vec<_>>>()
+    };
+    out.set_item("key_left", keys(|r| &r.key_left)?)?;
+    out.set_item("key_right", keys(|r| &r.key_right)?)?;
+    out.set_item("rows1", pairs.iter().map(|r| r.rows1).collect::<Vec<_>>())?;
+    out.set_item("rows2", pairs.iter().map(|r| r.rows2).collect::<Vec<_>>())?;
+    out.set_item(
+        "subtotal",
+        pairs.iter().map(|r| r.subtotal).collect::<Vec<_>>(),
+    )?;
+    out.set_item(
+        "weight_mass",
+        pairs.iter().map(|r| r.weight_mass).collect::<Vec<_>>(),
+    )?;
+    out.set_item(
+        "t_total",
+        pairs.iter().map(|r| r.t_total).collect::<Vec<_>>(),
+    )?;
+    out.set_item(
+        "skipped",
+        pairs.iter().map(|r| r.skipped.clone()).collect::<Vec<_>>(),
+    )?;
+    Ok(out)
+}
+
+#[pymodule]
+fn matcher_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    // the bucket views need numpy; fail the import rather than the first view
+    m.py().import("numpy")?;
+    m.add_class::<PySnapshot>()?;
+    m.add_function(wrap_pyfunction!(enumerate_to_snapshot, m)?)?;
+    m.add_function(wrap_pyfunction!(load_snapshot, m)?)?;
+    m.add_function(wrap_pyfunction!(run_all_pairs, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::path::Path;
+
+use crate::matching::{MatchConfig, PairResult};
+
+pub use sqlite::ResultsDb;
+
+/// One finished run, as handed to `ResultsDb::record_run`.
+#[cfg_attr(not(feature = "results-db"), allow(dead_code))]
+pub struct RunRecord<'a> {
+    pub mode: &'a str,
+    /// Input NPZ for enumerate/watch runs, snapshot NPZ for resumed runs.
+    pub input: &'a Path,
+    pub cfg: &'a MatchConfig,
+    pub t_enumerate: Option<f64>,
+    pub t_match: f64,
+    pub pairs: &'a [PairResult],
+}
+
+/// Without the "results-db" feature there is no SQLite; `--results-db` is refused.
+#[cfg(not(feature = "results-db"))]
+mod sqlite {
+    use anyhow::{Result, bail};
+    use std::convert::Infallible;
+    use std::path::Path;
+
+    use super::RunRecord;
+
+    /// Never built: `open` always fails.
+    pub struct ResultsDb(Infallible);
+
+    impl ResultsDb {
+        pub fn open(path: &Path) -> Result<Self> {
+            bail!(
+                "cannot open {}: this matcher was built without the \"results-db\" feature; rebuild with --features results-db to record runs",
+                path.display()
+            )
+        }
+
+        pub fn record_run(&mut self, _rec: &RunRecord) -> Result<i64> {
+            match self.0 {}
+        }
+
+        pub fn print_history(&self, _limit: usize) -> Result<()> {
+            match self.0 {}
+        }
+
+        pub fn print_run(&self, _run_id: i64) -> Result<()> {
+            match self.0 {}
+        }
+    }
+}
+
+#[cfg(feature = "results-db")]
+mod sqlite {
+    use anyhow::{Context, Result, bail};
+    use rusqlite::{Connection, OptionalExtension, params};
+    use sha2::{Digest, Sha256};
+    use std::fs::File;
+    use std::path::Path;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::RunRecord;
+    use crate::matching::report::fmt_value;
+    use crate::matching::{self, MatchConfig};
+
+    const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id            INTEGER PRIMARY KEY,
+    started_at    INTEGER NOT NULL,  -- unix seconds
+    mode          TEXT NOT NULL,     -- enumerate | resume | watch
+    input_path    TEXT NOT NULL,
+    input_sha256  TEXT NOT NULL,
+    config_sha256 TEXT NOT NULL,
+    config        TEXT NOT NULL,
+    omega         REAL NOT NULL,
+    n_pairs       INTEGER NOT NULL,
+    t_enumerate   REAL,              -- NULL when resumed from a snapshot
+    t_match       REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pair_results (
+    run_id    INTEGER NOT NULL REFERENCES runs(id),
+    key_left  TEXT NOT NULL,
+    key_right TEXT NOT NULL,
+    rows1     INTEGER NOT NULL,
+    rows2     INTEGER NOT NULL,
+    factor    REAL NOT NULL,
+    subtotal  REAL NOT NULL,
+    t_solve   REAL NOT NULL,
+    t_total   REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS pair_results_run ON pair_results(run_id);
+";
+
+    /// SQLite run history (`--results-db`): one `runs` row per run plus its
+    /// per-pair subtotals (pairs skipped as too large are left out), so every
+    /// Omega can be traced to an input revision.
+    pub struct ResultsDb {
+        conn: Connection,
+    }
+
+    pub fn file_sha256(path: &Path) -> Result<String> {
+        let mut f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        let mut h = Sha256::new();
+        std::io::copy(&mut f, &mut h).with_context(|| format!("hash {}", path.display()))?;
+        Ok(format!("{:x}", h.finalize()))
+    }
+
+    /// Canonical text of everything that can change Omega for a given input,
+    /// plus its hash.
+    fn config_fingerprint(cfg: &MatchConfig) -> (String, String) {
+        let first_limit = crate::settings::get()
+            .first_limit
+            .map_or(String::new(), |l| l.to_string());
+        let text = format!(
+            "neutral={:?};pairs={:?};enum_first_limit={}",
+            cfg.neutral, cfg.pair_whitelist, first_limit
+        );
+        let hash = format!("{:x}", Sha256::digest(text.as_bytes()));
+        (text, hash)
+    }
+
+    impl ResultsDb {
+        pub fn open(path: &Path) -> Result<Self> {
+            let conn = Connection::open(path)
+                .with_context(|| format!("open results db {}", path.display()))?;
+            conn.execute_batch(SCHEMA)
+                .context("create results db schema")?;
+            Ok(ResultsDb { conn })
+        }
+
+        pub fn record_run(&mut self, rec: &RunRecord) -> Result<i64> {
+            let input_hash = file_sha256(rec.input)?;
+            let (config, config_hash) = config_fingerprint(rec.cfg);
+            let omega = matching::omega_sum(rec.pairs);
+            let started_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                "INSERT INTO runs (started_at, mode, input_path, input_sha256, config_sha256, config,
+                                   omega, n_pairs, t_enumerate, t_match)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    started_at,
+                    rec.mode,
+                    rec.input.to_string_lossy(),
+                    input_hash,
+                    config_hash,
+                    config,
+                    omega,
+                    rec.pairs.iter().filter(|r| r.skipped.is_none()).count() as i64,
+                    rec.t_enumerate,
+                    rec.t_match,
+                ],
+            )?;
+            let run_id = tx.last_insert_rowid();
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO pair_results (run_id, key_left, key_right, rows1, rows2, factor,
+                                               subtotal, t_solve, t_total)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                )?;
+                for r in rec.pairs.iter().filter(|r| r.skipped.is_none()) {
+                    stmt.execute(params![
+                        run_id,
+                        format!("{:?}", r.key_left),
+                        format!("{:?}", r.key_right),
+                        r.rows1 as i64,
+                        r.rows2 as i64,
+                        r.factor,
+                        r.subtotal,
+                        r.t_solve,
+                        r.t_total,
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            eprintln!(
+                "[results-db] recorded run {} (input sha256 {}, config {})",
+                run_id,
+                &input_hash[..12],
+                &config_hash[..12]
+            );
+            Ok(run_id)
+        }
+
+        /// `matcher history`: newest runs first.
+        pub fn print_history(&self, limit: usize) -> Result<()> {
+            let mut stmt = self.conn.prepare(
+                "SELECT id, datetime(started_at, 'unixepoch'), mode, input_path, input_sha256,
+                        config_sha256, omega, n_pairs, t_enumerate, t_match
+                 FROM runs ORDER BY id DESC LIMIT ?1",
+            )?;
+            let mut rows = stmt.query(params![limit as i64])?;
+            println!(
+                "{:>5}  {:19}  {:9}  {:12}  {:12}  {:>20}  {:>6}  {:>9}  {:>9}  input",
+                "run",
+                "started (UTC)",
+                "mode",
+                "input",
+                "config",
+                "omega",
+                "pairs",
+                "enum_s",
+                "match_s"
+            );
+            while let Some(row) = rows.next()? {
+                let input_hash: String = row.get(4)?;
+                let config_hash: String = row.get(5)?;
+                let t_enum: Option<f64> = row.get(8)?;
+                println!(
+                    "{:>5}  {:19}  {:9}  {:12}  {:12}  {:>20.6}  {:>6}  {:>9}  {:>9.3}  {}",
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    &input_hash[..12],
+                    &config_hash[..12],
+                    row.get::<_, f64>(6)?,
+                    row.get::<_, i64>(7)?,
+                    t_enum.map_or("-".to_string(), |t| format!("{:.3}", t)),
+                    row.get::<_, f64>(9)?,
+                    row.get::<_, String>(3)?,
+                );
+            }
+            Ok(())
+        }
+
+        /// `matcher history <run_id>`: the run's config and per-pair subtotals.
+        pub fn print_run(&self, run_id: i64) -> Result<()> {
+            let head: Option<(String, String, String, f64)> = self
+                .conn
+                .query_row(
+                    "SELECT input_path, input_sha256, config, omega FROM runs WHERE id = ?1",
+                    params![run_id],
+                    |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+                )
+                .optional()?;
+            let Some((input, input_hash, config, omega)) = head else {
+                bail!("no run {} in results db", run_id);
+            };
+            println!("run {}: {} (sha256 {})", run_id, input, input_hash);
+            println!("config: {}", config);
+            let mut stmt = self.conn.prepare(
+                "SELECT key_left, key_right, rows1, rows2, factor, subtotal, t_total
+                 FROM pair_results WHERE run_id = ?1 ORDER BY subtotal DESC",
+            )?;
+            let mut rows = stmt.query(params![run_id])?;
+            while let Some(row) = rows.next()? {
+                println!(
+                    "[pair {} vs {}{}] rows1={}, rows2={} | total={:.3}s | subtotal={}",
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    if row.get::<_, f64>(4)? == 2.0 {
+                        " x2"
+                    } else {
+                        ""
+                    },
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, f64>(6)?,
+                    fmt_value(row.get::<_, f64>(5)?, None),
+                );
+            }
+            println!("Omega total: {}", fmt_value(omega, None));
+            Ok(())
+        }
+    }
+}
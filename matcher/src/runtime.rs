@@ -1,5 +1,16 @@
+use anyhow::{Context, Result, bail};
+#[cfg(feature = "progress")]
+pub use indicatif::ProgressBar;
+#[cfg(feature = "progress")]
+use indicatif::ProgressStyle;
 use rayon::ThreadPoolBuilder;
-use std::sync::Once;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Once};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::human::{Bytes, Secs};
 
 struct ThreadConfig {
     count: usize,
@@ -8,31 +19,39 @@ struct ThreadConfig {
 
 fn parse_env_threads(keys: &[&str]) -> Option<ThreadConfig> {
     for &key in keys {
-        if let Ok(v) = std::env::var(key) {
-            if let Ok(val) = v.parse::<usize>() {
-                if val > 0 {
-                    return Some(ThreadConfig {
-                        count: val,
-                        source: key.to_string(),
-                    });
-                }
-            }
+        if let Ok(v) = std::env::var(key)
+            && let Ok(val) = v.parse::<usize>()
+            && val > 0
+        {
+            return Some(ThreadConfig {
+                count: val,
+                source: key.to_string(),
+            });
         }
     }
     None
 }
 
+/// Variables consulted for the pool size, first set (and positive) wins.
+pub const THREAD_ENV_HINTS: [&str; 6] = [
+    "MATCHER_THREADS",
+    "RAYON_NUM_THREADS",
+    "SLURM_CPUS_PER_TASK",
+    "SLURM_CPUS_ON_NODE",
+    "PBS_NP",
+    "OMP_NUM_THREADS",
+];
+
 fn detect_thread_config() -> ThreadConfig {
-    const ENV_HINTS: [&str; 6] = [
-        "MATCHER_THREADS",
-        "RAYON_NUM_THREADS",
-        "SLURM_CPUS_PER_TASK",
-        "SLURM_CPUS_ON_NODE",
-        "PBS_NP",
-        "OMP_NUM_THREADS",
-    ];
-
-    if let Some(cfg) = parse_env_threads(&ENV_HINTS) {
+    // MATCHER_THREADS is validated by `settings`; the other hints are external
+    // and skipped when unusable
+    if let Some(count) = crate::settings::get().threads {
+        return ThreadConfig {
+            count,
+            source: "MATCHER_THREADS".to_string(),
+        };
+    }
+    if let Some(cfg) = parse_env_threads(&THREAD_ENV_HINTS[1..]) {
         return cfg;
     }
 
@@ -47,6 +66,179 @@ fn detect_thread_config() -> ThreadConfig {
     }
 }
 
+/// Scheduler variables that identify a batch job, recorded when set.
+const JOB_ENV: [&str; 6] = [
+    "SLURM_JOB_ID",
+    "SLURM_ARRAY_JOB_ID",
+    "SLURM_ARRAY_TASK_ID",
+    "SLURM_JOB_NODELIST",
+    "SLURM_PROCID",
+    "PBS_JOBID",
+];
+
+/// The machine and batch job a run executes on, for the run summary and the
+/// `[env]` log line; fields the platform does not expose stay empty/0.
+#[derive(Debug, Clone)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub cpu_model: String,
+    pub cpus: usize,
+    pub ram_bytes: u64,
+    /// (variable, value) for each set `JOB_ENV` entry.
+    pub job: Vec<(String, String)>,
+}
+
+impl HostInfo {
+    pub fn detect() -> Self {
+        let read = |p: &str| std::fs::read_to_string(p).unwrap_or_default();
+        let cpu_model = read("/proc/cpuinfo")
+            .lines()
+            .find(|l| l.starts_with("model name"))
+            .and_then(|l| l.split_once(':'))
+            .map_or(String::new(), |(_, v)| v.trim().to_string());
+        // "MemTotal:  16318480 kB"
+        let ram_kb = read("/proc/meminfo")
+            .lines()
+            .find_map(|l| l.strip_prefix("MemTotal:"))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        HostInfo {
+            hostname: read("/proc/sys/kernel/hostname").trim().to_string(),
+            cpu_model,
+            cpus: std::thread::available_parallelism().map_or(0, |n| n.get()),
+            ram_bytes: ram_kb * 1024,
+            job: JOB_ENV
+                .iter()
+                .filter_map(|&k| std::env::var(k).ok().map(|v| (k.to_string(), v)))
+                .collect(),
+        }
+    }
+
+    pub fn log(&self) {
+        let job: Vec<String> = self.job.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        eprintln!(
+            "[env] host={} cpu=\"{}\" cpus={} ram={}{}{}",
+            self.hostname,
+            self.cpu_model,
+            self.cpus,
+            Bytes(self.ram_bytes),
+            if job.is_empty() { "" } else { " " },
+            job.join(" ")
+        );
+    }
+}
+
+/// Load `KEY=VALUE` lines (as written by `matcher tune`) into the environment.
+/// Blank lines and `#` comments are skipped; variables already set win, so an
+/// explicit `ENUM_PEND_FLUSH=...` still overrides the file. Call before the
+/// thread pool is configured or any worker thread exists.
+pub fn apply_config_file(path: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!(
+                "{}:{}: expected KEY=VALUE, got {:?}",
+                path.display(),
+                lineno + 1,
+                line
+            );
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if std::env::var_os(key).is_some() {
+            eprintln!(
+                "[config] {} already set in the environment; keeping it over {}",
+                key,
+                path.display()
+            );
+            continue;
+        }
+        // SAFETY: called at startup, before any other thread reads the environment.
+        unsafe { std::env::set_var(key, value) };
+        crate::settings::note_config_source(key, path);
+        eprintln!("[config] {}={} (from {})", key, value, path.display());
+    }
+    Ok(())
+}
+
+/// Shared cancellation flag for long runs. Enumeration checks it before each
+/// root and matching before each pair; clones share the same flag, so an
+/// embedder keeps one and hands another to the run.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Cancel this token on the first SIGINT; a second SIGINT exits at once.
+    /// Without `platform` SIGINT keeps its default action.
+    #[cfg(not(feature = "platform"))]
+    pub fn cancel_on_sigint(&self) {}
+
+    /// Cancel this token on the first SIGINT; a second SIGINT exits at once.
+    #[cfg(feature = "platform")]
+    pub fn cancel_on_sigint(&self) {
+        self.cancel_on_signal(libc::SIGINT);
+    }
+
+    /// Treat SIGTERM like SIGINT, for runs that can save their state when a
+    /// batch scheduler asks them to stop (`--checkpoint`).
+    #[cfg(not(feature = "platform"))]
+    pub fn cancel_on_sigterm(&self) {}
+
+    /// Treat SIGTERM like SIGINT, for runs that can save their state when a
+    /// batch scheduler asks them to stop (`--checkpoint`).
+    #[cfg(feature = "platform")]
+    pub fn cancel_on_sigterm(&self) {
+        self.cancel_on_signal(libc::SIGTERM);
+    }
+
+    /// The first `sig` cancels; another signal exits with 128 + its number.
+    /// Only the first token registered is ever cancelled by a signal.
+    #[cfg(feature = "platform")]
+    fn cancel_on_signal(&self, sig: libc::c_int) {
+        use std::sync::OnceLock;
+        static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+        extern "C" fn on_signal(sig: libc::c_int) {
+            if let Some(flag) = FLAG.get()
+                && !flag.swap(true, Ordering::Relaxed)
+            {
+                return;
+            }
+            // SAFETY: _exit is async-signal-safe.
+            unsafe { libc::_exit(128 + sig) };
+        }
+        if FLAG.set(Arc::clone(&self.0)).is_ok()
+            || FLAG.get().is_some_and(|f| Arc::ptr_eq(f, &self.0))
+        {
+            let handler = on_signal as extern "C" fn(libc::c_int);
+            // SAFETY: the handler only touches an atomic and calls _exit.
+            unsafe { libc::signal(sig, handler as libc::sighandler_t) };
+        }
+    }
+}
+
+/// Returned when a run stops because its `CancelToken` was cancelled.
+#[derive(Debug)]
+pub struct Cancelled(pub String);
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled: {}", self.0)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
 pub fn configure_thread_pool() {
     static INIT: Once = Once::new();
     INIT.call_once(|| {
@@ -65,3 +257,222 @@ pub fn configure_thread_pool() {
         }
     });
 }
+
+/// Stand-in for `indicatif::ProgressBar` when built without `progress`.
+#[cfg(not(feature = "progress"))]
+pub struct ProgressBar;
+
+#[cfg(not(feature = "progress"))]
+impl ProgressBar {
+    pub fn hidden() -> Self {
+        ProgressBar
+    }
+    pub fn inc(&self, _delta: u64) {}
+    pub fn set_message(&self, _msg: String) {}
+    pub fn finish_and_clear(&self) {}
+}
+
+/// Without `progress` only the resume line is printed.
+#[cfg(not(feature = "progress"))]
+pub fn resumable_progress_bar(len: u64, unit: &str, resumed_from: u64) -> ProgressBar {
+    if resumed_from > 0 {
+        eprintln!("[progress] resumed from {unit} {resumed_from}/{len}");
+    }
+    ProgressBar
+}
+
+/// Progress bar over `len` units (e.g. "roots", "pairs") that starts at
+/// `resumed_from` when a checkpoint already covered the first units.
+#[cfg(feature = "progress")]
+pub fn resumable_progress_bar(len: u64, unit: &str, resumed_from: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::with_template(&format!(
+            "[{{elapsed_precise}}] {{bar:40}} {{pos}}/{{len}} {unit} {{msg}}"
+        ))
+        .unwrap()
+        .progress_chars("=>-"),
+    );
+    if resumed_from > 0 {
+        eprintln!("[progress] resumed from {unit} {resumed_from}/{len}");
+        pb.set_position(resumed_from.min(len));
+    }
+    pb
+}
+
+/// Sampling period for `UtilSampler`, from `MATCHER_UTIL_SAMPLE_MS` (default 50;
+/// 0 turns sampling off).
+fn util_sample_period() -> Option<Duration> {
+    let ms = crate::settings::get().util_sample_ms;
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+/// Without `platform` on-CPU time is not measured (reported as 0).
+#[cfg(not(feature = "platform"))]
+fn thread_cpu_ns() -> u64 {
+    0
+}
+
+#[cfg(feature = "platform")]
+fn thread_cpu_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: valid out-pointer; CLOCK_THREAD_CPUTIME_ID is always supported on Linux.
+    let rc = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    if rc != 0 {
+        return 0;
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[derive(Default)]
+struct WorkerSlot {
+    busy: AtomicBool,
+    busy_samples: AtomicU64,
+    busy_ns: AtomicU64,
+    cpu_ns: AtomicU64,
+}
+
+struct UtilState {
+    slots: Vec<WorkerSlot>,
+    samples: AtomicU64,
+    stop: AtomicBool,
+}
+
+/// Per-worker busy/idle sampling for a parallel phase. Workers wrap each unit
+/// of work in `busy()`; a background thread samples the busy flags, and the
+/// guards also accumulate busy wall time and on-CPU time per worker. A busy
+/// worker with low on-CPU share is stalled (page faults, oversubscription);
+/// idle samples mean load imbalance. Cache-miss stalls still count as on-CPU.
+pub struct UtilSampler {
+    label: &'static str,
+    state: Arc<UtilState>,
+    sampler: Option<JoinHandle<()>>,
+    t0: Instant,
+}
+
+/// Marks the current rayon worker busy until dropped.
+pub struct BusyGuard<'a> {
+    slot: Option<&'a WorkerSlot>,
+    t0: Instant,
+    cpu0: u64,
+}
+
+/// A sampler dropped without `finish` (a run that bailed or was cancelled)
+/// still stops its thread.
+impl Drop for UtilSampler {
+    fn drop(&mut self) {
+        self.state.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            slot.busy.store(false, Ordering::Relaxed);
+            slot.busy_ns
+                .fetch_add(self.t0.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            slot.cpu_ns
+                .fetch_add(thread_cpu_ns().saturating_sub(self.cpu0), Ordering::Relaxed);
+        }
+    }
+}
+
+impl UtilSampler {
+    pub fn start(label: &'static str) -> Self {
+        let state = Arc::new(UtilState {
+            slots: (0..rayon::current_num_threads())
+                .map(|_| WorkerSlot::default())
+                .collect(),
+            samples: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        });
+        let sampler = util_sample_period().map(|period| {
+            let state = Arc::clone(&state);
+            std::thread::spawn(move || {
+                while !state.stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(period);
+                    state.samples.fetch_add(1, Ordering::Relaxed);
+                    for slot in &state.slots {
+                        if slot.busy.load(Ordering::Relaxed) {
+                            slot.busy_samples.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            })
+        });
+        UtilSampler {
+            label,
+            state,
+            sampler,
+            t0: Instant::now(),
+        }
+    }
+
+    pub fn busy(&self) -> BusyGuard<'_> {
+        let slot = rayon::current_thread_index().and_then(|i| self.state.slots.get(i));
+        if let Some(slot) = slot {
+            slot.busy.store(true, Ordering::Relaxed);
+        }
+        BusyGuard {
+            slot,
+            t0: Instant::now(),
+            cpu0: thread_cpu_ns(),
+        }
+    }
+
+    /// Stop sampling and print the `[util]` summary lines.
+    pub fn finish(mut self) {
+        self.state.stop.store(true, Ordering::Relaxed);
+        if let Some(h) = self.sampler.take() {
+            let _ = h.join();
+        }
+        let wall = self.t0.elapsed().as_secs_f64();
+        let samples = self.state.samples.load(Ordering::Relaxed);
+        let per_worker: Vec<(f64, f64, f64)> = self
+            .state
+            .slots
+            .iter()
+            .map(|s| {
+                let sampled = if samples > 0 {
+                    s.busy_samples.load(Ordering::Relaxed) as f64 / samples as f64
+                } else {
+                    0.0
+                };
+                let busy = s.busy_ns.load(Ordering::Relaxed) as f64 * 1e-9;
+                let cpu = s.cpu_ns.load(Ordering::Relaxed) as f64 * 1e-9;
+                (sampled, busy, cpu)
+            })
+            .collect();
+        let busy: f64 = per_worker.iter().map(|w| w.1).sum();
+        let cpu: f64 = per_worker.iter().map(|w| w.2).sum();
+        let util: Vec<f64> = per_worker
+            .iter()
+            .map(|w| if wall > 0.0 { w.1 / wall } else { 0.0 })
+            .collect();
+        let min = util.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = util.iter().copied().fold(0.0, f64::max);
+        eprintln!(
+            "[util] {}: wall={} workers={} busy={:.1}% (min {:.1}%, max {:.1}%) on-cpu={:.1}% of busy, samples={}",
+            self.label,
+            Secs(wall),
+            util.len(),
+            100.0 * busy / (wall * util.len().max(1) as f64).max(f64::MIN_POSITIVE),
+            100.0 * min,
+            100.0 * max,
+            if busy > 0.0 { 100.0 * cpu / busy } else { 0.0 },
+            samples
+        );
+        let sampled: Vec<String> = per_worker
+            .iter()
+            .map(|w| format!("{:.0}", 100.0 * w.0))
+            .collect();
+        eprintln!(
+            "[util] {}: sampled busy % per worker = [{}]",
+            self.label,
+            sampled.join(", ")
+        );
+    }
+}
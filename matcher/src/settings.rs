@@ -0,0 +1,438 @@
+//! Every `ENUM_*` / `MATCHER_*` tunable, parsed and validated in one place.
+//! A malformed value (`ENUM_MAX_RSS_MB=abc`, `ENUM_PEND_FLUSH=0`) is an error
+//! naming the variable and what it accepts, instead of being silently ignored,
+//! and an unknown `ENUM_*` / `MATCHER_*` variable (likely a typo) is warned
+//! about. `init` logs each setting that was applied, with its source.
+//!
+//! The binary calls `init` after `--config` and `--set` are applied; enumeration calls it
+//! too, so embedders get the error from `enumerate_to_snapshot`. Everything
+//! else reads the parsed values through `get`.
+
+use anyhow::{Result, bail};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+use crate::enumeration::RootOrder;
+use crate::matching::IndexWidth;
+use crate::matching::report::FloatFormat;
+
+const KB: u64 = 1024;
+const MB: u64 = KB * 1024;
+const GB: u64 = MB * 1024;
+
+/// Every variable this module reads, with what it sets for `matcher --help`;
+/// anything else with our prefixes warns.
+const KNOWN: [(&str, &str); 32] = [
+    (
+        "ENUM_PEND_FLUSH",
+        "pending codes per frontier bucket before an early flush (default 32768)",
+    ),
+    ("ENUM_FIRST_LIMIT", "pre_jbt of the first root to enumerate"),
+    (
+        "ENUM_ROOT_ORDER",
+        "order roots are vacated in: column (default), row or min-growth",
+    ),
+    (
+        "ENUM_CODE_BITS",
+        "bits per j index in a code (at least bitwidth(M))",
+    ),
+    (
+        "ENUM_GPU",
+        "1 = evil-mask checks on the GPU (feature \"gpu\")",
+    ),
+    (
+        "ENUM_PRUNE_TABLE",
+        "0/1 = never/always tabulate evil masks and roots (default: N <= 6)",
+    ),
+    (
+        "ENUM_COMPAT_DEBUG",
+        "1 = print the compat summary after enumeration",
+    ),
+    (
+        "ENUM_MIN_WEIGHT",
+        "drop lighter completed states at snapshot build",
+    ),
+    (
+        "ENUM_WEIGHT_BITS",
+        "state weight width, 64 (default) or 32 to save memory",
+    ),
+    (
+        "ENUM_MAX_RSS_BYTES",
+        "spill frontiers to disk past this RSS",
+    ),
+    ("ENUM_MAX_RSS_MB", "the same in MiB"),
+    ("ENUM_MAX_RSS_GB", "the same in GiB"),
+    (
+        "ENUM_EST_NS_PER_STATE",
+        "`matcher estimate` runtime calibration",
+    ),
+    ("ENUM_INDEX_WIDTH", "snapshot row-offset width, 32 or 64"),
+    ("ENUM_HUGEPAGES", "1 = same as --hugepages"),
+    (
+        "ENUM_HUGEPAGE_MIN_MB",
+        "smallest buffer given huge pages (default 64)",
+    ),
+    (
+        "ENUM_SNAPSHOT_PATH",
+        "snapshot path when none is given on the command line",
+    ),
+    (
+        "ENUM_ROOT_GRAPH",
+        "write the root-to-root transition graph (.json or .dot)",
+    ),
+    (
+        "ENUM_FRONTIER_CSV",
+        "write one frontier-size row per vacated root",
+    ),
+    (
+        "ENUM_COMPLETED_STATES",
+        "append every completed state (large)",
+    ),
+    (
+        "ENUM_COUNTS_CSV",
+        "write completed-state counts per pop-key",
+    ),
+    (
+        "ENUM_CHECKPOINT_EVERY",
+        "roots between --checkpoint writes (default 5)",
+    ),
+    (
+        "ENUM_CHECKPOINT_KEEP",
+        "checkpoints kept, the latest and K-1 older ones (default 1)",
+    ),
+    ("MATCHER_THREADS", "rayon pool size"),
+    (
+        "MATCHER_UTIL_SAMPLE_MS",
+        "utilization sampling period, 0 = off (default 50)",
+    ),
+    ("MATCHER_ALLOC_STATS", "1 = count allocations"),
+    (
+        "MATCHER_MAX_PAIR_CELLS",
+        "skip pairs with more rows1 x rows2 than this",
+    ),
+    (
+        "MATCHER_MAX_PAIR_INDEX_MB",
+        "skip pairs whose row index would exceed this many MiB",
+    ),
+    (
+        "MATCHER_NS_PER_PAIR_COST",
+        "shard-balancing cost model calibration",
+    ),
+    (
+        "MATCHER_FLOAT_FORMAT",
+        "Omega and subtotals as fixed (default), sci or auto",
+    ),
+    (
+        "MATCHER_FLOAT_DIGITS",
+        "significant digits of sci / auto (default 10)",
+    ),
+    (
+        "MATCHER_OTLP_ENDPOINT",
+        "OTLP/HTTP collector for trace export",
+    ),
+];
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// `ENUM_PEND_FLUSH`: pending codes per frontier bucket before an early flush.
+    pub pend_flush: usize,
+    /// `ENUM_FIRST_LIMIT`: pre_jbt of the first root to enumerate.
+    pub first_limit: Option<usize>,
+    /// `ENUM_ROOT_ORDER=column|row|min-growth`: order roots are vacated in.
+    pub root_order: RootOrder,
+    /// `ENUM_CODE_BITS`: bits per j index in a code (at least bitwidth(M)).
+    pub code_bits: Option<u32>,
+    /// `ENUM_GPU=1`: evil-mask checks on the GPU (feature "gpu").
+    pub gpu: bool,
+    /// `ENUM_PRUNE_TABLE=0|1`: never / always (up to N = 8) tabulate evil
+    /// verdicts and roots; `None` tabulates up to N = 6, and up to N = 8 when
+    /// the table fits in a quarter of the available memory.
+    pub prune_table: Option<bool>,
+    /// `ENUM_COMPAT_DEBUG=1`: print the compat summary after enumeration.
+    pub compat_debug: bool,
+    /// `ENUM_MIN_WEIGHT`: drop lighter completed states at snapshot build.
+    pub min_weight: Option<u64>,
+    /// `ENUM_WEIGHT_BITS=32|64`: state weight width (default 64).
+    pub weight_bits: u32,
+    /// `ENUM_MAX_RSS_{BYTES,MB,GB}` (first set wins): spill frontiers to disk past it.
+    pub max_rss_bytes: Option<u64>,
+    /// `ENUM_EST_NS_PER_STATE`: `matcher estimate` runtime calibration.
+    pub est_ns_per_state: Option<f64>,
+    /// `ENUM_INDEX_WIDTH=32|64`: snapshot row-offset width.
+    pub index_width: IndexWidth,
+    /// `ENUM_HUGEPAGES=1`: same as `--hugepages`.
+    pub hugepages: bool,
+    /// `ENUM_HUGEPAGE_MIN_MB`: smallest buffer given huge pages.
+    pub hugepage_min_mb: usize,
+    /// `ENUM_SNAPSHOT_PATH`: snapshot path when none is given on the command line.
+    pub snapshot_path: Option<PathBuf>,
+    /// `ENUM_ROOT_GRAPH`, `ENUM_FRONTIER_CSV`, `ENUM_COMPLETED_STATES`,
+    /// `ENUM_COUNTS_CSV`: diagnostic outputs.
+    pub root_graph: Option<PathBuf>,
+    pub frontier_csv: Option<PathBuf>,
+    pub completed_states: Option<PathBuf>,
+    pub counts_csv: Option<PathBuf>,
+    /// `ENUM_CHECKPOINT_EVERY`: roots vacated between two `--checkpoint` writes.
+    pub checkpoint_every: usize,
+    /// `ENUM_CHECKPOINT_KEEP`: checkpoints kept, the latest included.
+    pub checkpoint_keep: usize,
+    /// `MATCHER_THREADS`: rayon pool size (other hints are read by `runtime`).
+    pub threads: Option<usize>,
+    /// `MATCHER_UTIL_SAMPLE_MS`: utilization sampling period, 0 = off.
+    pub util_sample_ms: u64,
+    /// `MATCHER_ALLOC_STATS=1`: count allocations.
+    pub alloc_stats: bool,
+    /// `MATCHER_MAX_PAIR_CELLS`: skip pairs with more rows1 x rows2 than this.
+    pub max_pair_cells: Option<u64>,
+    /// `MATCHER_MAX_PAIR_INDEX_MB`: skip pairs whose row index exceeds this.
+    pub max_pair_index_bytes: Option<u64>,
+    /// `MATCHER_NS_PER_PAIR_COST`: shard-balancing cost model calibration.
+    pub ns_per_pair_cost: Option<f64>,
+    /// `MATCHER_FLOAT_FORMAT=fixed|sci|auto`: how Omega and subtotals print.
+    pub float_format: FloatFormat,
+    /// `MATCHER_FLOAT_DIGITS`: significant digits of the sci / auto formats.
+    pub float_digits: usize,
+    /// `MATCHER_OTLP_ENDPOINT`: OTLP/HTTP collector for trace export (off when unset).
+    pub otlp_endpoint: Option<String>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Variables set from a `--config` file or `--set`, with where, for the source
+/// column.
+static SOURCES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Record that `key` came from the config file `path` (`runtime::apply_config_file`).
+pub fn note_config_source(key: &str, path: &std::path::Path) {
+    SOURCES
+        .lock()
+        .unwrap()
+        .insert(key.to_string(), format!("config {}", path.display()));
+}
+
+/// Apply one `--set KEY=VALUE`. It wins over the environment and `--config`,
+/// and unlike them rejects a key that is not a setting.
+pub fn apply_set_flag(spec: &str) -> Result<()> {
+    let Some((key, value)) = spec.split_once('=') else {
+        bail!("--set {:?}: expected KEY=VALUE", spec);
+    };
+    let key = key.trim();
+    if !KNOWN.iter().any(|(k, _)| *k == key) {
+        bail!("--set {}: not a known setting (see matcher --help)", key);
+    }
+    // SAFETY: called at startup, before any other thread reads the environment.
+    unsafe { std::env::set_var(key, value.trim()) };
+    SOURCES
+        .lock()
+        .unwrap()
+        .insert(key.to_string(), "--set".to_string());
+    Ok(())
+}
+
+/// The settings table printed after `matcher --help`.
+pub fn help() -> String {
+    let mut out =
+        String::from("Settings (environment, --config file, or --set KEY=VALUE; --set wins):\n");
+    for (key, what) in KNOWN {
+        out.push_str(&format!("  {:<26} {}\n", key, what));
+    }
+    out
+}
+
+/// Parse and validate the environment once, logging what was applied. Later
+/// calls return the first result without re-reading the environment.
+pub fn init() -> Result<&'static Settings> {
+    if let Some(s) = SETTINGS.get() {
+        return Ok(s);
+    }
+    let s = Settings::from_env()?;
+    Ok(SETTINGS.get_or_init(|| s))
+}
+
+/// The parsed settings. Reading them before `init` parses on the spot and
+/// panics on a malformed value; the binary and `enumerate_to_snapshot` call
+/// `init` first, so there the error is reported normally.
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(|| {
+        Settings::from_env().unwrap_or_else(|err| panic!("invalid tunable: {err:#}"))
+    })
+}
+
+/// The settings if they were parsed successfully, without parsing them: for
+/// code that also runs after `init` failed.
+pub fn try_get() -> Option<&'static Settings> {
+    SETTINGS.get()
+}
+
+/// One variable's raw value, and the log of what was applied.
+struct Reader {
+    applied: Vec<String>,
+}
+
+impl Reader {
+    /// Trimmed value of `key`; unset and empty are the same.
+    fn raw(&mut self, key: &str) -> Option<String> {
+        let v = std::env::var(key).ok()?;
+        let v = v.trim();
+        if v.is_empty() {
+            return None;
+        }
+        let source = SOURCES
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| "environment".to_string());
+        self.applied.push(format!("{}={} ({})", key, v, source));
+        Some(v.to_string())
+    }
+
+    /// `key` parsed as `T` and accepted by `valid`; `what` describes the
+    /// accepted values for the error.
+    fn parse<T: FromStr>(
+        &mut self,
+        key: &str,
+        what: &str,
+        valid: impl Fn(&T) -> bool,
+    ) -> Result<Option<T>> {
+        let Some(raw) = self.raw(key) else {
+            return Ok(None);
+        };
+        match raw.parse::<T>() {
+            Ok(v) if valid(&v) => Ok(Some(v)),
+            _ => bail!("{}={:?}: expected {}", key, raw, what),
+        }
+    }
+
+    fn flag(&mut self, key: &str) -> Result<bool> {
+        let Some(raw) = self.raw(key) else {
+            return Ok(false);
+        };
+        match raw.as_str() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            _ => bail!("{}={:?}: expected 0 or 1", key, raw),
+        }
+    }
+
+    fn path(&mut self, key: &str) -> Option<PathBuf> {
+        self.raw(key).map(PathBuf::from)
+    }
+}
+
+impl Settings {
+    fn from_env() -> Result<Self> {
+        let mut r = Reader {
+            applied: Vec::new(),
+        };
+        let positive = |v: &usize| *v > 0;
+        let rss = |r: &mut Reader, key: &str, unit: u64| -> Result<Option<u64>> {
+            Ok(r.parse::<u64>(key, "a whole number", |_| true)?
+                .map(|v| v.saturating_mul(unit)))
+        };
+        // all three are validated even though the first set one wins
+        let rss_bytes = rss(&mut r, "ENUM_MAX_RSS_BYTES", 1)?;
+        let rss_mb = rss(&mut r, "ENUM_MAX_RSS_MB", MB)?;
+        let rss_gb = rss(&mut r, "ENUM_MAX_RSS_GB", GB)?;
+        let max_rss_bytes = rss_bytes.or(rss_mb).or(rss_gb);
+        let s = Settings {
+            pend_flush: r
+                .parse("ENUM_PEND_FLUSH", "a positive count", positive)?
+                .unwrap_or(32_768),
+            first_limit: r.parse("ENUM_FIRST_LIMIT", "a positive count", positive)?,
+            root_order: r
+                .parse("ENUM_ROOT_ORDER", "column, row or min-growth", |_| true)?
+                .unwrap_or_default(),
+            code_bits: r.parse("ENUM_CODE_BITS", "a bit count in 1..=128", |b: &u32| {
+                (1..=128).contains(b)
+            })?,
+            gpu: r.flag("ENUM_GPU")?,
+            prune_table: r
+                .parse("ENUM_PRUNE_TABLE", "0 or 1", |v: &u8| *v <= 1)?
+                .map(|v| v == 1),
+            compat_debug: r.flag("ENUM_COMPAT_DEBUG")?,
+            min_weight: r
+                .parse("ENUM_MIN_WEIGHT", "a non-negative weight", |_| true)?
+                .filter(|&w| w > 0),
+            weight_bits: r
+                .parse("ENUM_WEIGHT_BITS", "32 or 64", |b: &u32| {
+                    *b == 32 || *b == 64
+                })?
+                .unwrap_or(64),
+            max_rss_bytes,
+            est_ns_per_state: r.parse(
+                "ENUM_EST_NS_PER_STATE",
+                "a positive number of nanoseconds",
+                |v: &f64| v.is_finite() && *v > 0.0,
+            )?,
+            index_width: r
+                .parse("ENUM_INDEX_WIDTH", "32 or 64", |_| true)?
+                .unwrap_or_default(),
+            hugepages: r.flag("ENUM_HUGEPAGES")?,
+            hugepage_min_mb: r
+                .parse("ENUM_HUGEPAGE_MIN_MB", "a whole number of MiB", |_| true)?
+                .unwrap_or(64),
+            snapshot_path: r.path("ENUM_SNAPSHOT_PATH"),
+            root_graph: r.path("ENUM_ROOT_GRAPH"),
+            frontier_csv: r.path("ENUM_FRONTIER_CSV"),
+            completed_states: r.path("ENUM_COMPLETED_STATES"),
+            counts_csv: r.path("ENUM_COUNTS_CSV"),
+            checkpoint_every: r
+                .parse("ENUM_CHECKPOINT_EVERY", "a positive root count", positive)?
+                .unwrap_or(5),
+            checkpoint_keep: r
+                .parse(
+                    "ENUM_CHECKPOINT_KEEP",
+                    "a positive checkpoint count",
+                    positive,
+                )?
+                .unwrap_or(1),
+            threads: r.parse("MATCHER_THREADS", "a positive thread count", positive)?,
+            util_sample_ms: r
+                .parse("MATCHER_UTIL_SAMPLE_MS", "milliseconds (0 = off)", |_| true)?
+                .unwrap_or(50),
+            alloc_stats: r.flag("MATCHER_ALLOC_STATS")?,
+            max_pair_cells: r.parse("MATCHER_MAX_PAIR_CELLS", "a positive count", |v: &u64| {
+                *v > 0
+            })?,
+            max_pair_index_bytes: r
+                .parse(
+                    "MATCHER_MAX_PAIR_INDEX_MB",
+                    "a positive number of MiB",
+                    |v: &u64| *v > 0,
+                )?
+                .map(|v| v.saturating_mul(MB)),
+            ns_per_pair_cost: r.parse(
+                "MATCHER_NS_PER_PAIR_COST",
+                "a positive number of nanoseconds",
+                |v: &f64| v.is_finite() && *v > 0.0,
+            )?,
+            float_format: r
+                .parse("MATCHER_FLOAT_FORMAT", "fixed, sci or auto", |_| true)?
+                .unwrap_or_default(),
+            float_digits: r
+                .parse("MATCHER_FLOAT_DIGITS", "a digit count in 1..=17", |d| {
+                    (1..=17).contains(d)
+                })?
+                .unwrap_or(10),
+            otlp_endpoint: r.parse(
+                "MATCHER_OTLP_ENDPOINT",
+                "an http:// URL (e.g. http://localhost:4318)",
+                |v: &String| v.len() > "http://".len() && v.starts_with("http://"),
+            )?,
+        };
+        for line in &r.applied {
+            eprintln!("[settings] {}", line);
+        }
+        for (key, _) in std::env::vars() {
+            if (key.starts_with("ENUM_") || key.starts_with("MATCHER_"))
+                && !KNOWN.iter().any(|(k, _)| *k == key)
+            {
+                eprintln!("[settings] warn: {} is not a known setting; ignored", key);
+            }
+        }
+        Ok(s)
+    }
+}
@@ -0,0 +1,284 @@
+//! Opt-in trace export over OTLP (feature "otlp"). With
+//! `MATCHER_OTLP_ENDPOINT=http://host:4318` (environment or `--config`), a run
+//! is recorded as spans: one `run` span per process, the `enumerate` / `save` /
+//! `load` / `match` phases under it, and one span per root and per bucket pair
+//! under those. The spans go through `tracing` and `tracing-opentelemetry` to
+//! the OpenTelemetry SDK, which batches them to `<endpoint>/v1/traces` as
+//! OTLP/HTTP JSON in the background and flushes the rest when the run ends (in
+//! watch mode, after each input). Only plain `http://` is spoken; point it at a
+//! collector to forward elsewhere. Export failures are warned about and never
+//! fail the run.
+//!
+//! Every run starts a trace of its own, with a random id. The jobs of a sharded
+//! run carry the same `matcher.config_sha256` on their `run` span, to be found
+//! together.
+
+use serde_json::Value;
+
+pub use exporter::{Span, enabled, finish, phase, run_attr, set_resource, span};
+
+/// Without the "otlp" feature nothing is recorded; a configured endpoint is
+/// warned about once.
+#[cfg(not(feature = "otlp"))]
+mod exporter {
+    use super::Value;
+    use crate::runtime::HostInfo;
+
+    pub fn enabled() -> bool {
+        false
+    }
+
+    pub fn set_resource(_host: &HostInfo) {
+        if crate::settings::get().otlp_endpoint.is_some() {
+            eprintln!(
+                "[otlp] warn: MATCHER_OTLP_ENDPOINT is set, and this matcher was built without the \"otlp\" feature; no traces are exported"
+            );
+        }
+    }
+
+    pub fn run_attr(_key: &str, _value: impl Into<Value>) {}
+
+    /// Inert: export is compiled out.
+    pub struct Span;
+
+    impl Span {
+        pub fn attr(&mut self, _key: &str, _value: impl Into<Value>) {}
+    }
+
+    pub fn span(_name: &str) -> Span {
+        Span
+    }
+
+    pub fn phase(_name: &str) -> Span {
+        Span
+    }
+
+    pub fn finish(_outcome: Option<&anyhow::Error>) {}
+}
+
+#[cfg(feature = "otlp")]
+mod exporter {
+    use anyhow::{Context, Result};
+    use opentelemetry::trace::{Status, TraceContextExt, TracerProvider as _};
+    use opentelemetry::{InstrumentationScope, KeyValue};
+    use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::{BatchConfigBuilder, BatchSpanProcessor, SdkTracerProvider};
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+    use tracing::Id;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::Value;
+    use crate::runtime::HostInfo;
+
+    /// Timeout of one export request.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    /// Spans per request, to keep bodies well under collector limits.
+    const BATCH: usize = 2000;
+    /// Finished spans held for the exporter before new ones are dropped: a
+    /// few seconds of pairs on a large machine.
+    const QUEUE: usize = 1 << 16;
+
+    /// The tracer provider, set up on first use; `None` when export is off
+    /// or could not start.
+    static PROVIDER: OnceLock<Option<SdkTracerProvider>> = OnceLock::new();
+    /// Host and batch-job attributes, read when the provider is set up.
+    static RESOURCE: Mutex<Vec<KeyValue>> = Mutex::new(Vec::new());
+    /// The current run, open until `finish`.
+    static RUN: Mutex<Option<tracing::Span>> = Mutex::new(None);
+    /// The open phase, parent of root and pair spans.
+    static PHASE: Mutex<Option<Id>> = Mutex::new(None);
+
+    pub fn enabled() -> bool {
+        crate::settings::get().otlp_endpoint.is_some()
+    }
+
+    fn provider() -> Option<&'static SdkTracerProvider> {
+        PROVIDER
+            .get_or_init(|| {
+                let endpoint = crate::settings::get().otlp_endpoint.as_deref()?;
+                install(endpoint)
+                    .map_err(|err| {
+                        eprintln!("[otlp] warn: cannot export to {}: {:#}", endpoint, err)
+                    })
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    /// Build the exporter and provider, and route `tracing` spans to it.
+    fn install(endpoint: &str) -> Result<SdkTracerProvider> {
+        let base = endpoint.trim_end_matches('/');
+        let url = if base.ends_with("/v1/traces") {
+            base.to_string()
+        } else {
+            format!("{}/v1/traces", base)
+        };
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(url)
+            .with_timeout(TIMEOUT)
+            .build()
+            .context("build the OTLP exporter")?;
+        let batches = BatchSpanProcessor::builder(exporter)
+            .with_batch_config(
+                BatchConfigBuilder::default()
+                    .with_max_queue_size(QUEUE)
+                    .with_max_export_batch_size(BATCH)
+                    .build(),
+            )
+            .build();
+        let resource = Resource::builder()
+            .with_service_name("matcher")
+            .with_attributes(RESOURCE.lock().unwrap().clone())
+            .build();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(batches)
+            .with_resource(resource)
+            .build();
+        let scope = InstrumentationScope::builder("matcher")
+            .with_version(env!("CARGO_PKG_VERSION"))
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer_with_scope(scope));
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+            .context("another tracing subscriber is installed")?;
+        Ok(provider)
+    }
+
+    fn otel_value(v: Value) -> opentelemetry::Value {
+        match v {
+            Value::Bool(b) => b.into(),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => i.into(),
+                None => n.as_f64().unwrap_or(f64::NAN).into(),
+            },
+            Value::String(s) => s.into(),
+            other => other.to_string().into(),
+        }
+    }
+
+    /// Host and batch-job attributes sent with every export.
+    pub fn set_resource(host: &HostInfo) {
+        if !enabled() {
+            return;
+        }
+        let mut res = vec![
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            KeyValue::new("host.name", host.hostname.clone()),
+            KeyValue::new("process.pid", std::process::id() as i64),
+        ];
+        res.extend(
+            host.job
+                .iter()
+                .map(|(k, v)| KeyValue::new(k.clone(), v.clone())),
+        );
+        *RESOURCE.lock().unwrap() = res;
+    }
+
+    /// Span id of the current run, starting one (in a new trace) if none is open.
+    fn run_id() -> Option<Id> {
+        RUN.lock()
+            .unwrap()
+            .get_or_insert_with(|| tracing::info_span!(parent: None, "matcher", otel.name = "run"))
+            .id()
+    }
+
+    /// Attach `key` to the `run` span.
+    pub fn run_attr(key: &str, value: impl Into<Value>) {
+        if provider().is_none() {
+            return;
+        }
+        run_id();
+        if let Some(run) = RUN.lock().unwrap().as_ref() {
+            run.set_attribute(key.to_string(), otel_value(value.into()));
+        }
+    }
+
+    /// An open span, ended when dropped. Inert when export is off.
+    pub struct Span {
+        span: tracing::Span,
+        /// For a phase, the phase it replaced as parent of new spans.
+        prev_phase: Option<Option<Id>>,
+    }
+
+    impl Span {
+        fn inert() -> Self {
+            Span {
+                span: tracing::Span::none(),
+                prev_phase: None,
+            }
+        }
+
+        pub fn attr(&mut self, key: &str, value: impl Into<Value>) {
+            if !self.span.is_none() {
+                self.span
+                    .set_attribute(key.to_string(), otel_value(value.into()));
+            }
+        }
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            if let Some(prev) = self.prev_phase.take() {
+                *PHASE.lock().unwrap() = prev;
+            }
+        }
+    }
+
+    /// A span under the open phase (or the run).
+    pub fn span(name: &str) -> Span {
+        if provider().is_none() {
+            return Span::inert();
+        }
+        let parent = PHASE.lock().unwrap().clone().or_else(run_id);
+        Span {
+            span: tracing::info_span!(parent: parent, "matcher", otel.name = name),
+            prev_phase: None,
+        }
+    }
+
+    /// A span under the run that parents the spans opened until it is dropped.
+    pub fn phase(name: &str) -> Span {
+        if provider().is_none() {
+            return Span::inert();
+        }
+        let span = tracing::info_span!(parent: run_id(), "matcher", otel.name = name);
+        let prev = std::mem::replace(&mut *PHASE.lock().unwrap(), span.id());
+        Span {
+            span,
+            prev_phase: Some(prev),
+        }
+    }
+
+    /// Close the run span (with an error status if `outcome` is one) and
+    /// flush it with everything still queued; the next span starts a fresh
+    /// run. Does nothing when no span was recorded, so subcommands export
+    /// nothing.
+    pub fn finish(outcome: Option<&anyhow::Error>) {
+        let Some(run) = RUN.lock().unwrap().take() else {
+            return;
+        };
+        let Some(provider) = PROVIDER.get().and_then(Option::as_ref) else {
+            return;
+        };
+        run.set_attribute(
+            "process.command_args",
+            std::env::args().skip(1).collect::<Vec<_>>().join(" "),
+        );
+        if let Some(err) = outcome {
+            run.set_status(Status::error(format!("{:#}", err)));
+        }
+        let trace_id = run.context().span().span_context().trace_id();
+        drop(run);
+        let endpoint = crate::settings::try_get()
+            .and_then(|s| s.otlp_endpoint.clone())
+            .unwrap_or_default();
+        match provider.force_flush() {
+            Ok(()) => eprintln!("[otlp] exported trace {} to {}", trace_id, endpoint),
+            Err(err) => eprintln!("[otlp] warn: export to {} failed: {}", endpoint, err),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Instant;
+
+use crate::enumeration::spill;
+use crate::human::{Bytes, Secs};
+
+/// ENUM_PEND_FLUSH values tried around the 32_768 default.
+const PEND_FLUSH_CANDIDATES: [usize; 4] = [8_192, 32_768, 131_072, 524_288];
+
+/// A trial within this fraction of the fastest counts as a tie; ties go to the
+/// lower peak RSS.
+const TIE_FRACTION: f64 = 0.05;
+
+struct Trial {
+    pend_flush: usize,
+    threads: usize,
+    wall: f64,
+    peak_rss: u64,
+}
+
+/// Thread counts to try: the detected pool size, then halvings down to 1.
+fn thread_candidates() -> Vec<usize> {
+    let mut t = rayon::current_num_threads().max(1);
+    let mut out = vec![t];
+    while t > 1 {
+        t /= 2;
+        out.push(t);
+    }
+    out
+}
+
+/// Enumerate one calibration slice in a child process, returning its wall time
+/// and peak RSS.
+fn run_trial(input: &Path, slice: usize, pend_flush: usize, threads: usize) -> Result<Trial> {
+    let exe = std::env::current_exe().context("locate matcher binary")?;
+    let snapshot = spill::spill_path(&format!("tune_{}.npz", std::process::id()));
+    let t0 = Instant::now();
+    let child = Command::new(exe)
+        .arg(input)
+        .arg(&snapshot)
+        .args(["--force-enumerate", "--skip-match"])
+        .env("ENUM_FIRST_LIMIT", slice.to_string())
+        .env("ENUM_PEND_FLUSH", pend_flush.to_string())
+        .env("MATCHER_THREADS", threads.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("spawn calibration run")?;
+
+    let waited = wait_with_peak_rss(child);
+    let wall = t0.elapsed().as_secs_f64();
+    let _ = std::fs::remove_file(&snapshot);
+    let (success, status, peak_rss) = waited?;
+    if !success {
+        bail!(
+            "calibration run (ENUM_PEND_FLUSH={} MATCHER_THREADS={}) failed with status {}",
+            pend_flush,
+            threads,
+            status
+        );
+    }
+    Ok(Trial {
+        pend_flush,
+        threads,
+        wall,
+        peak_rss,
+    })
+}
+
+/// Reap `child`: whether it exited 0, its raw wait status, and its peak RSS in
+/// bytes (from wait4, so each trial is measured on its own).
+#[cfg(feature = "platform")]
+fn wait_with_peak_rss(child: Child) -> Result<(bool, i32, u64)> {
+    let mut status = 0;
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: the pid is our own unreaped child; status and usage are valid out-params.
+    let rc = unsafe {
+        libc::wait4(
+            child.id() as libc::pid_t,
+            &mut status,
+            0,
+            usage.as_mut_ptr(),
+        )
+    };
+    if rc < 0 {
+        bail!("wait4: {}", std::io::Error::last_os_error());
+    }
+    let success = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+    // SAFETY: wait4 succeeded, so usage is filled in.
+    let usage = unsafe { usage.assume_init() };
+    Ok((success, status, usage.ru_maxrss as u64 * 1024))
+}
+
+/// Without `platform` peak RSS is not measured (0), so near-ties go to the
+/// first candidate rather than the leanest.
+#[cfg(not(feature = "platform"))]
+fn wait_with_peak_rss(mut child: Child) -> Result<(bool, i32, u64)> {
+    let status = child.wait().context("wait for calibration run")?;
+    Ok((status.success(), status.code().unwrap_or(-1), 0))
+}
+
+/// `matcher tune inputs.npz`: time one enumeration slice (the first `slice`
+/// pre_jbt of root (0,0)) for every ENUM_PEND_FLUSH x thread-count candidate,
+/// then write the fastest (lowest RSS among near-ties) to `config_out` as
+/// `KEY=VALUE` lines that `matcher --config` reads.
+pub fn tune(input: &Path, slice: usize, config_out: &Path) -> Result<()> {
+    let mut trials = Vec::new();
+    for threads in thread_candidates() {
+        for pend_flush in PEND_FLUSH_CANDIDATES {
+            let t = run_trial(input, slice, pend_flush, threads)?;
+            eprintln!(
+                "[tune] pend_flush={:>7} threads={:>3}: wall={} peak_rss={}",
+                t.pend_flush,
+                t.threads,
+                Secs(t.wall),
+                Bytes(t.peak_rss)
+            );
+            trials.push(t);
+        }
+    }
+
+    let fastest = trials.iter().map(|t| t.wall).fold(f64::INFINITY, f64::min);
+    let best = trials
+        .iter()
+        .filter(|t| t.wall <= fastest * (1.0 + TIE_FRACTION))
+        .min_by_key(|t| t.peak_rss)
+        .context("no calibration trials ran")?;
+
+    let text = format!(
+        "# written by `matcher tune {}` (slice of {} pre_jbt, {}, peak RSS {})\n\
+         # variables already set in the environment take precedence\n\
+         ENUM_PEND_FLUSH={}\n\
+         MATCHER_THREADS={}\n",
+        input.display(),
+        slice,
+        Secs(best.wall),
+        Bytes(best.peak_rss),
+        best.pend_flush,
+        best.threads
+    );
+    std::fs::write(config_out, text).with_context(|| format!("write {}", config_out.display()))?;
+    println!(
+        "recommended: ENUM_PEND_FLUSH={} MATCHER_THREADS={} (written to {}; run with --config {})",
+        best.pend_flush,
+        best.threads,
+        config_out.display(),
+        config_out.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use anyhow::{Context, Result, bail};
+use ndarray_npy::NpzReader;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::human::Secs;
+use crate::matching::npz_check::check_not_hdf5;
+
+/// Subdirectories of the watched dir that processed inputs are moved into.
+const DONE_DIR: &str = "processed";
+const FAILED_DIR: &str = "failed";
+
+/// Last observed (len, mtime) of a candidate input, and whether it was already
+/// reported as not ready.
+struct Seen {
+    len: u64,
+    mtime: Option<SystemTime>,
+    logged_unreadable: bool,
+}
+
+/// `<input>.done`, written by producers that want to mark an input complete.
+fn sentinel_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".done");
+    PathBuf::from(s)
+}
+
+/// True if another process holds an exclusive advisory lock (`flock`) on the
+/// file, i.e. a cooperating writer is still copying it in.
+#[cfg(feature = "platform")]
+fn locked_by_writer(path: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+    let Ok(f) = File::open(path) else {
+        return false;
+    };
+    let fd = f.as_raw_fd();
+    // SAFETY: fd is a valid open descriptor for the lifetime of `f`.
+    let rc = unsafe { libc::flock(fd, libc::LOCK_SH | libc::LOCK_NB) };
+    if rc != 0 {
+        return std::io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK);
+    }
+    unsafe { libc::flock(fd, libc::LOCK_UN) };
+    false
+}
+
+/// Without `platform` writer locks are not checked; the size/mtime settling
+/// and the zip directory check still apply.
+#[cfg(not(feature = "platform"))]
+fn locked_by_writer(_path: &Path) -> bool {
+    false
+}
+
+/// Checks that do not need a previous observation: sentinel present (when
+/// required), no writer lock, and the zip central directory parses (a
+/// truncated copy does not). Returns why the file is not ready yet.
+fn not_ready_reason(path: &Path, require_sentinel: bool) -> Option<String> {
+    if require_sentinel && !sentinel_path(path).exists() {
+        return Some(format!("no {} sentinel yet", sentinel_path(path).display()));
+    }
+    if locked_by_writer(path) {
+        return Some("a writer holds a lock on it".to_string());
+    }
+    let readable = File::open(path)
+        .ok()
+        .is_some_and(|f| NpzReader::new(f).is_ok());
+    if !readable {
+        return Some("not a readable npz yet".to_string());
+    }
+    None
+}
+
+/// One-shot guard for direct runs: poll the input every second until it is
+/// ready, failing after `wait` (immediately when `wait` is zero).
+pub fn wait_for_input(path: &Path, require_sentinel: bool, wait: Duration) -> Result<()> {
+    if let Ok(mut f) = File::open(path) {
+        // no amount of waiting turns it into an NPZ
+        check_not_hdf5(&mut f, &path.display().to_string())?;
+    }
+    let t0 = Instant::now();
+    let mut logged = false;
+    loop {
+        let Some(reason) = not_ready_reason(path, require_sentinel) else {
+            return Ok(());
+        };
+        if t0.elapsed() >= wait {
+            bail!("input {} is not complete: {}", path.display(), reason);
+        }
+        if !logged {
+            eprintln!(
+                "[input] {}: {}; waiting up to {}",
+                path.display(),
+                reason,
+                Secs::from(wait)
+            );
+            logged = true;
+        }
+        std::thread::sleep(Duration::from_secs(1).min(wait));
+    }
+}
+
+fn is_candidate(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    // skip dotfiles / in-flight temp names (rsync, `cp` to .part, ...)
+    path.is_file() && !name.starts_with('.') && name.ends_with(".npz")
+}
+
+/// An input is ready once its size and mtime held still for a full poll
+/// interval and `not_ready_reason` has no objection.
+fn ready(path: &Path, seen: &mut HashMap<PathBuf, Seen>, require_sentinel: bool) -> bool {
+    let Ok(meta) = fs::metadata(path) else {
+        return false;
+    };
+    let (len, mtime) = (meta.len(), meta.modified().ok());
+    let prev = seen.insert(
+        path.to_path_buf(),
+        Seen {
+            len,
+            mtime,
+            logged_unreadable: false,
+        },
+    );
+    let Some(prev) = prev else {
+        return false; // first sighting: wait one interval
+    };
+    if prev.len != len || prev.mtime != mtime {
+        return false;
+    }
+    let Some(reason) = not_ready_reason(path, require_sentinel) else {
+        return true;
+    };
+    if !prev.logged_unreadable {
+        eprintln!("[watch] {}: {}", path.display(), reason);
+    }
+    if let Some(s) = seen.get_mut(path) {
+        s.logged_unreadable = true;
+    }
+    false
+}
+
+fn move_into(path: &Path, sub: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(sub).with_context(|| format!("create dir {}", sub.display()))?;
+    let dest = sub.join(path.file_name().context("input has no file name")?);
+    fs::rename(path, &dest)
+        .with_context(|| format!("move {} -> {}", path.display(), dest.display()))?;
+    let sentinel = sentinel_path(path);
+    if sentinel.exists() {
+        fs::rename(&sentinel, sentinel_path(&dest))
+            .with_context(|| format!("move {}", sentinel.display()))?;
+    }
+    Ok(dest)
+}
+
+/// Poll `dir` every `poll` for new `*.npz` inputs and hand each to `process`
+/// once fully written (and, with `require_sentinel`, once `<input>.done`
+/// exists). Inputs are then moved to `dir/processed/` (or
+/// `dir/failed/`, with the error written next to the outputs in `out`).
+/// Runs until the process is killed.
+pub fn watch_dir(
+    dir: &Path,
+    out: &Path,
+    poll: Duration,
+    require_sentinel: bool,
+    mut process: impl FnMut(&Path) -> Result<()>,
+) -> Result<()> {
+    fs::create_dir_all(out).with_context(|| format!("create dir {}", out.display()))?;
+    eprintln!(
+        "[watch] watching {} every {}; outputs -> {}",
+        dir.display(),
+        Secs::from(poll),
+        out.display()
+    );
+
+    let mut seen: HashMap<PathBuf, Seen> = HashMap::new();
+    loop {
+        let mut inputs: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("read dir {}", dir.display()))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| is_candidate(p))
+            .collect();
+        inputs.sort();
+        seen.retain(|p, _| inputs.contains(p));
+
+        for input in inputs {
+            if !ready(&input, &mut seen, require_sentinel) {
+                continue;
+            }
+            seen.remove(&input);
+            eprintln!("[watch] processing {}", input.display());
+            let res = process(&input);
+            crate::telemetry::finish(res.as_ref().err());
+            match res {
+                Ok(()) => {
+                    let dest = move_into(&input, &dir.join(DONE_DIR))?;
+                    eprintln!("[watch] done; input moved to {}", dest.display());
+                }
+                Err(err) => {
+                    eprintln!("[watch] failed on {}: {:#}", input.display(), err);
+                    let stem = input
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .unwrap_or("input");
+                    let err_path = out.join(format!("{stem}_error.txt"));
+                    fs::write(&err_path, format!("{:#}\n", err))
+                        .with_context(|| format!("write {}", err_path.display()))?;
+                    move_into(&input, &dir.join(FAILED_DIR))?;
+                }
+            }
+        }
+        std::thread::sleep(poll);
+    }
+}
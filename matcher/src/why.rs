@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Value, json};
+use std::collections::BTreeSet;
+
+use crate::human::Secs;
+use crate::matching::MatchConfig;
+use crate::matching::report::fmt_value;
+use crate::runtime::{HostInfo, THREAD_ENV_HINTS};
+
+/// Settings that change which states or pairs are counted, so a difference in
+/// one of them can explain a different Omega; everything else but the command
+/// line and job ids moves timings.
+const OMEGA_KEYS: [&str; 8] = [
+    "version",
+    "config.neutral",
+    "config.pairs",
+    "config.given",
+    "env.ENUM_FIRST_LIMIT",
+    "env.ENUM_MIN_WEIGHT",
+    "env.ENUM_CODE_BITS",
+    "env.ENUM_WEIGHT_BITS",
+];
+
+/// The `run` object stored in results JSON: crate version, thread pool, host
+/// (CPU model, RAM, batch job ids), command line, matching config and every
+/// variable the run consumed: `ENUM_*` / `MATCHER_*` / `RAYON_*` (including
+/// those read from `--config`) and the thread-count hints.
+pub fn run_info(cfg: &MatchConfig, host: &HostInfo) -> Value {
+    let env: Map<String, Value> = std::env::vars()
+        .filter(|(k, _)| {
+            ["ENUM_", "MATCHER_", "RAYON_"]
+                .iter()
+                .any(|p| k.starts_with(p))
+                || THREAD_ENV_HINTS.contains(&k.as_str())
+        })
+        .map(|(k, v)| (k, Value::String(v)))
+        .collect();
+    let job: Map<String, Value> = host
+        .job
+        .iter()
+        .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "threads": rayon::current_num_threads(),
+        "cpus": host.cpus,
+        "host": host.hostname,
+        "cpu_model": host.cpu_model,
+        "ram_bytes": host.ram_bytes,
+        "job": job,
+        "args": std::env::args().skip(1).collect::<Vec<_>>().join(" "),
+        "config": {
+            "neutral": format!("{:?}", cfg.neutral),
+            "pairs": cfg.pair_whitelist.as_ref().map(|w| w.len()),
+            "report_every_rows": cfg.report_every_rows,
+            "audit_determinism": cfg.audit_determinism,
+            "exact": cfg.exact,
+            "given": cfg.given.map(|g| g.to_string()),
+            "normalize": cfg.normalize.map(|n| format!("{:?}", n)),
+        },
+        "env": env,
+    })
+}
+
+/// `a.b.c` -> rendered leaf value, for the `run` object of one results file.
+fn flatten(prefix: &str, v: &Value, out: &mut Vec<(String, String)>) {
+    match v {
+        Value::Object(m) => {
+            for (k, v) in m {
+                let key = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                flatten(&key, v, out);
+            }
+        }
+        Value::String(s) => out.push((prefix.to_string(), s.clone())),
+        other => out.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+fn load(path: &str) -> Result<Value> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path))?;
+    serde_json::from_str(&text).with_context(|| format!("parse {}", path))
+}
+
+/// `matcher why old.json new.json`: the Omega and wall-time change between two
+/// results files, then every recorded setting that differs, the ones that can
+/// change Omega first. Files written before run info was recorded compare on
+/// Omega and wall time only.
+pub fn why(path_a: &str, path_b: &str) -> Result<()> {
+    let (a, b) = (load(path_a)?, load(path_b)?);
+    let num = |d: &Value, k: &str| d.get(k).and_then(Value::as_f64);
+    if let (Some(oa), Some(ob)) = (num(&a, "omega"), num(&b, "omega")) {
+        let rel = if oa != 0.0 { (ob - oa) / oa.abs() } else { 0.0 };
+        println!(
+            "omega: {} -> {}{}",
+            fmt_value(oa, None),
+            fmt_value(ob, None),
+            if oa == ob {
+                " (identical)".to_string()
+            } else {
+                format!(" (rel {:+.3e})", rel)
+            }
+        );
+    }
+    if let (Some(wa), Some(wb)) = (num(&a, "wall"), num(&b, "wall")) {
+        println!(
+            "wall:  {} -> {} (x{:.2})",
+            Secs(wa),
+            Secs(wb),
+            wb / wa.max(f64::MIN_POSITIVE)
+        );
+    }
+
+    let (Some(run_a), Some(run_b)) = (a.get("run"), b.get("run")) else {
+        for (path, doc) in [(path_a, &a), (path_b, &b)] {
+            if doc.get("run").is_none() {
+                println!(
+                    "{}: no run info recorded (written by an older matcher)",
+                    path
+                );
+            }
+        }
+        return Ok(());
+    };
+    let (mut fa, mut fb) = (Vec::new(), Vec::new());
+    flatten("", run_a, &mut fa);
+    flatten("", run_b, &mut fb);
+    let get = |f: &[(String, String)], k: &str| {
+        f.iter()
+            .find(|(key, _)| key == k)
+            .map(|(_, v)| v.clone())
+            .unwrap_or_else(|| "(unset)".to_string())
+    };
+    let keys: BTreeSet<&String> = fa.iter().chain(&fb).map(|(k, _)| k).collect();
+    let tag = |k: &str| match k {
+        _ if OMEGA_KEYS.contains(&k) => "omega",
+        _ if k == "args" || k.starts_with("job.") => "context",
+        _ => "timing",
+    };
+    let mut diffs: Vec<(&str, &String, String, String)> = keys
+        .into_iter()
+        .map(|k| (tag(k), k, get(&fa, k), get(&fb, k)))
+        .filter(|(_, _, va, vb)| va != vb)
+        .collect();
+    if diffs.is_empty() {
+        println!("settings: identical");
+        return Ok(());
+    }
+    // omega-relevant first, then timing, then the command line and job ids
+    let order = ["omega", "timing", "context"];
+    diffs.sort_by_key(|&(t, k, _, _)| (order.iter().position(|&x| x == t), k));
+    for (t, k, va, vb) in diffs {
+        println!("[{}] {}: {} -> {}", t, k, va, vb);
+    }
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! The golden fixtures of `data/golden.json`, run through `matcher check`.
+//! Slow (an N = 8 enumeration), so ignored by default:
+//! `cargo test --release --test golden -- --ignored`.
+
+use std::process::Command;
+
+const BASELINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/golden.json");
+
+#[test]
+#[ignore]
+fn n8_first5_matches_its_recorded_results() {
+    let recorded: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../data/golden_n8_first5.json"
+        ))
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(recorded["omega"].as_f64(), Some(4292655082.0));
+
+    let out = Command::new(env!("CARGO_BIN_EXE_matcher"))
+        .args(["check", "--baseline", BASELINE])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success(),
+        "{}\n{}",
+        stdout,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(stdout.contains("[check] n8_first5: ok"), "{}", stdout);
+}